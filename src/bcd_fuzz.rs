@@ -0,0 +1,222 @@
+//! Differential property tests for the ROM's BCD arithmetic
+//!
+//! Calls `signed_add` and the `eval_add/sub/mul/div` operator handlers in
+//! the emulated Z80 with random signed 6.2 fixed-point operands and checks
+//! the result, its sign byte and the carry (overflow/error) flag against
+//! exact integer arithmetic on the host: a result too big for 8 digits
+//! must set the carry rather than wrap. Magnitudes are drawn with a
+//! random number of digits so small values, zeros and negative zeros turn
+//! up as often as full 8-digit ones.
+//!
+//! On failure proptest shrinks towards the minimal failing operands and
+//! prints them. Set `PROPTEST_CASES` to run more cases, e.g.
+//! `PROPTEST_CASES=100000 cargo test --release bcd_fuzz`.
+
+use std::cell::RefCell;
+
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestRunner};
+
+use crate::codegen::{BCD_TEMP1, BCD_TEMP2, SIGN_ACCUM, SIGN_OP};
+use crate::harness::{Harness, StopReason};
+use crate::model::Bcd;
+use crate::z80::FLAG_C;
+
+/// Largest magnitude that fits in 8 BCD digits, in hundredths
+const MAX_HUNDREDTHS: i128 = 99_999_999;
+
+/// Cycle budget for one call; long division needs well under 100k
+const CALL_CYCLES: u64 = 1_000_000;
+
+/// Sign-magnitude operand as the ROM stores it
+#[derive(Clone, Copy, Debug)]
+struct Operand {
+    negative: bool,
+    hundredths: u32,
+}
+
+impl Operand {
+    fn sign(self) -> u8 {
+        if self.negative {
+            0x80
+        } else {
+            0x00
+        }
+    }
+
+    fn exact(self) -> i128 {
+        let v = self.hundredths as i128;
+        if self.negative {
+            -v
+        } else {
+            v
+        }
+    }
+}
+
+/// Signed operand with 0 to 8 significant digits (so `-0.00` can occur)
+fn operand() -> impl Strategy<Value = Operand> {
+    (any::<bool>(), 0u32..=8, 0u32..100_000_000).prop_map(|(negative, digits, v)| Operand {
+        negative,
+        hundredths: v % 10u32.pow(digits),
+    })
+}
+
+/// What the ROM should leave behind for one operation
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    carry: bool,
+    /// Signed result in hundredths; `None` when the carry reports an error
+    value: Option<i128>,
+}
+
+/// Set up `acc op operand`, call `routine` and read back the result
+///
+/// The accumulator goes in BCD_TEMP2/SIGN_ACCUM and the operand in
+/// BCD_TEMP1/SIGN_OP, as `eval_pop` leaves them before dispatching.
+fn run(
+    h: &mut Harness,
+    routine: &str,
+    acc: Operand,
+    op: Operand,
+) -> Result<(Outcome, u8), TestCaseError> {
+    let addr = h.symbol(routine).expect("routine not in symbol table");
+    let layout = *h.layout();
+    let var = |offset| layout.var(offset);
+    h.poke_bytes(var(BCD_TEMP2), &Bcd::from_hundredths(acc.hundredths).0);
+    h.poke(var(SIGN_ACCUM), acc.sign());
+    h.poke_bytes(var(BCD_TEMP1), &Bcd::from_hundredths(op.hundredths).0);
+    h.poke(var(SIGN_OP), op.sign());
+
+    let stop = h.call(addr, CALL_CYCLES);
+    prop_assert_eq!(stop, StopReason::Returned, "{} did not return", routine);
+
+    let carry = h.cpu.f & FLAG_C != 0;
+    let sign = h.peek(var(SIGN_ACCUM));
+    let bcd = Bcd(h.peek_bytes(var(BCD_TEMP1), 4).try_into().unwrap());
+    let magnitude = bcd.hundredths();
+    prop_assert!(
+        magnitude.is_some(),
+        "{} left invalid BCD {:?}",
+        routine,
+        bcd
+    );
+    prop_assert!(
+        sign == 0x00 || sign == 0x80,
+        "{} left sign byte {:02X}",
+        routine,
+        sign
+    );
+    let magnitude = magnitude.unwrap() as i128;
+    let value = if sign == 0x80 { -magnitude } else { magnitude };
+    Ok((
+        Outcome {
+            carry,
+            value: Some(value),
+        },
+        sign,
+    ))
+}
+
+/// Exact result reduced the way the ROM keeps it: 8 digits, sign separate
+///
+/// Overflow wraps the magnitude modulo 10^8; `overflow_carry` says whether
+/// the routine reports it in the carry flag.
+fn expect(exact: i128, overflow_carry: bool) -> Outcome {
+    let magnitude = exact.abs() % (MAX_HUNDREDTHS + 1);
+    Outcome {
+        carry: overflow_carry && exact.abs() > MAX_HUNDREDTHS,
+        value: Some(if exact < 0 { -magnitude } else { magnitude }),
+    }
+}
+
+/// Run `check` against a freshly booted ROM for every generated operand pair
+fn fuzz(check: impl Fn(&mut Harness, Operand, Operand) -> Result<(), TestCaseError>) {
+    let h = RefCell::new(Harness::spreadsheet());
+    let mut runner = TestRunner::new(Config::default());
+    if let Err(e) = runner.run(&(operand(), operand()), |(acc, op)| {
+        check(&mut h.borrow_mut(), acc, op)
+    }) {
+        panic!("{}", e);
+    }
+}
+
+/// Check an `eval_*` handler: overflow is an error, zero is never negative
+fn check_eval(
+    h: &mut Harness,
+    routine: &str,
+    acc: Operand,
+    op: Operand,
+    exact: Option<i128>,
+) -> Result<(), TestCaseError> {
+    let (mut got, sign) = run(h, routine, acc, op)?;
+    let want = match exact {
+        Some(exact) if exact.abs() <= MAX_HUNDREDTHS => expect(exact, false),
+        _ => Outcome {
+            carry: true,
+            value: None,
+        },
+    };
+    if got.carry {
+        got.value = None;
+    } else if got.value == Some(0) {
+        prop_assert_eq!(sign, 0x00, "{} produced -0.00", routine);
+    }
+    prop_assert_eq!(got, want, "{} {:?} {:?}", routine, acc, op);
+    Ok(())
+}
+
+#[test]
+fn test_signed_add() {
+    fuzz(|h, acc, op| {
+        // Negative zero is allowed here; eval_norm clears it
+        let (got, _) = run(h, "signed_add", acc, op)?;
+        prop_assert_eq!(
+            got,
+            expect(acc.exact() + op.exact(), true),
+            "{:?} + {:?}",
+            acc,
+            op
+        );
+        Ok(())
+    });
+}
+
+#[test]
+fn test_eval_add() {
+    fuzz(|h, acc, op| check_eval(h, "eval_add", acc, op, Some(acc.exact() + op.exact())));
+}
+
+#[test]
+fn test_eval_sub() {
+    fuzz(|h, acc, op| check_eval(h, "eval_sub", acc, op, Some(acc.exact() - op.exact())));
+}
+
+#[test]
+fn test_eval_mul() {
+    // Truncated towards zero: the magnitude is scaled, then the sign applied
+    fuzz(|h, acc, op| {
+        let magnitude = acc.exact().abs() * op.exact().abs() / 100;
+        let exact = if acc.negative != op.negative {
+            -magnitude
+        } else {
+            magnitude
+        };
+        check_eval(h, "eval_mul", acc, op, Some(exact))
+    });
+}
+
+#[test]
+fn test_eval_div() {
+    fuzz(|h, acc, op| {
+        let exact = (op.hundredths != 0).then(|| {
+            let magnitude = acc.exact().abs() * 100 / op.exact().abs();
+            if acc.negative != op.negative {
+                -magnitude
+            } else {
+                magnitude
+            }
+        });
+        check_eval(h, "eval_div", acc, op, exact)
+    });
+}
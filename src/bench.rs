@@ -0,0 +1,193 @@
+//! Recalculation timing on the emulated board
+//!
+//! [`time_recalc`] puts a worksheet into RAM as the model holds it, so
+//! sheets too large to bake into the ROM can be timed as well, and runs
+//! `recalc_all` to completion. Each instruction's T-states go to the
+//! nearest ROM label at or below it; labels share the prefix of the
+//! routine they belong to (`bcd_` arithmetic, `parse_` operands,
+//! `pf_` functions, `eval_` the expression parser, `recalc_` the sweep;
+//! `lex_` and `atob_` compile formula text at entry and should not show
+//! up), so [`Timing::routines`] shows where the time goes.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::harness::{Harness, StopReason};
+use crate::model::Sheet;
+use crate::SpreadsheetCodeGen;
+
+/// Clock the report converts T-states at (a RetroShield Z80 runs at 4MHz)
+pub const CLOCK_HZ: u64 = 4_000_000;
+
+/// Longest recalculation timed (a minute at 4MHz)
+const MAX_CYCLES: u64 = 60 * CLOCK_HZ;
+
+/// Labels and routines listed by the report
+const REPORT_LINES: usize = 12;
+
+/// Cost of one recalculation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timing {
+    /// T-states from the call to `recalc_all` to its return
+    pub cycles: u64,
+    /// Formula cells on the sheet
+    pub formulas: usize,
+    /// T-states per ROM label, most first
+    pub labels: Vec<(String, u64)>,
+}
+
+impl Timing {
+    /// Seconds at [`CLOCK_HZ`]
+    pub fn seconds(&self) -> f64 {
+        self.cycles as f64 / CLOCK_HZ as f64
+    }
+
+    /// T-states per routine, the label up to its first `_`, most first
+    pub fn routines(&self) -> Vec<(String, u64)> {
+        let mut totals: HashMap<&str, u64> = HashMap::new();
+        for (label, cycles) in &self.labels {
+            let routine = label.split('_').next().unwrap_or(label);
+            *totals.entry(routine).or_default() += cycles;
+        }
+        let mut routines: Vec<_> = totals
+            .into_iter()
+            .map(|(r, c)| (r.to_string(), c))
+            .collect();
+        routines.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        routines
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "recalc_all: {} T-states, {:.3} s at {} MHz, {} formulas",
+            self.cycles,
+            self.seconds(),
+            CLOCK_HZ / 1_000_000,
+            self.formulas
+        )?;
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.cycles.max(1) as f64;
+        writeln!(f, "\nBy routine:")?;
+        for (routine, cycles) in self.routines().iter().take(REPORT_LINES) {
+            writeln!(
+                f,
+                "  {:>10} {:5.1}%  {}_*",
+                cycles,
+                percent(*cycles),
+                routine
+            )?;
+        }
+        writeln!(f, "\nBy label:")?;
+        for (label, cycles) in self.labels.iter().take(REPORT_LINES) {
+            writeln!(f, "  {:>10} {:5.1}%  {}", cycles, percent(*cycles), label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Time one `!` recalculation of `sheet` on a ROM for its layout;
+/// `Err` with why the run stopped if `recalc_all` did not return
+pub fn time_recalc(sheet: &Sheet) -> Result<Timing, StopReason> {
+    let layout = *sheet.layout();
+    let mut codegen = SpreadsheetCodeGen::new();
+    codegen.set_layout(layout);
+    let mut h = Harness::boot(codegen);
+    h.load_sheet(sheet);
+
+    let symbols: Vec<(String, u16)> = h
+        .symbols()
+        .into_iter()
+        .map(|(n, a)| (n.to_string(), a))
+        .collect();
+    let recalc = h.symbol("recalc_all").expect("ROM has recalc_all");
+    let mut per_label = vec![0u64; symbols.len()];
+    let start = h.cpu.cycles;
+    let stop = h.call_traced(recalc, MAX_CYCLES, |pc, cycles| {
+        let i = symbols.partition_point(|&(_, addr)| addr <= pc);
+        if i > 0 {
+            per_label[i - 1] += cycles;
+        }
+    });
+    if stop != StopReason::Returned {
+        return Err(stop);
+    }
+
+    let mut labels: Vec<_> = symbols
+        .into_iter()
+        .zip(per_label)
+        .filter(|&(_, cycles)| cycles > 0)
+        .map(|((label, _), cycles)| (label, cycles))
+        .collect();
+    labels.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let formulas = (0..layout.rows())
+        .flat_map(|row| (0..layout.cols()).map(move |col| (col, row)))
+        .filter(|&(col, row)| sheet.text(col, row).is_some_and(|t| t.starts_with('=')))
+        .count();
+    Ok(Timing {
+        cycles: h.cpu.cycles - start,
+        formulas,
+        labels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Layout;
+    use crate::template;
+
+    #[test]
+    fn test_time_recalc() {
+        // More formulas than the default 960-byte heap holds
+        let layout = Layout::new(0x8000, 0x8000, 16, 64).unwrap();
+        let csv: String = (1..=30)
+            .map(|r| format!("{}.25,=A{}*1.05+2.5,=@SUM(A{}:B{})/3\n", r, r, r, r))
+            .collect();
+        let sheet = template::load(&csv, layout).unwrap();
+        let timing = time_recalc(&sheet).unwrap();
+        assert_eq!(timing.formulas, 60);
+        let charged: u64 = timing.labels.iter().map(|(_, c)| c).sum();
+        assert_eq!(charged, timing.cycles);
+        assert!(timing.labels.windows(2).all(|w| w[0].1 >= w[1].1));
+
+        // Division and multiplication dominate; recalculation reads the
+        // tokens, so no formula text is scanned or converted
+        let routines = timing.routines();
+        assert_eq!(routines[0].0, "bcd");
+        let cycles = |name: &str| routines.iter().find(|(r, _)| r == name).map_or(0, |r| r.1);
+        assert!(cycles("bcd") * 100 / timing.cycles > 40, "{}", timing);
+        assert_eq!(cycles("lex") + cycles("atob"), 0, "{}", timing);
+        assert!(cycles("parse") * 100 / timing.cycles < 5, "{}", timing);
+        assert!(timing.to_string().starts_with("recalc_all: "));
+    }
+
+    #[test]
+    fn test_time_recalc_chain() {
+        // 63 formulas each reading the cell below, and the same chain
+        // pointing down: evaluating what a formula waits for first makes
+        // the upward one cost about the same rather than a sweep per link
+        let up: String = (2..=64)
+            .map(|r| format!("=A{}+1\n", r))
+            .chain(["1\n".to_string()])
+            .collect();
+        let down: String = ["1\n".to_string()]
+            .into_iter()
+            .chain((1..=63).map(|r| format!("=A{}+1\n", r)))
+            .collect();
+        let time = |csv: &str| {
+            let layout = Layout::new(0x8000, 0x8000, 16, 64).unwrap();
+            let sheet = template::load(csv, layout).unwrap();
+            assert_eq!(
+                sheet.value(0, 0).unwrap().to_string(),
+                if csv == up { "64.00" } else { "1.00" }
+            );
+            time_recalc(&sheet).unwrap()
+        };
+        let (up, down) = (time(&up), time(&down));
+        assert_eq!((up.formulas, down.formulas), (63, 63));
+        assert!(up.seconds() < 0.5, "{}", up);
+        assert!(up.cycles < down.cycles * 3 / 2, "{}\n{}", up, down);
+    }
+}
@@ -7,11 +7,12 @@
 //!   0x0000-0x00FF  Startup, vectors
 //!   0x0100-0x1FFF  Spreadsheet engine
 //!
-//! RAM (8KB):
+//! RAM (default 8KB, see `layout` for other sizes):
 //!   0x2000-0x37FF  Cell data (6KB = 1024 cells x 6 bytes)
 //!   0x3800-0x38FF  Input buffer (256 bytes)
 //!   0x3900-0x39FF  Display line buffer (256 bytes)
-//!   0x3A00-0x3DFF  Formula parse buffer, scratch (1KB)
+//!   0x3A00-0x3DBF  Formula/label heap
+//!   0x3DC0-0x3DFF  State block: BCD work area, variables
 //!   0x3E00-0x3FFF  Stack (512 bytes)
 //!
 //! Cell format (6 bytes) - 8-digit packed BCD:
@@ -19,81 +20,280 @@
 //!   byte 1: sign (0x00=positive, 0x80=negative)
 //!   bytes 2-5: 8-digit packed BCD (big-endian: d7d6 d5d4 d3d2 d1d0)
 
+use std::fmt::{self, Write};
 use std::ops::{Deref, DerefMut};
 use retroshield_z80_workbench::CodeGen;
 
-/// Memory constants
-const STACK_TOP: u16 = 0x3FFF;
+use crate::disasm;
+use crate::layout::Layout;
+use crate::model::{CellError, Sheet};
+use crate::uart::UartProfile;
 
-// RAM layout
-const CELL_DATA: u16 = 0x2000;      // 6KB for cells (1024 x 6 bytes)
-const INPUT_BUF: u16 = 0x3800;      // 256 bytes
-const SCRATCH: u16 = 0x3A00;        // 1KB scratch/formula
+/// ROM size
+pub(crate) const ROM_SIZE: usize = 0x2000;  // 8KB ROM at 0x0000
+
+/// Deepest parenthesis nesting a formula may use; each level holds about
+/// 20 bytes of the 512-byte stack
+pub(crate) const MAX_NESTING: u8 = 16;
 
 // Cell size for BCD
-const CELL_SIZE: u8 = 6;            // 6 bytes per cell
-
-// Spreadsheet state (in scratch area, above formula storage)
-const CURSOR_COL: u16 = 0x3DF0;     // Current column (0-15)
-const CURSOR_ROW: u16 = 0x3DF1;     // Current row (0-63)
-const VIEW_TOP: u16 = 0x3DF2;       // Top visible row
-const VIEW_LEFT: u16 = 0x3DF3;      // Left visible column
-const INPUT_LEN: u16 = 0x3DF4;      // Input buffer length
-const INPUT_POS: u16 = 0x3DF5;      // Input cursor position
-const EDIT_MODE: u16 = 0x3DF6;      // 0=navigate, 1=edit
-const TEMP1: u16 = 0x3DF8;          // Temp storage
-const TEMP2: u16 = 0x3DFA;          // Temp storage
-const FORMULA_PTR: u16 = 0x3DFC;    // Next free position in formula storage
-const COL_WIDTH_VAR: u16 = 0x3DFE;  // Column width (default 9)
-const RANGE_ROW2: u16 = 0x3DE0;     // Range function end row
-const RANGE_COL2: u16 = 0x3DDA;     // Range function end column
-const RANGE_CUR_COL: u16 = 0x3DDB;  // Current column in range iteration
-const SIGN_ACCUM: u16 = 0x3DDC;     // Sign of formula accumulator (0x00=pos, 0x80=neg)
-const SIGN_OP: u16 = 0x3DDD;        // Sign of current operand
-const FUNC_TYPE: u16 = 0x3DE1;      // Function type: 0=SUM, 1=AVG, 2=MIN, 3=MAX, 4=COUNT
-const FUNC_COUNT: u16 = 0x3DE2;     // Cell count for AVG
-const FUNC_MINMAX: u16 = 0x3DE4;    // Min/max accumulator (16-bit)
-const FUNC_SIGN: u16 = 0x3DE6;      // Sign of function accumulator (0x00=pos, 0x80=neg)
-const FUNC_SIGN2: u16 = 0x3DE7;     // Sign of current cell value in function
-
-// BCD working storage (in scratch area, before state variables)
-const BCD_TEMP1: u16 = 0x3DC0;      // 4-byte BCD temp
-const BCD_TEMP2: u16 = 0x3DC4;      // 4-byte BCD temp
-const BCD_ACCUM: u16 = 0x3DC8;      // 8-byte BCD accumulator for mul (ends at 0x3DCF)
-const ATOB_FLAGS: u16 = 0x3DD0;     // 2 bytes: [0]=decimal seen flag, [1]=frac digit count
-const FUNC_BCD: u16 = 0x3DD2;       // 4-byte BCD for function SUM/MIN/MAX accumulator
-const FUNC_BCD2: u16 = 0x3DD6;      // 4-byte BCD temp for cell value in functions
+pub(crate) const CELL_SIZE: u8 = 6;            // 6 bytes per cell
+
+// State block offsets (see `Layout::var`); 0x3DC0 in the default layout
+
+// BCD working storage
+pub(crate) const BCD_TEMP1: u16 = 0x00;        // 4-byte BCD temp
+pub(crate) const BCD_TEMP2: u16 = 0x04;        // 4-byte BCD temp
+const BCD_ACCUM: u16 = 0x08;        // 8-byte BCD accumulator for mul (ends at +0x0F)
+const ATOB_FLAGS: u16 = 0x10;       // 2 bytes: [0]=decimal seen flag, [1]=frac digit count
+const FUNC_BCD: u16 = 0x12;         // 4-byte BCD for function SUM/MIN/MAX accumulator
+const FUNC_BCD2: u16 = 0x16;        // 4-byte BCD temp for cell value in functions
+
+// Spreadsheet state
+const RANGE_COL2: u16 = 0x1A;       // Range function end column
+const RANGE_CUR_COL: u16 = 0x1B;    // Current column in range iteration
+pub(crate) const SIGN_ACCUM: u16 = 0x1C;       // Sign of formula accumulator (0x00=pos, 0x80=neg)
+pub(crate) const SIGN_OP: u16 = 0x1D;          // Sign of current operand
+const RANGE_ROW2: u16 = 0x20;       // Range function end row
+const FUNC_TYPE: u16 = 0x21;        // Function type: 0=SUM, 1=AVG, 2=MIN, 3=MAX, 4=COUNT
+const FUNC_COUNT: u16 = 0x22;       // Cell count for AVG
+const RECALC_LEFT: u16 = 0x24;      // Formulas a recalc sweep left pending
+const FUNC_SIGN: u16 = 0x26;        // Sign of function accumulator (0x00=pos, 0x80=neg)
+const FUNC_SIGN2: u16 = 0x27;       // Sign of current cell value in function
+const EVAL_DEPTH: u16 = 0x28;       // Open parentheses in the expression being evaluated
+const EVAL_ERR: u16 = 0x29;         // Error code of a failed evaluation (ERR_SYNTAX if no other)
+const RECALC_FIRST: u16 = 0x2A;     // First cell a recalc sweep left pending (0 if none)
+const RECALC_MOVED: u16 = 0x2C;     // Non-zero once a recalc sweep has resolved a formula
+const RECALC_WAIT: u16 = 0x2D;      // Last error cell a formula read (the pending cell it waits for)
+const RECALC_CIRC: u16 = 0x1E;      // First cell the last recalc found on a cycle (0 if none)
+const RECALC_LIMIT: u16 = 0x2F;     // Passes a recalc may run (/GIN, 1-99)
+const CURSOR_COL: u16 = 0x30;       // Current column
+const CURSOR_ROW: u16 = 0x31;       // Current row
+const VIEW_TOP: u16 = 0x32;         // Top visible row
+const VIEW_LEFT: u16 = 0x33;        // Left visible column
+const INPUT_LEN: u16 = 0x34;        // Input buffer length
+const INPUT_POS: u16 = 0x35;        // Input cursor position
+const EDIT_MODE: u16 = 0x36;        // 0=navigate, 1=edit
+const RECALC_FLAGS: u16 = 0x37;     // Global recalculation options (RECALC_MANUAL etc.)
+const TEMP1: u16 = 0x38;            // Temp storage
+pub(crate) const TEMP2: u16 = 0x3A;            // Temp storage
+pub(crate) const FORMULA_PTR: u16 = 0x3C;      // Next free position in formula storage
+const COL_WIDTH_VAR: u16 = 0x3E;    // Column width (default 9)
+const RECALC_PASSES: u16 = 0x3F;    // Passes the last recalc ran (0 during the first)
+
+// Financial function work area: offsets into the display line buffer
+// (see `Layout::line_buf`). Floats are 8 bytes: sign (0x00/0x80), signed
+// power of ten, then a 12-digit packed BCD mantissa 0.d1d2..d12 with d1
+// non-zero; zero has exponent FIN_ZERO_EXP and mantissa 0
+const FIN_A: u16 = 0x00;            // Float accumulator (FA)
+const FIN_B: u16 = 0x08;            // Float operand (FB)
+const FIN_P: u16 = 0x10;            // 13-byte digit buffer P[0].P[1..12] for results
+const FIN_E: u16 = 0x1D;            // 13-byte digit buffer for the operand
+const FIN_V: u16 = 0x30;            // Float v = 1/(1+rate)
+const FIN_AN: u16 = 0x38;           // Float annuity factor a_n (@NPV: running sum)
+const FIN_VN: u16 = 0x40;           // Float v^n (@NPV: discount of the cell before)
+const FIN_X: u16 = 0x48;            // Float first argument
+const FIN_N: u16 = 0x50;            // Periods, 16-bit binary (@NPV: first row)
+const FIN_ERR: u16 = 0x52;          // Non-zero after a float divide by zero
+const FIN_ZERO_EXP: u8 = 0xC0;      // Exponent of zero (-64)
 
 // Display constants
 const CELL_WIDTH: u8 = 9;           // Width per cell display
 const VISIBLE_COLS: u8 = 8;         // Columns visible at once
-const VISIBLE_ROWS: u8 = 10;        // Rows visible at once
+pub(crate) const VISIBLE_ROWS: u8 = 10;        // Rows visible at once
 
 // VT220 screen layout (1-based row numbers)
 const TITLE_ROW: u8 = 1;            // Title line
 const HELP_ROW: u8 = 2;             // Help/instructions
-const HEADER_ROW: u8 = 4;           // Column headers (A B C D...)
-const DATA_ROW: u8 = 5;             // First data row
-const STATUS_ROW: u8 = 15;          // Status line (after 10 data rows)
-const INPUT_ROW: u8 = 16;           // Input prompt row
+pub(crate) const HEADER_ROW: u8 = 4;           // Column headers (A B C D...)
+pub(crate) const DATA_ROW: u8 = 5;             // First data row
+// RECALC_FLAGS bits
+const RECALC_MANUAL: u8 = 0x01;     // Recalculate only on '!' (/GRM), not after every change (/GRA)
+const RECALC_BY_COL: u8 = 0x02;     // Column by column (/GOC), not row by row (/GOR)
+const RECALC_FULL: u8 = 0x04;       // Run every pass (/GIF), not stop once nothing changes (/GIC)
 
-// Grid size
-const GRID_COLS: u8 = 16;           // A-P
-const GRID_ROWS: u8 = 64;           // 1-64
+pub(crate) const STATUS_ROW: u8 = 15;          // Status line (after 10 data rows)
+const STATUS_FLAGS_COL: u8 = 46;    // Status line column of the recalculation flags
+pub(crate) const INPUT_ROW: u8 = 16;           // Input prompt row
 
 // Cell types
-const CELL_NUMBER: u8 = 1;
-const CELL_FORMULA: u8 = 2;
-const CELL_ERROR: u8 = 3;
-const CELL_REPEAT: u8 = 4;
-const CELL_LABEL: u8 = 5;
+pub(crate) const CELL_NUMBER: u8 = 1;
+pub(crate) const CELL_FORMULA: u8 = 2;
+pub(crate) const CELL_ERROR: u8 = 3;
+pub(crate) const CELL_REPEAT: u8 = 4;
+pub(crate) const CELL_LABEL: u8 = 5;
+
+// Error codes, kept in byte 1 of a CELL_ERROR cell
+pub(crate) const ERR_ERROR: u8 = 1;            // @ERROR
+pub(crate) const ERR_DIV0: u8 = 2;             // Division by zero
+pub(crate) const ERR_REF: u8 = 3;              // Reference outside the grid, bad position
+pub(crate) const ERR_SYNTAX: u8 = 4;           // Malformed formula or bad argument
+pub(crate) const ERR_OVF: u8 = 5;              // Result too large
+pub(crate) const ERR_CIRC: u8 = 6;             // Circular reference
+pub(crate) const ERR_NA: u8 = 7;               // @NA, value not found
+pub(crate) const ERR_PENDING: u8 = 0xFF;       // Formula not yet recalculated (during recalc only)
+
+/// Error names by code (from ERR_ERROR), right-aligned to the cell width
+pub(crate) const ERROR_NAMES: [&str; 7] = [
+    "   #ERR", " #DIV/0", "   #REF", "#SYNTAX", "   #OVF", "  #CIRC", "    #NA",
+];
+
+// A formula's heap block: its value (sign and 4 BCD bytes), the offset
+// from the text to the tokens, the text and NUL, then the tokens
+pub(crate) const FORMULA_VALUE: u16 = 6;       // Value: this far before the text (the cell's pointer)
+
+// Formula tokens, compiled from the text when it is entered (see `lex`).
+// Any other character is a token by itself: + - * / ( ) , : and the
+// strays that make a formula malformed
+pub(crate) const TOK_END: u8 = 0x00;           // End of the tokens
+pub(crate) const TOK_NUM: u8 = 0x01;           // Number from a digit, 4 BCD bytes follow
+pub(crate) const TOK_DOT: u8 = 0x02;           // Number from a '.', 4 BCD bytes follow
+pub(crate) const TOK_REF: u8 = 0x03;           // Reference: column (REF_* flags) and 0-based row follow
+pub(crate) const TOK_OVF: u8 = 0x04;           // Number too large for the cell
+pub(crate) const TOK_CMP: u8 = 0x10;           // Comparison, plus its outcome mask (1 = <, 2 = =, 4 = >)
+pub(crate) const TOK_FUNC: u8 = 0x80;          // @function, plus its index in FUNC_NAMES
+pub(crate) const REF_DOLLAR: u8 = 0x80;        // Reference column written with a '$' in front
+pub(crate) const REF_ROW_DOLLAR: u8 = 0x40;    // Reference row written with a '$' in front
+
+/// @function names by token (from TOK_FUNC); the first five are the
+/// aggregates, numbered as FUNC_TYPE
+pub(crate) const FUNC_NAMES: [&str; 23] = [
+    "SUM", "AVG", "MIN", "MAX", "COUNT", "IF", "AND", "OR", "NOT", "NA", "ISERROR", "ERROR",
+    "ABS", "INT", "MOD", "ROUND", "CHOOSE", "INDEX", "LOOKUP", "NPV", "PMT", "PV", "FV",
+];
+
+/// Handlers of the @functions after the aggregates, in FUNC_NAMES order
+const FUNC_HANDLERS: [&str; 18] = [
+    "pf_if", "pf_and", "pf_or", "pf_not", "pf_na", "pf_iserror", "pf_err", "pf_abs",
+    "pf_int", "pf_mod", "pf_round", "pf_choose", "pf_index", "pf_lookup", "pf_npv", "pf_pmt",
+    "pf_pv", "pf_fv",
+];
+
+/// Offset in the input buffer `lex` compiles a formula to: past the text
+/// and its NUL, with room for five token bytes per character and the end
+const TOK_BUF: u16 = 0x30;
+
+/// Render string constant bytes as assembler DB operands
+fn quote_bytes(bytes: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    for &b in bytes {
+        if (0x20..0x7F).contains(&b) && b != b'"' {
+            text.push(b as char);
+        } else {
+            if !text.is_empty() {
+                parts.push(format!("\"{}\"", std::mem::take(&mut text)));
+            }
+            parts.push(format!("${:02X}", b));
+        }
+    }
+    if !text.is_empty() {
+        parts.push(format!("\"{}\"", text));
+    }
+    parts.join(",")
+}
+
+/// What to type into a cell of `sheet` to get it back, for the baked
+/// sheet: the text of formulas and labels, the shortest number text, or
+/// a number that fails the same way (`None` for empty and repeat cells)
+fn sheet_entry(sheet: &Sheet, col: u8, row: u8) -> Option<String> {
+    if let Some(text) = sheet.text(col, row) {
+        return Some(text);
+    }
+    match sheet.cell(col, row)[0] {
+        CELL_NUMBER => {
+            let value = sheet.value(col, row)?;
+            let v = value.bcd.hundredths()?;
+            let mut text = format!("{}{}", if value.is_negative() { "-" } else { "" }, v / 100);
+            if v % 100 != 0 {
+                write!(text, ".{:02}", v % 100).unwrap();
+                text.truncate(text.trim_end_matches('0').len());
+            }
+            Some(text)
+        }
+        CELL_ERROR if sheet.error(col, row) == Some(CellError::Overflow) => Some("1000000".to_string()),
+        CELL_ERROR => Some("-".to_string()),
+        _ => None,
+    }
+}
 
 /// Spreadsheet code generator - wraps the framework's CodeGen
 /// and adds spreadsheet-specific methods
 pub struct SpreadsheetCodeGen {
     inner: CodeGen,
+    /// Every label in definition order, for the symbol map and listing
+    symbols: Vec<(String, u16)>,
+    /// Address ranges holding string constants rather than code
+    data: Vec<(u16, u16)>,
+    /// Absolute label references (operand address, label)
+    fixups: Vec<(u16, String)>,
+    /// Relative branch displacements (operand address, label)
+    relatives: Vec<(u16, String)>,
+    /// Worksheet copied into RAM at power-on
+    sheet: Option<Sheet>,
+    /// Serial hardware used by `getchar`/`putchar`
+    uart: UartProfile,
+    /// RAM addresses and grid size
+    layout: Layout,
+}
+
+/// A single problem found while finishing the ROM image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildProblem {
+    /// An absolute reference to a label that was never defined
+    UnresolvedLabel { label: String, at: u16 },
+    /// A JR/DJNZ whose target is undefined
+    UnresolvedBranch { label: String, at: u16 },
+    /// A JR/DJNZ whose target is outside -128..=127 bytes
+    BranchOutOfRange { label: String, at: u16, distance: i32 },
+    /// The image does not fit in ROM or runs into cell storage
+    RomOverflow { size: usize, limit: usize },
+    /// The baked-in worksheet was built for another RAM layout or grid
+    SheetLayout { sheet: Layout, rom: Layout },
+}
+
+impl fmt::Display for BuildProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildProblem::UnresolvedLabel { label, at } => {
+                write!(f, "{:04X}: undefined label '{}'", at, label)
+            }
+            BuildProblem::UnresolvedBranch { label, at } => {
+                write!(f, "{:04X}: relative branch to undefined label '{}'", at, label)
+            }
+            BuildProblem::BranchOutOfRange { label, at, distance } => write!(
+                f,
+                "{:04X}: relative branch to '{}' out of range ({} bytes)",
+                at, label, distance
+            ),
+            BuildProblem::RomOverflow { size, limit } => {
+                write!(f, "ROM image is {} bytes, limit is {}", size, limit)
+            }
+            BuildProblem::SheetLayout { sheet, rom } => {
+                write!(f, "worksheet is laid out as {}, ROM as {}", sheet, rom)
+            }
+        }
+    }
 }
 
+/// Everything that prevented [`SpreadsheetCodeGen::generate`] from producing a ROM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildError {
+    pub problems: Vec<BuildProblem>,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} build problem(s)", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildError {}
+
 impl Default for SpreadsheetCodeGen {
     fn default() -> Self {
         Self::new()
@@ -118,11 +318,231 @@ impl SpreadsheetCodeGen {
     pub fn new() -> Self {
         Self {
             inner: CodeGen::new(),
+            symbols: Vec::new(),
+            data: Vec::new(),
+            fixups: Vec::new(),
+            relatives: Vec::new(),
+            sheet: None,
+            uart: UartProfile::default(),
+            layout: Layout::default(),
+        }
+    }
+
+    /// Generate I/O code for this UART instead of the default MC6850 at 0x80
+    pub fn set_uart(&mut self, uart: UartProfile) {
+        self.uart = uart;
+    }
+
+    /// Serial hardware the ROM is generated for
+    pub fn uart(&self) -> &UartProfile {
+        &self.uart
+    }
+
+    /// Generate for this RAM layout and grid instead of 16x64 in 8KB at 0x2000
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// RAM layout the ROM is generated for
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Bake `sheet` into the ROM so every power-on starts with it loaded
+    ///
+    /// The cells are entered again in row-major order, as
+    /// [`crate::template::load`] builds them; repeating labels are not kept.
+    pub fn set_sheet(&mut self, sheet: Sheet) {
+        self.sheet = Some(sheet);
+    }
+
+    /// Emit a 16-bit placeholder for `name`, patched by `resolve_fixups`
+    pub fn fixup(&mut self, name: &str) {
+        self.fixups.push((self.pos(), name.to_string()));
+        self.inner.fixup(name);
+    }
+
+    /// CALL label
+    pub fn call(&mut self, name: &str) {
+        self.emit(&[0xCD]);
+        self.fixup(name);
+    }
+
+    /// JP NZ, label
+    pub fn jp_nz(&mut self, name: &str) {
+        self.emit(&[0xC2]);
+        self.fixup(name);
+    }
+
+    /// JP Z, label
+    pub fn jp_z(&mut self, name: &str) {
+        self.emit(&[0xCA]);
+        self.fixup(name);
+    }
+
+    /// LD HL, label
+    pub fn ld_hl_label(&mut self, name: &str) {
+        self.emit(&[0x21]);
+        self.fixup(name);
+    }
+
+    /// Emit a JR/DJNZ displacement byte for `name`
+    ///
+    /// The displacement is filled in by [`Self::finish`] once every label
+    /// is known, so forward branches work as well as backward ones.
+    pub fn emit_relative(&mut self, name: &str) {
+        self.relatives.push((self.pos(), name.to_string()));
+        self.emit(&[0x00]);
+    }
+
+    /// Patch relative branches and absolute fixups, checking the image
+    ///
+    /// Collects every problem rather than stopping at the first, so one
+    /// build reports all missing labels and out-of-range branches.
+    fn finish(&mut self) -> Result<(), BuildError> {
+        let layout = self.layout;
+        let mut problems = Vec::new();
+
+        for (at, label) in &self.fixups {
+            if self.inner.get_label(label).is_none() {
+                problems.push(BuildProblem::UnresolvedLabel { label: label.clone(), at: *at });
+            }
+        }
+
+        let mut patches = Vec::new();
+        for (at, label) in &self.relatives {
+            let Some(target) = self.inner.get_label(label) else {
+                problems.push(BuildProblem::UnresolvedBranch { label: label.clone(), at: *at });
+                continue;
+            };
+            // Displacement is relative to the address after the operand byte
+            let distance = target as i32 - (*at as i32 + 1);
+            if (-128..=127).contains(&distance) {
+                patches.push((*at as usize, distance as i8 as u8));
+            } else {
+                problems.push(BuildProblem::BranchOutOfRange {
+                    label: label.clone(),
+                    at: *at,
+                    distance,
+                });
+            }
+        }
+
+        // Code must stay in ROM and clear of the cell area that follows it
+        let limit = ROM_SIZE.min(layout.cell_data() as usize);
+        if self.rom().len() > limit {
+            problems.push(BuildProblem::RomOverflow { size: self.rom().len(), limit });
+        }
+
+        if !problems.is_empty() {
+            return Err(BuildError { problems });
+        }
+        for (at, offset) in patches {
+            self.rom_mut()[at] = offset;
+        }
+        self.inner.resolve_fixups();
+        Ok(())
+    }
+
+    /// Define a label at the current position (recorded for the symbol map)
+    pub fn label(&mut self, name: &str) {
+        self.symbols.push((name.to_string(), self.pos()));
+        self.inner.label(name);
+    }
+
+    /// Emit a NUL-terminated string, marking it as data in the listing
+    pub fn emit_string(&mut self, s: &str) {
+        let start = self.pos();
+        self.inner.emit_string(s);
+        self.data.push((start, self.pos()));
+    }
+
+    /// Emit raw bytes, marking them as data in the listing
+    fn emit_data(&mut self, bytes: &[u8]) {
+        let start = self.pos();
+        self.emit(bytes);
+        self.data.push((start, self.pos()));
+    }
+
+    /// Labels and their addresses, sorted by address
+    pub fn symbols(&self) -> Vec<(String, u16)> {
+        let mut symbols = self.symbols.clone();
+        symbols.sort_by_key(|(_, addr)| *addr);
+        symbols
+    }
+
+    /// Symbol map text: one `ADDR  name` line per label
+    pub fn symbol_map(&self) -> String {
+        let mut out = String::new();
+        for (name, addr) in self.symbols() {
+            writeln!(out, "{:04X}  {}", addr, name).unwrap();
+        }
+        out
+    }
+
+    /// Annotated disassembly of the ROM with labels interleaved
+    ///
+    /// String constants are shown as `DB` lines; jump, call and load
+    /// operands that hit a label are annotated with its name.
+    pub fn listing(&self) -> String {
+        let rom = self.rom();
+        let symbols = self.symbols();
+        let name_at = |addr: u16| {
+            symbols
+                .iter()
+                .find(|(_, a)| *a == addr)
+                .map(|(n, _)| n.as_str())
+        };
+        let mut out = String::new();
+        let mut next_sym = 0;
+        let mut pc = 0usize;
+        while pc < rom.len() {
+            while next_sym < symbols.len() && symbols[next_sym].1 as usize <= pc {
+                writeln!(out, "{}:", symbols[next_sym].0).unwrap();
+                next_sym += 1;
+            }
+            // Stop the current item at the next label so every label gets a line
+            let limit = symbols
+                .get(next_sym)
+                .map_or(rom.len(), |(_, a)| *a as usize)
+                .min(rom.len());
+            let data = self
+                .data
+                .iter()
+                .find(|(start, end)| (*start as usize..*end as usize).contains(&pc));
+            let (len, text) = match data {
+                Some(&(_, end)) => {
+                    let end = (end as usize).min(limit);
+                    (end - pc, format!("DB {}", quote_bytes(&rom[pc..end])))
+                }
+                None => {
+                    let insn = disasm::disassemble(rom, pc, pc as u16);
+                    let text = match insn.target.and_then(name_at) {
+                        Some(name) => format!("{:<20}; {}", insn.text, name),
+                        None => insn.text,
+                    };
+                    (insn.len.min(rom.len() - pc), text)
+                }
+            };
+            let bytes: Vec<String> = rom[pc..pc + len]
+                .iter()
+                .take(4)
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            writeln!(out, "  {:04X}  {:<12}  {}", pc, bytes.join(" "), text).unwrap();
+            pc += len;
         }
+        out
     }
 
     /// Generate the complete spreadsheet ROM
-    pub fn generate(&mut self) {
+    pub fn generate(&mut self) -> Result<(), BuildError> {
+        if let Some(sheet) = &self.sheet {
+            if *sheet.layout() != self.layout {
+                let problem = BuildProblem::SheetLayout { sheet: *sheet.layout(), rom: self.layout };
+                return Err(BuildError { problems: vec![problem] });
+            }
+        }
         self.emit_spreadsheet_startup();
         self.emit_main_loop();
         self.emit_display();
@@ -130,9 +550,11 @@ impl SpreadsheetCodeGen {
         self.emit_cell_ops();
         self.emit_bcd_ops();
         self.emit_formula();
+        self.emit_finance();
         self.emit_io();
         self.emit_strings();
-        self.resolve_fixups();
+        self.emit_sheet_data();
+        self.finish()
     }
 
     /// Convert to final ROM bytes
@@ -142,8 +564,15 @@ impl SpreadsheetCodeGen {
 
     /// Startup code (renamed to avoid conflict with framework method)
     fn emit_spreadsheet_startup(&mut self) {
+        let layout = self.layout;
         // Initialize stack
-        self.ld_sp(STACK_TOP);
+        self.ld_sp(layout.stack_top());
+
+        // Bring up the UART before anything is printed
+        for (port, value) in self.uart.init.clone() {
+            self.ld_a(value);
+            self.emit(&[0xD3, port]); // OUT (port), A
+        }
 
         // Print welcome banner first
         self.ld_hl_label("welcome_msg");
@@ -151,23 +580,28 @@ impl SpreadsheetCodeGen {
 
         // Clear cursor position
         self.xor_a();
-        self.ld_addr_a(CURSOR_COL);
-        self.ld_addr_a(CURSOR_ROW);
-        self.ld_addr_a(VIEW_TOP);
-        self.ld_addr_a(VIEW_LEFT);
-        self.ld_addr_a(EDIT_MODE);
+        self.ld_addr_a(layout.var(CURSOR_COL));
+        self.ld_addr_a(layout.var(CURSOR_ROW));
+        self.ld_addr_a(layout.var(VIEW_TOP));
+        self.ld_addr_a(layout.var(VIEW_LEFT));
+        self.ld_addr_a(layout.var(EDIT_MODE));
+        self.ld_addr_a(layout.var(RECALC_CIRC) + 1); // no cycle found yet
+        self.ld_addr_a(layout.var(RECALC_FLAGS)); // automatic, row by row
+        self.ld_addr_a(layout.var(RECALC_PASSES));
+        self.inc_a();
+        self.ld_addr_a(layout.var(RECALC_LIMIT)); // no iteration
 
         // Initialize column width
         self.ld_a(CELL_WIDTH);
-        self.ld_addr_a(COL_WIDTH_VAR);
+        self.ld_addr_a(layout.var(COL_WIDTH_VAR));
 
         // Initialize formula storage pointer
-        self.ld_hl(SCRATCH);
-        self.ld_addr_hl(FORMULA_PTR);
+        self.ld_hl(layout.scratch());
+        self.ld_addr_hl(layout.var(FORMULA_PTR));
 
         // Clear all cells
-        self.ld_hl(CELL_DATA);
-        self.ld_bc(6144); // 1024 cells × 6 bytes
+        self.ld_hl(layout.cell_data());
+        self.ld_bc((layout.cell_count() * CELL_SIZE as usize) as u16); // cells × 6 bytes
         self.label("clear_cells_loop");
         self.emit(&[0x36, 0x00]); // LD (HL), 0
         self.inc_hl();
@@ -176,12 +610,88 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xB1]); // OR C
         self.jp_nz("clear_cells_loop");
 
+        if self.sheet.is_some() {
+            self.emit_sheet_load();
+        }
+
         // Initial display
         self.call("refresh_display");
     }
 
+    /// Enter the baked-in worksheet cell by cell, then recalculate it (the
+    /// recalculation mode is still automatic at power-on)
+    ///
+    /// Entries are `DB col, row, text, 0` records ending with a column of
+    /// 0xFF. Each is copied to the input buffer and confirmed through
+    /// `parse_and_store` at its cell, so formulas are compiled on the Z80
+    /// and only their text takes ROM space.
+    fn emit_sheet_load(&mut self) {
+        let layout = self.layout;
+        self.ld_hl_label("sheet_entries");
+        self.label("sheet_entry_loop");
+        self.ld_a_hl_ind();
+        self.inc_a();
+        self.emit(&[0x28]); // JR Z, sheet_loaded (column 0xFF)
+        self.emit_relative("sheet_loaded");
+        self.dec_a();
+        self.ld_addr_a(layout.var(CURSOR_COL));
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.ld_addr_a(layout.var(CURSOR_ROW));
+        self.inc_hl();
+        // Copy the text and its NUL, counting the characters in B
+        self.emit(&[0x11]); // LD DE, INPUT_BUF
+        self.emit_word(layout.input_buf());
+        self.emit(&[0x06, 0xFF]); // LD B, -1
+        self.label("sheet_text_loop");
+        self.ld_a_hl_ind();
+        self.emit(&[0x12]); // LD (DE), A
+        self.inc_hl();
+        self.inc_de();
+        self.emit(&[0x04]); // INC B
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, sheet_text_loop
+        self.emit_relative("sheet_text_loop");
+        self.ld_a_b();
+        self.ld_addr_a(layout.var(INPUT_LEN));
+        self.push_hl();
+        self.call("parse_and_store");
+        self.pop_hl();
+        self.emit(&[0x18]); // JR sheet_entry_loop
+        self.emit_relative("sheet_entry_loop");
+        self.label("sheet_loaded");
+        self.xor_a();
+        self.ld_addr_a(layout.var(CURSOR_COL));
+        self.ld_addr_a(layout.var(CURSOR_ROW));
+        self.call("recalculate");
+    }
+
+    /// Entry records for `emit_sheet_load`, in the row-major order the
+    /// template was typed in
+    fn emit_sheet_data(&mut self) {
+        let layout = self.layout;
+        let Some(sheet) = self.sheet.take() else {
+            return;
+        };
+        self.label("sheet_entries");
+        let mut records = Vec::new();
+        for index in 0..layout.cell_count() {
+            let (col, row) = ((index % layout.cols() as usize) as u8, (index / layout.cols() as usize) as u8);
+            let Some(text) = sheet_entry(&sheet, col, row) else {
+                continue;
+            };
+            records.extend_from_slice(&[col, row]);
+            records.extend_from_slice(text.as_bytes());
+            records.push(0);
+        }
+        records.push(0xFF);
+        self.emit_data(&records);
+        self.sheet = Some(sheet);
+    }
+
     /// Main loop - handle input and display
     fn emit_main_loop(&mut self) {
+        let layout = self.layout;
         self.label("main_loop");
 
         // Read a character
@@ -189,7 +699,7 @@ impl SpreadsheetCodeGen {
 
         // Check edit mode - save char in B, check mode, restore to A
         self.ld_b_a();
-        self.ld_a_addr(EDIT_MODE);
+        self.ld_a_addr(layout.var(EDIT_MODE));
         self.or_a_a();
         self.ld_a_b();
         self.jp_nz("edit_mode_input");
@@ -218,6 +728,9 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xFE, b'-']);
         self.emit(&[0xCA]); // JP Z, start_number
         self.fixup("start_number");
+        self.emit(&[0xFE, b'.']); // ".5"
+        self.emit(&[0xCA]); // JP Z, start_number
+        self.fixup("start_number");
 
         // Digit to start number entry
         self.emit(&[0xFE, b'0']);
@@ -288,49 +801,49 @@ impl SpreadsheetCodeGen {
         // Cursor movement
         self.label("move_left");
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.or_a_a();
         self.emit(&[0xCA]); // JP Z, move_done (already at left edge)
         self.fixup("move_done");
         self.dec_a();
         self.emit(&[0x32]); // LD (CURSOR_COL), A
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.emit(&[0xC3]); // JP move_done
         self.fixup("move_done");
 
         self.label("move_right");
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
-        self.emit(&[0xFE, GRID_COLS - 1]); // CP GRID_COLS-1
+        self.emit_word(layout.var(CURSOR_COL));
+        self.emit(&[0xFE, layout.cols() - 1]); // CP cols-1
         self.emit(&[0xD2]); // JP NC, move_done (already at right edge)
         self.fixup("move_done");
         self.inc_a();
         self.emit(&[0x32]); // LD (CURSOR_COL), A
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.emit(&[0xC3]); // JP move_done
         self.fixup("move_done");
 
         self.label("move_up");
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.or_a_a();
         self.emit(&[0xCA]); // JP Z, move_done (already at top)
         self.fixup("move_done");
         self.dec_a();
         self.emit(&[0x32]); // LD (CURSOR_ROW), A
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.emit(&[0xC3]); // JP move_done
         self.fixup("move_done");
 
         self.label("move_down");
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
-        self.emit(&[0xFE, GRID_ROWS - 1]); // CP GRID_ROWS-1
+        self.emit_word(layout.var(CURSOR_ROW));
+        self.emit(&[0xFE, layout.rows() - 1]); // CP rows-1
         self.emit(&[0xD2]); // JP NC, move_done (already at bottom)
         self.fixup("move_done");
         self.inc_a();
         self.emit(&[0x32]); // LD (CURSOR_ROW), A
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         // Fall through to move_done
 
         self.label("move_done");
@@ -346,7 +859,7 @@ impl SpreadsheetCodeGen {
         self.label("start_edit");
         self.emit(&[0x3E, 0x01]); // LD A, 1
         self.emit(&[0x32]); // LD (EDIT_MODE), A
-        self.emit_word(EDIT_MODE);
+        self.emit_word(layout.var(EDIT_MODE));
         // Load current cell content into INPUT_BUF
         self.emit(&[0xCD]); // CALL load_cell_to_input
         self.fixup("load_cell_to_input");
@@ -359,16 +872,16 @@ impl SpreadsheetCodeGen {
         self.label("start_formula");
         self.emit(&[0x3E, 0x01]); // LD A, 1
         self.emit(&[0x32]); // LD (EDIT_MODE), A
-        self.emit_word(EDIT_MODE);
+        self.emit_word(layout.var(EDIT_MODE));
         self.emit(&[0x3E, b'=']); // LD A, '='
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.ld_hl_ind_a();
         self.emit(&[0x3E, 0x01]); // LD A, 1
         self.emit(&[0x32]); // LD (INPUT_LEN), A
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0x32]); // LD (INPUT_POS), A
-        self.emit_word(INPUT_POS);
+        self.emit_word(layout.var(INPUT_POS));
         self.emit(&[0xCD]); // CALL show_input_line
         self.fixup("show_input_line");
         self.emit(&[0xC3]); // JP main_loop
@@ -379,16 +892,16 @@ impl SpreadsheetCodeGen {
         self.push_af(); //save digit)
         self.emit(&[0x3E, 0x01]); // LD A, 1
         self.emit(&[0x32]); // LD (EDIT_MODE), A
-        self.emit_word(EDIT_MODE);
+        self.emit_word(layout.var(EDIT_MODE));
         self.pop_af(); //restore digit)
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.ld_hl_ind_a();
         self.emit(&[0x3E, 0x01]); // LD A, 1
         self.emit(&[0x32]); // LD (INPUT_LEN), A
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0x32]); // LD (INPUT_POS), A
-        self.emit_word(INPUT_POS);
+        self.emit_word(layout.var(INPUT_POS));
         self.emit(&[0xCD]); // CALL show_input_line
         self.fixup("show_input_line");
         self.emit(&[0xC3]); // JP main_loop
@@ -421,22 +934,22 @@ impl SpreadsheetCodeGen {
         // Add character to input buffer
         self.push_af();
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0xFE, 40]); // CP 40 (max input length)
         self.emit(&[0xD2]); // JP NC, edit_input_full
         self.fixup("edit_input_full");
         self.ld_e_a();
         self.emit(&[0x16, 0x00]); // LD D, 0
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.add_hl_de();
         self.pop_af();
         self.ld_hl_ind_a();
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.inc_a();
         self.emit(&[0x32]); // LD (INPUT_LEN), A
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0xCD]); // CALL show_input_line
         self.fixup("show_input_line");
         self.emit(&[0xC3]); // JP main_loop
@@ -449,13 +962,13 @@ impl SpreadsheetCodeGen {
 
         self.label("edit_backspace");
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.or_a_a();
         self.emit(&[0xCA]); // JP Z, main_loop (nothing to delete)
         self.fixup("main_loop");
         self.dec_a();
         self.emit(&[0x32]); // LD (INPUT_LEN), A
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0xCD]); // CALL show_input_line
         self.fixup("show_input_line");
         self.emit(&[0xC3]); // JP main_loop
@@ -464,7 +977,7 @@ impl SpreadsheetCodeGen {
         self.label("cancel_edit");
         self.xor_a();
         self.emit(&[0x32]); // LD (EDIT_MODE), A
-        self.emit_word(EDIT_MODE);
+        self.emit_word(layout.var(EDIT_MODE));
         self.emit(&[0xCD]); // CALL refresh_display
         self.fixup("refresh_display");
         self.emit(&[0xC3]); // JP main_loop
@@ -473,11 +986,11 @@ impl SpreadsheetCodeGen {
         self.label("confirm_edit");
         // Null-terminate input buffer
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.ld_e_a();
         self.emit(&[0x16, 0x00]); // LD D, 0
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.add_hl_de();
         self.emit(&[0x36, 0x00]); // LD (HL), 0
         // Parse input and store in cell
@@ -485,7 +998,7 @@ impl SpreadsheetCodeGen {
         self.fixup("parse_and_store");
         self.xor_a();
         self.emit(&[0x32]); // LD (EDIT_MODE), A
-        self.emit_word(EDIT_MODE);
+        self.emit_word(layout.var(EDIT_MODE));
         self.emit(&[0xCD]); // CALL recalculate
         self.fixup("recalculate");
         self.emit(&[0xCD]); // CALL refresh_display
@@ -583,33 +1096,34 @@ impl SpreadsheetCodeGen {
         self.fixup("goto_check_col");
         self.emit(&[0xD6, 0x20]); // SUB 0x20 (to uppercase)
         self.label("goto_check_col");
-        // Check if valid column (A-P)
-        self.emit(&[0xFE, b'A']);
+        // Save the letter as a column (A and up)
+        self.emit(&[0xD6, b'A']); // SUB 'A'
         self.emit(&[0xDA]); // JP C, goto_cancel (< 'A')
         self.fixup("goto_cancel");
-        self.emit(&[0xFE, b'P' + 1]);
-        self.emit(&[0xD2]); // JP NC, goto_cancel (> 'P')
-        self.fixup("goto_cancel");
-        // Save column
-        self.emit(&[0xD6, b'A']); // SUB 'A'
         self.emit(&[0x32]); // LD (TEMP1), A
-        self.emit_word(TEMP1);
-        // Get row number (1 or 2 digits)
+        self.emit_word(layout.var(TEMP1));
+        // Get row number (1 or 2 digits); a letter instead makes this a
+        // global option, /GR or /GO
         self.emit(&[0xCD]); // CALL getchar
         self.fixup("getchar");
         self.emit(&[0xCD]); // CALL putchar (echo)
         self.fixup("putchar");
         // Check if digit
         self.emit(&[0xFE, b'0']);
-        self.emit(&[0xDA]); // JP C, goto_cancel
-        self.fixup("goto_cancel");
+        self.emit(&[0xDA]); // JP C, goto_option
+        self.fixup("goto_option");
         self.emit(&[0xFE, b'9' + 1]);
-        self.emit(&[0xD2]); // JP NC, goto_cancel
-        self.fixup("goto_cancel");
+        self.emit(&[0xD2]); // JP NC, goto_option
+        self.fixup("goto_option");
         // First digit
         self.emit(&[0xD6, b'0']); // SUB '0'
         self.emit(&[0x32]); // LD (TEMP1+1), A
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
+        self.emit(&[0x3A]); // LD A, (TEMP1)
+        self.emit_word(layout.var(TEMP1));
+        self.emit(&[0xFE, layout.cols()]); // CP cols
+        self.emit(&[0xD2]); // JP NC, goto_cancel (past the last column)
+        self.fixup("goto_cancel");
         // Try to get second digit (or Enter)
         self.emit(&[0xCD]); // CALL getchar
         self.fixup("getchar");
@@ -629,7 +1143,7 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xD6, b'0']); // SUB '0'
         self.ld_b_a(); //second digit)
         self.emit(&[0x3A]); // LD A, (TEMP1+1) (first digit)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         // Multiply by 10: A*10 = A*8 + A*2
         self.ld_c_a();
         self.emit(&[0x87]); // ADD A, A (*2)
@@ -646,27 +1160,27 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x81]); // ADD A, C (now A = 10*C)
         self.emit(&[0x80]); // ADD A, B (add second digit)
         self.emit(&[0x32]); // LD (TEMP1+1), A
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         // Wait for Enter
         self.emit(&[0xCD]); // CALL getchar
         self.fixup("getchar");
         self.label("goto_execute");
         // Set cursor to new position
         self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.emit(&[0x32]); // LD (CURSOR_COL), A
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.dec_a(); //convert 1-based to 0-based)
-        // Clamp to valid range (0-63)
-        self.emit(&[0xFE, GRID_ROWS]); // CP GRID_ROWS
+        // Clamp to the last row
+        self.emit(&[0xFE, layout.rows()]); // CP rows
         self.emit(&[0xDA]); // JP C, goto_row_ok
         self.fixup("goto_row_ok");
-        self.emit(&[0x3E, GRID_ROWS - 1]); // LD A, GRID_ROWS-1
+        self.emit(&[0x3E, layout.rows() - 1]); // LD A, rows-1
         self.label("goto_row_ok");
         self.emit(&[0x32]); // LD (CURSOR_ROW), A
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.emit(&[0xCD]); // CALL adjust_view
         self.fixup("adjust_view");
         self.emit(&[0xCD]); // CALL refresh_display
@@ -674,6 +1188,85 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xC3]); // JP main_loop
         self.fixup("main_loop");
 
+        // /GRA and /GRM set automatic or manual recalculation, /GOR and
+        // /GOC row or column order, /GIC and /GIF whether iteration stops
+        // once nothing changes, and /GIN asks for the number of passes.
+        // A = the key after R, O or I
+        self.label("goto_option");
+        self.emit(&[0xE6, 0xDF]); // AND 0xDF (to uppercase)
+        self.ld_c_a();
+        self.emit(&[0x3A]); // LD A, (TEMP1)
+        self.emit_word(layout.var(TEMP1));
+        self.emit(&[0x06, RECALC_MANUAL]); // LD B, RECALC_MANUAL
+        self.emit(&[0x11, b'M', b'A']); // LD DE, 'A' << 8 | 'M' (keys to clear and set)
+        self.emit(&[0xFE, b'R' - b'A']); // CP 'R'
+        self.emit(&[0x28]); // JR Z, goto_option_key
+        self.emit_relative("goto_option_key");
+        self.emit(&[0x06, RECALC_BY_COL]); // LD B, RECALC_BY_COL
+        self.emit(&[0x11, b'C', b'R']); // LD DE, 'R' << 8 | 'C'
+        self.emit(&[0xFE, b'O' - b'A']); // CP 'O'
+        self.emit(&[0x28]); // JR Z, goto_option_key
+        self.emit_relative("goto_option_key");
+        self.emit(&[0xFE, b'I' - b'A']); // CP 'I'
+        self.emit(&[0x20]); // JR NZ, goto_cancel
+        self.emit_relative("goto_cancel");
+        self.ld_a_c();
+        self.emit(&[0xFE, b'N']); // CP 'N'
+        self.emit(&[0x28]); // JR Z, goto_passes
+        self.emit_relative("goto_passes");
+        self.emit(&[0x06, RECALC_FULL]); // LD B, RECALC_FULL
+        self.emit(&[0x11, b'F', b'C']); // LD DE, 'C' << 8 | 'F'
+        self.label("goto_option_key");
+        self.emit(&[0x21]); // LD HL, RECALC_FLAGS
+        self.emit_word(layout.var(RECALC_FLAGS));
+        self.ld_a_c();
+        self.emit(&[0xBA]); // CP D
+        self.emit(&[0x28]); // JR Z, goto_option_clear
+        self.emit_relative("goto_option_clear");
+        self.emit(&[0xBB]); // CP E
+        self.emit(&[0x20]); // JR NZ, goto_cancel
+        self.emit_relative("goto_cancel");
+        self.ld_a_hl_ind();
+        self.emit(&[0xB0]); // OR B
+        self.emit(&[0x18]); // JR goto_option_set
+        self.emit_relative("goto_option_set");
+        self.label("goto_option_clear");
+        self.ld_a_b();
+        self.emit(&[0x2F]); // CPL
+        self.emit(&[0xA6]); // AND (HL)
+        self.label("goto_option_set");
+        self.ld_hl_ind_a();
+        self.emit(&[0xCD]); // CALL recalculate
+        self.fixup("recalculate");
+        self.emit(&[0x18]); // JR goto_cancel
+        self.emit_relative("goto_cancel");
+
+        self.label("goto_passes");
+        self.emit(&[0x06, INPUT_ROW]); // LD B, INPUT_ROW
+        self.emit(&[0x0E, 1]); // LD C, 1
+        self.emit(&[0xCD]); // CALL cursor_pos
+        self.fixup("cursor_pos");
+        self.emit(&[0xCD]); // CALL clear_to_eol
+        self.fixup("clear_to_eol");
+        self.emit(&[0x21]); // LD HL, passes_prompt
+        self.fixup("passes_prompt");
+        self.emit(&[0xCD]); // CALL print_string
+        self.fixup("print_string");
+        self.emit(&[0xCD]); // CALL read_number
+        self.fixup("read_number");
+        self.emit(&[0x38]); // JR C, goto_cancel
+        self.emit_relative("goto_cancel");
+        // 1-99 passes
+        self.dec_a();
+        self.emit(&[0xFE, 99]); // CP 99
+        self.emit(&[0x30]); // JR NC, goto_cancel
+        self.emit_relative("goto_cancel");
+        self.inc_a();
+        self.emit(&[0x32]); // LD (RECALC_LIMIT), A
+        self.emit_word(layout.var(RECALC_LIMIT));
+        self.emit(&[0xCD]); // CALL recalculate
+        self.fixup("recalculate");
+
         self.label("goto_cancel");
         // Invalid input - just refresh and return
         self.emit(&[0xCD]); // CALL refresh_display
@@ -685,14 +1278,16 @@ impl SpreadsheetCodeGen {
         self.label("cmd_clear");
         // Get cell address and set type to empty (0)
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
         self.emit(&[0x36, 0x00]); // LD (HL), 0 (CELL_EMPTY)
+        self.emit(&[0xCD]); // CALL recalculate
+        self.fixup("recalculate");
         self.emit(&[0xCD]); // CALL refresh_display
         self.fixup("refresh_display");
         self.emit(&[0xC3]); // JP main_loop
@@ -718,13 +1313,13 @@ impl SpreadsheetCodeGen {
         self.fixup("getchar");
         // Store character in TEMP2
         self.emit(&[0x32]); // LD (TEMP2), A
-        self.emit_word(TEMP2);
+        self.emit_word(layout.var(TEMP2));
         // Get cell address
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
@@ -735,8 +1330,10 @@ impl SpreadsheetCodeGen {
         self.inc_hl(); //point to byte 2)
         // Get char back from TEMP2
         self.emit(&[0x3A]); // LD A, (TEMP2)
-        self.emit_word(TEMP2);
+        self.emit_word(layout.var(TEMP2));
         self.ld_hl_ind_a(); //store repeat char)
+        self.emit(&[0xCD]); // CALL recalculate
+        self.fixup("recalculate");
         self.emit(&[0xCD]); // CALL refresh_display
         self.fixup("refresh_display");
         self.emit(&[0xC3]); // JP main_loop
@@ -758,7 +1355,7 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xCD]); // CALL cursor_show
         self.fixup("cursor_show");
 
-        // Get destination column (A-P)
+        // Get destination column letter
         self.emit(&[0xCD]); // CALL getchar
         self.fixup("getchar");
         self.emit(&[0xCD]); // CALL putchar (echo)
@@ -772,70 +1369,41 @@ impl SpreadsheetCodeGen {
         self.fixup("repl_col_check");
         self.emit(&[0xD6, 0x20]); // SUB 0x20 (to uppercase)
         self.label("repl_col_check");
-        // Check range A-P
+        // Check column range
         self.emit(&[0xFE, b'A']);
         self.emit(&[0xDA]); // JP C, repl_cancel (< 'A')
         self.fixup("repl_cancel");
-        self.emit(&[0xFE, b'Q']);
-        self.emit(&[0xD2]); // JP NC, repl_cancel (> 'P')
+        self.emit(&[0xFE, b'A' + layout.cols()]);
+        self.emit(&[0xD2]); // JP NC, repl_cancel (past the last column)
         self.fixup("repl_cancel");
-        // Convert to column number (0-15)
+        // Convert to column number
         self.emit(&[0xD6, b'A']); // SUB 'A'
         self.emit(&[0x32]); // LD (TEMP1), A (dest col)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
 
         // Get destination row (1-64)
-        self.emit(&[0x0E, 0x00]); // LD C, 0 (row accumulator)
-        self.label("repl_row_loop");
-        self.emit(&[0xCD]); // CALL getchar
-        self.fixup("getchar");
-        self.emit(&[0xFE, 0x0D]); // CP CR
-        self.emit(&[0xCA]); // JP Z, repl_row_done
-        self.fixup("repl_row_done");
-        self.emit(&[0xCD]); // CALL putchar (echo)
-        self.fixup("putchar");
-        // Check if digit 0-9
-        self.emit(&[0xFE, b'0']);
+        self.emit(&[0xCD]); // CALL read_number
+        self.fixup("read_number");
         self.emit(&[0xDA]); // JP C, repl_cancel
         self.fixup("repl_cancel");
-        self.emit(&[0xFE, b'9' + 1]);
-        self.emit(&[0xD2]); // JP NC, repl_cancel
-        self.fixup("repl_cancel");
-        // Add to accumulator: C = C * 10 + (A - '0')
-        self.emit(&[0xD6, b'0']); // SUB '0'
-        self.ld_b_a(); //save digit)
-        self.ld_a_c();
-        // Multiply by 10: A*10 = A*2 + A*8
-        self.emit(&[0x87]); // ADD A, A (x2)
-        self.ld_c_a(); //save x2)
-        self.emit(&[0x87]); // ADD A, A (x4)
-        self.emit(&[0x87]); // ADD A, A (x8)
-        self.emit(&[0x81]); // ADD A, C (x10)
-        self.emit(&[0x80]); // ADD A, B (add digit)
-        self.ld_c_a();
-        self.emit(&[0xC3]); // JP repl_row_loop
-        self.fixup("repl_row_loop");
-
-        self.label("repl_row_done");
-        // C = row (1-based), convert to 0-based
-        self.ld_a_c();
+        // A = row (1-based), convert to 0-based
         self.or_a_a();
         self.emit(&[0xCA]); // JP Z, repl_cancel (row = 0 invalid)
         self.fixup("repl_cancel");
         self.dec_a();
-        self.emit(&[0xFE, GRID_ROWS]); // CP GRID_ROWS
-        self.emit(&[0xD2]); // JP NC, repl_cancel (>= 64)
+        self.emit(&[0xFE, layout.rows()]); // CP rows
+        self.emit(&[0xD2]); // JP NC, repl_cancel (past the last row)
         self.fixup("repl_cancel");
         self.emit(&[0x32]); // LD (TEMP1+1), A (dest row)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
 
         // Now copy: source = current cell, dest = TEMP1 (col, row)
         // Get source cell address
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
@@ -843,10 +1411,10 @@ impl SpreadsheetCodeGen {
 
         // Get dest cell address
         self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
@@ -862,22 +1430,21 @@ impl SpreadsheetCodeGen {
         self.inc_hl();
         self.inc_de();
         self.emit(&[0x10]); // DJNZ repl_copy_loop
-        let repl_copy_offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        self.rom_mut()[repl_copy_offset] = (self.get_label("repl_copy_loop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
+        self.emit_relative("repl_copy_loop");
 
         // Move cursor to destination cell
         self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.emit(&[0x32]); // LD (CURSOR_COL), A
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.emit(&[0x32]); // LD (CURSOR_ROW), A
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
 
         // Adjust view and refresh
+        self.emit(&[0xCD]); // CALL recalculate
+        self.fixup("recalculate");
         self.emit(&[0xCD]); // CALL adjust_view
         self.fixup("adjust_view");
         self.emit(&[0xCD]); // CALL refresh_display
@@ -908,24 +1475,51 @@ impl SpreadsheetCodeGen {
         self.fixup("cursor_show");
 
         // Get width (1-2 digits)
+        self.emit(&[0xCD]); // CALL read_number
+        self.fixup("read_number");
+        self.emit(&[0xDA]); // JP C, width_cancel
+        self.fixup("width_cancel");
+        // Validate width: 5-15
+        self.emit(&[0xFE, 5]); // CP 5
+        self.emit(&[0xDA]); // JP C, width_cancel (< 5)
+        self.fixup("width_cancel");
+        self.emit(&[0xFE, 16]); // CP 16
+        self.emit(&[0xD2]); // JP NC, width_cancel (>= 16)
+        self.fixup("width_cancel");
+        // Store new width
+        self.emit(&[0x32]); // LD (COL_WIDTH_VAR), A
+        self.emit_word(layout.var(COL_WIDTH_VAR));
+        self.emit(&[0xCD]); // CALL refresh_display
+        self.fixup("refresh_display");
+        self.emit(&[0xC3]); // JP main_loop
+        self.fixup("main_loop");
+
+        self.label("width_cancel");
+        self.emit(&[0xCD]); // CALL refresh_display
+        self.fixup("refresh_display");
+        self.emit(&[0xC3]); // JP main_loop
+        self.fixup("main_loop");
+
+        // Read a number typed at the prompt, echoing the digits, up to
+        // Enter: A = the number (mod 256). Carry set if any other key is
+        // typed
+        self.label("read_number");
         self.emit(&[0x0E, 0x00]); // LD C, 0 (accumulator)
-        self.label("width_digit_loop");
+        self.label("read_number_loop");
         self.emit(&[0xCD]); // CALL getchar
         self.fixup("getchar");
         self.emit(&[0xFE, 0x0D]); // CP CR
-        self.emit(&[0xCA]); // JP Z, width_done
-        self.fixup("width_done");
+        self.emit(&[0x28]); // JR Z, read_number_done
+        self.emit_relative("read_number_done");
         self.emit(&[0xCD]); // CALL putchar (echo)
         self.fixup("putchar");
         // Check if digit
-        self.emit(&[0xFE, b'0']);
-        self.emit(&[0xDA]); // JP C, width_cancel
-        self.fixup("width_cancel");
-        self.emit(&[0xFE, b'9' + 1]);
-        self.emit(&[0xD2]); // JP NC, width_cancel
-        self.fixup("width_cancel");
-        // C = C * 10 + digit
         self.emit(&[0xD6, b'0']); // SUB '0'
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xFE, 10]); // CP 10
+        self.emit(&[0x3F]); // CCF
+        self.emit(&[0xD8]); // RET C
+        // C = C * 10 + digit
         self.ld_b_a();
         self.ld_a_c();
         self.emit(&[0x87]); // ADD A, A (x2)
@@ -935,104 +1529,169 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x81]); // ADD A, C (x10)
         self.emit(&[0x80]); // ADD A, B
         self.ld_c_a();
-        self.emit(&[0xC3]); // JP width_digit_loop
-        self.fixup("width_digit_loop");
-
-        self.label("width_done");
-        // Validate width: 5-15
+        self.emit(&[0x18]); // JR read_number_loop
+        self.emit_relative("read_number_loop");
+        self.label("read_number_done");
         self.ld_a_c();
-        self.emit(&[0xFE, 5]); // CP 5
-        self.emit(&[0xDA]); // JP C, width_cancel (< 5)
-        self.fixup("width_cancel");
-        self.emit(&[0xFE, 16]); // CP 16
-        self.emit(&[0xD2]); // JP NC, width_cancel (>= 16)
-        self.fixup("width_cancel");
-        // Store new width
-        self.emit(&[0x32]); // LD (COL_WIDTH_VAR), A
-        self.emit_word(COL_WIDTH_VAR);
-        self.emit(&[0xCD]); // CALL refresh_display
-        self.fixup("refresh_display");
-        self.emit(&[0xC3]); // JP main_loop
-        self.fixup("main_loop");
+        self.or_a_a();
+        self.ret();
 
-        self.label("width_cancel");
+        // '!': recalculate all formulas and redraw
+        self.label("do_recalc");
+        self.emit(&[0xCD]); // CALL recalc_all
+        self.fixup("recalc_all");
         self.emit(&[0xCD]); // CALL refresh_display
         self.fixup("refresh_display");
         self.emit(&[0xC3]); // JP main_loop
         self.fixup("main_loop");
 
-        // Recalculate all formulas
-        self.label("do_recalc");
-        // Loop through all 1024 cells (16 cols x 64 rows)
+        // Re-evaluate every formula cell after the cells it reads. All
+        // formulas are first marked pending (an error cell with code
+        // ERR_PENDING), so reading one fails and leaves the reader pending
+        // too. Each sweep evaluates the pending formulas in memory order,
+        // and sweeps repeat while they resolve any. A sweep that resolves
+        // none is stuck on a reference cycle: one formula on the cycle
+        // gets ERR_CIRC, which its readers then take, and the sweeps go on
+        // from there. The first such formula is kept in RECALC_CIRC for
+        // the status line.
+        // With iteration (RECALC_LIMIT above 1) that formula keeps its last
+        // value instead, and once nothing is pending further passes
+        // evaluate every formula in order from the current values, like
+        // pressing '!' again, until a pass changes no value or the limit
+        // is reached
+        self.label("recalc_all");
+        self.emit(&[0x21, 0x00, 0x00]); // LD HL, 0
+        self.emit(&[0x22]); // LD (RECALC_CIRC), HL
+        self.emit_word(layout.var(RECALC_CIRC));
+        self.xor_a();
+        self.emit(&[0x32]); // LD (RECALC_PASSES), A (first pass)
+        self.emit_word(layout.var(RECALC_PASSES));
+        self.emit(&[0x21]); // LD HL, CELL_DATA
+        self.emit_word(layout.cell_data());
+        self.emit(&[0x01]); // LD BC, cell count
+        self.emit_word(layout.cell_count() as u16);
+        self.label("recalc_mark");
+        self.emit(&[0xCD]); // CALL recalc_formula
+        self.fixup("recalc_formula");
+        self.emit(&[0x20]); // JR NZ, recalc_mark_next
+        self.emit_relative("recalc_mark_next");
+        // Pending, and on no chain yet (link in bytes 4-5 cleared)
+        self.push_hl();
+        self.emit(&[0x36, CELL_ERROR]); // LD (HL), CELL_ERROR
+        self.inc_hl();
+        self.emit(&[0x36, ERR_PENDING]); // LD (HL), ERR_PENDING
+        self.inc_hl();
+        self.inc_hl();
+        self.inc_hl();
+        self.emit(&[0x36, 0x00]); // LD (HL), 0
+        self.inc_hl();
+        self.emit(&[0x36, 0x00]); // LD (HL), 0
+        self.pop_hl();
+        self.label("recalc_mark_next");
+        self.emit(&[0x11, CELL_SIZE, 0x00]); // LD DE, CELL_SIZE
+        self.add_hl_de();
+        self.emit(&[0x0B]); // DEC BC
+        self.ld_a_b();
+        self.emit(&[0xB1]); // OR C
+        self.emit(&[0x20]); // JR NZ, recalc_mark
+        self.emit_relative("recalc_mark");
+
+        self.label("recalc_sweep");
+        self.emit(&[0x21, 0x00, 0x00]); // LD HL, 0
+        self.emit(&[0x22]); // LD (RECALC_FIRST), HL (no pending cell yet)
+        self.emit_word(layout.var(RECALC_FIRST));
+        self.emit(&[0x22]); // LD (RECALC_LEFT), HL
+        self.emit_word(layout.var(RECALC_LEFT));
+        self.xor_a();
+        self.emit(&[0x32]); // LD (RECALC_MOVED), A
+        self.emit_word(layout.var(RECALC_MOVED));
         self.emit(&[0x21]); // LD HL, CELL_DATA
-        self.emit_word(CELL_DATA);
-        self.emit(&[0x11, 0x00, 0x04]); // LD DE, 1024 (cell count)
+        self.emit_word(layout.cell_data());
+        self.emit(&[0x11]); // LD DE, cell count
+        self.emit_word(layout.cell_count() as u16);
 
         self.label("recalc_loop");
         self.push_hl(); //save cell pointer)
         self.push_de(); //save counter)
 
-        // Check if this cell is a formula (type = 2)
+        // Only pending formulas are evaluated, or after the first pass
+        // every formula
+        self.emit(&[0x3A]); // LD A, (RECALC_PASSES)
+        self.emit_word(layout.var(RECALC_PASSES));
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, recalc_pending
+        self.emit_relative("recalc_pending");
+        self.emit(&[0xCD]); // CALL recalc_formula
+        self.fixup("recalc_formula");
+        self.emit(&[0xC2]); // JP NZ, recalc_next
+        self.fixup("recalc_next");
+        self.emit(&[0x18]); // JR recalc_cell
+        self.emit_relative("recalc_cell");
+        self.label("recalc_pending");
         self.ld_a_hl_ind();
-        self.emit(&[0xFE, 0x02]); // CP 2 (CELL_FORMULA)
+        self.emit(&[0xFE, CELL_ERROR]); // CP CELL_ERROR
         self.emit(&[0xC2]); // JP NZ, recalc_next
         self.fixup("recalc_next");
-
-        // It's a formula - get pointer from bytes 2-3
         self.inc_hl();
-        self.inc_hl();
-        self.emit(&[0x5E]); // LD E, (HL)
-        self.inc_hl();
-        self.emit(&[0x56]); // LD D, (HL)
-        // DE = formula pointer, save HL (points to high byte of pointer)
+        self.ld_a_hl_ind(); // error code
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0xFE, ERR_PENDING]); // CP ERR_PENDING
+        self.emit(&[0xC2]); // JP NZ, recalc_next
+        self.fixup("recalc_next");
+        self.label("recalc_cell");
+        self.emit(&[0xCD]); // CALL recalc_one
+        self.fixup("recalc_one");
+        self.emit(&[0x20]); // JR NZ, recalc_next
+        self.emit_relative("recalc_next");
+        // Waiting for a pending cell: evaluate that first, and whatever it
+        // waits for in turn
         self.push_hl();
-
-        // Copy formula pointer to TEMP2 for later
-        self.ex_de_hl(); //HL = formula string)
-        self.push_hl(); //save formula pointer)
-
-        // Skip the '=' and evaluate the expression
-        self.inc_hl(); //skip '=')
-        self.emit(&[0xCD]); // CALL eval_expr
-        self.fixup("eval_expr");
-        // HL = result
-
-        // Get formula pointer back
-        self.pop_de(); //DE = formula string pointer)
-        // Find end of string (null terminator)
-        self.label("recalc_find_end");
-        self.emit(&[0x1A]); // LD A, (DE)
-        self.inc_de();
+        self.emit(&[0xCD]); // CALL recalc_chase
+        self.fixup("recalc_chase");
+        self.pop_hl();
+        self.emit(&[0x20]); // JR NZ, recalc_next
+        self.emit_relative("recalc_next");
+        // Still waiting, on a cycle: count it and remember the first such
+        self.emit(&[0x3A]); // LD A, (RECALC_FIRST+1)
+        self.emit_word(layout.var(RECALC_FIRST) + 1);
         self.or_a_a();
-        self.emit(&[0xC2]); // JP NZ, recalc_find_end
-        self.fixup("recalc_find_end");
-        // DE now points to value storage location
-        // Store new BCD value (4 bytes from BCD_TEMP1) at (DE)
-        self.ex_de_hl(); //HL = storage ptr)
-        self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0x06, 4]); // LD B, 4
-        self.label("recalc_store_loop");
-        self.emit(&[0x1A]); // LD A, (DE)
-        self.emit(&[0x77]); // LD (HL), A
+        self.emit(&[0x20]); // JR NZ, recalc_count
+        self.emit_relative("recalc_count");
+        self.emit(&[0x22]); // LD (RECALC_FIRST), HL
+        self.emit_word(layout.var(RECALC_FIRST));
+        self.label("recalc_count");
+        self.emit(&[0x2A]); // LD HL, (RECALC_LEFT)
+        self.emit_word(layout.var(RECALC_LEFT));
         self.inc_hl();
-        self.inc_de();
-        self.emit(&[0x10]); // DJNZ recalc_store_loop
-        self.emit_relative("recalc_store_loop");
-
-        // Restore cell pointer high byte position
-        self.pop_hl();
+        self.emit(&[0x22]); // LD (RECALC_LEFT), HL
+        self.emit_word(layout.var(RECALC_LEFT));
 
         self.label("recalc_next");
         self.pop_de(); //restore counter)
         self.pop_hl(); //restore cell pointer)
-        // Move to next cell (6 bytes)
-        self.inc_hl();
-        self.inc_hl();
-        self.inc_hl();
-        self.inc_hl();
-        self.inc_hl();
-        self.inc_hl();
+        // Move to the next cell in the row, or in column order to the one
+        // below, and past the last row to the top of the next column
+        self.emit(&[0x01, CELL_SIZE, 0x00]); // LD BC, CELL_SIZE
+        self.emit(&[0x3A]); // LD A, (RECALC_FLAGS)
+        self.emit_word(layout.var(RECALC_FLAGS));
+        self.emit(&[0xE6, RECALC_BY_COL]); // AND RECALC_BY_COL
+        self.emit(&[0x28, 0x03]); // JR Z, +3
+        self.emit(&[0x01]); // LD BC, row size
+        self.emit_word(layout.cols() as u16 * CELL_SIZE as u16);
+        self.emit(&[0x09]); // ADD HL, BC
+        self.push_de();
+        self.emit(&[0x11]); // LD DE, end of CELL_DATA
+        self.emit_word(layout.cell_data() + (layout.cell_count() * CELL_SIZE as usize) as u16);
+        self.or_a_a();
+        self.emit(&[0xED, 0x52]); // SBC HL, DE
+        self.add_hl_de();
+        self.emit(&[0x38]); // JR C, recalc_step_done
+        self.emit_relative("recalc_step_done");
+        self.emit(&[0x11]); // LD DE, CELL_SIZE + CELL_DATA - end of CELL_DATA
+        self.emit_word((CELL_SIZE as u16 + layout.cell_data()).wrapping_sub(layout.cell_data() + (layout.cell_count() * CELL_SIZE as usize) as u16));
+        self.add_hl_de();
+        self.label("recalc_step_done");
+        self.pop_de();
         // Decrement counter
         self.emit(&[0x1B]); // DEC DE
         self.ld_a_d();
@@ -1040,11 +1699,269 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xC2]); // JP NZ, recalc_loop
         self.fixup("recalc_loop");
 
-        // Refresh display and return to main loop
-        self.emit(&[0xCD]); // CALL refresh_display
-        self.fixup("refresh_display");
-        self.emit(&[0xC3]); // JP main_loop
-        self.fixup("main_loop");
+        // After the first pass, one sweep is a pass
+        self.emit(&[0x3A]); // LD A, (RECALC_PASSES)
+        self.emit_word(layout.var(RECALC_PASSES));
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, recalc_pass_done
+        self.emit_relative("recalc_pass_done");
+        // Sweep again if that one resolved anything
+        self.emit(&[0x3A]); // LD A, (RECALC_MOVED)
+        self.emit_word(layout.var(RECALC_MOVED));
+        self.or_a_a();
+        self.emit(&[0xC2]); // JP NZ, recalc_sweep
+        self.fixup("recalc_sweep");
+        // Nothing left pending: the first pass is done
+        self.emit(&[0x2A]); // LD HL, (RECALC_FIRST)
+        self.emit_word(layout.var(RECALC_FIRST));
+        self.emit(&[0x7C]); // LD A, H
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, recalc_pass_done
+        self.emit_relative("recalc_pass_done");
+        // Stuck on a cycle: follow the cell each pending formula waits
+        // for. After as many steps as there are pending formulas this is
+        // a formula on the cycle
+        self.emit(&[0xED, 0x4B]); // LD BC, (RECALC_LEFT)
+        self.emit_word(layout.var(RECALC_LEFT));
+        self.label("recalc_walk");
+        self.push_bc();
+        self.emit(&[0xCD]); // CALL recalc_eval
+        self.fixup("recalc_eval");
+        self.emit(&[0x2A]); // LD HL, (RECALC_WAIT)
+        self.emit_word(layout.var(RECALC_WAIT));
+        self.pop_bc();
+        self.emit(&[0x0B]); // DEC BC
+        self.ld_a_b();
+        self.emit(&[0xB1]); // OR C
+        self.emit(&[0x20]); // JR NZ, recalc_walk
+        self.emit_relative("recalc_walk");
+        self.emit(&[0x3A]); // LD A, (RECALC_CIRC+1)
+        self.emit_word(layout.var(RECALC_CIRC) + 1);
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, recalc_circ
+        self.emit_relative("recalc_circ");
+        self.emit(&[0x22]); // LD (RECALC_CIRC), HL
+        self.emit_word(layout.var(RECALC_CIRC));
+        self.label("recalc_circ");
+        // Without iteration the formula fails, with it it keeps its value
+        self.emit(&[0x3A]); // LD A, (RECALC_LIMIT)
+        self.emit_word(layout.var(RECALC_LIMIT));
+        self.dec_a();
+        self.emit(&[0x3E, ERR_CIRC]); // LD A, ERR_CIRC
+        self.emit(&[0x28, 0x01]); // JR Z, +1
+        self.xor_a();
+        self.emit(&[0xCD]); // CALL formula_status
+        self.fixup("formula_status");
+        self.emit(&[0xC3]); // JP recalc_sweep
+        self.fixup("recalc_sweep");
+
+        // Count the pass; stop at the limit, or once a pass changes
+        // nothing unless every pass is to run. The first pass changes
+        // something left for the next only if it found a cycle
+        self.label("recalc_pass_done");
+        self.emit(&[0x21]); // LD HL, RECALC_PASSES
+        self.emit_word(layout.var(RECALC_PASSES));
+        self.ld_a_hl_ind();
+        self.emit(&[0x34]); // INC (HL)
+        self.or_a_a();
+        self.emit(&[0x3A]); // LD A, (RECALC_MOVED)
+        self.emit_word(layout.var(RECALC_MOVED));
+        self.emit(&[0x20, 0x03]); // JR NZ, +3
+        self.emit(&[0x3A]); // LD A, (RECALC_CIRC+1)
+        self.emit_word(layout.var(RECALC_CIRC) + 1);
+        self.ld_c_a();
+        self.emit(&[0x3A]); // LD A, (RECALC_LIMIT)
+        self.emit_word(layout.var(RECALC_LIMIT));
+        self.emit(&[0xBE]); // CP (HL)
+        self.ret_z();
+        self.emit(&[0x3A]); // LD A, (RECALC_FLAGS)
+        self.emit_word(layout.var(RECALC_FLAGS));
+        self.emit(&[0xE6, RECALC_FULL]); // AND RECALC_FULL
+        self.emit(&[0xB1]); // OR C
+        self.ret_z();
+        self.emit(&[0xC3]); // JP recalc_sweep
+        self.fixup("recalc_sweep");
+
+        // Re-evaluate the formula cell at HL, storing its value and status.
+        // Z if it still waits for a pending cell (the cell is left as it
+        // was); HL is kept
+        self.label("recalc_one");
+        self.push_hl();
+        self.emit(&[0xCD]); // CALL recalc_eval
+        self.fixup("recalc_eval");
+        // On error keep the previous value
+        self.emit(&[0x38]); // JR C, recalc_skip
+        self.emit_relative("recalc_skip");
+        // The value is stored before the text
+        self.emit(&[0x21]); // LD HL, -FORMULA_VALUE
+        self.emit_word(FORMULA_VALUE.wrapping_neg());
+        self.add_hl_de();
+        // Store sign, then the 4 BCD bytes from BCD_TEMP1; C collects the
+        // bits that changed
+        self.emit(&[0x4E]); // LD C, (HL)
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x77]); // LD (HL), A
+        self.emit(&[0xA9]); // XOR C
+        self.ld_c_a();
+        self.inc_hl();
+        self.emit(&[0x11]); // LD DE, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x06, 4]); // LD B, 4
+        self.label("recalc_store_loop");
+        self.emit(&[0x1A]); // LD A, (DE)
+        self.emit(&[0xAE]); // XOR (HL)
+        self.emit(&[0xB1]); // OR C
+        self.ld_c_a();
+        self.emit(&[0x1A]); // LD A, (DE)
+        self.emit(&[0x77]); // LD (HL), A
+        self.inc_hl();
+        self.inc_de();
+        self.emit(&[0x10]); // DJNZ recalc_store_loop
+        self.emit_relative("recalc_store_loop");
+        self.ld_a_c();
+        self.or_a_a();
+        self.emit(&[0x28, 0x03]); // JR Z, +3
+        self.emit(&[0x32]); // LD (RECALC_MOVED), A (the value changed)
+        self.emit_word(layout.var(RECALC_MOVED));
+        self.xor_a(); // no error
+        self.label("recalc_skip");
+        self.pop_hl(); // cell
+        self.emit(&[0xFE, ERR_PENDING]); // CP ERR_PENDING
+        self.ret_z();
+        // Set the type and error code; a pending cell always changes
+        self.push_hl();
+        self.inc_hl();
+        self.emit(&[0xBE]); // CP (HL)
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x28]); // JR Z, recalc_status
+        self.emit_relative("recalc_status");
+        self.ld_b_a();
+        self.emit(&[0x3E, 0x01]); // LD A, 1
+        self.emit(&[0x32]); // LD (RECALC_MOVED), A
+        self.emit_word(layout.var(RECALC_MOVED));
+        self.ld_a_b();
+        self.label("recalc_status");
+        self.emit(&[0xCD]); // CALL formula_status
+        self.fixup("formula_status");
+        self.pop_hl();
+        self.emit(&[0xF6, 0xFF]); // OR 0xFF (NZ: resolved)
+        self.ret();
+
+        // The pending formula cell at HL waits for the one at RECALC_WAIT:
+        // evaluate that first, depth first, so a chain of formulas each
+        // reading the one below resolves in one sweep rather than one
+        // sweep per link. The chain is a stack linked through bytes 4-5
+        // of its cells (unused while a formula is pending), 1 below the
+        // bottom, so it needs no more room than the grid. Reaching a cell
+        // already on the chain is a cycle: the chain is taken apart and
+        // left pending for the sweeps. NZ if the cell at HL resolved
+        self.label("recalc_chase");
+        self.emit(&[0x11, 0x01, 0x00]); // LD DE, 1
+        self.push_hl();
+        self.emit(&[0x01, 0x04, 0x00]); // LD BC, 4
+        self.emit(&[0x09]); // ADD HL, BC
+        self.emit(&[0x73]); // LD (HL), E
+        self.inc_hl();
+        self.emit(&[0x72]); // LD (HL), D
+        self.pop_hl();
+        // HL = top of the chain, waiting for the cell at RECALC_WAIT
+        self.label("recalc_chase_wait");
+        self.ex_de_hl();
+        self.emit(&[0x2A]); // LD HL, (RECALC_WAIT)
+        self.emit_word(layout.var(RECALC_WAIT));
+        self.push_hl();
+        self.emit(&[0x01, 0x04, 0x00]); // LD BC, 4
+        self.emit(&[0x09]); // ADD HL, BC
+        self.ld_a_hl_ind();
+        self.inc_hl();
+        self.emit(&[0xB6]); // OR (HL)
+        self.emit(&[0x20]); // JR NZ, recalc_chase_cycle
+        self.emit_relative("recalc_chase_cycle");
+        self.emit(&[0x72]); // LD (HL), D
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x73]); // LD (HL), E
+        self.pop_hl();
+        self.label("recalc_chase_eval");
+        self.emit(&[0xCD]); // CALL recalc_one
+        self.fixup("recalc_one");
+        self.emit(&[0x28]); // JR Z, recalc_chase_wait
+        self.emit_relative("recalc_chase_wait");
+        // Resolved: back to the cell below, until the bottom one resolves
+        self.emit(&[0xCD]); // CALL recalc_unlink
+        self.fixup("recalc_unlink");
+        self.emit(&[0x20]); // JR NZ, recalc_chase_eval
+        self.emit_relative("recalc_chase_eval");
+        self.inc_a(); // NZ
+        self.ret();
+        self.label("recalc_chase_cycle");
+        self.pop_hl();
+        self.ex_de_hl();
+        self.label("recalc_chase_unwind");
+        self.emit(&[0xCD]); // CALL recalc_unlink
+        self.fixup("recalc_unlink");
+        self.emit(&[0x20]); // JR NZ, recalc_chase_unwind
+        self.emit_relative("recalc_chase_unwind");
+        self.ret(); // Z
+
+        // Take the cell at HL off the chain: HL = the cell below it, Z if
+        // that is the bottom marker
+        self.label("recalc_unlink");
+        self.emit(&[0x01, 0x04, 0x00]); // LD BC, 4
+        self.emit(&[0x09]); // ADD HL, BC
+        self.emit(&[0x5E]); // LD E, (HL)
+        self.emit(&[0x36, 0x00]); // LD (HL), 0
+        self.inc_hl();
+        self.emit(&[0x56]); // LD D, (HL)
+        self.emit(&[0x36, 0x00]); // LD (HL), 0
+        self.ex_de_hl();
+        self.emit(&[0x7D]); // LD A, L
+        self.dec_a();
+        self.emit(&[0xB4]); // OR H
+        self.ret();
+
+        // Z if the cell at HL holds a formula: a formula cell, or an error
+        // cell with a formula (pointer in bytes 2-3 not 0)
+        self.label("recalc_formula");
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, CELL_FORMULA]); // CP CELL_FORMULA
+        self.ret_z();
+        self.emit(&[0xFE, CELL_ERROR]); // CP CELL_ERROR
+        self.emit(&[0xC0]); // RET NZ
+        self.inc_hl();
+        self.inc_hl();
+        self.inc_hl();
+        self.ld_a_hl_ind(); // pointer high byte
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0xD6, 0x01]); // SUB 1 (carry only if 0)
+        self.emit(&[0x9F]); // SBC A, A
+        self.ret();
+
+        // Evaluate the formula of the cell at HL from its tokens: result in
+        // BCD_TEMP1 and SIGN_ACCUM, or carry set and A = error code; DE =
+        // formula text
+        self.label("recalc_eval");
+        self.inc_hl();
+        self.inc_hl();
+        self.emit(&[0x5E]); // LD E, (HL)
+        self.inc_hl();
+        self.emit(&[0x56]); // LD D, (HL)
+        self.ex_de_hl(); // HL = formula string
+        self.push_hl();
+        // The offset to the tokens is the byte before the text
+        self.emit(&[0x2B]); // DEC HL
+        self.ld_a_hl_ind();
+        self.inc_hl();
+        self.emit(&[0x85]); // ADD A, L
+        self.emit(&[0x6F]); // LD L, A
+        self.emit(&[0x30, 0x01]); // JR NC, +1
+        self.emit(&[0x24]); // INC H
+        self.emit(&[0xCD]); // CALL eval_expr
+        self.fixup("eval_expr");
+        self.pop_de();
+        self.ret();
 
         // Quit
         self.label("quit");
@@ -1057,14 +1974,15 @@ impl SpreadsheetCodeGen {
 
     /// Display routines
     fn emit_display(&mut self) {
+        let layout = self.layout;
         // Adjust view to keep cursor visible
         self.label("adjust_view");
         // Check if cursor is above view
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (VIEW_TOP)
-        self.emit_word(VIEW_TOP);
+        self.emit_word(layout.var(VIEW_TOP));
         self.emit(&[0xB8]); // CP B
         self.emit(&[0xDA]); // JP C, adjust_check_bottom
         self.fixup("adjust_check_bottom");
@@ -1073,15 +1991,15 @@ impl SpreadsheetCodeGen {
         // Cursor above view - set VIEW_TOP = CURSOR_ROW
         self.ld_a_b();
         self.emit(&[0x32]); // LD (VIEW_TOP), A
-        self.emit_word(VIEW_TOP);
+        self.emit_word(layout.var(VIEW_TOP));
 
         self.label("adjust_check_bottom");
         // Check if cursor is below view
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (VIEW_TOP)
-        self.emit_word(VIEW_TOP);
+        self.emit_word(layout.var(VIEW_TOP));
         self.emit(&[0xC6, VISIBLE_ROWS - 1]); // ADD A, VISIBLE_ROWS-1
         self.emit(&[0xB8]); // CP B
         self.emit(&[0xD2]); // JP NC, adjust_check_left
@@ -1090,15 +2008,15 @@ impl SpreadsheetCodeGen {
         self.ld_a_b();
         self.emit(&[0xD6, VISIBLE_ROWS - 1]); // SUB VISIBLE_ROWS-1
         self.emit(&[0x32]); // LD (VIEW_TOP), A
-        self.emit_word(VIEW_TOP);
+        self.emit_word(layout.var(VIEW_TOP));
 
         self.label("adjust_check_left");
         // Similar logic for columns
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (VIEW_LEFT)
-        self.emit_word(VIEW_LEFT);
+        self.emit_word(layout.var(VIEW_LEFT));
         self.emit(&[0xB8]); // CP B
         self.emit(&[0xDA]); // JP C, adjust_check_right
         self.fixup("adjust_check_right");
@@ -1106,14 +2024,14 @@ impl SpreadsheetCodeGen {
         self.fixup("adjust_check_right");
         self.ld_a_b();
         self.emit(&[0x32]); // LD (VIEW_LEFT), A
-        self.emit_word(VIEW_LEFT);
+        self.emit_word(layout.var(VIEW_LEFT));
 
         self.label("adjust_check_right");
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (VIEW_LEFT)
-        self.emit_word(VIEW_LEFT);
+        self.emit_word(layout.var(VIEW_LEFT));
         self.emit(&[0xC6, VISIBLE_COLS - 1]); // ADD A, VISIBLE_COLS-1
         self.emit(&[0xB8]); // CP B
         self.emit(&[0xD2]); // JP NC, adjust_done
@@ -1121,7 +2039,7 @@ impl SpreadsheetCodeGen {
         self.ld_a_b();
         self.emit(&[0xD6, VISIBLE_COLS - 1]); // SUB VISIBLE_COLS-1
         self.emit(&[0x32]); // LD (VIEW_LEFT), A
-        self.emit_word(VIEW_LEFT);
+        self.emit_word(layout.var(VIEW_LEFT));
 
         self.label("adjust_done");
         self.ret();
@@ -1177,13 +2095,13 @@ impl SpreadsheetCodeGen {
 
         // Print column headers
         self.emit(&[0x3A]); // LD A, (VIEW_LEFT)
-        self.emit_word(VIEW_LEFT);
+        self.emit_word(layout.var(VIEW_LEFT));
         self.ld_b_a(); //B = current column)
         self.emit(&[0x0E, VISIBLE_COLS]); // LD C, VISIBLE_COLS (counter)
 
         self.label("header_col_loop");
         self.ld_a_b();
-        self.emit(&[0xFE, GRID_COLS]); // CP GRID_COLS
+        self.emit(&[0xFE, layout.cols()]); // CP cols
         self.emit(&[0xD2]); // JP NC, header_done
         self.fixup("header_done");
         self.emit(&[0xC6, b'A']); // ADD A, 'A'
@@ -1210,29 +2128,29 @@ impl SpreadsheetCodeGen {
 
         // Print each row
         self.emit(&[0x3A]); // LD A, (VIEW_TOP)
-        self.emit_word(VIEW_TOP);
+        self.emit_word(layout.var(VIEW_TOP));
         self.emit(&[0x32]); // LD (TEMP1), A (current row in grid)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.emit(&[0x3E, 0]); // LD A, 0
         self.emit(&[0x32]); // LD (TEMP1+1), A (screen row offset, 0-9)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
 
         self.label("display_row_loop");
         self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
-        self.emit(&[0xFE, GRID_ROWS]); // CP GRID_ROWS
+        self.emit_word(layout.var(TEMP1));
+        self.emit(&[0xFE, layout.rows()]); // CP rows
         self.emit(&[0xD2]); // JP NC, display_done
         self.fixup("display_done");
         // Check if we've done all visible rows
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.emit(&[0xFE, VISIBLE_ROWS]); // CP VISIBLE_ROWS
         self.emit(&[0xD2]); // JP NC, display_done
         self.fixup("display_done");
 
         // Position cursor at start of this row: DATA_ROW + screen_row_offset
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.emit(&[0xC6, DATA_ROW]); // ADD A, DATA_ROW
         self.ld_b_a(); //row)
         self.emit(&[0x0E, 1]); // LD C, 1 (col)
@@ -1241,7 +2159,7 @@ impl SpreadsheetCodeGen {
 
         // Print row number (1-based, right-aligned in 4 chars)
         self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.inc_a(); //1-based)
         self.emit(&[0x6F]); // LD L, A
         self.emit(&[0x26, 0x00]); // LD H, 0
@@ -1250,27 +2168,27 @@ impl SpreadsheetCodeGen {
 
         // Print cells in this row
         self.emit(&[0x3A]); // LD A, (VIEW_LEFT)
-        self.emit_word(VIEW_LEFT);
+        self.emit_word(layout.var(VIEW_LEFT));
         self.ld_b_a(); //B = current col)
         self.emit(&[0x0E, VISIBLE_COLS]); // LD C, VISIBLE_COLS
 
         self.label("display_cell_loop");
         self.ld_a_b();
-        self.emit(&[0xFE, GRID_COLS]); // CP GRID_COLS
+        self.emit(&[0xFE, layout.cols()]); // CP cols
         self.emit(&[0xD2]); // JP NC, display_row_end
         self.fixup("display_row_end");
 
         // Check if this is the cursor cell
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.emit(&[0xB8]); // CP B
         self.emit(&[0xC2]); // JP NZ, not_cursor_cell
         self.fixup("not_cursor_cell");
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.push_hl();
         self.emit(&[0x2A]); // LD HL, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.emit(&[0xBD]); // CP L
         self.pop_hl();
         self.emit(&[0xC2]); // JP NZ, not_cursor_cell
@@ -1293,7 +2211,7 @@ impl SpreadsheetCodeGen {
         self.ld_a_b(); //col)
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (TEMP1) (row)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
@@ -1303,15 +2221,15 @@ impl SpreadsheetCodeGen {
 
         // Check if cursor cell for closing bracket
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.emit(&[0xB8]); // CP B
         self.emit(&[0xC2]); // JP NZ, cell_no_bracket
         self.fixup("cell_no_bracket");
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.push_hl();
         self.emit(&[0x2A]); // LD HL, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.emit(&[0xBD]); // CP L
         self.pop_hl();
         self.emit(&[0xC2]); // JP NZ, cell_no_bracket
@@ -1336,16 +2254,16 @@ impl SpreadsheetCodeGen {
         self.label("display_row_end");
         // Increment grid row (TEMP1)
         self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.inc_a();
         self.emit(&[0x32]); // LD (TEMP1), A
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         // Increment screen row offset (TEMP1+1)
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.inc_a();
         self.emit(&[0x32]); // LD (TEMP1+1), A
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.emit(&[0xC3]); // JP display_row_loop (always loop, check at top)
         self.fixup("display_row_loop");
 
@@ -1394,10 +2312,7 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xCD]); // CALL putchar
         self.fixup("putchar");
         self.emit(&[0x10]); // DJNZ print_empty_loop
-        let offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder for relative jump
-        self.rom_mut()[offset] = (self.get_label("print_empty_loop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
+        self.emit_relative("print_empty_loop");
         self.ret();
 
         self.label("print_cell_number");
@@ -1408,7 +2323,7 @@ impl SpreadsheetCodeGen {
         // Copy 4 BCD bytes to BCD_TEMP1
         self.push_bc(); // save sign
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x06, 4]); // LD B, 4
         self.label("load_bcd_loop");
         self.ld_a_hl_ind();
@@ -1426,11 +2341,29 @@ impl SpreadsheetCodeGen {
         self.fixup("print_bcd_cell_signed");
         self.ret();
 
+        // Error cell: the name of the error code in byte 1
         self.label("print_cell_error");
-        self.emit(&[0x21]); // LD HL, error_str
-        self.fixup("error_str");
-        self.emit(&[0xCD]); // CALL print_string
-        self.fixup("print_string");
+        self.inc_hl();
+        self.ld_a_hl_ind(); // error code (ERR_ERROR and up)
+        self.emit(&[0x21]); // LD HL, error_names
+        self.fixup("error_names");
+        self.emit(&[0x11, CELL_WIDTH - 2, 0x00]); // LD DE, CELL_WIDTH-2
+        self.label("print_error_find");
+        self.dec_a();
+        self.emit(&[0x28]); // JR Z, print_error_name
+        self.emit_relative("print_error_name");
+        self.add_hl_de();
+        self.emit(&[0x18]); // JR print_error_find
+        self.emit_relative("print_error_find");
+        self.label("print_error_name");
+        self.emit(&[0x06, CELL_WIDTH - 2]); // LD B, CELL_WIDTH-2
+        self.label("print_error_loop");
+        self.ld_a_hl_ind();
+        self.emit(&[0xCD]); // CALL putchar
+        self.fixup("putchar");
+        self.inc_hl();
+        self.emit(&[0x10]); // DJNZ print_error_loop
+        self.emit_relative("print_error_loop");
         self.ret();
 
         // Formula cell - get pointer and read sign + BCD value
@@ -1441,14 +2374,10 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x5E]); // LD E, (HL)
         self.inc_hl();
         self.emit(&[0x56]); // LD D, (HL)
-        // DE = formula pointer, scan to end of string to find value
-        self.ex_de_hl(); //HL = formula pointer)
-        self.label("find_formula_value");
-        self.ld_a_hl_ind();
-        self.inc_hl();
-        self.or_a_a();
-        self.emit(&[0xC2]); // JP NZ, find_formula_value
-        self.fixup("find_formula_value");
+        // DE = formula pointer, the value is stored before the text
+        self.emit(&[0x21]); // LD HL, -FORMULA_VALUE
+        self.emit_word(FORMULA_VALUE.wrapping_neg());
+        self.add_hl_de();
         // HL now points to sign byte, then 4 BCD bytes
         self.ld_a_hl_ind(); // load sign
         self.ld_c_a(); // save sign in C
@@ -1456,7 +2385,7 @@ impl SpreadsheetCodeGen {
         // Copy BCD to BCD_TEMP1
         self.push_bc(); // save sign
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x06, 4]); // LD B, 4
         self.label("load_formula_bcd");
         self.ld_a_hl_ind();
@@ -1485,10 +2414,7 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xCD]); // CALL putchar
         self.fixup("putchar");
         self.emit(&[0x10]); // DJNZ print_repeat_loop
-        let repeat_offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        self.rom_mut()[repeat_offset] = (self.get_label("print_repeat_loop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
+        self.emit_relative("print_repeat_loop");
         self.ret();
 
         // Print label cell (left-aligned string)
@@ -1513,10 +2439,7 @@ impl SpreadsheetCodeGen {
         self.fixup("putchar");
         self.inc_hl();
         self.emit(&[0x10]); // DJNZ print_label_loop
-        let label_offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        self.rom_mut()[label_offset] = (self.get_label("print_label_loop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
+        self.emit_relative("print_label_loop");
         self.ret(); //printed all CELL_WIDTH-2 chars)
         // Pad remaining with spaces
         self.label("print_label_pad");
@@ -1528,26 +2451,15 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xCD]); // CALL putchar
         self.fixup("putchar");
         self.emit(&[0x10]); // DJNZ print_label_pad_loop
-        let pad_offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        self.rom_mut()[pad_offset] = (self.get_label("print_label_pad_loop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
+        self.emit_relative("print_label_pad_loop");
         self.ret();
 
         // Print status line showing current cell
         self.label("print_status");
-        self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
-        self.emit(&[0xC6, b'A']); // ADD A, 'A'
-        self.emit(&[0xCD]); // CALL putchar
-        self.fixup("putchar");
-        self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
-        self.inc_a(); //1-based)
-        self.emit(&[0x6F]); // LD L, A
-        self.emit(&[0x26, 0x00]); // LD H, 0
-        self.emit(&[0xCD]); // CALL print_int
-        self.fixup("print_int");
+        self.emit(&[0xED, 0x4B]); // LD BC, (CURSOR_COL) (C = col, B = row)
+        self.emit_word(layout.var(CURSOR_COL));
+        self.emit(&[0xCD]); // CALL print_cell_name
+        self.fixup("print_cell_name");
         self.emit(&[0x3E, b':']); // LD A, ':'
         self.emit(&[0xCD]); // CALL putchar
         self.fixup("putchar");
@@ -1556,16 +2468,115 @@ impl SpreadsheetCodeGen {
         self.fixup("putchar");
         // Print current cell's content/formula
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
         self.emit(&[0xCD]); // CALL print_cell_content
         self.fixup("print_cell_content");
-        self.ret();
+        // Recalculation mode and order
+        self.emit(&[0x06, STATUS_ROW]); // LD B, STATUS_ROW
+        self.emit(&[0x0E, STATUS_FLAGS_COL]); // LD C, STATUS_FLAGS_COL
+        self.emit(&[0xCD]); // CALL cursor_pos
+        self.fixup("cursor_pos");
+        self.emit(&[0x21]); // LD HL, auto_str
+        self.fixup("auto_str");
+        self.emit(&[0x3A]); // LD A, (RECALC_FLAGS)
+        self.emit_word(layout.var(RECALC_FLAGS));
+        self.emit(&[0xE6, RECALC_MANUAL]); // AND RECALC_MANUAL
+        self.emit(&[0x28, 0x03]); // JR Z, +3
+        self.emit(&[0x21]); // LD HL, manual_str
+        self.fixup("manual_str");
+        self.emit(&[0xCD]); // CALL print_string
+        self.fixup("print_string");
+        self.emit(&[0x21]); // LD HL, by_row_str
+        self.fixup("by_row_str");
+        self.emit(&[0x3A]); // LD A, (RECALC_FLAGS)
+        self.emit_word(layout.var(RECALC_FLAGS));
+        self.emit(&[0xE6, RECALC_BY_COL]); // AND RECALC_BY_COL
+        self.emit(&[0x28, 0x03]); // JR Z, +3
+        self.emit(&[0x21]); // LD HL, by_col_str
+        self.fixup("by_col_str");
+        self.emit(&[0xCD]); // CALL print_string
+        self.fixup("print_string");
+        // Heap bytes left
+        self.emit(&[0x21]); // LD HL, free_str
+        self.fixup("free_str");
+        self.emit(&[0xCD]); // CALL print_string
+        self.fixup("print_string");
+        self.emit(&[0xCD]); // CALL heap_free
+        self.fixup("heap_free");
+        self.emit(&[0xCD]); // CALL print_int_pos
+        self.fixup("print_int_pos");
+        self.emit(&[0x3E, b' ']); // LD A, ' '
+        self.emit(&[0xCD]); // CALL putchar
+        self.fixup("putchar");
+        // With iteration, ITER and the passes run
+        self.emit(&[0x3A]); // LD A, (RECALC_LIMIT)
+        self.emit_word(layout.var(RECALC_LIMIT));
+        self.emit(&[0xFE, 0x02]); // CP 2
+        self.emit(&[0x38]); // JR C, status_circ
+        self.emit_relative("status_circ");
+        self.emit(&[0x21]); // LD HL, iter_str
+        self.fixup("iter_str");
+        self.emit(&[0xCD]); // CALL print_string
+        self.fixup("print_string");
+        self.emit(&[0x3A]); // LD A, (RECALC_PASSES)
+        self.emit_word(layout.var(RECALC_PASSES));
+        self.emit(&[0xCD]); // CALL print_byte_dec
+        self.fixup("print_byte_dec");
+        self.emit(&[0x3E, b' ']); // LD A, ' '
+        self.emit(&[0xCD]); // CALL putchar
+        self.fixup("putchar");
+        self.label("status_circ");
+        // CIRC and the cell where the last recalculation found a cycle
+        self.emit(&[0x2A]); // LD HL, (RECALC_CIRC)
+        self.emit_word(layout.var(RECALC_CIRC));
+        self.emit(&[0x7C]); // LD A, H
+        self.or_a_a();
+        self.ret_z();
+        self.push_hl();
+        self.emit(&[0x21]); // LD HL, circ_str
+        self.fixup("circ_str");
+        self.emit(&[0xCD]); // CALL print_string
+        self.fixup("print_string");
+        self.pop_hl();
+        // Cell address to row (B) and column (C)
+        self.emit(&[0x11]); // LD DE, CELL_DATA
+        self.emit_word(layout.cell_data());
+        self.or_a_a();
+        self.emit(&[0xED, 0x52]); // SBC HL, DE
+        self.emit(&[0x11]); // LD DE, row size
+        self.emit_word(layout.cols() as u16 * CELL_SIZE as u16);
+        self.emit(&[0x06, 0xFF]); // LD B, -1
+        self.label("status_circ_row");
+        self.inc_b();
+        self.emit(&[0xED, 0x52]); // SBC HL, DE
+        self.emit(&[0x30]); // JR NC, status_circ_row
+        self.emit_relative("status_circ_row");
+        self.add_hl_de();
+        self.emit(&[0x7D]); // LD A, L (column * CELL_SIZE)
+        self.emit(&[0x0E, 0xFF]); // LD C, -1
+        self.label("status_circ_col");
+        self.inc_c();
+        self.emit(&[0xD6, CELL_SIZE]); // SUB CELL_SIZE
+        self.emit(&[0x30]); // JR NC, status_circ_col
+        self.emit_relative("status_circ_col");
+
+        // Print a cell name like B12 (C = column, B = 0-based row)
+        self.label("print_cell_name");
+        self.ld_a_c();
+        self.emit(&[0xC6, b'A']); // ADD A, 'A'
+        self.emit(&[0xCD]); // CALL putchar
+        self.fixup("putchar");
+        self.emit(&[0x68]); // LD L, B
+        self.emit(&[0x2C]); // INC L (1-based)
+        self.emit(&[0x26, 0x00]); // LD H, 0
+        self.emit(&[0xC3]); // JP print_int
+        self.fixup("print_int");
 
         // Print cell content (raw value or formula)
         self.label("print_cell_content");
@@ -1582,7 +2593,7 @@ impl SpreadsheetCodeGen {
         // Copy 4 BCD bytes to BCD_TEMP1
         self.push_bc(); // save sign
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x06, 4]); // LD B, 4
         self.label("load_status_bcd");
         self.ld_a_hl_ind();
@@ -1607,7 +2618,7 @@ impl SpreadsheetCodeGen {
         // Print INPUT_BUF, skipping leading zeros
         self.label("status_skip_zeros");
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.emit(&[0x06, 7]); // LD B, 7 (skip up to 7 leading zeros)
         self.label("status_skip_zeros_loop");
         self.ld_a_hl_ind();
@@ -1623,12 +2634,16 @@ impl SpreadsheetCodeGen {
         self.ret();
 
         self.label("print_content_formula");
-        // Print the formula text (stored at formula pointer)
+        // Print the formula text (stored at formula pointer); error cells
+        // without a formula have a null pointer and print nothing
         self.inc_hl();
         self.inc_hl();
         self.emit(&[0x5E]); // LD E, (HL)
         self.inc_hl();
         self.emit(&[0x56]); // LD D, (HL)
+        self.ld_a_d();
+        self.or_a_a();
+        self.ret_z();
         self.ex_de_hl(); //HL = formula pointer)
         self.emit(&[0xCD]); // CALL print_string
         self.fixup("print_string");
@@ -1650,9 +2665,9 @@ impl SpreadsheetCodeGen {
         self.fixup("putchar");
         // Print input buffer
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.ld_b_a();
         self.or_a_a();
         self.emit(&[0xCA]); // JP Z, show_input_done
@@ -1663,12 +2678,7 @@ impl SpreadsheetCodeGen {
         self.fixup("putchar");
         self.inc_hl();
         self.emit(&[0x10]); // DJNZ
-        let offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        // Calculate relative offset for DJNZ
-        let target = self.get_label("show_input_loop").unwrap_or(0);
-        let current = self.pos();
-        self.rom_mut()[offset] = target.wrapping_sub(current) as u8;
+        self.emit_relative("show_input_loop");
         self.label("show_input_done");
         // Clear to end of line (removes old chars when backspacing)
         self.emit(&[0xCD]); // CALL clear_to_eol
@@ -1678,16 +2688,17 @@ impl SpreadsheetCodeGen {
 
     /// Input handling
     fn emit_input(&mut self) {
+        let layout = self.layout;
         // Parse input buffer and store in current cell
         self.label("parse_and_store");
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.or_a_a();
         self.ret_z(); //empty input)
 
         // Check if formula (starts with '=')
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.ld_a_hl_ind();
         self.emit(&[0xFE, b'=']);
         self.emit(&[0xCA]); // JP Z, parse_formula
@@ -1701,16 +2712,16 @@ impl SpreadsheetCodeGen {
         // Otherwise parse as number
         self.emit(&[0xCD]); // CALL parse_number
         self.fixup("parse_number");
-        // C = sign, BCD value in BCD_TEMP1, carry set if error
+        // C = sign, BCD value in BCD_TEMP1, carry set (code in A) if error
         self.emit(&[0xDA]); // JP C, store_error
         self.fixup("store_error");
         // Store as number in current cell (6 bytes: type, sign, 4 BCD bytes)
         self.push_bc(); // save sign in C
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
@@ -1721,7 +2732,7 @@ impl SpreadsheetCodeGen {
         self.inc_hl();
         // Copy 4 BCD bytes from BCD_TEMP1 to cell
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x06, 4]); // LD B, 4
         self.label("store_num_loop");
         self.emit(&[0x1A]); // LD A, (DE)
@@ -1732,30 +2743,43 @@ impl SpreadsheetCodeGen {
         self.emit_relative("store_num_loop");
         self.ret();
 
+        // Store an error cell with the code in A at the cursor
+        self.label("store_syntax");
+        self.emit(&[0x3E, ERR_SYNTAX]); // LD A, ERR_SYNTAX
         self.label("store_error");
+        self.push_af();
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
-        self.emit(&[0x36, CELL_ERROR]); // LD (HL), CELL_ERROR
+        self.pop_af();
+        self.emit(&[0xCD]); // CALL formula_status (CELL_ERROR)
+        self.fixup("formula_status");
+        self.inc_hl();
+        self.emit(&[0x36, 0x00]); // LD (HL), 0 (no formula)
+        self.inc_hl();
+        self.emit(&[0x36, 0x00]); // LD (HL), 0
         self.ret();
 
         // Parse and store label (starts with ")
         self.label("parse_label");
         // Copy label text to SCRATCH storage area (reuse formula storage)
-        // Get storage pointer
-        self.emit(&[0x2A]); // LD HL, (FORMULA_PTR)
-        self.emit_word(FORMULA_PTR);
+        // Get storage pointer, room for the text and NUL
+        self.emit(&[0x3E, 1]); // LD A, 1
+        self.emit(&[0xCD]); // CALL heap_alloc
+        self.fixup("heap_alloc");
+        self.emit(&[0xDA]); // JP C, memory_full
+        self.fixup("memory_full");
         self.push_hl(); //save label pointer for cell)
         // Copy input buffer to storage
         self.emit(&[0x11]); // LD DE, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.ld_b_a(); //loop count)
         self.label("copy_label_loop");
         self.emit(&[0x1A]); // LD A, (DE)
@@ -1763,22 +2787,19 @@ impl SpreadsheetCodeGen {
         self.inc_de();
         self.inc_hl();
         self.emit(&[0x10]); // DJNZ copy_label_loop
-        let copy_label_offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        self.rom_mut()[copy_label_offset] = (self.get_label("copy_label_loop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
+        self.emit_relative("copy_label_loop");
         // Add null terminator
         self.emit(&[0x36, 0x00]); // LD (HL), 0
         self.inc_hl();
         // Update FORMULA_PTR
         self.emit(&[0x22]); // LD (FORMULA_PTR), HL
-        self.emit_word(FORMULA_PTR);
+        self.emit_word(layout.var(FORMULA_PTR));
         // Get cell address
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
@@ -1794,15 +2815,201 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x72]); // LD (HL), D
         self.ret();
 
+        // Heap room for the input text plus A bytes: HL = FORMULA_PTR, or
+        // carry if even compacting the heap leaves too little
+        self.label("heap_alloc");
+        self.emit(&[0x21]); // LD HL, INPUT_LEN
+        self.emit_word(layout.var(INPUT_LEN));
+        self.emit(&[0x86]); // ADD A, (HL)
+        self.ld_c_a();
+        self.emit(&[0x06, 0x00]); // LD B, 0
+        self.emit(&[0xCD]); // CALL heap_room
+        self.fixup("heap_room");
+        self.emit(&[0xD0]); // RET NC
+        self.push_bc();
+        self.emit(&[0xCD]); // CALL heap_collect
+        self.fixup("heap_collect");
+        self.pop_bc();
+        self.label("heap_room");
+        self.emit(&[0xCD]); // CALL heap_free
+        self.fixup("heap_free");
+        self.emit(&[0xED, 0x42]); // SBC HL, BC (carry clear from heap_free)
+        self.emit(&[0x2A]); // LD HL, (FORMULA_PTR)
+        self.emit_word(layout.var(FORMULA_PTR));
+        self.ret();
+
+        // HL = bytes left between FORMULA_PTR and the state block
+        self.label("heap_free");
+        self.emit(&[0xED, 0x5B]); // LD DE, (FORMULA_PTR)
+        self.emit_word(layout.var(FORMULA_PTR));
+        self.emit(&[0x21]); // LD HL, heap end
+        self.emit_word(layout.heap_end());
+        self.or_a_a();
+        self.emit(&[0xED, 0x52]); // SBC HL, DE
+        self.ret();
+
+        // Slide every block some cell still points at down over the
+        // dead ones, in heap order. A label block is its text and NUL; a
+        // formula block (see FORMULA_VALUE) starts with the value, whose
+        // sign is never the '"' a label starts with. Each pointer to the
+        // block (/R copies share them) is moved to the block's new
+        // address, TEMP2 plus C, before the bytes are. Ends with
+        // FORMULA_PTR after the last live block
+        self.label("heap_collect");
+        self.emit(&[0x21]); // LD HL, SCRATCH
+        self.emit_word(layout.scratch());
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.label("gc_block");
+        // HL = this block; done when it reaches FORMULA_PTR
+        self.emit(&[0xED, 0x5B]); // LD DE, (FORMULA_PTR)
+        self.emit_word(layout.var(FORMULA_PTR));
+        self.or_a_a();
+        self.emit(&[0xED, 0x52]); // SBC HL, DE
+        self.add_hl_de(); // carry still set if HL < FORMULA_PTR
+        self.emit(&[0x30]); // JR NC, gc_done
+        self.emit_relative("gc_done");
+        // C = bytes from the block to what the cells point at
+        self.emit(&[0x01]); // LD BC, 0
+        self.emit_word(0);
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'"']); // CP '"'
+        self.emit(&[0x28, 0x03]); // JR Z, +3
+        self.emit(&[0x0E, FORMULA_VALUE as u8]); // LD C, FORMULA_VALUE
+        self.emit(&[0x09]); // ADD HL, BC
+        self.emit(&[0x54]); // LD D, H
+        self.emit(&[0x5D]); // LD E, L
+        self.label("gc_text");
+        self.ld_a_hl_ind();
+        self.inc_hl();
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, gc_text
+        self.emit_relative("gc_text");
+        self.emit(&[0xB1]); // OR C (a label ends at its NUL)
+        self.emit(&[0x28]); // JR Z, gc_end
+        self.emit_relative("gc_end");
+        self.label("gc_tokens");
+        self.emit(&[0xCD]); // CALL tok_next
+        self.fixup("tok_next");
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, gc_tokens
+        self.emit_relative("gc_tokens");
+        self.label("gc_end");
+        self.push_hl(); // next block
+        // B = 1 once a cell pointing at DE is found
+        self.emit(&[0x21]); // LD HL, CELL_DATA
+        self.emit_word(layout.cell_data());
+        self.label("gc_scan");
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, CELL_FORMULA]); // CP CELL_FORMULA
+        self.emit(&[0x38]); // JR C, gc_next
+        self.emit_relative("gc_next");
+        self.emit(&[0xFE, CELL_REPEAT]); // CP CELL_REPEAT
+        self.emit(&[0x28]); // JR Z, gc_next
+        self.emit_relative("gc_next");
+        self.emit(&[0xFE, CELL_LABEL + 1]); // CP CELL_LABEL + 1
+        self.emit(&[0x30]); // JR NC, gc_next
+        self.emit_relative("gc_next");
+        self.inc_hl();
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.inc_hl();
+        self.emit(&[0xBB]); // CP E
+        self.emit(&[0x20]); // JR NZ, gc_other
+        self.emit_relative("gc_other");
+        self.ld_a_hl_ind();
+        self.emit(&[0xBA]); // CP D
+        self.emit(&[0x20]); // JR NZ, gc_other
+        self.emit_relative("gc_other");
+        self.push_de();
+        self.emit(&[0xED, 0x5B]); // LD DE, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0x7B]); // LD A, E
+        self.emit(&[0x81]); // ADD A, C
+        self.ld_e_a();
+        self.emit(&[0x30, 0x01]); // JR NC, +1
+        self.emit(&[0x14]); // INC D
+        self.emit(&[0x72]); // LD (HL), D
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x73]); // LD (HL), E
+        self.inc_hl();
+        self.pop_de();
+        self.emit(&[0x06, 0x01]); // LD B, 1
+        self.label("gc_other");
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x2B]); // DEC HL
+        self.label("gc_next");
+        self.emit(&[0x7D]); // LD A, L
+        self.emit(&[0xC6, CELL_SIZE]); // ADD A, CELL_SIZE
+        self.emit(&[0x6F]); // LD L, A
+        self.emit(&[0x30, 0x01]); // JR NC, +1
+        self.emit(&[0x24]); // INC H
+        // The cells end where the input buffer starts
+        let [end_lo, end_hi] = layout.input_buf().to_le_bytes();
+        self.emit(&[0x7D]); // LD A, L
+        self.emit(&[0xFE, end_lo]); // CP end lo
+        self.emit(&[0x20]); // JR NZ, gc_scan
+        self.emit_relative("gc_scan");
+        self.emit(&[0x7C]); // LD A, H
+        self.emit(&[0xFE, end_hi]); // CP end hi
+        self.emit(&[0x20]); // JR NZ, gc_scan
+        self.emit_relative("gc_scan");
+        self.pop_hl(); // next block
+        self.emit(&[0x10]); // DJNZ gc_block (nothing points here)
+        self.emit_relative("gc_block");
+        // Move the block, DE - C up to HL, down to TEMP2
+        self.emit(&[0x7B]); // LD A, E
+        self.emit(&[0x91]); // SUB C
+        self.ld_e_a();
+        self.emit(&[0x30, 0x01]); // JR NC, +1
+        self.emit(&[0x15]); // DEC D
+        self.push_hl();
+        self.or_a_a();
+        self.emit(&[0xED, 0x52]); // SBC HL, DE
+        self.emit(&[0x44]); // LD B, H
+        self.emit(&[0x4D]); // LD C, L
+        self.ex_de_hl();
+        self.emit(&[0xED, 0x5B]); // LD DE, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xED, 0xB0]); // LDIR
+        self.emit(&[0xED, 0x53]); // LD (TEMP2), DE
+        self.emit_word(layout.var(TEMP2));
+        self.pop_hl();
+        self.emit(&[0xC3]); // JP gc_block
+        self.fixup("gc_block");
+        self.label("gc_done");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0x22]); // LD (FORMULA_PTR), HL
+        self.emit_word(layout.var(FORMULA_PTR));
+        self.ret();
+
+        // The entry does not fit: say so until a key is pressed and leave
+        // the cell as it was
+        self.label("memory_full");
+        self.emit(&[0x06, INPUT_ROW]); // LD B, INPUT_ROW
+        self.emit(&[0x0E, 1]); // LD C, 1
+        self.emit(&[0xCD]); // CALL cursor_pos
+        self.fixup("cursor_pos");
+        self.emit(&[0xCD]); // CALL clear_to_eol
+        self.fixup("clear_to_eol");
+        self.emit(&[0x21]); // LD HL, memory_full_msg
+        self.fixup("memory_full_msg");
+        self.emit(&[0xCD]); // CALL print_string
+        self.fixup("print_string");
+        self.emit(&[0xC3]); // JP getchar
+        self.fixup("getchar");
+
         // Load current cell content into INPUT_BUF
         // Sets INPUT_LEN and INPUT_POS appropriately
         self.label("load_cell_to_input");
         // Get current cell
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
@@ -1817,13 +3024,16 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xFE, CELL_FORMULA]); // CP CELL_FORMULA
         self.emit(&[0xCA]); // JP Z, load_cell_formula
         self.fixup("load_cell_formula");
-        // Error or unknown - treat as empty
+        self.emit(&[0xFE, CELL_ERROR]); // CP CELL_ERROR (its formula, if any)
+        self.emit(&[0xCA]); // JP Z, load_cell_formula
+        self.fixup("load_cell_formula");
+        // Unknown - treat as empty
         self.label("load_cell_empty");
         self.xor_a();
         self.emit(&[0x32]); // LD (INPUT_LEN), A
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0x32]); // LD (INPUT_POS), A
-        self.emit_word(INPUT_POS);
+        self.emit_word(layout.var(INPUT_POS));
         self.ret();
 
         // Load number into INPUT_BUF
@@ -1846,9 +3056,13 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x5E]); // LD E, (HL)
         self.inc_hl();
         self.emit(&[0x56]); // LD D, (HL)
+        self.ld_a_d();
+        self.or_a_a();
+        self.emit(&[0xCA]); // JP Z, load_cell_empty (no formula)
+        self.fixup("load_cell_empty");
         // DE = formula pointer, copy to INPUT_BUF
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.emit(&[0x06, 0x00]); // LD B, 0 (length counter)
         self.label("load_formula_loop");
         self.emit(&[0x1A]); // LD A, (DE)
@@ -1864,18 +3078,19 @@ impl SpreadsheetCodeGen {
         self.label("load_formula_done");
         self.ld_a_b();
         self.emit(&[0x32]); // LD (INPUT_LEN), A
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0x32]); // LD (INPUT_POS), A
-        self.emit_word(INPUT_POS);
+        self.emit_word(layout.var(INPUT_POS));
         self.ret();
 
         // Parse number from INPUT_BUF to BCD
         // Returns: C = sign (0x00 = positive, 0x80 = negative)
-        // BCD value is stored in BCD_TEMP1, carry set on error
+        // BCD value is stored in BCD_TEMP1, carry set on error with
+        // A = ERR_SYNTAX or ERR_OVF
         self.label("parse_number");
         self.emit(&[0x0E, 0x00]); // LD C, 0 (positive)
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
 
         // Check for minus sign
         self.ld_a_hl_ind();
@@ -1884,8 +3099,15 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x0E, 0x80]); // LD C, 0x80 (negative) - 2 bytes
         self.inc_hl(); // skip minus sign - 1 byte
 
-        // Validate at least one digit exists
+        // Validate at least one digit exists, after the point for ".5"
         self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'.']);
+        self.emit(&[0x20]); // JR NZ, parse_num_digit
+        self.emit_relative("parse_num_digit");
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.emit(&[0x2B]); // DEC HL
+        self.label("parse_num_digit");
         self.emit(&[0xFE, b'0']);
         self.emit(&[0xDA]); // JP C, parse_num_error
         self.fixup("parse_num_error");
@@ -1896,31 +3118,30 @@ impl SpreadsheetCodeGen {
         // Call ascii_to_bcd (HL points to digit string)
         self.emit(&[0xCD]); // CALL ascii_to_bcd
         self.fixup("ascii_to_bcd");
-        // BCD value now in BCD_TEMP1
-        self.or_a_a(); // clear carry
+        // BCD value now in BCD_TEMP1, carry set if it was too big
+        self.emit(&[0x3E, ERR_OVF]); // LD A, ERR_OVF
         self.ret();
 
         self.label("parse_num_error");
+        self.emit(&[0x3E, ERR_SYNTAX]); // LD A, ERR_SYNTAX
         self.emit(&[0x37]); // SCF (set carry)
         self.ret();
     }
 
     /// Cell operations
     fn emit_cell_ops(&mut self) {
+        let layout = self.layout;
         // Get cell address from B=col, C=row
         // Returns address in HL
         self.label("get_cell_addr");
-        // Address = CELL_DATA + (row * 16 + col) * 6
-        // Use 16-bit arithmetic to avoid overflow when row >= 16
+        // Address = CELL_DATA + (row * cols + col) * 6
+        // Use 16-bit arithmetic to avoid overflow for large grids
         self.emit(&[0x69]); // LD L, C (row)
         self.emit(&[0x26, 0x00]); // LD H, 0 (HL = row, 16-bit)
-        self.add_hl_hl(); // x2
-        self.add_hl_hl(); // x4
-        self.add_hl_hl(); // x8
-        self.add_hl_hl(); // x16
+        self.emit_mul_hl(layout.cols());
         self.emit(&[0x58]); // LD E, B (col)
         self.emit(&[0x16, 0x00]); // LD D, 0 (DE = col, 16-bit)
-        self.add_hl_de(); // HL = row*16 + col
+        self.add_hl_de(); // HL = row*cols + col
         // Multiply by 6: HL * 6 = HL * 4 + HL * 2
         self.add_hl_hl(); // x2
         self.push_hl(); // save x2
@@ -1929,18 +3150,45 @@ impl SpreadsheetCodeGen {
         self.add_hl_de(); // HL = x4 + x2 = x6
         // Add base address
         self.emit(&[0x11]); // LD DE, CELL_DATA
-        self.emit_word(CELL_DATA);
+        self.emit_word(layout.cell_data());
         self.add_hl_de();
         self.ret();
 
-        // Recalculate all formula cells
+        // Recalculate all formula cells after a change, unless the
+        // recalculation mode is manual
         self.label("recalculate");
-        // For now, just a stub - formulas store their calculated value
-        self.ret();
+        self.emit(&[0x3A]); // LD A, (RECALC_FLAGS)
+        self.emit_word(layout.var(RECALC_FLAGS));
+        self.emit(&[0xE6, RECALC_MANUAL]); // AND RECALC_MANUAL
+        self.emit(&[0xC0]); // RET NZ
+        self.emit(&[0xC3]); // JP recalc_all
+        self.fixup("recalc_all");
+    }
+
+    /// HL = HL * `factor`, shift-and-add over the bits of the constant
+    ///
+    /// A power of two is just shifts; otherwise DE holds the multiplicand
+    /// and is clobbered.
+    fn emit_mul_hl(&mut self, factor: u8) {
+        if factor.is_power_of_two() {
+            for _ in 0..factor.trailing_zeros() {
+                self.add_hl_hl();
+            }
+            return;
+        }
+        self.emit(&[0x54]); // LD D, H
+        self.emit(&[0x5D]); // LD E, L
+        for bit in (0..7 - factor.leading_zeros()).rev() {
+            self.add_hl_hl();
+            if factor & (1 << bit) != 0 {
+                self.add_hl_de();
+            }
+        }
     }
 
     /// BCD arithmetic operations (8-digit packed BCD)
     fn emit_bcd_ops(&mut self) {
+        let layout = self.layout;
         // BCD values are stored big-endian: d7d6 d5d4 d3d2 d1d0
         // Sign is separate (byte 1 of cell: 0x00=positive, 0x80=negative)
 
@@ -1955,6 +3203,8 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x13]); // INC DE
         self.emit(&[0x13]); // INC DE (DE points to LSB)
         self.emit(&[0x06, 4]); // LD B, 4 (4 bytes)
+        // bcd_add_n: the same for B bytes, HL and DE at the LSBs
+        self.label("bcd_add_n");
         self.or_a_a(); // clear carry
         self.label("bcd_add_loop");
         self.emit(&[0x1A]); // LD A, (DE)
@@ -1979,6 +3229,8 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x13]);
         self.emit(&[0x13]);
         self.emit(&[0x06, 4]); // LD B, 4 (4 bytes)
+        // bcd_sub_n: the same for B bytes, HL and DE at the LSBs
+        self.label("bcd_sub_n");
         self.or_a_a(); // clear carry (no initial borrow)
         self.label("bcd_sub_loop");
         // Load subtrahend, save it, load minuend, subtract, adjust
@@ -1998,14 +3250,15 @@ impl SpreadsheetCodeGen {
         // Returns: Z if equal, C if (HL) < (DE)
         self.label("bcd_cmp");
         self.emit(&[0x06, 4]); // LD B, 4
-        self.label("bcd_cmp_loop");
+        // bcd_cmp_n: the same for B bytes
+        self.label("bcd_cmp_n");
         self.emit(&[0x1A]); // LD A, (DE)
         self.emit(&[0xBE]); // CP (HL)
         self.emit(&[0xC0]); // RET NZ (return with flags set)
         self.emit(&[0x23]); // INC HL
         self.emit(&[0x13]); // INC DE
         self.emit(&[0x10]); // DJNZ
-        self.emit_relative("bcd_cmp_loop");
+        self.emit_relative("bcd_cmp_n");
         self.ret(); // Z set if equal
 
         // bcd_zero: Zero 4-byte BCD at (HL)
@@ -2023,34 +3276,36 @@ impl SpreadsheetCodeGen {
         // bcd_copy: Copy 4-byte BCD from (DE) to (HL)
         self.label("bcd_copy");
         self.emit(&[0x06, 4]); // LD B, 4
-        self.label("bcd_copy_loop");
+        // bcd_copy_n: the same for B bytes
+        self.label("bcd_copy_n");
         self.emit(&[0x1A]); // LD A, (DE)
         self.emit(&[0x77]); // LD (HL), A
         self.emit(&[0x23]); // INC HL
         self.emit(&[0x13]); // INC DE
         self.emit(&[0x10]); // DJNZ
-        self.emit_relative("bcd_copy_loop");
+        self.emit_relative("bcd_copy_n");
         self.ret();
 
         // signed_add: Signed BCD addition (callable subroutine version)
         // Input: BCD_TEMP2 + BCD_TEMP1, SIGN_ACCUM = sign of TEMP2, SIGN_OP = sign of TEMP1
-        // Output: Result in BCD_TEMP1, sign in SIGN_ACCUM
+        // Output: Result in BCD_TEMP1, sign in SIGN_ACCUM, carry set if the
+        // magnitude needs more than 8 digits
         self.label("signed_add");
         // Check if signs are the same
         self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
-        self.emit_word(SIGN_ACCUM);
+        self.emit_word(layout.var(SIGN_ACCUM));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (SIGN_OP)
-        self.emit_word(SIGN_OP);
+        self.emit_word(layout.var(SIGN_OP));
         self.emit(&[0xB8]); // CP B
         self.emit(&[0xCA]); // JP Z, signed_add_same
         self.fixup("signed_add_same");
 
         // Different signs: subtract smaller magnitude from larger
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0xCD]); // CALL bcd_cmp (C set if TEMP2 < TEMP1)
         self.fixup("bcd_cmp");
         self.emit(&[0xDA]); // JP C, signed_add_op_larger
@@ -2058,16 +3313,16 @@ impl SpreadsheetCodeGen {
 
         // TEMP2 >= TEMP1: result = TEMP2 - TEMP1, sign = SIGN_ACCUM
         self.emit(&[0x21]); // LD HL, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0xCD]); // CALL bcd_sub
         self.fixup("bcd_sub");
         // Copy result from TEMP2 to TEMP1
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0xCD]); // CALL bcd_copy
         self.fixup("bcd_copy");
         self.ret();
@@ -2075,36 +3330,37 @@ impl SpreadsheetCodeGen {
         // TEMP1 > TEMP2: result = TEMP1 - TEMP2, sign = SIGN_OP
         self.label("signed_add_op_larger");
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0xCD]); // CALL bcd_sub
         self.fixup("bcd_sub");
         // Set sign to SIGN_OP
         self.emit(&[0x3A]); // LD A, (SIGN_OP)
-        self.emit_word(SIGN_OP);
+        self.emit_word(layout.var(SIGN_OP));
         self.emit(&[0x32]); // LD (SIGN_ACCUM), A
-        self.emit_word(SIGN_ACCUM);
+        self.emit_word(layout.var(SIGN_ACCUM));
         self.ret();
 
         // Same signs: add magnitudes, keep sign
         self.label("signed_add_same");
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0xCD]); // CALL bcd_add
         self.fixup("bcd_add");
         self.ret();
 
         // bcd_mul: Multiply BCD at BCD_TEMP1 by BCD at BCD_TEMP2
-        // Result in BCD_TEMP1 (only lower 8 digits kept)
+        // Result in BCD_TEMP1, carry set (and TEMP1 unchanged) if the scaled
+        // product needs more than 8 digits
         // Algorithm: Process multiplier from MSB to LSB
         //   For each digit: shift accumulator left, then add (multiplicand × digit)
         self.label("bcd_mul");
         // Clear accumulator (8 bytes for intermediate result)
         self.emit(&[0x21]); // LD HL, BCD_ACCUM
-        self.emit_word(BCD_ACCUM);
+        self.emit_word(layout.var(BCD_ACCUM));
         self.emit(&[0x06, 8]); // LD B, 8
         self.emit(&[0xAF]);
         self.label("bcd_mul_clr");
@@ -2116,7 +3372,7 @@ impl SpreadsheetCodeGen {
         // Process multiplier from MSB to LSB (8 digits = 4 bytes)
         self.emit(&[0x0E, 8]); // LD C, 8 (digit counter)
         self.emit(&[0x21]); // LD HL, BCD_TEMP2 (MSB first)
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
 
         self.label("bcd_mul_digit");
         // Get multiplier digit (high nibble first, then low)
@@ -2155,9 +3411,9 @@ impl SpreadsheetCodeGen {
         // Shift 8-byte accumulator right by 2 BCD digits (1 byte)
         // This is needed because: cents × cents = cents², divide by 100 to get cents
         self.emit(&[0x21]); // LD HL, BCD_ACCUM+7 (destination)
-        self.emit_word(BCD_ACCUM + 7);
+        self.emit_word(layout.var(BCD_ACCUM) + 7);
         self.emit(&[0x11]); // LD DE, BCD_ACCUM+6 (source)
-        self.emit_word(BCD_ACCUM + 6);
+        self.emit_word(layout.var(BCD_ACCUM) + 6);
         self.emit(&[0x06, 7]); // LD B, 7 (copy 7 bytes)
         self.label("bcd_shr_loop");
         self.emit(&[0x1A]); // LD A, (DE)
@@ -2168,15 +3424,22 @@ impl SpreadsheetCodeGen {
         self.emit_relative("bcd_shr_loop");
         // Clear byte 0 (MSB)
         self.emit(&[0x21]); // LD HL, BCD_ACCUM
-        self.emit_word(BCD_ACCUM);
+        self.emit_word(layout.var(BCD_ACCUM));
         self.xor_a();
         self.emit(&[0x77]); // LD (HL), A
+        // Anything left in bytes 1-3 is an overflow
+        for _ in 0..3 {
+            self.inc_hl();
+            self.emit(&[0xB6]); // OR (HL)
+        }
+        self.emit(&[0xC6, 0xFF]); // ADD A, 0xFF (carry if any were non-zero)
+        self.emit(&[0xD8]); // RET C
 
         // Copy lower 4 bytes of accumulator to BCD_TEMP1
         self.emit(&[0x11]); // LD DE, BCD_ACCUM+4
-        self.emit_word(BCD_ACCUM + 4);
+        self.emit_word(layout.var(BCD_ACCUM) + 4);
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0xCD]); // CALL bcd_copy
         self.fixup("bcd_copy");
         self.ret();
@@ -2187,7 +3450,7 @@ impl SpreadsheetCodeGen {
         self.push_af();
         // Shift accumulator left by one BCD digit (×10)
         self.emit(&[0x21]); // LD HL, BCD_ACCUM
-        self.emit_word(BCD_ACCUM);
+        self.emit_word(layout.var(BCD_ACCUM));
         self.emit(&[0xCD]); // CALL bcd_shift_left
         self.fixup("bcd_shift_left");
         self.pop_af();
@@ -2199,11 +3462,22 @@ impl SpreadsheetCodeGen {
         self.push_bc(); // Save B (digit counter) - bcd_add uses B internally
         // Add BCD_TEMP1 to accumulator at current position
         self.emit(&[0x21]); // LD HL, BCD_ACCUM+4 (lower 4 bytes)
-        self.emit_word(BCD_ACCUM + 4);
+        self.emit_word(layout.var(BCD_ACCUM) + 4);
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0xCD]); // CALL bcd_add
         self.fixup("bcd_add");
+        // Propagate the carry into the upper 4 bytes (HL = BCD_ACCUM+3)
+        self.emit(&[0x0E, 4]); // LD C, 4
+        self.label("bcd_mul_carry_loop");
+        self.emit(&[0x7E]); // LD A, (HL)
+        self.emit(&[0xCE, 0x00]); // ADC A, 0
+        self.emit(&[0x27]); // DAA
+        self.emit(&[0x77]); // LD (HL), A
+        self.emit(&[0x2B]); // DEC HL
+        self.dec_c();
+        self.emit(&[0x20]); // JR NZ, bcd_mul_carry_loop
+        self.emit_relative("bcd_mul_carry_loop");
         self.pop_bc(); // Restore digit counter
         self.emit(&[0x10]); // DJNZ bcd_mul_add_loop
         self.emit_relative("bcd_mul_add_loop");
@@ -2216,7 +3490,10 @@ impl SpreadsheetCodeGen {
         self.add_hl_de(); // HL points to byte 7 (LSB)
         self.emit(&[0x06, 8]); // LD B, 8
         self.emit(&[0xAF]); // carry nibble = 0
-        self.label("bcd_shl_loop");
+
+        // bcd_shift_digits: Shift B bytes of BCD ending at (HL) left one digit
+        // A = digit shifted into the bottom; returns the digit shifted out in A
+        self.label("bcd_shift_digits");
         self.emit(&[0x4F]); // LD C, A (save carry nibble from previous byte)
         self.emit(&[0x7E]); // LD A, (HL)
         self.emit(&[0x57]); // LD D, A (save original)
@@ -2236,15 +3513,31 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x0F]);
         self.emit(&[0x2B]); // DEC HL (move toward MSB)
         self.emit(&[0x10]); // DJNZ
-        self.emit_relative("bcd_shl_loop");
+        self.emit_relative("bcd_shift_digits");
         self.ret();
 
         // bcd_div: Divide BCD at BCD_TEMP1 by BCD at BCD_TEMP2
-        // Quotient in BCD_TEMP1, uses repeated subtraction
+        // Quotient in BCD_TEMP1 (scaled ×100 for 2 decimal places), carry set
+        // and A = ERR_DIV0 on divide by zero, or ERR_OVF if the quotient needs
+        // more than 8 digits. Schoolbook long division: each step shifts the
+        // dividend one digit into a 9-digit remainder, then subtracts the
+        // divisor while it fits, counting subtractions into the digit the
+        // shift vacated. The ×100 scaling is two extra steps shifting in zeros,
+        // so the dividend keeps all 8 digits; the first two quotient digits
+        // fall off the top and must be zero.
         self.label("bcd_div");
+        self.emit(&[0x01, 10, 8]); // LD BC, 0x080A (B = 8 dividend digits, C = 10 steps)
+        self.emit(&[0x18]); // JR bcd_div_start
+        self.emit_relative("bcd_div_start");
+
+        // Entry point for division without ×100 scaling (used by AVG)
+        self.label("bcd_div_noscale");
+        self.emit(&[0x01, 8, 8]); // LD BC, 0x0808 (B = 8 dividend digits, C = 8 steps)
+
+        self.label("bcd_div_start");
         // Check for divide by zero
         self.emit(&[0x21]); // LD HL, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0x7E]); // LD A, (HL)
         self.emit(&[0x23]);
         self.emit(&[0xB6]); // OR (HL)
@@ -2254,94 +3547,110 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xB6]); // OR (HL)
         self.emit(&[0xC2]); // JP NZ, bcd_div_ok
         self.fixup("bcd_div_ok");
-        self.emit(&[0x37]); // SCF (divide by zero)
+        self.emit(&[0x3E, ERR_DIV0]); // LD A, ERR_DIV0
+        self.emit(&[0x37]); // SCF (divide by zero, BCD_TEMP1 unchanged)
         self.ret();
 
         self.label("bcd_div_ok");
-        // Scale dividend by ×100 for fixed-point (2 decimal places)
-        // Shift BCD_TEMP1 left by 2 BCD digits (1 byte)
-        // This is needed because: cents / cents = dimensionless, multiply by 100 to get cents
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1 (destination)
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0x11]); // LD DE, BCD_TEMP1+1 (source)
-        self.emit_word(BCD_TEMP1 + 1);
-        self.emit(&[0x06, 3]); // LD B, 3 (copy 3 bytes)
-        self.label("bcd_div_shl_loop");
-        self.emit(&[0x1A]); // LD A, (DE)
-        self.emit(&[0x77]); // LD (HL), A
-        self.emit(&[0x23]); // INC HL
-        self.emit(&[0x13]); // INC DE
-        self.emit(&[0x10]); // DJNZ bcd_div_shl_loop
-        self.emit_relative("bcd_div_shl_loop");
-        // Clear last byte (LSB) with zeros
-        self.xor_a();
-        self.emit(&[0x77]); // LD (HL), A
-
-        // Entry point for division without ×100 scaling (used by AVG)
-        self.label("bcd_div_noscale");
-        // Clear quotient accumulator
+        // Clear remainder: BCD_ACCUM = overflow digit, BCD_ACCUM+1..+4 = low 8 digits
         self.emit(&[0x21]); // LD HL, BCD_ACCUM
-        self.emit_word(BCD_ACCUM);
+        self.emit_word(layout.var(BCD_ACCUM));
         self.emit(&[0xCD]); // CALL bcd_zero
         self.fixup("bcd_zero");
         self.emit(&[0x21]); // LD HL, BCD_ACCUM+4
-        self.emit_word(BCD_ACCUM + 4);
+        self.emit_word(layout.var(BCD_ACCUM) + 4);
         self.emit(&[0xCD]); // CALL bcd_zero
         self.fixup("bcd_zero");
 
-        // Repeated subtraction: while BCD_TEMP1 >= BCD_TEMP2, subtract and increment quotient
-        self.label("bcd_div_loop");
-        // Compare BCD_TEMP1 with BCD_TEMP2
-        // bcd_cmp returns C if (DE) < (HL), so swap args to get C when TEMP1 < TEMP2
+        self.label("bcd_div_step");
+        // Next dividend digit is the top digit of BCD_TEMP1, or 0 once all
+        // 8 have been taken (the ×100 steps)
+        self.ld_a_b();
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, bcd_div_digit (A = 0)
+        self.emit_relative("bcd_div_digit");
+        self.emit(&[0x05]); // DEC B
+        self.emit(&[0x3A]); // LD A, (BCD_TEMP1)
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x0F]); // RRCA x4
+        self.emit(&[0x0F]);
+        self.emit(&[0x0F]);
+        self.emit(&[0x0F]);
+        self.emit(&[0xE6, 0x0F]); // AND 0x0F
+        self.label("bcd_div_digit");
+        self.push_bc(); // save digit and step counters
+        // Shift the digit into the bottom of the 5-byte remainder
+        self.emit(&[0x21]); // LD HL, BCD_ACCUM+4 (LSB)
+        self.emit_word(layout.var(BCD_ACCUM) + 4);
+        self.emit(&[0x06, 5]); // LD B, 5
+        self.emit(&[0xCD]); // CALL bcd_shift_digits
+        self.fixup("bcd_shift_digits");
+        // Shift BCD_TEMP1 left, dropping the digit just taken (or a quotient
+        // digit that overflows 8 digits) and opening a quotient digit
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1+3 (LSB)
+        self.emit_word(layout.var(BCD_TEMP1) + 3);
+        self.emit(&[0x06, 4]); // LD B, 4
+        self.xor_a();
+        self.emit(&[0xCD]); // CALL bcd_shift_digits
+        self.fixup("bcd_shift_digits");
+
+        self.label("bcd_div_sub");
+        // Remainder >= divisor if the overflow digit is set...
+        self.emit(&[0x3A]); // LD A, (BCD_ACCUM)
+        self.emit_word(layout.var(BCD_ACCUM));
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, bcd_div_fits
+        self.emit_relative("bcd_div_fits");
+        // ...or the low 8 digits compare >= (C set if remainder < divisor)
         self.emit(&[0x21]); // LD HL, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
-        self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0x11]); // LD DE, BCD_ACCUM+1
+        self.emit_word(layout.var(BCD_ACCUM) + 1);
         self.emit(&[0xCD]); // CALL bcd_cmp
         self.fixup("bcd_cmp");
-        self.emit(&[0xDA]); // JP C, bcd_div_done (TEMP1 < TEMP2)
-        self.fixup("bcd_div_done2");
+        self.emit(&[0x38]); // JR C, bcd_div_next
+        self.emit_relative("bcd_div_next");
 
-        // Subtract: BCD_TEMP1 -= BCD_TEMP2
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.label("bcd_div_fits");
+        // Remainder -= divisor, borrowing from the overflow digit
+        self.emit(&[0x21]); // LD HL, BCD_ACCUM+1
+        self.emit_word(layout.var(BCD_ACCUM) + 1);
         self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0xCD]); // CALL bcd_sub
         self.fixup("bcd_sub");
-
-        // Increment quotient (BCD_ACCUM, lower 4 bytes)
-        self.emit(&[0x21]); // LD HL, BCD_ACCUM+7 (LSB)
-        self.emit_word(BCD_ACCUM + 7);
-        self.emit(&[0x7E]); // LD A, (HL)
-        self.emit(&[0xC6, 0x01]); // ADD A, 1
-        self.emit(&[0x27]); // DAA
-        self.emit(&[0x77]); // LD (HL), A
-        self.emit(&[0x30]); // JR NC, bcd_div_loop (no carry, continue)
-        self.emit_relative("bcd_div_loop");
-        // Propagate carry through quotient
-        self.emit(&[0x06, 3]); // LD B, 3 (3 more bytes)
-        self.label("bcd_div_carry");
-        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x21]); // LD HL, BCD_ACCUM
+        self.emit_word(layout.var(BCD_ACCUM));
         self.emit(&[0x7E]); // LD A, (HL)
-        self.emit(&[0xCE, 0x00]); // ADC A, 0
-        self.emit(&[0x27]); // DAA
+        self.emit(&[0xDE, 0x00]); // SBC A, 0
         self.emit(&[0x77]); // LD (HL), A
-        self.emit(&[0x30]); // JR NC, bcd_div_loop
-        self.emit_relative("bcd_div_loop");
-        self.emit(&[0x10]); // DJNZ bcd_div_carry
-        self.emit_relative("bcd_div_carry");
-        self.emit(&[0xC3]); // JP bcd_div_loop
-        self.fixup("bcd_div_loop");
-
-        self.label("bcd_div_done2");
-        // Copy quotient to BCD_TEMP1
-        self.emit(&[0x11]); // LD DE, BCD_ACCUM+4
-        self.emit_word(BCD_ACCUM + 4);
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0xCD]); // CALL bcd_copy
-        self.fixup("bcd_copy");
+        // Count the subtraction in the quotient digit (never exceeds 9)
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1+3
+        self.emit_word(layout.var(BCD_TEMP1) + 3);
+        self.emit(&[0x34]); // INC (HL)
+        self.emit(&[0x18]); // JR bcd_div_sub
+        self.emit_relative("bcd_div_sub");
+
+        self.label("bcd_div_next");
+        self.pop_bc();
+        self.dec_c();
+        self.emit(&[0x28]); // JR Z, bcd_div_done
+        self.emit_relative("bcd_div_done");
+        // Two steps into a scaled division TEMP1's last byte holds the two
+        // quotient digits the ×100 steps will shift out
+        self.ld_a_c();
+        self.emit(&[0xFE, 8]); // CP 8
+        self.emit(&[0xC2]); // JP NZ, bcd_div_step
+        self.fixup("bcd_div_step");
+        self.emit(&[0x3A]); // LD A, (BCD_TEMP1+3)
+        self.emit_word(layout.var(BCD_TEMP1) + 3);
+        self.or_a_a();
+        self.emit(&[0xCA]); // JP Z, bcd_div_step
+        self.fixup("bcd_div_step");
+        self.emit(&[0x3E, ERR_OVF]); // LD A, ERR_OVF
+        self.emit(&[0x37]); // SCF
+        self.ret();
+        self.label("bcd_div_done");
         self.or_a_a(); // clear carry (success)
         self.ret();
 
@@ -2349,11 +3658,12 @@ impl SpreadsheetCodeGen {
         // Input: HL = pointer to null-terminated ASCII digits
         // Handles leading minus sign and decimal point (2 fixed decimal places)
         // Examples: "123.45" -> 12345, "123" -> 12300, "0.5" -> 50
+        // Carry set if the value needs more than 8 digits
         self.label("ascii_to_bcd");
         // Clear BCD_TEMP1
         self.push_hl();
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0xCD]); // CALL bcd_zero
         self.fixup("bcd_zero");
         self.pop_hl();
@@ -2361,10 +3671,10 @@ impl SpreadsheetCodeGen {
         // Initialize: ATOB_FLAGS[0] = 0xFF (no decimal seen), ATOB_FLAGS[1] = 0 (frac digit count)
         self.emit(&[0x3E, 0xFF]); // LD A, 0xFF
         self.emit(&[0x32]); // LD (ATOB_FLAGS), A (decimal flag: FF=not seen)
-        self.emit_word(ATOB_FLAGS);
+        self.emit_word(layout.var(ATOB_FLAGS));
         self.xor_a();
         self.emit(&[0x32]); // LD (ATOB_FLAGS+1), A (frac digit count = 0)
-        self.emit_word(ATOB_FLAGS + 1);
+        self.emit_word(layout.var(ATOB_FLAGS) + 1);
 
         // Check for minus sign
         self.emit(&[0x7E]); // LD A, (HL)
@@ -2386,7 +3696,7 @@ impl SpreadsheetCodeGen {
         // It's a decimal point - mark it and continue
         self.xor_a();
         self.emit(&[0x32]); // LD (ATOB_FLAGS), A (decimal flag = 0, seen)
-        self.emit_word(ATOB_FLAGS);
+        self.emit_word(layout.var(ATOB_FLAGS));
         self.inc_hl();
         self.emit(&[0xC3]); // JP atob_loop
         self.fixup("atob_loop");
@@ -2402,51 +3712,31 @@ impl SpreadsheetCodeGen {
 
         // Check if we've already parsed 2 fractional digits
         self.emit(&[0x3A]); // LD A, (ATOB_FLAGS+1)
-        self.emit_word(ATOB_FLAGS + 1);
+        self.emit_word(layout.var(ATOB_FLAGS) + 1);
         self.emit(&[0xFE, 2]); // CP 2
         self.emit(&[0xD2]); // JP NC, atob_done (already have 2 frac digits)
         self.fixup("atob_done");
 
-        // It's a valid digit - process it
+        // It's a valid digit - shift it in at the bottom
         self.emit(&[0x7E]); // LD A, (HL) - reload char
         self.push_hl();
         self.emit(&[0xD6, 0x30]); // SUB '0' (convert to digit)
-        self.push_af();
-
-        // Shift BCD_TEMP1 left by one digit (4 bits)
-        self.emit(&[0x06, 4]); // LD B, 4
-        self.label("atob_shift");
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1+3 (LSB)
-        self.emit_word(BCD_TEMP1 + 3);
-        self.or_a_a(); // clear carry
-        self.emit(&[0xCB, 0x26]); // SLA (HL)
-        self.emit(&[0x2B]); // DEC HL
-        self.emit(&[0xCB, 0x16]); // RL (HL)
-        self.emit(&[0x2B]); // DEC HL
-        self.emit(&[0xCB, 0x16]); // RL (HL)
-        self.emit(&[0x2B]); // DEC HL
-        self.emit(&[0xCB, 0x16]); // RL (HL)
-        self.emit(&[0x10]); // DJNZ
-        self.emit_relative("atob_shift");
-
-        // Add new digit to LSB
-        self.pop_af();
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1+3
-        self.emit_word(BCD_TEMP1 + 3);
-        self.emit(&[0xB6]); // OR (HL)
-        self.emit(&[0x77]); // LD (HL), A
+        self.emit(&[0xCD]); // CALL bcd_shl_a
+        self.fixup("bcd_shl_a");
         self.pop_hl();
+        self.emit(&[0xC6, 0xFF]); // ADD A, 0xFF (carry if a digit fell off the top)
+        self.emit(&[0xD8]); // RET C
 
         // If decimal was seen, increment frac digit count
         self.emit(&[0x3A]); // LD A, (ATOB_FLAGS)
-        self.emit_word(ATOB_FLAGS);
+        self.emit_word(layout.var(ATOB_FLAGS));
         self.or_a_a();
         self.emit(&[0x20, 0x07]); // JR NZ, +7 (skip if decimal not seen, 0xFF)
         self.emit(&[0x3A]); // LD A, (ATOB_FLAGS+1) - 3 bytes
-        self.emit_word(ATOB_FLAGS + 1);
+        self.emit_word(layout.var(ATOB_FLAGS) + 1);
         self.inc_a(); // 1 byte
         self.emit(&[0x32]); // LD (ATOB_FLAGS+1), A - 3 bytes
-        self.emit_word(ATOB_FLAGS + 1);
+        self.emit_word(layout.var(ATOB_FLAGS) + 1);
         // Total: 7 bytes
 
         self.emit(&[0x23]); // INC HL (next input char)
@@ -2456,49 +3746,30 @@ impl SpreadsheetCodeGen {
         // Done parsing - need to scale if fewer than 2 frac digits
         self.label("atob_done");
         self.emit(&[0x3A]); // LD A, (ATOB_FLAGS)
-        self.emit_word(ATOB_FLAGS);
+        self.emit_word(layout.var(ATOB_FLAGS));
         self.or_a_a();
-        self.emit(&[0x20, 0x03]); // JR NZ, atob_no_decimal (FF = no decimal seen)
+        self.emit(&[0x20]); // JR NZ, atob_scale_2 (FF = no decimal seen)
+        self.emit_relative("atob_scale_2");
         // Decimal was seen - check frac digit count
-        self.emit(&[0xC3]); // JP atob_check_frac
-        self.fixup("atob_check_frac");
-
-        self.label("atob_no_decimal");
-        // No decimal point - multiply by 100 (shift left 8 bits = 2 BCD digits)
-        self.emit(&[0x06, 8]); // LD B, 8 (shift 8 bits)
-        self.emit(&[0xC3]); // JP atob_scale_loop
-        self.fixup("atob_scale_loop");
-
-        self.label("atob_check_frac");
         self.emit(&[0x3A]); // LD A, (ATOB_FLAGS+1)
-        self.emit_word(ATOB_FLAGS + 1);
+        self.emit_word(layout.var(ATOB_FLAGS) + 1);
         self.emit(&[0xFE, 2]); // CP 2
         self.ret_nc(); // >= 2 frac digits, done
         self.emit(&[0xFE, 1]); // CP 1
-        self.emit(&[0xCA]); // JP Z, atob_scale_1
-        self.fixup("atob_scale_1");
-        // 0 frac digits (e.g., "123." entered) - multiply by 100
-        self.emit(&[0x06, 8]); // LD B, 8
-        self.emit(&[0xC3]); // JP atob_scale_loop
-        self.fixup("atob_scale_loop");
-
+        self.emit(&[0x28]); // JR Z, atob_scale_1
+        self.emit_relative("atob_scale_1");
+
+        // No decimal point, or 0 frac digits (e.g., "123." entered) -
+        // multiply by 100 (2 digits), then by 10 for 1 frac digit
+        self.label("atob_scale_2");
+        self.emit(&[0xCD]); // CALL bcd_shl
+        self.fixup("bcd_shl");
+        self.emit(&[0xC6, 0xFF]); // ADD A, 0xFF (carry if a digit fell off the top)
+        self.emit(&[0xD8]); // RET C
         self.label("atob_scale_1");
-        // 1 frac digit - multiply by 10 (shift left 4 bits)
-        self.emit(&[0x06, 4]); // LD B, 4
-
-        self.label("atob_scale_loop");
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1+3
-        self.emit_word(BCD_TEMP1 + 3);
-        self.or_a_a();
-        self.emit(&[0xCB, 0x26]); // SLA (HL)
-        self.emit(&[0x2B]); // DEC HL
-        self.emit(&[0xCB, 0x16]); // RL (HL)
-        self.emit(&[0x2B]); // DEC HL
-        self.emit(&[0xCB, 0x16]); // RL (HL)
-        self.emit(&[0x2B]); // DEC HL
-        self.emit(&[0xCB, 0x16]); // RL (HL)
-        self.emit(&[0x10]); // DJNZ atob_scale_loop
-        self.emit_relative("atob_scale_loop");
+        self.emit(&[0xCD]); // CALL bcd_shl
+        self.fixup("bcd_shl");
+        self.emit(&[0xC6, 0xFF]); // ADD A, 0xFF
         self.ret();
 
         // bcd_to_ascii: Convert packed BCD at BCD_TEMP1 to ASCII in INPUT_BUF
@@ -2506,9 +3777,9 @@ impl SpreadsheetCodeGen {
         // Sets INPUT_LEN = 9
         self.label("bcd_to_ascii");
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
 
         // Output first 3 BCD bytes (6 digits = whole part)
         self.emit(&[0x06, 3]); // LD B, 3
@@ -2563,7 +3834,7 @@ impl SpreadsheetCodeGen {
         // Store length = 9
         self.emit(&[0x3E, 9]); // LD A, 9
         self.emit(&[0x32]); // LD (INPUT_LEN), A
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.ret();
 
         // btoa_digit: Output single BCD digit (A) to (HL), increment HL and C
@@ -2585,89 +3856,109 @@ impl SpreadsheetCodeGen {
 
     /// Formula parsing and evaluation
     fn emit_formula(&mut self) {
-        // Parse formula from INPUT_BUF
-        // Formula storage format: null-terminated string + 2-byte value
+        let layout = self.layout;
+        // Parse formula from INPUT_BUF into a heap block (see
+        // FORMULA_VALUE); the cell points at the text
         self.label("parse_formula");
 
         // Check for empty formula (just '=')
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0xFE, 2]); // CP 2 (need at least '=' + 1 char)
-        self.emit(&[0xDA]); // JP C, store_error
-        self.fixup("store_error");
-
-        // Save formula pointer (where we'll store the formula)
-        self.emit(&[0x2A]); // LD HL, (FORMULA_PTR)
-        self.emit_word(FORMULA_PTR);
-        self.push_hl(); //save formula start address)
+        self.emit(&[0xDA]); // JP C, store_syntax
+        self.fixup("store_syntax");
 
-        // Copy formula text from INPUT_BUF to formula storage
-        self.emit(&[0x11]); // LD DE, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        // Compile the text after the '=' into TOK_BUF
+        self.emit(&[0x21]); // LD HL, INPUT_BUF + 1
+        self.emit_word(layout.input_buf() + 1);
+        self.emit(&[0x11]); // LD DE, TOK_BUF
+        self.emit_word(layout.input_buf() + TOK_BUF);
+        self.emit(&[0xCD]); // CALL lex
+        self.fixup("lex");
+        self.emit(&[0x7B]); // LD A, E
+        self.emit(&[0xD6, (layout.input_buf() + TOK_BUF) as u8]); // SUB TOK_BUF (token bytes)
+
+        // Room for the text, NUL, value, offset and tokens
+        self.push_af();
+        self.emit(&[0xC6, 7]); // ADD A, 7
+        self.emit(&[0xCD]); // CALL heap_alloc
+        self.fixup("heap_alloc");
+        self.pop_bc(); // B = token bytes
+        self.emit(&[0xDA]); // JP C, memory_full
+        self.fixup("memory_full");
+        // The value is stored once evaluated; then the offset, past the
+        // text and NUL
+        self.emit(&[0x11]); // LD DE, 5
+        self.emit_word(5);
+        self.add_hl_de();
         self.emit(&[0x3A]); // LD A, (INPUT_LEN)
-        self.emit_word(INPUT_LEN);
-        self.ld_b_a(); //counter)
-        self.label("copy_formula_loop");
-        self.emit(&[0x1A]); // LD A, (DE)
+        self.emit_word(layout.var(INPUT_LEN));
+        self.ld_c_a();
+        self.inc_a();
         self.ld_hl_ind_a();
-        self.inc_de();
         self.inc_hl();
-        self.emit(&[0x10]); // DJNZ copy_formula_loop
-        let offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        self.rom_mut()[offset] = (self.get_label("copy_formula_loop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
-        // Null terminate
-        self.emit(&[0x36, 0x00]); // LD (HL), 0
-        self.inc_hl();
-        // HL now points to where we'll store the calculated value
-        self.push_hl(); //save value address)
+        self.push_hl(); // formula text address (the cell's pointer)
 
-        // Evaluate the expression (skip the '=')
-        self.emit(&[0x21]); // LD HL, INPUT_BUF + 1
-        self.emit_word(INPUT_BUF + 1);
+        // Copy formula text from INPUT_BUF, then the NUL
+        self.ex_de_hl();
+        self.emit(&[0x21]); // LD HL, INPUT_BUF
+        self.emit_word(layout.input_buf());
+        self.push_bc();
+        self.emit(&[0x06, 0x00]); // LD B, 0
+        self.emit(&[0xED, 0xB0]); // LDIR
+        self.xor_a();
+        self.emit(&[0x12]); // LD (DE), A
+        self.inc_de();
+        // Then the tokens
+        self.pop_bc();
+        self.emit(&[0x48]); // LD C, B
+        self.emit(&[0x06, 0x00]); // LD B, 0
+        self.emit(&[0x21]); // LD HL, TOK_BUF
+        self.emit_word(layout.input_buf() + TOK_BUF);
+        self.push_de(); // tokens address
+        self.emit(&[0xED, 0xB0]); // LDIR
+        self.emit(&[0xED, 0x53]); // LD (FORMULA_PTR), DE
+        self.emit_word(layout.var(FORMULA_PTR));
+
+        // Evaluate the tokens. A formula that fails is stored all the
+        // same, with a zero value and the error code, so recalculation
+        // can retry it
+        self.pop_hl();
         self.emit(&[0xCD]); // CALL eval_expr
         self.fixup("eval_expr");
-        // HL = result, carry set on error
-        self.emit(&[0xDA]); // JP C, formula_eval_error
-        self.fixup("formula_eval_error");
 
-        // Store sign + 4-byte BCD value after formula string
-        self.pop_hl(); // HL = value address
+        // Store sign + 4-byte BCD value before the offset
+        self.pop_de(); // DE = formula text address
+        self.push_de();
+        self.push_af(); // A = error code (0 if none)
+        self.emit(&[0x21]); // LD HL, -FORMULA_VALUE
+        self.emit_word(FORMULA_VALUE.wrapping_neg());
+        self.add_hl_de();
         // Store sign byte first
         self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
-        self.emit_word(SIGN_ACCUM);
+        self.emit_word(layout.var(SIGN_ACCUM));
         self.emit(&[0x77]); // LD (HL), A
         self.inc_hl();
         // Store 4 BCD bytes
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0x06, 4]); // LD B, 4
-        self.label("store_formula_bcd");
-        self.emit(&[0x1A]); // LD A, (DE)
-        self.emit(&[0x77]); // LD (HL), A
-        self.inc_hl();
-        self.inc_de();
-        self.emit(&[0x10]); // DJNZ store_formula_bcd
-        self.emit_relative("store_formula_bcd");
-        // Update FORMULA_PTR (HL now points past 5-byte value)
-        self.emit(&[0x22]); // LD (FORMULA_PTR), HL
-        self.emit_word(FORMULA_PTR);
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.ex_de_hl();
+        self.emit(&[0x01]); // LD BC, 4
+        self.emit_word(4);
+        self.emit(&[0xED, 0xB0]); // LDIR
 
-        // Store formula pointer in cell
-        self.pop_hl(); //formula start address)
-        self.push_hl(); //save it again)
+        // Store type, error code and formula pointer in cell
         self.emit(&[0x3A]); // LD A, (CURSOR_COL)
-        self.emit_word(CURSOR_COL);
+        self.emit_word(layout.var(CURSOR_COL));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (CURSOR_ROW)
-        self.emit_word(CURSOR_ROW);
+        self.emit_word(layout.var(CURSOR_ROW));
         self.ld_c_a();
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
-        self.emit(&[0x36, CELL_FORMULA]); // LD (HL), CELL_FORMULA
-        self.inc_hl();
-        self.emit(&[0x36, 0x00]); // LD (HL), 0 (flags)
+        self.pop_af(); // error code
+        self.emit(&[0xCD]); // CALL formula_status
+        self.fixup("formula_status");
         self.inc_hl();
         self.pop_de(); //formula address)
         self.emit(&[0x73]); // LD (HL), E
@@ -2675,182 +3966,744 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x72]); // LD (HL), D
         self.ret();
 
-        self.label("formula_eval_error");
-        // Clean up stack and store error
-        self.pop_hl(); //discard value address)
-        self.pop_hl(); //discard formula address)
-        self.emit(&[0xC3]); // JP store_error
-        self.fixup("store_error");
+        // Formula cell at HL: CELL_FORMULA if the error code in A is 0,
+        // else CELL_ERROR with the code in byte 1. Returns HL on byte 1
+        self.label("formula_status");
+        self.emit(&[0x36, CELL_FORMULA]); // LD (HL), CELL_FORMULA
+        self.or_a_a();
+        self.emit(&[0x28, 0x02]); // JR Z, +2
+        self.emit(&[0x36, CELL_ERROR]); // LD (HL), CELL_ERROR
+        self.inc_hl();
+        self.ld_hl_ind_a();
+        self.ret();
 
-        // Evaluate expression with chaining support (e.g., =A1+A2+A3)
-        // Input: HL = pointer to expression string
-        // Output: Result in BCD_TEMP1, carry set on error
-        self.label("eval_expr");
-        self.emit(&[0x22]); // LD (TEMP2), HL (save expr ptr)
-        self.emit_word(TEMP2);
+        // Compile the formula text at HL into tokens at DE (see TOK_END),
+        // returning DE past them. References get their column and row,
+        // numbers their BCD and @function names their index; anything the
+        // evaluator would reject is left for it to reject
+        self.label("lex");
+        self.ld_a_hl_ind();
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, lex_end
+        self.emit_relative("lex_end");
+        self.emit(&[0xFE, b'@']);
+        self.emit(&[0xCA]); // JP Z, lex_func
+        self.fixup("lex_func");
+        self.emit(&[0xFE, b'<']);
+        self.emit(&[0x28]); // JR Z, lex_less
+        self.emit_relative("lex_less");
+        self.emit(&[0xFE, b'>']);
+        self.emit(&[0x28]); // JR Z, lex_greater
+        self.emit_relative("lex_greater");
+        self.emit(&[0xFE, b'$']);
+        self.emit(&[0x28]); // JR Z, lex_dollar
+        self.emit_relative("lex_dollar");
+        self.emit(&[0xFE, b'.']);
+        self.emit(&[0x28]); // JR Z, lex_number
+        self.emit_relative("lex_number");
+        self.emit(&[0xFE, b'=']);
+        self.emit(&[0x3E, TOK_CMP | 2]); // LD A, TOK_CMP | 2 (flags kept)
+        self.emit(&[0x28]); // JR Z, lex_next
+        self.emit_relative("lex_next");
+        self.ld_a_hl_ind();
+        self.emit(&[0xD6, b'0']); // SUB '0'
+        self.emit(&[0xFE, 10]); // CP 10
+        self.emit(&[0x38]); // JR C, lex_number
+        self.emit_relative("lex_number");
+        self.ld_a_hl_ind();
+        self.emit(&[0xE6, 0xDF]); // AND 0xDF (uppercase)
+        self.emit(&[0xD6, b'A']); // SUB 'A'
+        self.emit(&[0xFE, 26]); // CP 26
+        self.emit(&[0x06, 0x00]); // LD B, 0 (no '$', flags kept)
+        self.emit(&[0x38]); // JR C, lex_ref
+        self.emit_relative("lex_ref");
+        // Any other character is its own token
+        self.label("lex_char");
+        self.ld_a_hl_ind();
+        // Token in A for the character at HL
+        self.label("lex_next");
+        self.inc_hl();
+        // Token in A, HL already past its text
+        self.label("lex_put");
+        self.emit(&[0x12]); // LD (DE), A
+        self.inc_de();
+        self.emit(&[0x18]); // JR lex
+        self.emit_relative("lex");
+        self.label("lex_end");
+        self.emit(&[0x12]); // LD (DE), A (TOK_END)
+        self.inc_de();
+        self.ret();
 
-        // Parse first operand (result goes to BCD_TEMP1, sign in TEMP1)
-        self.emit(&[0xCD]); // CALL parse_operand
-        self.fixup("parse_operand");
-        self.emit(&[0xD8]); // RET C (error)
-        // Save first operand's sign as accumulator sign
-        self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
-        self.emit(&[0x32]); // LD (SIGN_ACCUM), A
-        self.emit_word(SIGN_ACCUM);
+        // Comparisons: < <= <> > >=
+        self.label("lex_less");
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'>']);
+        self.emit(&[0x3E, TOK_CMP | 5]); // LD A, TOK_CMP | 5 (<>)
+        self.emit(&[0x28]); // JR Z, lex_next
+        self.emit_relative("lex_next");
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'=']);
+        self.emit(&[0x3E, TOK_CMP | 3]); // LD A, TOK_CMP | 3 (<=)
+        self.emit(&[0x28]); // JR Z, lex_next
+        self.emit_relative("lex_next");
+        self.emit(&[0x3E, TOK_CMP | 1]); // LD A, TOK_CMP | 1 (<)
+        self.emit(&[0x18]); // JR lex_put
+        self.emit_relative("lex_put");
+        self.label("lex_greater");
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'=']);
+        self.emit(&[0x3E, TOK_CMP | 6]); // LD A, TOK_CMP | 6 (>=)
+        self.emit(&[0x28]); // JR Z, lex_next
+        self.emit_relative("lex_next");
+        self.emit(&[0x3E, TOK_CMP | 4]); // LD A, TOK_CMP | 4 (>)
+        self.emit(&[0x18]); // JR lex_put
+        self.emit_relative("lex_put");
+
+        // Number: TOK_NUM or TOK_DOT and the BCD, or TOK_OVF, then past
+        // its digits and points as parse_number reads them
+        self.label("lex_number");
+        self.push_de();
+        self.push_hl();
+        self.emit(&[0xCD]); // CALL ascii_to_bcd
+        self.fixup("ascii_to_bcd");
+        self.pop_hl();
+        self.pop_de();
+        self.emit(&[0x3E, TOK_OVF]); // LD A, TOK_OVF
+        self.emit(&[0x38]); // JR C, lex_digits_put
+        self.emit_relative("lex_digits_put");
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'.']);
+        self.emit(&[0x3E, TOK_DOT]); // LD A, TOK_DOT
+        self.emit(&[0x28, 0x02]); // JR Z, +2
+        self.emit(&[0x3E, TOK_NUM]); // LD A, TOK_NUM
+        self.emit(&[0x12]); // LD (DE), A
+        self.inc_de();
+        self.push_hl();
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x01]); // LD BC, 4
+        self.emit_word(4);
+        self.emit(&[0xED, 0xB0]); // LDIR
+        self.pop_hl();
+        self.emit(&[0x18]); // JR lex_digits
+        self.emit_relative("lex_digits");
+        self.label("lex_digits_put");
+        self.emit(&[0x12]); // LD (DE), A
+        self.inc_de();
+        self.label("lex_digits");
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'.']);
+        self.emit(&[0x28]); // JR Z, lex_digit
+        self.emit_relative("lex_digit");
+        self.emit(&[0xD6, b'0']); // SUB '0'
+        self.emit(&[0xFE, 10]); // CP 10
+        self.emit(&[0xD2]); // JP NC, lex
+        self.fixup("lex");
+        self.label("lex_digit");
+        self.inc_hl();
+        self.emit(&[0x18]); // JR lex_digits
+        self.emit_relative("lex_digits");
 
-        // Main evaluation loop - check for more operators
-        self.label("eval_loop");
-        // Save accumulator: copy BCD_TEMP1 to BCD_ACCUM
-        self.emit(&[0x21]); // LD HL, BCD_ACCUM
-        self.emit_word(BCD_ACCUM);
-        self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0xCD]); // CALL bcd_copy
-        self.fixup("bcd_copy");
+        // '$' before a column letter, or a '$' by itself
+        self.label("lex_dollar");
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.emit(&[0xE6, 0xDF]); // AND 0xDF (uppercase)
+        self.emit(&[0xD6, b'A']); // SUB 'A'
+        self.emit(&[0xFE, 26]); // CP 26
+        self.emit(&[0x06, REF_DOLLAR]); // LD B, REF_DOLLAR (flags kept)
+        self.emit(&[0x38]); // JR C, lex_ref
+        self.emit_relative("lex_ref");
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x18]); // JR lex_char
+        self.emit_relative("lex_char");
 
+        // Reference: A = column, B = its flags, HL on the letter. The row
+        // digits wrap at 256 as in the text; a row of 0 wraps to 255
+        self.label("lex_ref");
+        self.emit(&[0xB0]); // OR B
+        self.ld_b_a();
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'$']);
+        self.emit(&[0x20, 0x03]); // JR NZ, +3
+        self.emit(&[0xCB, 0xF0]); // SET 6, B (REF_ROW_DOLLAR)
+        self.inc_hl();
+        self.emit(&[0x0E, 0x00]); // LD C, 0
+        self.label("lex_row");
+        self.ld_a_hl_ind();
+        self.emit(&[0xD6, b'0']); // SUB '0'
+        self.emit(&[0xFE, 10]); // CP 10
+        self.emit(&[0x30]); // JR NC, lex_row_done
+        self.emit_relative("lex_row_done");
+        self.push_de();
+        self.ld_e_a();
+        self.ld_a_c();
+        self.emit(&[0x87]); // ADD A, A (x2)
+        self.emit(&[0x87]); // ADD A, A (x4)
+        self.emit(&[0x81]); // ADD A, C (x5)
+        self.emit(&[0x87]); // ADD A, A (x10)
+        self.emit(&[0x83]); // ADD A, E
+        self.ld_c_a();
+        self.pop_de();
+        self.inc_hl();
+        self.emit(&[0x18]); // JR lex_row
+        self.emit_relative("lex_row");
+        self.label("lex_row_done");
+        self.dec_c(); // 0-based
+        self.emit(&[0x3E, TOK_REF]); // LD A, TOK_REF
+        self.emit(&[0x12]); // LD (DE), A
+        self.inc_de();
+        for reg in [0x78, 0x79] {
+            // LD A, B / C: column, row
+            self.emit(&[reg]);
+            self.emit(&[0x12]); // LD (DE), A
+            self.inc_de();
+        }
+        self.emit(&[0xC3]); // JP lex
+        self.fixup("lex");
+
+        // @function: the index of the name in lex_names that the text
+        // starts with, matched without case, or the '@' by itself
+        self.label("lex_func");
+        self.push_de();
+        self.emit(&[0x11]); // LD DE, lex_names
+        self.fixup("lex_names");
+        self.emit(&[0x0E, TOK_FUNC]); // LD C, TOK_FUNC
+        self.label("lex_func_name");
+        self.push_hl(); // the '@'
+        self.label("lex_func_letter");
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.emit(&[0xE6, 0xDF]); // AND 0xDF (uppercase)
+        self.ld_b_a();
+        self.emit(&[0x1A]); // LD A, (DE)
+        self.inc_de();
+        self.emit(&[0xA8]); // XOR B
+        self.emit(&[0x87]); // ADD A, A (carry = last letter)
+        self.emit(&[0x20]); // JR NZ, lex_func_skip
+        self.emit_relative("lex_func_skip");
+        self.emit(&[0x30]); // JR NC, lex_func_letter
+        self.emit_relative("lex_func_letter");
+        self.pop_af(); // drop the '@'
+        self.pop_de();
+        self.ld_a_c();
+        self.emit(&[0xC3]); // JP lex_next
+        self.fixup("lex_next");
+        // No match: on to the next name
+        self.label("lex_func_skip");
+        self.emit(&[0x38]); // JR C, lex_func_other
+        self.emit_relative("lex_func_other");
+        self.label("lex_func_rest");
+        self.emit(&[0x1A]); // LD A, (DE)
+        self.inc_de();
+        self.emit(&[0x87]); // ADD A, A
+        self.emit(&[0x30]); // JR NC, lex_func_rest
+        self.emit_relative("lex_func_rest");
+        self.label("lex_func_other");
+        self.pop_hl(); // the '@'
+        self.emit(&[0x0C]); // INC C
+        self.emit(&[0x1A]); // LD A, (DE)
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, lex_func_name
+        self.emit_relative("lex_func_name");
+        self.pop_de();
+        self.emit(&[0xC3]); // JP lex_char
+        self.fixup("lex_char");
+
+        // FUNC_NAMES, each with bit 7 set on its last letter, then a 0
+        self.label("lex_names");
+        let mut names = Vec::new();
+        for name in FUNC_NAMES {
+            names.extend_from_slice(name.as_bytes());
+            *names.last_mut().unwrap() |= 0x80;
+        }
+        names.push(0);
+        self.emit_data(&names);
+
+        // Evaluate an expression with the usual precedence:
+        //   expr   = sum { (<|>|=|<=|>=|<>) sum }
+        //   sum    = term { (+|-) term }
+        //   term   = factor { (*|/) factor }
+        //   factor = { + | - } ( ( expr ) | operand )
+        // Each level keeps its pending left-hand value (4 BCD bytes and the
+        // sign) on the stack while it parses the right-hand side, so nested
+        // parentheses and @functions cannot overwrite it. EVAL_DEPTH counts
+        // open parentheses (including those of @IF/@AND/@OR/@NOT); more
+        // than MAX_NESTING is an error rather than a stack overflow.
+        // Errors are raised through eval_fail with their code; any other
+        // failure is ERR_SYNTAX.
+        // Input: HL = the expression's tokens
+        // Output: Result in BCD_TEMP1, sign in SIGN_ACCUM and A = 0, or
+        // carry set, A = error code and a zero result
+        self.label("eval_expr");
+        self.emit(&[0x22]); // LD (TEMP2), HL (save expr ptr)
+        self.emit_word(layout.var(TEMP2));
+        self.xor_a();
+        self.emit(&[0x32]); // LD (EVAL_DEPTH), A
+        self.emit_word(layout.var(EVAL_DEPTH));
+        self.emit(&[0x3E, ERR_SYNTAX]); // LD A, ERR_SYNTAX
+        self.emit(&[0x32]); // LD (EVAL_ERR), A
+        self.emit_word(layout.var(EVAL_ERR));
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0x38]); // JR C, eval_expr_fail
+        self.emit_relative("eval_expr_fail");
+        // Whole expression consumed? Anything left is a stray operator or ')'
         self.emit(&[0x2A]); // LD HL, (TEMP2)
-        self.emit_word(TEMP2);
+        self.emit_word(layout.var(TEMP2));
         self.ld_a_hl_ind();
         self.or_a_a();
-        self.emit(&[0xCA]); // JP Z, eval_done (no more operators)
-        self.fixup("eval_done");
+        self.emit(&[0xC8]); // RET Z (carry clear)
+        self.label("eval_expr_fail");
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0xCD]); // CALL bcd_zero (A = 0)
+        self.fixup("bcd_zero");
+        self.emit(&[0x32]); // LD (SIGN_ACCUM), A
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x3A]); // LD A, (EVAL_ERR)
+        self.emit_word(layout.var(EVAL_ERR));
+        self.emit(&[0x37]); // SCF
+        self.ret();
 
-        // Save operator
-        self.emit(&[0x32]); // LD (TEMP1+1), A
-        self.emit_word(TEMP1 + 1);
-        self.inc_hl(); // past operator
+        // Sums joined by comparisons, each giving 1.00 (true) or 0.00.
+        // The operator is pushed as a mask of the outcomes that make it
+        // true: 1 = less, 2 = equal, 4 = greater
+        self.label("eval_compare");
+        self.emit(&[0xCD]); // CALL eval_sum
+        self.fixup("eval_sum");
+        self.emit(&[0xD8]); // RET C
+        self.label("eval_compare_loop");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.ld_a_hl_ind();
+        self.emit(&[0xD6, TOK_CMP + 1]); // SUB TOK_CMP + 1
+        self.emit(&[0xFE, 6]); // CP 6
+        self.emit(&[0xD0]); // RET NC (not a comparison, carry clear)
+        self.inc_a(); // mask
+        self.inc_hl();
         self.emit(&[0x22]); // LD (TEMP2), HL
-        self.emit_word(TEMP2);
-
-        // Parse next operand (result goes to BCD_TEMP1, sign in TEMP1)
-        self.emit(&[0xCD]); // CALL parse_operand
-        self.fixup("parse_operand");
-        self.emit(&[0xDA]); // JP C, eval_chain_error
-        self.fixup("eval_chain_error");
-        // Save operand's sign to SIGN_OP
-        self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
-        self.emit(&[0x32]); // LD (SIGN_OP), A
-        self.emit_word(SIGN_OP);
-
-        // Now: BCD_TEMP1 = new operand, BCD_ACCUM = old accumulator
-        // Copy BCD_ACCUM to BCD_TEMP2 for operation
-        self.emit(&[0x21]); // LD HL, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
-        self.emit(&[0x11]); // LD DE, BCD_ACCUM
-        self.emit_word(BCD_ACCUM);
-        self.emit(&[0xCD]); // CALL bcd_copy
-        self.fixup("bcd_copy");
-        // BCD_TEMP1 = new operand, BCD_TEMP2 = old accumulator
-
-        // Get operator and dispatch
-        self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xCD]); // CALL eval_push
+        self.fixup("eval_push");
+        self.emit(&[0xCD]); // CALL eval_sum
+        self.fixup("eval_sum");
+        self.emit(&[0xCD]); // CALL eval_pop (A = mask)
+        self.fixup("eval_pop");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_cmp
+        self.fixup("eval_cmp");
+        self.emit(&[0xC3]); // JP eval_compare_loop
+        self.fixup("eval_compare_loop");
+
+        // Terms joined by + and -
+        self.label("eval_sum");
+        self.emit(&[0xCD]); // CALL eval_term
+        self.fixup("eval_term");
+        self.emit(&[0xD8]); // RET C
+        self.label("eval_sum_loop");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.ld_a_hl_ind();
         self.emit(&[0xFE, b'+']);
-        self.emit(&[0xCA]); // JP Z, eval_add
-        self.fixup("eval_add");
+        self.emit(&[0x28]); // JR Z, eval_sum_op
+        self.emit_relative("eval_sum_op");
         self.emit(&[0xFE, b'-']);
-        self.emit(&[0xCA]); // JP Z, eval_sub
-        self.fixup("eval_sub");
+        self.emit(&[0x28]); // JR Z, eval_sum_op
+        self.emit_relative("eval_sum_op");
+        self.or_a_a(); // not ours: clear carry, leave it to the caller
+        self.ret();
+        self.label("eval_sum_op");
+        self.inc_hl(); // past operator
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xCD]); // CALL eval_push (save left side and operator)
+        self.fixup("eval_push");
+        self.emit(&[0xCD]); // CALL eval_term
+        self.fixup("eval_term");
+        self.emit(&[0xCD]); // CALL eval_pop (left side to BCD_TEMP2, A = operator)
+        self.fixup("eval_pop");
+        self.emit(&[0xD8]); // RET C (error in the term)
+        self.emit(&[0xFE, b'-']);
+        self.emit(&[0x28]); // JR Z, eval_sum_sub
+        self.emit_relative("eval_sum_sub");
+        self.emit(&[0xCD]); // CALL eval_add
+        self.fixup("eval_add");
+        self.emit(&[0x18]); // JR eval_sum_next
+        self.emit_relative("eval_sum_next");
+        self.label("eval_sum_sub");
+        self.emit(&[0xCD]); // CALL eval_sub
+        self.fixup("eval_sub");
+        self.label("eval_sum_next");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xC3]); // JP eval_sum_loop
+        self.fixup("eval_sum_loop");
+
+        // Factors joined by * and /
+        self.label("eval_term");
+        self.emit(&[0xCD]); // CALL eval_factor
+        self.fixup("eval_factor");
+        self.emit(&[0xD8]); // RET C
+        self.label("eval_term_loop");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.ld_a_hl_ind();
         self.emit(&[0xFE, b'*']);
-        self.emit(&[0xCA]); // JP Z, eval_mul
-        self.fixup("eval_mul");
+        self.emit(&[0x28]); // JR Z, eval_term_op
+        self.emit_relative("eval_term_op");
         self.emit(&[0xFE, b'/']);
-        self.emit(&[0xCA]); // JP Z, eval_div
+        self.emit(&[0x28]); // JR Z, eval_term_op
+        self.emit_relative("eval_term_op");
+        self.or_a_a(); // clear carry
+        self.ret();
+        self.label("eval_term_op");
+        self.inc_hl(); // past operator
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xCD]); // CALL eval_push
+        self.fixup("eval_push");
+        self.emit(&[0xCD]); // CALL eval_factor
+        self.fixup("eval_factor");
+        self.emit(&[0xCD]); // CALL eval_pop
+        self.fixup("eval_pop");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xFE, b'/']);
+        self.emit(&[0x28]); // JR Z, eval_term_div
+        self.emit_relative("eval_term_div");
+        self.emit(&[0xCD]); // CALL eval_mul
+        self.fixup("eval_mul");
+        self.emit(&[0x18]); // JR eval_term_next
+        self.emit_relative("eval_term_next");
+        self.label("eval_term_div");
+        self.emit(&[0xCD]); // CALL eval_div
         self.fixup("eval_div");
-        // Unknown operator - error
-        self.emit(&[0x37]); // SCF
+        self.label("eval_term_next");
+        self.emit(&[0xD8]); // RET C (divide by zero)
+        self.emit(&[0xC3]); // JP eval_term_loop
+        self.fixup("eval_term_loop");
+
+        // A factor with any number of unary + and - in front of it
+        // C = 0x80 if an odd number of minus signs negate the value
+        self.label("eval_factor");
+        self.emit(&[0x0E, 0x00]); // LD C, 0
+        self.label("eval_factor_sign");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'+']);
+        self.emit(&[0x28]); // JR Z, eval_factor_skip
+        self.emit_relative("eval_factor_skip");
+        self.emit(&[0xFE, b'-']);
+        self.emit(&[0x20]); // JR NZ, eval_factor_value
+        self.emit_relative("eval_factor_value");
+        self.ld_a_c();
+        self.emit(&[0xEE, 0x80]); // XOR 0x80
+        self.ld_c_a();
+        self.label("eval_factor_skip");
+        self.inc_hl();
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0x18]); // JR eval_factor_sign
+        self.emit_relative("eval_factor_sign");
+        self.label("eval_factor_value");
+        self.ld_a_c();
+        self.or_a_a();
+        self.emit(&[0xCA]); // JP Z, eval_primary (no negation)
+        self.fixup("eval_primary");
+        // One stack slot however many signs there were
+        self.push_bc();
+        self.emit(&[0xCD]); // CALL eval_primary
+        self.fixup("eval_primary");
+        self.pop_bc();
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0xA9]); // XOR C
+        self.emit(&[0x32]); // LD (SIGN_ACCUM), A
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0xC3]); // JP eval_norm (-0 is 0)
+        self.fixup("eval_norm");
+
+        // A parenthesised expression or a single operand
+        self.label("eval_primary");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'(']);
+        self.emit(&[0x20]); // JR NZ, eval_primary_operand
+        self.emit_relative("eval_primary_operand");
+        self.inc_hl(); // past '('
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xCD]); // CALL eval_enter
+        self.fixup("eval_enter");
+        self.emit(&[0xD8]); // RET C (too deep)
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xC3]); // JP eval_close (result is already normalised)
+        self.fixup("eval_close");
+        self.label("eval_primary_operand");
+        // Operand value goes to BCD_TEMP1, sign in TEMP1
+        self.emit(&[0xCD]); // CALL parse_operand
+        self.fixup("parse_operand");
+        self.emit(&[0xD8]); // RET C (error)
+        self.emit(&[0x3A]); // LD A, (TEMP1)
+        self.emit_word(layout.var(TEMP1));
+        self.emit(&[0x32]); // LD (SIGN_ACCUM), A
+        self.emit_word(layout.var(SIGN_ACCUM));
+        // Fall through into eval_norm
+
+        // A zero result is always positive (no -0.00 from -5+5 or 0*-3)
+        self.label("eval_norm");
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x7E]); // LD A, (HL)
+        self.emit(&[0x23]);
+        self.emit(&[0xB6]); // OR (HL)
+        self.emit(&[0x23]);
+        self.emit(&[0xB6]); // OR (HL)
+        self.emit(&[0x23]);
+        self.emit(&[0xB6]); // OR (HL) (clears carry)
+        self.emit(&[0xC0]); // RET NZ
+        self.emit(&[0x32]); // LD (SIGN_ACCUM), A (A = 0)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.ret();
+
+        // Push BCD_TEMP1 and SIGN_ACCUM (the left-hand side) plus the
+        // operator in A under the return address: 6 bytes
+        self.label("eval_push");
+        self.emit(&[0xE1]); // POP HL (return address)
+        self.emit(&[0xED, 0x5B]); // LD DE, (BCD_TEMP1)
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.push_de();
+        self.emit(&[0xED, 0x5B]); // LD DE, (BCD_TEMP1+2)
+        self.emit_word(layout.var(BCD_TEMP1) + 2);
+        self.push_de();
+        self.ld_b_a(); // operator
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.ld_c_a();
+        self.push_bc();
+        self.emit(&[0xE9]); // JP (HL) (return)
+
+        // Pop what eval_push saved: the right-hand side's sign moves to
+        // SIGN_OP, the left side goes to BCD_TEMP2/SIGN_ACCUM, A = operator.
+        // Preserves the carry flag of the right-hand side's evaluation.
+        self.label("eval_pop");
+        self.emit(&[0xE1]); // POP HL (return address)
+        self.pop_bc(); // B = operator, C = left sign
+        self.pop_de();
+        self.emit(&[0xED, 0x53]); // LD (BCD_TEMP2+2), DE
+        self.emit_word(layout.var(BCD_TEMP2) + 2);
+        self.pop_de();
+        self.emit(&[0xED, 0x53]); // LD (BCD_TEMP2), DE
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.ex_de_hl(); // DE = return address
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x32]); // LD (SIGN_OP), A
+        self.emit_word(layout.var(SIGN_OP));
+        self.ld_a_c();
+        self.emit(&[0x32]); // LD (SIGN_ACCUM), A
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.ld_a_b(); // operator
+        self.ex_de_hl();
+        self.emit(&[0xE9]); // JP (HL) (return)
+
+        // Open a nesting level; carry set if there are already MAX_NESTING
+        self.label("eval_enter");
+        self.emit(&[0x3A]); // LD A, (EVAL_DEPTH)
+        self.emit_word(layout.var(EVAL_DEPTH));
+        self.emit(&[0xFE, MAX_NESTING]); // CP MAX_NESTING
+        self.emit(&[0x3F]); // CCF (carry = too deep)
+        self.emit(&[0xD8]); // RET C
+        self.inc_a();
+        self.emit(&[0x32]); // LD (EVAL_DEPTH), A
+        self.emit_word(layout.var(EVAL_DEPTH));
         self.ret();
 
-        self.label("eval_done");
-        // Result is in BCD_TEMP1, copy back to BCD_ACCUM for formula storage
-        // Actually, we need to return the BCD in a usable format
+        // Take the ')' that closes a nesting level; carry set if missing
+        self.label("eval_close");
+        self.emit(&[0x3E, b')']); // LD A, ')'
+        self.emit(&[0xCD]); // CALL eval_expect
+        self.fixup("eval_expect");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x21]); // LD HL, EVAL_DEPTH
+        self.emit_word(layout.var(EVAL_DEPTH));
+        self.emit(&[0x35]); // DEC (HL) (carry stays clear)
+        self.ret();
+
+        // Take the character in A at (TEMP2); carry set if it is not there
+        self.label("eval_expect");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xBE]); // CP (HL)
+        self.emit(&[0x37]); // SCF (flags Z untouched)
+        self.emit(&[0xC0]); // RET NZ
+        self.inc_hl();
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
         self.or_a_a(); // clear carry
         self.ret();
 
-        self.label("eval_chain_error");
-        self.emit(&[0x37]); // SCF
+        // Skip a function argument without evaluating it: move (TEMP2) to
+        // the next ',' or ')' outside parentheses, or to the end
+        self.label("eval_skip_arg");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0x0E, 0x00]); // LD C, 0 (open parentheses)
+        self.label("eval_skip_loop");
+        self.ld_a_hl_ind();
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, eval_skip_done
+        self.emit_relative("eval_skip_done");
+        self.emit(&[0xFE, b'(']);
+        self.emit(&[0x20]); // JR NZ, eval_skip_close
+        self.emit_relative("eval_skip_close");
+        self.inc_c();
+        self.emit(&[0x18]); // JR eval_skip_next
+        self.emit_relative("eval_skip_next");
+        self.label("eval_skip_close");
+        self.emit(&[0xFE, b')']);
+        self.emit(&[0x20]); // JR NZ, eval_skip_comma
+        self.emit_relative("eval_skip_comma");
+        self.ld_a_c();
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, eval_skip_done
+        self.emit_relative("eval_skip_done");
+        self.dec_c();
+        self.emit(&[0x18]); // JR eval_skip_next
+        self.emit_relative("eval_skip_next");
+        self.label("eval_skip_comma");
+        self.emit(&[0xFE, b',']);
+        self.emit(&[0x20]); // JR NZ, eval_skip_next
+        self.emit_relative("eval_skip_next");
+        self.ld_a_c();
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, eval_skip_done
+        self.emit_relative("eval_skip_done");
+        self.label("eval_skip_next");
+        self.emit(&[0xCD]); // CALL tok_next
+        self.fixup("tok_next");
+        self.emit(&[0x18]); // JR eval_skip_loop
+        self.emit_relative("eval_skip_loop");
+        self.label("eval_skip_done");
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
         self.ret();
 
-        // Signed addition: BCD_TEMP2 + BCD_TEMP1 -> BCD_TEMP1
-        // SIGN_ACCUM = sign of TEMP2, SIGN_OP = sign of TEMP1
-        self.label("eval_add");
-        // Check if signs are the same
-        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
-        self.emit_word(SIGN_ACCUM);
-        self.ld_b_a();
-        self.emit(&[0x3A]); // LD A, (SIGN_OP)
-        self.emit_word(SIGN_OP);
-        self.emit(&[0xB8]); // CP B (compare signs)
-        self.emit(&[0xCA]); // JP Z, eval_add_same_sign
-        self.fixup("eval_add_same_sign");
+        // Step HL past the token there and its operand bytes: A = the
+        // token. Keeps BC and DE
+        self.label("tok_next");
+        self.ld_a_hl_ind();
+        self.inc_hl();
+        self.emit(&[0xFE, TOK_REF + 1]); // CP TOK_REF + 1
+        self.emit(&[0xD0]); // RET NC (no operand bytes)
+        self.or_a_a();
+        self.emit(&[0xC8]); // RET Z (TOK_END)
+        self.inc_hl(); // column and row, or two of the BCD bytes
+        self.inc_hl();
+        self.emit(&[0xFE, TOK_REF]); // CP TOK_REF
+        self.emit(&[0xC8]); // RET Z
+        self.inc_hl();
+        self.inc_hl();
+        self.ret();
 
-        // Different signs: need to subtract smaller from larger
-        // Compare magnitudes: TEMP2 vs TEMP1
+        // Comparison: BCD_TEMP2 (sign SIGN_ACCUM) against BCD_TEMP1 (sign
+        // SIGN_OP) with the outcome mask in A -> 1.00 or 0.00 via eval_bool
+        self.label("eval_cmp");
+        self.push_af(); // mask
+        self.emit(&[0xCD]); // CALL eval_order
+        self.fixup("eval_order");
+        self.pop_bc(); // B = mask
+        self.emit(&[0xA0]); // AND B
+        self.emit(&[0x18]); // JR eval_bool
+        self.emit_relative("eval_bool");
+
+        // Signed order of BCD_TEMP2 (sign SIGN_ACCUM) against BCD_TEMP1
+        // (sign SIGN_OP): A = 1 if less, 2 if equal, 4 if greater
+        self.label("eval_order");
+        self.emit(&[0x3A]); // LD A, (SIGN_OP)
+        self.emit_word(layout.var(SIGN_OP));
+        self.ld_b_a();
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0xB8]); // CP B
+        self.emit(&[0x28]); // JR Z, eval_cmp_same
+        self.emit_relative("eval_cmp_same");
+        // Signs differ (zero is always positive): the negative one is less
+        self.or_a_a();
+        self.emit(&[0x3E, 0x01]); // LD A, 1 (less)
+        self.emit(&[0xC0]); // RET NZ
+        self.emit(&[0x3E, 0x04]); // LD A, 4 (greater)
+        self.ret();
+        self.label("eval_cmp_same");
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0xCD]); // CALL bcd_cmp (C set if TEMP2 < TEMP1)
         self.fixup("bcd_cmp");
-        self.emit(&[0xDA]); // JP C, eval_add_op_larger (TEMP2 < TEMP1)
-        self.fixup("eval_add_op_larger");
-
-        // TEMP2 >= TEMP1: result = TEMP2 - TEMP1, sign = SIGN_ACCUM
-        self.emit(&[0x21]); // LD HL, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
-        self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0xCD]); // CALL bcd_sub (TEMP2 - TEMP1 -> TEMP2)
-        self.fixup("bcd_sub");
-        // Copy result from TEMP2 to TEMP1
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
-        self.emit(&[0xCD]); // CALL bcd_copy
-        self.fixup("bcd_copy");
-        // Sign stays as SIGN_ACCUM (already set)
-        self.emit(&[0xC3]); // JP eval_loop
-        self.fixup("eval_loop");
+        self.emit(&[0x3E, 0x02]); // LD A, 2 (equal)
+        self.ret_z();
+        self.emit(&[0x3E, 0x01]); // LD A, 1 (less)
+        self.emit(&[0x38]); // JR C, eval_cmp_sign
+        self.emit_relative("eval_cmp_sign");
+        self.emit(&[0x3E, 0x04]); // LD A, 4 (greater)
+        self.label("eval_cmp_sign");
+        // Both negative: the larger magnitude is the smaller value
+        self.ld_b_a();
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.or_a_a();
+        self.ld_a_b();
+        self.ret_z();
+        self.emit(&[0xEE, 0x05]); // XOR 5 (less <-> greater)
+        self.ret();
 
-        // TEMP1 > TEMP2: result = TEMP1 - TEMP2, sign = SIGN_OP
-        self.label("eval_add_op_larger");
+        // Truth value in A (0 = false) -> BCD_TEMP1 = 1.00 or 0.00, positive
+        // in SIGN_ACCUM and in TEMP1 (for parse_func's callers)
+        self.label("eval_bool");
+        self.ld_b_a();
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
-        self.emit(&[0xCD]); // CALL bcd_sub (TEMP1 - TEMP2 -> TEMP1)
-        self.fixup("bcd_sub");
-        // Set result sign to SIGN_OP
-        self.emit(&[0x3A]); // LD A, (SIGN_OP)
-        self.emit_word(SIGN_OP);
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0xCD]); // CALL bcd_zero (A = 0, HL = BCD_TEMP1+3)
+        self.fixup("bcd_zero");
         self.emit(&[0x32]); // LD (SIGN_ACCUM), A
-        self.emit_word(SIGN_ACCUM);
-        self.emit(&[0xC3]); // JP eval_loop
-        self.fixup("eval_loop");
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x32]); // LD (TEMP1), A
+        self.emit_word(layout.var(TEMP1));
+        self.ld_a_b();
+        self.or_a_a(); // clear carry
+        self.emit(&[0xC8]); // RET Z
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x36, 0x01]); // LD (HL), 0x01 (1.00)
+        self.ret();
 
-        // Same signs: just add magnitudes, keep the sign
-        self.label("eval_add_same_sign");
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
-        self.emit(&[0xCD]); // CALL bcd_add
-        self.fixup("bcd_add");
-        // Sign stays as SIGN_ACCUM (same as SIGN_OP)
-        self.emit(&[0xC3]); // JP eval_loop
-        self.fixup("eval_loop");
+        // Operator handlers: BCD_TEMP2 (sign SIGN_ACCUM) op BCD_TEMP1 (sign
+        // SIGN_OP) -> BCD_TEMP1, sign in SIGN_ACCUM, carry set on error
+
+        // Signed addition
+        self.label("eval_add");
+        self.emit(&[0xCD]); // CALL signed_add
+        self.fixup("signed_add");
+        self.emit(&[0xDA]); // JP C, eval_overflow
+        self.fixup("eval_overflow");
+        self.emit(&[0xC3]); // JP eval_norm
+        self.fixup("eval_norm");
 
         // Signed subtraction: A - B = A + (-B)
         // Just flip SIGN_OP and use addition logic
         self.label("eval_sub");
         self.emit(&[0x3A]); // LD A, (SIGN_OP)
-        self.emit_word(SIGN_OP);
+        self.emit_word(layout.var(SIGN_OP));
         self.emit(&[0xEE, 0x80]); // XOR 0x80 (flip sign)
         self.emit(&[0x32]); // LD (SIGN_OP), A
-        self.emit_word(SIGN_OP);
+        self.emit_word(layout.var(SIGN_OP));
         self.emit(&[0xC3]); // JP eval_add
         self.fixup("eval_add");
 
@@ -2858,156 +4711,145 @@ impl SpreadsheetCodeGen {
         self.label("eval_mul");
         // Result sign = SIGN_ACCUM XOR SIGN_OP
         self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
-        self.emit_word(SIGN_ACCUM);
+        self.emit_word(layout.var(SIGN_ACCUM));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (SIGN_OP)
-        self.emit_word(SIGN_OP);
+        self.emit_word(layout.var(SIGN_OP));
         self.emit(&[0xA8]); // XOR B
         self.emit(&[0x32]); // LD (SIGN_ACCUM), A (result sign)
-        self.emit_word(SIGN_ACCUM);
+        self.emit_word(layout.var(SIGN_ACCUM));
         // Do the multiplication
         self.emit(&[0xCD]); // CALL bcd_mul
         self.fixup("bcd_mul");
-        self.emit(&[0xC3]); // JP eval_loop
-        self.fixup("eval_loop");
+        self.emit(&[0xDA]); // JP C, eval_overflow
+        self.fixup("eval_overflow");
+        self.emit(&[0xC3]); // JP eval_norm
+        self.fixup("eval_norm");
 
         // BCD_TEMP2 / BCD_TEMP1 -> BCD_TEMP1 (with sign handling)
         self.label("eval_div");
         // Result sign = SIGN_ACCUM XOR SIGN_OP
         self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
-        self.emit_word(SIGN_ACCUM);
+        self.emit_word(layout.var(SIGN_ACCUM));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (SIGN_OP)
-        self.emit_word(SIGN_OP);
+        self.emit_word(layout.var(SIGN_OP));
         self.emit(&[0xA8]); // XOR B
         self.emit(&[0x32]); // LD (SIGN_ACCUM), A (result sign)
-        self.emit_word(SIGN_ACCUM);
+        self.emit_word(layout.var(SIGN_ACCUM));
         // bcd_div: BCD_TEMP1 / BCD_TEMP2 -> BCD_TEMP1
         // We need: TEMP2 (old accum) / TEMP1 (new operand) -> TEMP1
         // Swap TEMP1 and TEMP2 first
         self.emit(&[0x21]); // LD HL, BCD_ACCUM (use as temp)
-        self.emit_word(BCD_ACCUM);
+        self.emit_word(layout.var(BCD_ACCUM));
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0xCD]); // CALL bcd_copy (ACCUM = TEMP1)
         self.fixup("bcd_copy");
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0xCD]); // CALL bcd_copy (TEMP1 = TEMP2)
         self.fixup("bcd_copy");
         self.emit(&[0x21]); // LD HL, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0x11]); // LD DE, BCD_ACCUM
-        self.emit_word(BCD_ACCUM);
+        self.emit_word(layout.var(BCD_ACCUM));
         self.emit(&[0xCD]); // CALL bcd_copy (TEMP2 = ACCUM, completing swap)
         self.fixup("bcd_copy");
         // Now TEMP1 has dividend, TEMP2 has divisor
         self.emit(&[0xCD]); // CALL bcd_div
         self.fixup("bcd_div");
-        self.emit(&[0xC3]); // JP eval_loop
-        self.fixup("eval_loop");
-
-        // Parse an operand (cell reference or number)
-        // Input: (TEMP2) = pointer to string
-        // Output: HL = value, (TEMP2) updated, carry set on error
-        // Supports absolute references: $A$1, $A1, A$1
+        self.emit(&[0xDA]); // JP C, eval_fail (A = ERR_DIV0 or ERR_OVF)
+        self.fixup("eval_fail");
+        self.emit(&[0xC3]); // JP eval_norm
+        self.fixup("eval_norm");
+
+        // Parse an operand token (cell reference, number or @function)
+        // Input: (TEMP2) = pointer to the token
+        // Output: value in BCD_TEMP1 and sign in TEMP1, (TEMP2) past the
+        // operand, carry set on error
         self.label("parse_operand");
         self.emit(&[0x2A]); // LD HL, (TEMP2)
-        self.emit_word(TEMP2);
+        self.emit_word(layout.var(TEMP2));
         self.ld_a_hl_ind();
-
-        // Check for @ (function prefix)
-        self.emit(&[0xFE, b'@']);
-        self.emit(&[0xCA]); // JP Z, parse_func
+        self.emit(&[0xFE, TOK_FUNC]); // CP TOK_FUNC
+        self.emit(&[0xD2]); // JP NC, parse_func
         self.fixup("parse_func");
+        self.emit(&[0xFE, TOK_REF]); // CP TOK_REF
+        self.emit(&[0x28]); // JR Z, parse_op_ref
+        self.emit_relative("parse_op_ref");
+        self.emit(&[0xFE, TOK_OVF]); // CP TOK_OVF
+        self.emit(&[0x28]); // JR Z, eval_overflow
+        self.emit_relative("eval_overflow");
+        // Nothing that starts a number (a missing operand, as in "=1+")
+        // is a syntax error rather than zero
+        self.dec_a();
+        self.emit(&[0xFE, TOK_DOT]); // CP TOK_DOT (TOK_NUM or TOK_DOT)
+        self.emit(&[0x3F]); // CCF
+        self.emit(&[0xD8]); // RET C (ERR_SYNTAX)
+        // Number: the BCD follows the token, always positive (eval_factor
+        // has already taken any minus signs)
+        self.inc_hl();
+        self.emit(&[0x11]); // LD DE, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x01]); // LD BC, 4
+        self.emit_word(4);
+        self.emit(&[0xED, 0xB0]); // LDIR
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.xor_a(); // clear carry
+        self.emit(&[0x32]); // LD (TEMP1), A (positive)
+        self.emit_word(layout.var(TEMP1));
+        self.ret();
 
-        // Skip leading $ (absolute column marker)
-        self.emit(&[0xFE, b'$']);
-        self.emit(&[0xC2]); // JP NZ, parse_op_no_dollar1
-        self.fixup("parse_op_no_dollar1");
-        self.inc_hl(); //skip $)
-        self.ld_a_hl_ind();
-        self.label("parse_op_no_dollar1");
-
-        // Convert lowercase to uppercase (a-z -> A-Z)
-        self.emit(&[0xFE, b'a']);
-        self.emit(&[0xDA]); // JP C, parse_op_check_upper (< 'a')
-        self.fixup("parse_op_check_upper");
-        self.emit(&[0xFE, b'z' + 1]);
-        self.emit(&[0xD2]); // JP NC, parse_op_check_upper (> 'z')
-        self.fixup("parse_op_check_upper");
-        self.emit(&[0xD6, 0x20]); // SUB 0x20 (convert to uppercase)
-
-        self.label("parse_op_check_upper");
-        // Check if it's a letter (cell reference A-P)
-        self.emit(&[0xFE, b'A']);
-        self.emit(&[0xDA]); // JP C, parse_op_number
-        self.fixup("parse_op_number");
-        self.emit(&[0xFE, b'P' + 1]);
-        self.emit(&[0xD2]); // JP NC, parse_op_number
-        self.fixup("parse_op_number");
-
-        // It's a cell reference
-        self.emit(&[0xD6, b'A']); // SUB 'A' (column)
+        // Cell reference, a reference error outside the grid
+        self.label("parse_op_ref");
+        self.inc_hl();
+        self.ld_a_hl_ind(); // column and flags
+        self.emit(&[0xE6, !(REF_DOLLAR | REF_ROW_DOLLAR)]); // AND column
         self.ld_b_a();
         self.inc_hl();
-        // Skip $ before row (absolute row marker)
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b'$']);
-        self.emit(&[0xC2]); // JP NZ, parse_op_no_dollar2
-        self.fixup("parse_op_no_dollar2");
-        self.inc_hl(); //skip $)
-        self.label("parse_op_no_dollar2");
-        // Parse row number
-        self.emit(&[0x0E, 0x00]); // LD C, 0 (accumulator)
-        self.label("parse_row_loop");
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b'0']);
-        self.emit(&[0xDA]); // JP C, parse_row_done
-        self.fixup("parse_row_done");
-        self.emit(&[0xFE, b'9' + 1]);
-        self.emit(&[0xD2]); // JP NC, parse_row_done
-        self.fixup("parse_row_done");
-        self.emit(&[0xD6, b'0']); // SUB '0'
-        self.ld_e_a();
-        self.ld_a_c();
-        self.emit(&[0x87]); // ADD A, A (×2)
-        self.emit(&[0x87]); // ADD A, A (×4)
-        self.emit(&[0x81]); // ADD A, C (×5)
-        self.emit(&[0x87]); // ADD A, A (×10)
-        self.emit(&[0x83]); // ADD A, E
-        self.ld_c_a();
+        self.emit(&[0x4E]); // LD C, (HL) (row)
         self.inc_hl();
-        self.emit(&[0xC3]); // JP parse_row_loop
-        self.fixup("parse_row_loop");
-
-        self.label("parse_row_done");
-        self.emit(&[0x22]); // LD (TEMP2), HL (update pointer)
-        self.emit_word(TEMP2);
-        // B = col, C = row (1-based), convert to 0-based
-        self.dec_c();
-        // Get cell value as BCD into BCD_TEMP1
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xFE, layout.cols()]); // CP cols
+        self.emit(&[0x30]); // JR NC, eval_ref
+        self.emit_relative("eval_ref");
+        self.ld_a_c();
+        self.emit(&[0xFE, layout.rows()]); // CP rows
+        self.emit(&[0x30]); // JR NC, eval_ref
+        self.emit_relative("eval_ref");
+
+        // Get cell value as BCD into BCD_TEMP1, sign into TEMP1; an error
+        // cell fails with its own code
+        // (entry for the table functions: B = col, C = 0-based row)
+        self.label("load_cell_value");
         self.emit(&[0xCD]); // CALL get_cell_addr
         self.fixup("get_cell_addr");
         self.ld_a_hl_ind(); // type
         self.or_a_a();
         self.emit(&[0xCA]); // JP Z, parse_op_zero (empty cell = 0)
         self.fixup("parse_op_zero");
+        self.emit(&[0xFE, CELL_ERROR]); // CP CELL_ERROR
+        self.emit(&[0x28]); // JR Z, cell_fail
+        self.emit_relative("cell_fail");
         // Check if formula (type 2)
         self.emit(&[0xFE, CELL_FORMULA]); // CP CELL_FORMULA
-        self.emit(&[0xCA]); // JP Z, parse_op_formula
-        self.fixup("parse_op_formula");
+        self.emit(&[0x28]); // JR Z, parse_op_formula
+        self.emit_relative("parse_op_formula");
         // Number cell: copy sign and BCD from cell to BCD_TEMP1
+        self.label("load_cell_copy");
         self.inc_hl();
         self.ld_a_hl_ind(); // sign
         self.emit(&[0x32]); // LD (BCD_SIGN), A - save sign for later
-        self.emit_word(TEMP1); // using TEMP1 to store sign
+        self.emit_word(layout.var(TEMP1)); // using TEMP1 to store sign
         self.inc_hl();
         // Copy 4 BCD bytes to BCD_TEMP1
         self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x06, 4]); // LD B, 4
         self.label("load_cell_bcd");
         self.ld_a_hl_ind();
@@ -3019,7 +4861,7 @@ impl SpreadsheetCodeGen {
         self.or_a_a(); // clear carry
         self.ret();
 
-        // Formula cell: get computed value from formula storage
+        // Formula cell: its computed value is stored before the text
         self.label("parse_op_formula");
         self.inc_hl(); // skip type
         self.inc_hl(); // skip flags
@@ -3027,448 +4869,285 @@ impl SpreadsheetCodeGen {
         self.emit(&[0x5E]); // LD E, (HL)
         self.inc_hl();
         self.emit(&[0x56]); // LD D, (HL)
-        // DE = formula pointer, find end of string
-        self.ex_de_hl();
-        self.label("parse_op_find_end");
-        self.ld_a_hl_ind();
-        self.inc_hl();
-        self.or_a_a();
-        self.emit(&[0xC2]); // JP NZ, parse_op_find_end
-        self.fixup("parse_op_find_end");
-        // HL now points to sign byte, then 4 BCD bytes
-        self.ld_a_hl_ind(); // load sign
-        self.emit(&[0x32]); // LD (TEMP1), A
-        self.emit_word(TEMP1);
-        self.inc_hl(); // point to BCD
-        self.emit(&[0x11]); // LD DE, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0x06, 4]); // LD B, 4
-        self.label("load_formula_bcd_op");
-        self.ld_a_hl_ind();
-        self.emit(&[0x12]); // LD (DE), A
-        self.inc_hl();
-        self.inc_de();
-        self.emit(&[0x10]); // DJNZ load_formula_bcd_op
-        self.emit_relative("load_formula_bcd_op");
-        self.or_a_a(); // clear carry
-        self.ret();
+        self.emit(&[0x21]); // LD HL, -(FORMULA_VALUE + 1)
+        self.emit_word((FORMULA_VALUE + 1).wrapping_neg());
+        self.add_hl_de(); // one before the sign, as for a number cell
+        self.emit(&[0x18]); // JR load_cell_copy
+        self.emit_relative("load_cell_copy");
 
         self.label("parse_op_zero");
         // Zero BCD_TEMP1
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0xCD]); // CALL bcd_zero
         self.fixup("bcd_zero");
         self.emit(&[0xAF]); // XOR A
         self.emit(&[0x32]); // LD (TEMP1), A (sign = 0)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.or_a_a();
         self.ret();
 
-        // Parse number operand to BCD
-        // Uses ascii_to_bcd which stops at non-digit chars
-        self.label("parse_op_number");
-        self.emit(&[0x2A]); // LD HL, (TEMP2)
-        self.emit_word(TEMP2);
-        self.emit(&[0xAF]); // XOR A (clear sign)
-        self.emit(&[0x32]); // LD (TEMP1), A
-        self.emit_word(TEMP1);
-
-        // Check minus
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b'-']);
-        self.emit(&[0x20, 0x06]); // JR NZ, +6 (skip negative handling: 2+3+1=6 bytes)
-        self.emit(&[0x3E, 0x80]); // LD A, 0x80 (negative sign) - 2 bytes
-        self.emit(&[0x32]); // LD (TEMP1), A - 3 bytes with word
-        self.emit_word(TEMP1);
-        self.inc_hl(); // - 1 byte
-
-        // Call ascii_to_bcd (HL points to digit string)
-        // Result in BCD_TEMP1, HL updated past digits
-        self.emit(&[0xCD]); // CALL ascii_to_bcd
-        self.fixup("ascii_to_bcd");
-
-        // Update TEMP2 with new position (scan past digits and decimal point)
-        self.emit(&[0x2A]); // LD HL, (TEMP2)
-        self.emit_word(TEMP2);
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b'-']);
-        self.emit(&[0x20, 0x01]); // JR NZ, +1
+        // Raise an error: the code of the error cell at HL, ERR_OVF,
+        // ERR_REF, or the code in A. Returns with carry set
+        self.label("cell_fail");
+        self.emit(&[0x22]); // LD (RECALC_WAIT), HL (for recalc_all)
+        self.emit_word(layout.var(RECALC_WAIT));
         self.inc_hl();
-        self.label("parse_opn_scan");
         self.ld_a_hl_ind();
-        // Check for decimal point
-        self.emit(&[0xFE, b'.']);
-        self.emit(&[0xCA]); // JP Z, parse_opn_next (skip decimal point)
-        self.fixup("parse_opn_next");
-        // Check for digit
-        self.emit(&[0xFE, b'0']);
-        self.emit(&[0xDA]); // JP C, parse_opn_done (< '0')
-        self.fixup("parse_opn_done");
-        self.emit(&[0xFE, b'9' + 1]);
-        self.emit(&[0xD2]); // JP NC, parse_opn_done (> '9')
-        self.fixup("parse_opn_done");
-        self.label("parse_opn_next");
-        self.inc_hl();
-        self.emit(&[0xC3]); // JP parse_opn_scan
-        self.fixup("parse_opn_scan");
-
-        self.label("parse_opn_done");
-        self.emit(&[0x22]); // LD (TEMP2), HL
-        self.emit_word(TEMP2);
-        self.or_a_a(); // clear carry
+        self.emit(&[0x18]); // JR eval_fail
+        self.emit_relative("eval_fail");
+        self.label("eval_overflow");
+        self.emit(&[0x3E, ERR_OVF]); // LD A, ERR_OVF
+        self.emit(&[0x18]); // JR eval_fail
+        self.emit_relative("eval_fail");
+        self.label("eval_ref");
+        self.emit(&[0x3E, ERR_REF]); // LD A, ERR_REF
+        self.label("eval_fail");
+        self.emit(&[0x32]); // LD (EVAL_ERR), A
+        self.emit_word(layout.var(EVAL_ERR));
+        self.emit(&[0x37]); // SCF
         self.ret();
 
-        // Parse function like @SUM(A1:A5), @AVG, @MIN, @MAX, @COUNT
+        // Parse an @function token: @SUM, @AVG, @MIN, @MAX, @COUNT, one
+        // of the logic functions @IF, @AND, @OR, @NOT, one of the
+        // numeric functions @ABS, @INT, @ROUND, @MOD, a table function or
+        // a financial function @NPV, @PMT, @PV, @FV. A = the token; the
+        // handlers start with HL on it
         // FUNC_TYPE: 0=SUM, 1=AVG, 2=MIN, 3=MAX, 4=COUNT
         self.label("parse_func");
-        self.inc_hl(); //skip @)
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]); // AND 0xDF (uppercase)
-
-        // Check first letter: S=SUM, A=AVG, M=MIN/MAX, C=COUNT
-        self.emit(&[0xFE, b'S']);
-        self.emit(&[0xCA]); // JP Z, pf_sum
-        self.fixup("pf_sum");
-        self.emit(&[0xFE, b'A']);
-        self.emit(&[0xCA]); // JP Z, pf_avg
-        self.fixup("pf_avg");
-        self.emit(&[0xFE, b'M']);
-        self.emit(&[0xCA]); // JP Z, pf_minmax
-        self.fixup("pf_minmax");
-        self.emit(&[0xFE, b'C']);
-        self.emit(&[0xCA]); // JP Z, pf_count
-        self.fixup("pf_count");
-        self.emit(&[0xC3]); // JP pf_error
-        self.fixup("pf_error");
-
-        // @SUM - check "UM("
-        self.label("pf_sum");
-        self.emit(&[0x3E, 0x00]); // LD A, 0 (SUM type)
-        self.emit(&[0x32]); // LD (FUNC_TYPE), A
-        self.emit_word(FUNC_TYPE);
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]); // uppercase
-        self.emit(&[0xFE, b'U']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'M']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.emit(&[0xC3]); // JP pf_parse_paren
-        self.fixup("pf_parse_paren");
-
-        // @AVG - check "VG("
-        self.label("pf_avg");
-        self.emit(&[0x3E, 0x01]); // LD A, 1 (AVG type)
-        self.emit(&[0x32]); // LD (FUNC_TYPE), A
-        self.emit_word(FUNC_TYPE);
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'V']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'G']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.emit(&[0xC3]); // JP pf_parse_paren
-        self.fixup("pf_parse_paren");
-
-        // @MIN or @MAX - check "IN(" or "AX("
-        self.label("pf_minmax");
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'I']);
-        self.emit(&[0xCA]); // JP Z, pf_min
-        self.fixup("pf_min");
-        self.emit(&[0xFE, b'A']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        // MAX
-        self.emit(&[0x3E, 0x03]); // LD A, 3 (MAX type)
+        self.emit(&[0xD6, TOK_FUNC + 5]); // SUB TOK_FUNC + 5
+        self.emit(&[0x30]); // JR NC, parse_func_jump
+        self.emit_relative("parse_func_jump");
+        self.emit(&[0xC6, 5]); // ADD A, 5 (aggregate: FUNC_TYPE)
         self.emit(&[0x32]); // LD (FUNC_TYPE), A
-        self.emit_word(FUNC_TYPE);
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'X']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
+        self.emit_word(layout.var(FUNC_TYPE));
         self.emit(&[0xC3]); // JP pf_parse_paren
         self.fixup("pf_parse_paren");
-
-        self.label("pf_min");
-        self.emit(&[0x3E, 0x02]); // LD A, 2 (MIN type)
-        self.emit(&[0x32]); // LD (FUNC_TYPE), A
-        self.emit_word(FUNC_TYPE);
+        self.label("parse_func_jump");
+        self.push_hl();
+        self.emit(&[0x87]); // ADD A, A
+        self.ld_e_a();
+        self.emit(&[0x16, 0x00]); // LD D, 0
+        self.emit(&[0x21]); // LD HL, pf_table
+        self.fixup("pf_table");
+        self.add_hl_de();
+        self.emit(&[0x5E]); // LD E, (HL)
         self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'N']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.emit(&[0xC3]); // JP pf_parse_paren
-        self.fixup("pf_parse_paren");
+        self.emit(&[0x56]); // LD D, (HL)
+        self.ex_de_hl();
+        self.emit(&[0xE3]); // EX (SP), HL (HL = the token again)
+        self.ret(); // to the handler
+        self.label("pf_table");
+        let start = self.pos();
+        for handler in FUNC_HANDLERS {
+            self.fixup(handler);
+        }
+        self.data.push((start, self.pos()));
 
-        // @COUNT - check "OUNT("
-        self.label("pf_count");
-        self.emit(&[0x3E, 0x04]); // LD A, 4 (COUNT type)
-        self.emit(&[0x32]); // LD (FUNC_TYPE), A
-        self.emit_word(FUNC_TYPE);
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'O']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'U']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'N']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]);
-        self.emit(&[0xFE, b'T']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        // fall through to pf_parse_paren
+        // @ERROR: always ERR_ERROR
+        self.label("pf_err");
+        self.emit(&[0x3E, ERR_ERROR]); // LD A, ERR_ERROR
+        self.emit(&[0xC3]); // JP eval_fail
+        self.fixup("eval_fail");
 
-        // Parse opening paren
+        // Arguments: a comma-separated mix of ranges, single cells and
+        // literal numbers, all feeding the same accumulators
         self.label("pf_parse_paren");
-        self.inc_hl();
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b'(']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.inc_hl();
-
-        // Parse first cell: col1, row1
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]); // AND 0xDF (uppercase)
-        self.emit(&[0xFE, b'A']);
-        self.emit(&[0xDA]); // JP C, pf_error
-        self.fixup("pf_error");
-        self.emit(&[0xFE, b'Q']);
-        self.emit(&[0xD2]); // JP NC, pf_error
-        self.fixup("pf_error");
-        self.emit(&[0xD6, b'A']); // SUB 'A'
-        self.emit(&[0x32]); // LD (TEMP1), A (col1)
-        self.emit_word(TEMP1);
-        self.inc_hl();
-        // Parse row1
-        self.emit(&[0x0E, 0x00]); // LD C, 0
-        self.label("pf_row1_loop");
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b'0']);
-        self.emit(&[0xDA]); // JP C, pf_row1_done
-        self.fixup("pf_row1_done");
-        self.emit(&[0xFE, b'9' + 1]);
-        self.emit(&[0xD2]); // JP NC, pf_row1_done
-        self.fixup("pf_row1_done");
-        self.emit(&[0xD6, b'0']); // digit
-        self.ld_b_a();
-        self.ld_a_c();
-        self.emit(&[0x87]); // x2
-        self.emit(&[0x4F]); // save
-        self.emit(&[0x87]); // x4
-        self.emit(&[0x87]); // x8
-        self.emit(&[0x81]); // +x2 = x10
-        self.emit(&[0x80]); // +digit
-        self.ld_c_a();
-        self.inc_hl();
-        self.emit(&[0xC3]); // JP pf_row1_loop
-        self.fixup("pf_row1_loop");
-        self.label("pf_row1_done");
-        self.ld_a_c();
-        self.dec_a(); //0-based)
-        self.emit(&[0x32]); // LD (TEMP1+1), A (row1)
-        self.emit_word(TEMP1 + 1);
-
-        // Check for :
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b':']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.inc_hl();
-
-        // Parse second cell - col2 and row2
-        self.ld_a_hl_ind();
-        self.emit(&[0xE6, 0xDF]); // uppercase
-        self.emit(&[0xFE, b'A']);
-        self.emit(&[0xDA]); // JP C, pf_error
-        self.fixup("pf_error");
-        self.emit(&[0xD6, b'A']); // SUB 'A'
-        self.emit(&[0x32]); // LD (RANGE_COL2), A (col2)
-        self.emit_word(RANGE_COL2);
-        self.inc_hl();
-        // Parse row2
-        self.emit(&[0x0E, 0x00]); // LD C, 0
-        self.label("pf_row2_loop");
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b'0']);
-        self.emit(&[0xDA]); // JP C, pf_row2_done
-        self.fixup("pf_row2_done");
-        self.emit(&[0xFE, b'9' + 1]);
-        self.emit(&[0xD2]); // JP NC, pf_row2_done
-        self.fixup("pf_row2_done");
-        self.emit(&[0xD6, b'0']);
-        self.ld_b_a();
-        self.ld_a_c();
-        self.emit(&[0x87]); // x2
-        self.emit(&[0x4F]); // save
-        self.emit(&[0x87]); // x4
-        self.emit(&[0x87]); // x8
-        self.emit(&[0x81]); // x10
-        self.emit(&[0x80]); // +digit
-        self.ld_c_a();
-        self.inc_hl();
-        self.emit(&[0xC3]); // JP pf_row2_loop
-        self.fixup("pf_row2_loop");
-        self.label("pf_row2_done");
-        self.ld_a_c();
-        self.dec_a(); //0-based)
-        self.emit(&[0x32]); // LD (RANGE_ROW2), A (row2)
-        self.emit_word(RANGE_ROW2);
-
-        // Check for )
-        self.ld_a_hl_ind();
-        self.emit(&[0xFE, b')']);
-        self.emit(&[0xC2]); // JP NZ, pf_error
-        self.fixup("pf_error");
-        self.inc_hl();
-        self.emit(&[0x22]); // LD (TEMP2), HL (update pointer - overwrites low byte)
-        self.emit_word(TEMP2);
+        self.push_hl();
 
         // Initialize accumulators for BCD functions
         // Clear FUNC_BCD (4-byte BCD sum/min/max accumulator)
         self.emit(&[0x21]); // LD HL, FUNC_BCD
-        self.emit_word(FUNC_BCD);
+        self.emit_word(layout.var(FUNC_BCD));
         self.emit(&[0xCD]); // CALL bcd_zero
         self.fixup("bcd_zero");
         // Clear count and sign
         self.xor_a();
         self.emit(&[0x32]); // LD (FUNC_COUNT), A
-        self.emit_word(FUNC_COUNT);
+        self.emit_word(layout.var(FUNC_COUNT));
         self.emit(&[0x32]); // LD (FUNC_COUNT+1), A
-        self.emit_word(FUNC_COUNT + 1);
+        self.emit_word(layout.var(FUNC_COUNT) + 1);
         self.emit(&[0x32]); // LD (FUNC_SIGN), A (accumulator is positive)
-        self.emit_word(FUNC_SIGN);
+        self.emit_word(layout.var(FUNC_SIGN));
+        self.pop_hl();
 
-        // For MIN, initialize FUNC_BCD to max BCD value (99999999)
-        self.emit(&[0x3A]); // LD A, (FUNC_TYPE)
-        self.emit_word(FUNC_TYPE);
-        self.emit(&[0xFE, 0x02]); // CP 2 (MIN)
-        self.emit(&[0xC2]); // JP NZ, pf_init_done
-        self.fixup("pf_init_done");
-        // Set FUNC_BCD to 99 99 99 99 (max BCD value)
-        self.emit(&[0x21]); // LD HL, FUNC_BCD
-        self.emit_word(FUNC_BCD);
-        self.emit(&[0x3E, 0x99]); // LD A, 0x99
-        self.emit(&[0x77]); // LD (HL), A
-        self.inc_hl();
-        self.emit(&[0x77]); // LD (HL), A
+        // Parse opening paren
         self.inc_hl();
-        self.emit(&[0x77]); // LD (HL), A
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'(']);
+        self.emit(&[0xC2]); // JP NZ, pf_error
+        self.fixup("pf_error");
+
+        // Next argument (HL at the '(' or ',' before it)
+        self.label("pf_agg_arg");
         self.inc_hl();
-        self.emit(&[0x77]); // LD (HL), A
-        self.label("pf_init_done");
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, TOK_REF]); // CP TOK_REF
+        self.emit(&[0xC2]); // JP NZ, pf_agg_number
+        self.fixup("pf_agg_number");
+
+        self.emit(&[0xCD]); // CALL pf_range (a single cell is a 1x1 range)
+        self.fixup("pf_range");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
 
         // Initialize current column = col1
         self.emit(&[0x3A]); // LD A, (TEMP1) (col1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.emit(&[0x32]); // LD (RANGE_CUR_COL), A
-        self.emit_word(RANGE_CUR_COL);
+        self.emit_word(layout.var(RANGE_CUR_COL));
 
         // Outer loop: columns
         self.label("pf_col_loop");
         // C = row1 (reset for each column)
         self.emit(&[0x3A]); // LD A, (TEMP1+1) (row1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.ld_c_a();
 
-        // Inner loop: rows
-        self.label("pf_row_loop");
-        // Get cell value at (current_col, C)
-        self.emit(&[0x3A]); // LD A, (RANGE_CUR_COL)
-        self.emit_word(RANGE_CUR_COL);
-        self.ld_b_a(); // col
-        self.push_bc(); // save row counter (C) and col (B)
-        self.emit(&[0xCD]); // CALL get_cell_addr
-        self.fixup("get_cell_addr");
-        // HL = cell addr
+        // Inner loop: rows
+        self.label("pf_row_loop");
+        // Get cell value at (current_col, C)
+        self.emit(&[0x3A]); // LD A, (RANGE_CUR_COL)
+        self.emit_word(layout.var(RANGE_CUR_COL));
+        self.ld_b_a(); // col
+        self.push_bc(); // save row counter (C) and col (B)
+        self.emit(&[0xCD]); // CALL get_cell_addr
+        self.fixup("get_cell_addr");
+        self.emit(&[0xCD]); // CALL pf_cell
+        self.fixup("pf_cell");
+        self.pop_bc(); // restore row counter
+        self.emit(&[0xD8]); // RET C (the running total overflowed)
+
+        // Increment row first, then check if done with column (C > row2)
+        self.inc_c();
+        self.ld_a_c(); // current row (after increment)
+        self.ld_b_a(); // save in B
+        self.emit(&[0x3A]); // LD A, (RANGE_ROW2)
+        self.emit_word(layout.var(RANGE_ROW2));
+        self.emit(&[0xB8]); // CP B
+        self.emit(&[0xD2]); // JP NC, pf_row_loop (current <= row2)
+        self.fixup("pf_row_loop");
+
+        // Move to next column
+        // Increment column first, then check if done (current_col > col2)
+        self.emit(&[0x3A]); // LD A, (RANGE_CUR_COL)
+        self.emit_word(layout.var(RANGE_CUR_COL));
+        self.inc_a();
+        self.emit(&[0x32]); // LD (RANGE_CUR_COL), A
+        self.emit_word(layout.var(RANGE_CUR_COL));
+        self.ld_b_a(); // save incremented value in B
+        self.emit(&[0x3A]); // LD A, (RANGE_COL2)
+        self.emit_word(layout.var(RANGE_COL2));
+        self.emit(&[0xB8]); // CP B
+        self.emit(&[0xD2]); // JP NC, pf_col_loop (current <= col2)
+        self.fixup("pf_col_loop");
+
+        // ',' takes another argument, ')' ends the list
+        self.label("pf_agg_next");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b',']);
+        self.emit(&[0xCA]); // JP Z, pf_agg_arg
+        self.fixup("pf_agg_arg");
+        self.emit(&[0xFE, b')']);
+        self.emit(&[0xC2]); // JP NZ, pf_error
+        self.fixup("pf_error");
+        self.inc_hl();
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xC3]); // JP pf_done
+        self.fixup("pf_done");
+
+        // Literal number: optional '-', then a number that starts with a
+        // digit is required
+        self.label("pf_agg_number");
+        self.emit(&[0x06, 0x00]); // LD B, 0 (positive)
+        self.emit(&[0xFE, b'-']);
+        self.emit(&[0x20]); // JR NZ, pf_agg_digit
+        self.emit_relative("pf_agg_digit");
+        self.emit(&[0x06, 0x80]); // LD B, 0x80 (negative)
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.label("pf_agg_digit");
+        self.emit(&[0xFE, TOK_OVF]); // CP TOK_OVF (too many digits)
+        self.emit(&[0xCA]); // JP Z, eval_overflow
+        self.fixup("eval_overflow");
+        self.emit(&[0xFE, TOK_NUM]); // CP TOK_NUM
+        self.emit(&[0xC2]); // JP NZ, pf_error
+        self.fixup("pf_error");
+        self.ld_a_b();
+        self.emit(&[0x32]); // LD (FUNC_SIGN2), A
+        self.emit_word(layout.var(FUNC_SIGN2));
+        self.inc_hl(); // BCD
+        self.push_hl();
+        self.emit(&[0x11]); // LD DE, 4
+        self.emit_word(4);
+        self.add_hl_de();
+        self.emit(&[0x22]); // LD (TEMP2), HL (past the number)
+        self.emit_word(layout.var(TEMP2));
+        self.pop_hl();
+        self.emit(&[0xCD]); // CALL pf_accum
+        self.fixup("pf_accum");
+        self.emit(&[0xD8]); // RET C (the running total overflowed)
+        self.emit(&[0x18]); // JR pf_agg_next
+        self.emit_relative("pf_agg_next");
+
+        // Error cell: drop our return address and the range loop's BC,
+        // and fail the function with the cell's code
+        self.label("pf_cell_error");
+        self.pop_bc();
+        self.pop_bc();
+        self.emit(&[0xC3]); // JP cell_fail
+        self.fixup("cell_fail");
+
+        // Add the cell at HL to the accumulators if it holds a number or
+        // formula; an error cell fails the function. Carry set (via
+        // eval_overflow) if the running total overflows
+        self.label("pf_cell");
         self.ld_a_hl_ind(); // type
         self.emit(&[0xFE, CELL_NUMBER]); // CP CELL_NUMBER
         self.emit(&[0xCA]); // JP Z, pf_is_number
         self.fixup("pf_is_number");
-        self.emit(&[0xFE, CELL_FORMULA]); // CP CELL_FORMULA
-        self.emit(&[0xCA]); // JP Z, pf_is_formula
-        self.fixup("pf_is_formula");
-        // Not a number or formula - skip
-        self.emit(&[0xC3]); // JP pf_skip
-        self.fixup("pf_skip");
+        self.emit(&[0xFE, CELL_ERROR]); // CP CELL_ERROR
+        self.emit(&[0x28]); // JR Z, pf_cell_error
+        self.emit_relative("pf_cell_error");
+        self.emit(&[0xEE, CELL_FORMULA]); // XOR CELL_FORMULA (clears carry)
+        self.emit(&[0xC0]); // RET NZ (not a number or formula - skip)
 
-        // Handle formula cell - get BCD value from formula storage
-        self.label("pf_is_formula");
+        // Handle formula cell - its value is stored before the text
         self.inc_hl();
         self.inc_hl();
         self.emit(&[0x5E]); // LD E, (HL) - get formula pointer low
         self.inc_hl();
         self.emit(&[0x56]); // LD D, (HL) - get formula pointer high
-        self.ex_de_hl(); // HL = formula pointer
-        // Scan to end of formula string
-        self.label("pf_scan_formula");
-        self.ld_a_hl_ind();
-        self.inc_hl();
-        self.or_a_a();
-        self.emit(&[0xC2]); // JP NZ, pf_scan_formula
-        self.fixup("pf_scan_formula");
-        // HL now points to sign byte after null terminator
-        self.ld_a_hl_ind(); // read sign
-        self.emit(&[0x32]); // LD (FUNC_SIGN2), A
-        self.emit_word(FUNC_SIGN2);
-        self.inc_hl(); // HL now points to BCD value
-        self.emit(&[0xC3]); // JP pf_read_bcd
-        self.fixup("pf_read_bcd");
+        self.emit(&[0x21]); // LD HL, -(FORMULA_VALUE + 1)
+        self.emit_word((FORMULA_VALUE + 1).wrapping_neg());
+        self.add_hl_de(); // one before the sign (so the number path below fits)
 
         // Handle number cell - BCD is at bytes 2-5
         self.label("pf_is_number");
         self.inc_hl(); // skip type
         self.ld_a_hl_ind(); // read sign byte
         self.emit(&[0x32]); // LD (FUNC_SIGN2), A
-        self.emit_word(FUNC_SIGN2);
+        self.emit_word(layout.var(FUNC_SIGN2));
         self.inc_hl(); // HL now points to BCD data
 
-        // Common code to read BCD value (HL points to BCD data)
-        self.label("pf_read_bcd");
+        // Accumulate the BCD value at HL, signed FUNC_SIGN2
+        self.label("pf_accum");
         // Found a value - increment count
         self.push_hl(); // save BCD addr
         self.emit(&[0x2A]); // LD HL, (FUNC_COUNT)
-        self.emit_word(FUNC_COUNT);
+        self.emit_word(layout.var(FUNC_COUNT));
         self.inc_hl();
         self.emit(&[0x22]); // LD (FUNC_COUNT), HL
-        self.emit_word(FUNC_COUNT);
+        self.emit_word(layout.var(FUNC_COUNT));
         self.pop_hl(); // restore BCD addr
 
         // Copy 4-byte BCD to FUNC_BCD2
         self.emit(&[0x11]); // LD DE, FUNC_BCD2
-        self.emit_word(FUNC_BCD2);
+        self.emit_word(layout.var(FUNC_BCD2));
         self.emit(&[0x06, 4]); // LD B, 4
         self.label("pf_copy_bcd");
         self.ld_a_hl_ind();
@@ -3479,163 +5158,109 @@ impl SpreadsheetCodeGen {
         self.emit_relative("pf_copy_bcd");
         // FUNC_BCD2 now has the cell's BCD value
 
-        // Check function type for SUM/AVG vs MIN/MAX
         self.emit(&[0x3A]); // LD A, (FUNC_TYPE)
-        self.emit_word(FUNC_TYPE);
-        self.emit(&[0xFE, 0x02]); // CP 2 (MIN)
-        self.emit(&[0xCA]); // JP Z, pf_do_min
-        self.fixup("pf_do_min");
-        self.emit(&[0xFE, 0x03]); // CP 3 (MAX)
-        self.emit(&[0xCA]); // JP Z, pf_do_max
-        self.fixup("pf_do_max");
-
-        // SUM/AVG/COUNT: signed add FUNC_BCD2 to FUNC_BCD
-        // Set up for eval_add: FUNC_BCD → BCD_TEMP2, FUNC_BCD2 → BCD_TEMP1
-        self.pop_bc(); // restore row counter
-        self.push_bc(); // save it again for after eval_add
+        self.emit_word(layout.var(FUNC_TYPE));
+        self.emit(&[0xFE, 0x04]); // CP 4 (COUNT)
+        self.emit(&[0xC8]); // RET Z (only the count matters)
 
+        // Set up for signed_add and eval_order: FUNC_BCD → BCD_TEMP2,
+        // FUNC_BCD2 → BCD_TEMP1
         // Copy FUNC_BCD to BCD_TEMP2 (accumulator to temp)
         // bcd_copy copies from (DE) to (HL)
         self.emit(&[0x21]); // LD HL, BCD_TEMP2 (dest)
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0x11]); // LD DE, FUNC_BCD (src)
-        self.emit_word(FUNC_BCD);
+        self.emit_word(layout.var(FUNC_BCD));
         self.emit(&[0xCD]); // CALL bcd_copy
         self.fixup("bcd_copy");
 
         // Copy FUNC_BCD2 to BCD_TEMP1 (operand to temp)
         self.emit(&[0x21]); // LD HL, BCD_TEMP1 (dest)
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, FUNC_BCD2 (src)
-        self.emit_word(FUNC_BCD2);
+        self.emit_word(layout.var(FUNC_BCD2));
         self.emit(&[0xCD]); // CALL bcd_copy
         self.fixup("bcd_copy");
 
         // Copy signs: FUNC_SIGN → SIGN_ACCUM, FUNC_SIGN2 → SIGN_OP
         self.emit(&[0x3A]); // LD A, (FUNC_SIGN)
-        self.emit_word(FUNC_SIGN);
+        self.emit_word(layout.var(FUNC_SIGN));
         self.emit(&[0x32]); // LD (SIGN_ACCUM), A
-        self.emit_word(SIGN_ACCUM);
+        self.emit_word(layout.var(SIGN_ACCUM));
         self.emit(&[0x3A]); // LD A, (FUNC_SIGN2)
-        self.emit_word(FUNC_SIGN2);
+        self.emit_word(layout.var(FUNC_SIGN2));
         self.emit(&[0x32]); // LD (SIGN_OP), A
-        self.emit_word(SIGN_OP);
+        self.emit_word(layout.var(SIGN_OP));
+        self.emit(&[0x3A]); // LD A, (FUNC_TYPE)
+        self.emit_word(layout.var(FUNC_TYPE));
+        self.emit(&[0xFE, 0x02]); // CP 2
+        self.emit(&[0x30]); // JR NC, pf_do_minmax (MIN or MAX)
+        self.emit_relative("pf_do_minmax");
 
-        // Call signed addition (result in BCD_TEMP1, sign in SIGN_ACCUM)
+        // SUM/AVG: signed addition (result in BCD_TEMP1, sign in SIGN_ACCUM)
         self.emit(&[0xCD]); // CALL signed_add
         self.fixup("signed_add");
+        self.emit(&[0xDC]); // CALL C, eval_overflow (returns with carry kept)
+        self.fixup("eval_overflow");
 
         // Copy result back: BCD_TEMP1 → FUNC_BCD, SIGN_ACCUM → FUNC_SIGN
         // bcd_copy copies from (DE) to (HL)
         self.emit(&[0x21]); // LD HL, FUNC_BCD (dest)
-        self.emit_word(FUNC_BCD);
+        self.emit_word(layout.var(FUNC_BCD));
         self.emit(&[0x11]); // LD DE, BCD_TEMP1 (src)
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0xCD]); // CALL bcd_copy
         self.fixup("bcd_copy");
         self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
-        self.emit_word(SIGN_ACCUM);
-        self.emit(&[0x32]); // LD (FUNC_SIGN), A
-        self.emit_word(FUNC_SIGN);
-
-        self.pop_bc(); // restore row counter
-        self.emit(&[0xC3]); // JP pf_next
-        self.fixup("pf_next");
-
-        // MIN: if FUNC_BCD2 < FUNC_BCD, update FUNC_BCD
-        self.label("pf_do_min");
-        self.pop_bc(); // restore row counter
-        // bcd_cmp returns C if (DE) < (HL), so check if FUNC_BCD2 < FUNC_BCD
-        self.emit(&[0x21]); // LD HL, FUNC_BCD
-        self.emit_word(FUNC_BCD);
-        self.emit(&[0x11]); // LD DE, FUNC_BCD2
-        self.emit_word(FUNC_BCD2);
-        self.emit(&[0xCD]); // CALL bcd_cmp
-        self.fixup("bcd_cmp");
-        self.emit(&[0xD2]); // JP NC, pf_next (FUNC_BCD2 >= FUNC_BCD, don't update)
-        self.fixup("pf_next");
-        // FUNC_BCD2 < FUNC_BCD, copy FUNC_BCD2 to FUNC_BCD and sign
-        self.emit(&[0x21]); // LD HL, FUNC_BCD
-        self.emit_word(FUNC_BCD);
-        self.emit(&[0x11]); // LD DE, FUNC_BCD2
-        self.emit_word(FUNC_BCD2);
-        self.emit(&[0xCD]); // CALL bcd_copy
-        self.fixup("bcd_copy");
-        // Copy sign too
-        self.emit(&[0x3A]); // LD A, (FUNC_SIGN2)
-        self.emit_word(FUNC_SIGN2);
+        self.emit_word(layout.var(SIGN_ACCUM));
         self.emit(&[0x32]); // LD (FUNC_SIGN), A
-        self.emit_word(FUNC_SIGN);
-        self.emit(&[0xC3]); // JP pf_next
-        self.fixup("pf_next");
+        self.emit_word(layout.var(FUNC_SIGN));
+        self.ret();
 
-        // MAX: if FUNC_BCD2 > FUNC_BCD, update FUNC_BCD
-        self.label("pf_do_max");
-        self.pop_bc(); // restore row counter
-        // bcd_cmp returns C if (DE) < (HL), so check if FUNC_BCD < FUNC_BCD2 (i.e., FUNC_BCD2 > FUNC_BCD)
-        self.emit(&[0x21]); // LD HL, FUNC_BCD2
-        self.emit_word(FUNC_BCD2);
-        self.emit(&[0x11]); // LD DE, FUNC_BCD
-        self.emit_word(FUNC_BCD);
-        self.emit(&[0xCD]); // CALL bcd_cmp
-        self.fixup("bcd_cmp");
-        self.emit(&[0xD2]); // JP NC, pf_next (FUNC_BCD >= FUNC_BCD2, don't update)
-        self.fixup("pf_next");
-        // FUNC_BCD < FUNC_BCD2, so FUNC_BCD2 is larger - copy FUNC_BCD2 to FUNC_BCD and sign
+        // MIN/MAX: the first value is taken as it is; after that the
+        // signed order of the two decides, as in the comparison operators
+        self.label("pf_do_minmax");
+        self.emit(&[0x2A]); // LD HL, (FUNC_COUNT)
+        self.emit_word(layout.var(FUNC_COUNT));
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x7C]); // LD A, H
+        self.or_l();
+        self.emit(&[0x28]); // JR Z, pf_take_value
+        self.emit_relative("pf_take_value");
+        self.emit(&[0xCD]); // CALL eval_order (FUNC_BCD against FUNC_BCD2)
+        self.fixup("eval_order");
+        self.ld_b_a();
+        // MIN takes the new value if the old one is greater (4), MAX if
+        // it is less (1)
+        self.emit(&[0x3A]); // LD A, (FUNC_TYPE)
+        self.emit_word(layout.var(FUNC_TYPE));
+        self.emit(&[0xD6, 0x02]); // SUB 2 (0 = MIN, 1 = MAX)
+        self.emit(&[0x20]); // JR NZ, pf_minmax_mask
+        self.emit_relative("pf_minmax_mask");
+        self.emit(&[0x3E, 0x04]); // LD A, 4
+        self.label("pf_minmax_mask");
+        self.emit(&[0xA0]); // AND B (clears carry)
+        self.ret_z();
+        // FUNC_BCD2 wins - copy it to FUNC_BCD with its sign
+        self.label("pf_take_value");
         self.emit(&[0x21]); // LD HL, FUNC_BCD
-        self.emit_word(FUNC_BCD);
+        self.emit_word(layout.var(FUNC_BCD));
         self.emit(&[0x11]); // LD DE, FUNC_BCD2
-        self.emit_word(FUNC_BCD2);
+        self.emit_word(layout.var(FUNC_BCD2));
         self.emit(&[0xCD]); // CALL bcd_copy
         self.fixup("bcd_copy");
         // Copy sign too
         self.emit(&[0x3A]); // LD A, (FUNC_SIGN2)
-        self.emit_word(FUNC_SIGN2);
+        self.emit_word(layout.var(FUNC_SIGN2));
         self.emit(&[0x32]); // LD (FUNC_SIGN), A
-        self.emit_word(FUNC_SIGN);
-        self.emit(&[0xC3]); // JP pf_next (skip pf_skip to avoid double BC pop)
-        self.fixup("pf_next");
-
-        self.label("pf_skip");
-        // Not a number - skip (just restore BC)
-        self.pop_bc();
-
-        self.label("pf_next");
-        // Increment row first, then check if done with column (C > row2)
-        self.inc_c();
-        self.ld_a_c(); // current row (after increment)
-        self.ld_b_a(); // save in B
-        self.emit(&[0x3A]); // LD A, (RANGE_ROW2)
-        self.emit_word(RANGE_ROW2);
-        self.emit(&[0xB8]); // CP B
-        self.emit(&[0xDA]); // JP C, pf_next_col (row2 < current = done with this column)
-        self.fixup("pf_next_col");
-        self.emit(&[0xC3]); // JP pf_row_loop
-        self.fixup("pf_row_loop");
-
-        // Move to next column
-        self.label("pf_next_col");
-        // Increment column first, then check if done (current_col > col2)
-        self.emit(&[0x3A]); // LD A, (RANGE_CUR_COL)
-        self.emit_word(RANGE_CUR_COL);
-        self.inc_a();
-        self.emit(&[0x32]); // LD (RANGE_CUR_COL), A
-        self.emit_word(RANGE_CUR_COL);
-        self.ld_b_a(); // save incremented value in B
-        self.emit(&[0x3A]); // LD A, (RANGE_COL2)
-        self.emit_word(RANGE_COL2);
-        self.emit(&[0xB8]); // CP B
-        self.emit(&[0xDA]); // JP C, pf_done (col2 < current = done)
-        self.fixup("pf_done");
-        // Continue to next column (already incremented above)
-        self.emit(&[0xC3]); // JP pf_col_loop
-        self.fixup("pf_col_loop");
-
+        self.emit_word(layout.var(FUNC_SIGN));
+        self.or_a_a(); // clear carry (bcd_cmp's survives the copy)
+        self.ret();
         // Return result based on function type
         // Result must go in BCD_TEMP1 for consistency with parse_operand
         self.label("pf_done");
         self.emit(&[0x3A]); // LD A, (FUNC_TYPE)
-        self.emit_word(FUNC_TYPE);
+        self.emit_word(layout.var(FUNC_TYPE));
 
         // SUM (0): copy FUNC_BCD to BCD_TEMP1, FUNC_SIGN to TEMP1 (for eval_expr)
         self.or_a_a();
@@ -3643,16 +5268,16 @@ impl SpreadsheetCodeGen {
         self.fixup("pf_not_sum");
         // bcd_copy copies from (DE) to (HL)
         self.emit(&[0x21]); // LD HL, BCD_TEMP1 (dest)
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, FUNC_BCD (src)
-        self.emit_word(FUNC_BCD);
+        self.emit_word(layout.var(FUNC_BCD));
         self.emit(&[0xCD]); // CALL bcd_copy
         self.fixup("bcd_copy");
         // Copy sign to TEMP1 (where eval_expr expects it)
         self.emit(&[0x3A]); // LD A, (FUNC_SIGN)
-        self.emit_word(FUNC_SIGN);
+        self.emit_word(layout.var(FUNC_SIGN));
         self.emit(&[0x32]); // LD (TEMP1), A
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.or_a_a(); // clear carry
         self.ret();
 
@@ -3663,14 +5288,14 @@ impl SpreadsheetCodeGen {
         self.fixup("pf_not_avg");
         // Copy FUNC_BCD to BCD_TEMP1 (dividend)
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0x11]); // LD DE, FUNC_BCD
-        self.emit_word(FUNC_BCD);
+        self.emit_word(layout.var(FUNC_BCD));
         self.emit(&[0xCD]); // CALL bcd_copy
         self.fixup("bcd_copy");
         // Convert count to BCD in BCD_TEMP2
         self.emit(&[0x2A]); // LD HL, (FUNC_COUNT)
-        self.emit_word(FUNC_COUNT);
+        self.emit_word(layout.var(FUNC_COUNT));
         // Check for divide by zero
         self.emit(&[0x7C]); // LD A, H
         self.emit(&[0xB5]); // OR L
@@ -3678,12 +5303,12 @@ impl SpreadsheetCodeGen {
         self.fixup("pf_avg_div");
         // Division by zero - zero the result (positive)
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
+        self.emit_word(layout.var(BCD_TEMP1));
         self.emit(&[0xCD]); // CALL bcd_zero
         self.fixup("bcd_zero");
         self.xor_a();
         self.emit(&[0x32]); // LD (TEMP1), A (positive)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.or_a_a();
         self.ret();
         self.label("pf_avg_div");
@@ -3712,12 +5337,12 @@ impl SpreadsheetCodeGen {
         // A = BCD of count, store in BCD_TEMP2 byte 3 (LSB)
         self.push_af(); // save BCD count
         self.emit(&[0x21]); // LD HL, BCD_TEMP2
-        self.emit_word(BCD_TEMP2);
+        self.emit_word(layout.var(BCD_TEMP2));
         self.emit(&[0xCD]); // CALL bcd_zero
         self.fixup("bcd_zero");
         self.pop_af();
         self.emit(&[0x21]); // LD HL, BCD_TEMP2+3 (LSB)
-        self.emit_word(BCD_TEMP2 + 3);
+        self.emit_word(layout.var(BCD_TEMP2) + 3);
         self.emit(&[0x77]); // LD (HL), A
         // BCD_TEMP2 = count as BCD (e.g., 3 -> 00 00 00 03)
         // Call bcd_div_noscale: BCD_TEMP1 / BCD_TEMP2 -> BCD_TEMP1 (no ×100)
@@ -3725,75 +5350,822 @@ impl SpreadsheetCodeGen {
         self.fixup("bcd_div_noscale");
         // Copy sign to TEMP1 (AVG sign = SUM sign since count is positive)
         self.emit(&[0x3A]); // LD A, (FUNC_SIGN)
-        self.emit_word(FUNC_SIGN);
-        self.emit(&[0x32]); // LD (TEMP1), A
-        self.emit_word(TEMP1);
-        self.or_a_a();
-        self.ret();
+        self.emit_word(layout.var(FUNC_SIGN));
+        self.emit(&[0x32]); // LD (TEMP1), A
+        self.emit_word(layout.var(TEMP1));
+        self.or_a_a();
+        self.ret();
+
+        // MIN (2) or MAX (3): copy FUNC_BCD to BCD_TEMP1
+        self.label("pf_not_avg");
+        self.emit(&[0xFE, 0x02]); // CP 2
+        self.emit(&[0xCA]); // JP Z, pf_ret_bcd
+        self.fixup("pf_ret_bcd");
+        self.emit(&[0xFE, 0x03]); // CP 3
+        self.emit(&[0xCA]); // JP Z, pf_ret_bcd
+        self.fixup("pf_ret_bcd");
+
+        // COUNT (4): convert count to BCD in BCD_TEMP1
+        self.emit(&[0x2A]); // LD HL, (FUNC_COUNT)
+        self.emit_word(layout.var(FUNC_COUNT));
+        // Convert to BCD (same as above, but put in byte 2 for display as X.00)
+        self.emit(&[0x7D]); // LD A, L
+        self.emit(&[0x06, 0x00]); // LD B, 0 (tens)
+        self.label("pf_cnt_cvt");
+        self.emit(&[0xFE, 10]); // CP 10
+        self.emit(&[0xDA]); // JP C, pf_cnt_done
+        self.fixup("pf_cnt_done");
+        self.emit(&[0xD6, 10]); // SUB 10
+        self.inc_b();
+        self.emit(&[0xC3]); // JP pf_cnt_cvt
+        self.fixup("pf_cnt_cvt");
+        self.label("pf_cnt_done");
+        self.emit(&[0x4F]); // LD C, A (ones)
+        self.ld_a_b();
+        self.emit(&[0x07]); // RLCA ×4
+        self.emit(&[0x07]);
+        self.emit(&[0x07]);
+        self.emit(&[0x07]);
+        self.emit(&[0xB1]); // OR C
+        // A = BCD of count, store as count.00
+        self.push_af();
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0xCD]); // CALL bcd_zero
+        self.fixup("bcd_zero");
+        self.pop_af();
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1+2
+        self.emit_word(layout.var(BCD_TEMP1) + 2);
+        self.emit(&[0x77]); // LD (HL), A
+        // COUNT is always positive
+        self.xor_a();
+        self.emit(&[0x32]); // LD (TEMP1), A
+        self.emit_word(layout.var(TEMP1));
+        self.or_a_a();
+        self.ret();
+
+        // pf_ret_bcd: copy FUNC_BCD to BCD_TEMP1 for MIN/MAX result
+        self.label("pf_ret_bcd");
+        // bcd_copy copies from (DE) to (HL)
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1 (dest)
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, FUNC_BCD (src)
+        self.emit_word(layout.var(FUNC_BCD));
+        self.emit(&[0xCD]); // CALL bcd_copy
+        self.fixup("bcd_copy");
+        // Copy sign to TEMP1 for MIN/MAX result
+        self.emit(&[0x3A]); // LD A, (FUNC_SIGN)
+        self.emit_word(layout.var(FUNC_SIGN));
+        self.emit(&[0x32]); // LD (TEMP1), A
+        self.emit_word(layout.var(TEMP1));
+        self.or_a_a();
+        self.ret();
+
+        // Logic functions take full expressions as arguments, each one a
+        // nesting level like a parenthesis. Results are 1.00 or 0.00, and
+        // any non-zero value counts as true. Arguments that cannot change
+        // the result are skipped, not evaluated.
+
+        // Check for '(' after the name at HL and open a nesting level
+        self.label("pf_open");
+        self.inc_hl();
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b'(']);
+        self.emit(&[0x37]); // SCF (flags Z untouched)
+        self.emit(&[0xC0]); // RET NZ
+        self.inc_hl();
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xC3]); // JP eval_enter
+        self.fixup("eval_enter");
+
+        // @IF(cond,a,b): evaluate cond, then only the branch it selects
+        self.label("pf_if");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x3E, b',']); // LD A, ','
+        self.emit(&[0xCD]); // CALL eval_expect
+        self.fixup("eval_expect");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_norm (Z if the condition is zero)
+        self.fixup("eval_norm");
+        self.emit(&[0x28]); // JR Z, pf_if_else
+        self.emit_relative("pf_if_else");
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x3E, b',']); // LD A, ','
+        self.emit(&[0xCD]); // CALL eval_expect
+        self.fixup("eval_expect");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_skip_arg
+        self.fixup("eval_skip_arg");
+        self.emit(&[0x18]); // JR pf_if_done
+        self.emit_relative("pf_if_done");
+        self.label("pf_if_else");
+        self.emit(&[0xCD]); // CALL eval_skip_arg
+        self.fixup("eval_skip_arg");
+        self.emit(&[0x3E, b',']); // LD A, ','
+        self.emit(&[0xCD]); // CALL eval_expect
+        self.fixup("eval_expect");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.label("pf_if_done");
+        // Shared ending: ')' and the result sign to TEMP1 for
+        // parse_operand's caller (eval_primary then normalises it)
+        self.label("pf_value_done");
+        self.emit(&[0xCD]); // CALL eval_close
+        self.fixup("eval_close");
+        self.emit(&[0xD8]); // RET C
+        // Sign to TEMP1 for parse_operand's caller
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x32]); // LD (TEMP1), A
+        self.emit_word(layout.var(TEMP1));
+        self.or_a_a();
+        self.ret();
+
+        // @NOT(x): 1 if x is zero
+        self.label("pf_not");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_close
+        self.fixup("eval_close");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_norm (Z if zero)
+        self.fixup("eval_norm");
+        self.emit(&[0x3E, 0x00]); // LD A, 0
+        self.emit(&[0x20]); // JR NZ, pf_not_done
+        self.emit_relative("pf_not_done");
+        self.inc_a();
+        self.label("pf_not_done");
+        self.emit(&[0xC3]); // JP eval_bool
+        self.fixup("eval_bool");
+
+        // @AND(a,b,...) stops at the first false argument and
+        // @OR(a,b,...) at the first true one. A = the truth value that
+        // stops (0x00 for AND, 0xFF for OR), kept on the stack
+        self.label("pf_and");
+        self.xor_a();
+        self.emit(&[0x18]); // JR pf_logic
+        self.emit_relative("pf_logic");
+        self.label("pf_or");
+        self.emit(&[0x3E, 0xFF]); // LD A, 0xFF
+        self.label("pf_logic");
+        self.push_af();
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0x38]); // JR C, pf_logic_error
+        self.emit_relative("pf_logic_error");
+        self.label("pf_logic_arg");
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0x38]); // JR C, pf_logic_error
+        self.emit_relative("pf_logic_error");
+        self.emit(&[0xCD]); // CALL eval_norm (Z if false)
+        self.fixup("eval_norm");
+        self.emit(&[0x3E, 0x00]); // LD A, 0
+        self.emit(&[0x28, 0x01]); // JR Z, +1
+        self.dec_a(); // A = 0xFF (true)
+        self.pop_bc();
+        self.push_bc(); // B = stopping value
+        self.emit(&[0xB8]); // CP B
+        self.emit(&[0x28]); // JR Z, pf_logic_stop
+        self.emit_relative("pf_logic_stop");
+        self.emit(&[0x3E, b',']); // LD A, ','
+        self.emit(&[0xCD]); // CALL eval_expect
+        self.fixup("eval_expect");
+        self.emit(&[0x30]); // JR NC, pf_logic_arg
+        self.emit_relative("pf_logic_arg");
+        // Every argument evaluated: the result is the opposite of the stop
+        self.emit(&[0xCD]); // CALL eval_close
+        self.fixup("eval_close");
+        self.pop_bc();
+        self.emit(&[0xD8]); // RET C
+        self.ld_a_b();
+        self.emit(&[0x2F]); // CPL
+        self.emit(&[0xC3]); // JP eval_bool
+        self.fixup("eval_bool");
+        // Decided early: skip the remaining arguments
+        self.label("pf_logic_stop");
+        self.emit(&[0xCD]); // CALL pf_skip_rest
+        self.fixup("pf_skip_rest");
+        self.emit(&[0xCD]); // CALL eval_close
+        self.fixup("eval_close");
+        self.pop_bc();
+        self.emit(&[0xD8]); // RET C
+        self.ld_a_b();
+        self.emit(&[0xC3]); // JP eval_bool
+        self.fixup("eval_bool");
+        self.label("pf_logic_error");
+        self.pop_bc();
+        self.emit(&[0x37]); // SCF
+        self.ret();
+
+        // @NA: not available, for cells still to be filled in
+        self.label("pf_na");
+        self.emit(&[0x3E, ERR_NA]); // LD A, ERR_NA
+        self.emit(&[0xC3]); // JP eval_fail
+        self.fixup("eval_fail");
+
+        // @ISERROR(x): 1 if x fails, else 0. After a failure the depth and
+        // error code are reset and parsing resumes from x's start, skipping
+        // over it
+        self.label("pf_iserror");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x2A]); // LD HL, (TEMP2) (start of x)
+        self.emit_word(layout.var(TEMP2));
+        self.push_hl();
+        self.emit(&[0x3A]); // LD A, (EVAL_DEPTH)
+        self.emit_word(layout.var(EVAL_DEPTH));
+        self.push_af();
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.pop_bc(); // B = depth
+        self.pop_hl(); // start of x
+        self.emit(&[0x3E, 0x00]); // LD A, 0 (false, carry kept)
+        self.emit(&[0x30]); // JR NC, pf_iserror_done
+        self.emit_relative("pf_iserror_done");
+        // A cell still pending recalculation is not an error to trap
+        self.emit(&[0x3A]); // LD A, (EVAL_ERR)
+        self.emit_word(layout.var(EVAL_ERR));
+        self.emit(&[0xFE, ERR_PENDING]); // CP ERR_PENDING
+        self.emit(&[0x37]); // SCF
+        self.ret_z();
+        self.ld_a_b();
+        self.emit(&[0x32]); // LD (EVAL_DEPTH), A
+        self.emit_word(layout.var(EVAL_DEPTH));
+        self.emit(&[0x3E, ERR_SYNTAX]); // LD A, ERR_SYNTAX
+        self.emit(&[0x32]); // LD (EVAL_ERR), A
+        self.emit_word(layout.var(EVAL_ERR));
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xCD]); // CALL eval_skip_arg
+        self.fixup("eval_skip_arg");
+        self.emit(&[0x3E, 0x01]); // LD A, 1 (true)
+        self.label("pf_iserror_done");
+        self.emit(&[0xCD]); // CALL eval_bool
+        self.fixup("eval_bool");
+        self.emit(&[0xC3]); // JP pf_value_done
+        self.fixup("pf_value_done");
+
+        // Skip any remaining ",arg"s up to the closing ')'
+        self.label("pf_skip_rest");
+        self.emit(&[0x2A]); // LD HL, (TEMP2)
+        self.emit_word(layout.var(TEMP2));
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b',']);
+        self.emit(&[0xC0]); // RET NZ
+        self.inc_hl();
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xCD]); // CALL eval_skip_arg
+        self.fixup("eval_skip_arg");
+        self.emit(&[0x18]); // JR pf_skip_rest
+        self.emit_relative("pf_skip_rest");
+
+        // ',' and the next argument: carry set if either is missing
+        self.label("pf_next_arg");
+        self.emit(&[0x3E, b',']); // LD A, ','
+        self.emit(&[0xCD]); // CALL eval_expect
+        self.fixup("eval_expect");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xC3]); // JP eval_compare
+        self.fixup("eval_compare");
+
+        // Two-argument functions: x is evaluated and saved, then y.
+        // Returns with x in BCD_TEMP2/SIGN_ACCUM, y in BCD_TEMP1/SIGN_OP
+        self.label("pf_two_args");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_push
+        self.fixup("eval_push");
+        self.emit(&[0xCD]); // CALL pf_next_arg
+        self.fixup("pf_next_arg");
+        // x is under our return address, so no tail jump here
+        self.emit(&[0xCD]); // CALL eval_pop (keeps carry)
+        self.fixup("eval_pop");
+        self.ret();
+
+        // @ABS(x)
+        self.label("pf_abs");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.xor_a();
+        self.emit(&[0x32]); // LD (SIGN_ACCUM), A
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0xC3]); // JP pf_value_done
+        self.fixup("pf_value_done");
+
+        // @INT(x): truncate toward zero by clearing the two decimals
+        self.label("pf_int");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.xor_a();
+        self.emit(&[0x32]); // LD (BCD_TEMP1+3), A
+        self.emit_word(layout.var(BCD_TEMP1) + 3);
+        self.emit(&[0xC3]); // JP pf_value_done
+        self.fixup("pf_value_done");
+
+        // @MOD(x,y): remainder of |x| / |y| with the sign of x, straight
+        // from the long division's remainder so it is exact
+        self.label("pf_mod");
+        self.emit(&[0xCD]); // CALL pf_two_args
+        self.fixup("pf_two_args");
+        self.emit(&[0xD8]); // RET C
+        // bcd_div divides BCD_TEMP1 by BCD_TEMP2: swap x and y
+        self.emit(&[0x21]); // LD HL, FUNC_BCD
+        self.emit_word(layout.var(FUNC_BCD));
+        self.emit(&[0x11]); // LD DE, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0xCD]); // CALL bcd_copy (FUNC_BCD = y)
+        self.fixup("bcd_copy");
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0xCD]); // CALL bcd_copy (TEMP1 = x)
+        self.fixup("bcd_copy");
+        self.emit(&[0x21]); // LD HL, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0x11]); // LD DE, FUNC_BCD
+        self.emit_word(layout.var(FUNC_BCD));
+        self.emit(&[0xCD]); // CALL bcd_copy (TEMP2 = y)
+        self.fixup("bcd_copy");
+        self.emit(&[0xCD]); // CALL bcd_div_noscale
+        self.fixup("bcd_div_noscale");
+        self.emit(&[0xDA]); // JP C, eval_fail (y = 0, A = ERR_DIV0)
+        self.fixup("eval_fail");
+        // Remainder is in BCD_ACCUM+1..+4
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, BCD_ACCUM+1
+        self.emit_word(layout.var(BCD_ACCUM) + 1);
+        self.emit(&[0xCD]); // CALL bcd_copy
+        self.fixup("bcd_copy");
+        self.emit(&[0xC3]); // JP pf_value_done (sign of x is in SIGN_ACCUM)
+        self.fixup("pf_value_done");
+
+        // @ROUND(x,n): n = 2..-5 decimals, halves away from zero. The
+        // 2-n digits below are shifted out, the top one of them rounds up
+        // what is left, and the result is shifted back
+        self.label("pf_round");
+        self.emit(&[0xCD]); // CALL pf_two_args
+        self.fixup("pf_two_args");
+        self.emit(&[0xD8]); // RET C
+        // n must be a whole number of at most 2 (5 if negative); its
+        // decimals are ignored
+        self.emit(&[0x2A]); // LD HL, (BCD_TEMP1) (top two bytes)
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x7C]); // LD A, H
+        self.emit(&[0xB5]); // OR L
+        self.emit(&[0x37]); // SCF
+        self.emit(&[0xC0]); // RET NZ (n too large)
+        self.emit(&[0x3A]); // LD A, (BCD_TEMP1+2) (units of n)
+        self.emit_word(layout.var(BCD_TEMP1) + 2);
+        self.ld_b_a();
+        self.emit(&[0x3A]); // LD A, (SIGN_OP)
+        self.emit_word(layout.var(SIGN_OP));
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, pf_round_neg
+        self.emit_relative("pf_round_neg");
+        self.emit(&[0x3E, 2]); // LD A, 2
+        self.emit(&[0x90]); // SUB B (digits to drop = 2 - n)
+        self.emit(&[0xD8]); // RET C (n > 2)
+        self.emit(&[0x18]); // JR pf_round_digits
+        self.emit_relative("pf_round_digits");
+        self.label("pf_round_neg");
+        self.ld_a_b();
+        self.emit(&[0xFE, 6]); // CP 6
+        self.emit(&[0x3F]); // CCF
+        self.emit(&[0xD8]); // RET C (n < -5)
+        self.emit(&[0xC6, 2]); // ADD A, 2 (digits to drop = 2 + |n|)
+        self.label("pf_round_digits");
+        self.ld_c_a(); // bcd_copy leaves C alone
+        // x back to BCD_TEMP1 (sign of x is in SIGN_ACCUM)
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0xCD]); // CALL bcd_copy
+        self.fixup("bcd_copy");
+        self.ld_a_c();
+        self.or_a_a();
+        self.emit(&[0xCA]); // JP Z, pf_value_done (n = 2)
+        self.fixup("pf_value_done");
+        self.ld_b_a();
+        self.push_bc();
+        self.label("pf_round_shr");
+        self.emit(&[0xCD]); // CALL bcd_shr
+        self.fixup("bcd_shr");
+        self.emit(&[0x10]); // DJNZ pf_round_shr
+        self.emit_relative("pf_round_shr");
+        self.emit(&[0xCD]); // CALL bcd_round (A = highest digit dropped)
+        self.fixup("bcd_round");
+        self.pop_bc();
+        self.label("pf_round_shl");
+        self.emit(&[0xCD]); // CALL bcd_shl
+        self.fixup("bcd_shl");
+        self.or_a_a(); // a digit shifted out means it rounded up past the top
+        self.emit(&[0xC2]); // JP NZ, eval_overflow
+        self.fixup("eval_overflow");
+        self.emit(&[0x10]); // DJNZ pf_round_shl
+        self.emit_relative("pf_round_shl");
+        self.emit(&[0xC3]); // JP pf_value_done
+        self.fixup("pf_value_done");
+
+        // Parse a range X1:Y2 at HL: col1/row1 to TEMP1/TEMP1+1, col2/row2
+        // to RANGE_COL2/RANGE_ROW2 (rows 0-based); a lone X1 is the range
+        // X1:X1. HL is left after the range; carry set (via pf_error) if it
+        // is malformed, or via eval_ref if a corner is outside the grid
+        self.label("pf_range");
+        self.emit(&[0xCD]); // CALL pf_corner
+        self.fixup("pf_corner");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xED, 0x43]); // LD (TEMP1), BC (col1, row1)
+        self.emit_word(layout.var(TEMP1));
+        // Check for : (without one the cell is a range by itself)
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, b':']);
+        self.emit(&[0x20]); // JR NZ, pf_range_end
+        self.emit_relative("pf_range_end");
+        self.inc_hl();
+        self.emit(&[0xCD]); // CALL pf_corner
+        self.fixup("pf_corner");
+        self.emit(&[0xD8]); // RET C
+        self.label("pf_range_end");
+        self.ld_a_c();
+        self.emit(&[0x32]); // LD (RANGE_COL2), A
+        self.emit_word(layout.var(RANGE_COL2));
+        self.ld_a_b();
+        self.emit(&[0x32]); // LD (RANGE_ROW2), A
+        self.emit_word(layout.var(RANGE_ROW2));
+        self.or_a_a(); // clear carry
+        self.ret();
+
+        // Corner of a range: the reference token at HL -> C = column and
+        // B = 0-based row, HL past it. Anything else, or a '$' before the
+        // column, fails through pf_error; a '$' before the row or a corner
+        // outside the grid through eval_ref
+        self.label("pf_corner");
+        self.ld_a_hl_ind();
+        self.emit(&[0xFE, TOK_REF]); // CP TOK_REF
+        self.emit(&[0xC2]); // JP NZ, pf_error
+        self.fixup("pf_error");
+        self.inc_hl();
+        self.emit(&[0x4E]); // LD C, (HL) (column and flags)
+        self.inc_hl();
+        self.emit(&[0x46]); // LD B, (HL) (row)
+        self.inc_hl();
+        self.emit(&[0xCB, 0x79]); // BIT 7, C (REF_DOLLAR)
+        self.emit(&[0xC2]); // JP NZ, pf_error
+        self.fixup("pf_error");
+        self.ld_a_c();
+        self.emit(&[0xFE, layout.cols()]); // CP cols (REF_ROW_DOLLAR is past it)
+        self.emit(&[0xD2]); // JP NC, eval_ref
+        self.fixup("eval_ref");
+        self.ld_a_b();
+        self.emit(&[0xFE, layout.rows()]); // CP rows
+        self.emit(&[0xD2]); // JP NC, eval_ref
+        self.fixup("eval_ref");
+        self.or_a_a(); // clear carry
+        self.ret();
+
+        // Table functions. Positions are 1-based whole numbers up to 99
+        // (decimals ignored); anything else is an error
+
+        // ',' and a position argument -> A (1-99); carry set on error
+        self.label("pf_position_arg");
+        self.emit(&[0xCD]); // CALL pf_next_arg
+        self.fixup("pf_next_arg");
+        self.emit(&[0xD8]); // RET C
+        // Position from BCD_TEMP1 (sign SIGN_ACCUM) -> A; clobbers B, C
+        self.label("pf_position");
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, pf_position_error (negative)
+        self.emit_relative("pf_position_error");
+        self.emit(&[0x2A]); // LD HL, (BCD_TEMP1) (top two bytes)
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x7C]); // LD A, H
+        self.emit(&[0xB5]); // OR L
+        self.emit(&[0x20]); // JR NZ, pf_position_error (over 99)
+        self.emit_relative("pf_position_error");
+        self.emit(&[0x3A]); // LD A, (BCD_TEMP1+2) (units)
+        self.emit_word(layout.var(BCD_TEMP1) + 2);
+        self.emit(&[0xCD]); // CALL bcd_to_bin
+        self.fixup("bcd_to_bin");
+        self.emit(&[0xC0]); // RET NZ (carry clear from ADD)
+        // Negative, over 99 or 0: a reference error
+        self.label("pf_position_error");
+        self.emit(&[0xC3]); // JP eval_ref
+        self.fixup("eval_ref");
+
+        // Packed BCD byte in A to binary: tens * 10 + ones; clobbers B, C
+        self.label("bcd_to_bin");
+        self.ld_c_a();
+        self.emit(&[0xE6, 0xF0]); // AND 0xF0
+        self.emit(&[0x0F]); // RRCA (tens * 8)
+        self.ld_b_a();
+        self.emit(&[0x0F]); // RRCA
+        self.emit(&[0x0F]); // RRCA (tens * 2)
+        self.emit(&[0x80]); // ADD A, B
+        self.ld_b_a();
+        self.ld_a_c();
+        self.emit(&[0xE6, 0x0F]); // AND 0x0F
+        self.emit(&[0x80]); // ADD A, B
+        self.ret();
+
+        // @CHOOSE(n,a,b,...): evaluate only the n-th of the arguments
+        self.label("pf_choose");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL pf_position
+        self.fixup("pf_position");
+        self.emit(&[0xD8]); // RET C
+        self.ld_b_a();
+        self.label("pf_choose_skip");
+        self.emit(&[0x3E, b',']); // LD A, ','
+        self.emit(&[0xCD]); // CALL eval_expect
+        self.fixup("eval_expect");
+        self.emit(&[0xDA]); // JP C, eval_ref (fewer than n choices)
+        self.fixup("eval_ref");
+        self.emit(&[0x05]); // DEC B
+        self.emit(&[0x28]); // JR Z, pf_choose_take
+        self.emit_relative("pf_choose_take");
+        self.emit(&[0xCD]); // CALL eval_skip_arg (keeps B)
+        self.fixup("eval_skip_arg");
+        self.emit(&[0x18]); // JR pf_choose_skip
+        self.emit_relative("pf_choose_skip");
+        self.label("pf_choose_take");
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL pf_skip_rest
+        self.fixup("pf_skip_rest");
+        self.emit(&[0xC3]); // JP pf_value_done
+        self.fixup("pf_value_done");
+
+        // @INDEX(range,row,col): the cell at that position in the range.
+        // The range bounds stay on the stack while the positions are
+        // evaluated, since those may use range functions themselves
+        self.label("pf_index");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL pf_range
+        self.fixup("pf_range");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0x2A]); // LD HL, (TEMP1) (L = col1, H = row1)
+        self.emit_word(layout.var(TEMP1));
+        self.push_hl();
+        self.emit(&[0x3A]); // LD A, (RANGE_COL2)
+        self.emit_word(layout.var(RANGE_COL2));
+        self.emit(&[0x6F]); // LD L, A
+        self.emit(&[0x3A]); // LD A, (RANGE_ROW2)
+        self.emit_word(layout.var(RANGE_ROW2));
+        self.emit(&[0x67]); // LD H, A
+        self.push_hl();
+        self.emit(&[0xCD]); // CALL pf_position_arg (row)
+        self.fixup("pf_position_arg");
+        self.emit(&[0x38]); // JR C, pf_index_error
+        self.emit_relative("pf_index_error");
+        self.push_af();
+        self.emit(&[0xCD]); // CALL pf_position_arg (col)
+        self.fixup("pf_position_arg");
+        self.pop_bc(); // B = row position (flags kept)
+        self.label("pf_index_error");
+        self.pop_hl(); // L = col2, H = row2
+        self.pop_de(); // E = col1, D = row1
+        self.emit(&[0xD8]); // RET C
+        // Column col1 + col - 1, at most col2
+        self.emit(&[0x83]); // ADD A, E
+        self.dec_a();
+        self.emit(&[0x5F]); // LD E, A
+        self.emit(&[0x7D]); // LD A, L
+        self.emit(&[0xBB]); // CP E
+        self.emit(&[0xDA]); // JP C, eval_ref (col2 < column)
+        self.fixup("eval_ref");
+        // Row row1 + row - 1, at most row2
+        self.ld_a_b();
+        self.emit(&[0x82]); // ADD A, D
+        self.dec_a();
+        self.ld_c_a();
+        self.emit(&[0x7C]); // LD A, H
+        self.emit(&[0xB9]); // CP C
+        self.emit(&[0xDA]); // JP C, eval_ref (row2 < row)
+        self.fixup("eval_ref");
+        self.emit(&[0x43]); // LD B, E
+        self.label("pf_table_cell");
+        self.emit(&[0xCD]); // CALL load_cell_value
+        self.fixup("load_cell_value");
+        self.emit(&[0xD8]); // RET C (error cell)
+        self.emit(&[0xC3]); // JP eval_close (keeps the sign in TEMP1)
+        self.fixup("eval_close");
+
+        // @LOOKUP(x,range): scan a one-column (or one-row) range while its
+        // entries are <= x and return the cell beside (or below) the last
+        // such entry. x stays in BCD_TEMP2, its sign in FUNC_SIGN; the
+        // candidate (row, col) is kept in FUNC_COUNT, col 0xFF if none
+        self.label("pf_lookup");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x21]); // LD HL, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0x11]); // LD DE, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0xCD]); // CALL bcd_copy
+        self.fixup("bcd_copy");
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x32]); // LD (FUNC_SIGN), A
+        self.emit_word(layout.var(FUNC_SIGN));
+        self.emit(&[0x3E, b',']); // LD A, ','
+        self.emit(&[0xCD]); // CALL eval_expect
+        self.fixup("eval_expect");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL pf_range
+        self.fixup("pf_range");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0x2A]); // LD HL, (TEMP1)
+        self.emit_word(layout.var(TEMP1));
+        self.emit(&[0x45]); // LD B, L (col1)
+        self.emit(&[0x4C]); // LD C, H (row1)
+        // D, E = column and row step: down a column, else along a row
+        self.emit(&[0x11, 0x01, 0x00]); // LD DE, 0x0001
+        self.emit(&[0x3A]); // LD A, (RANGE_COL2)
+        self.emit_word(layout.var(RANGE_COL2));
+        self.emit(&[0xB8]); // CP B
+        self.emit(&[0x28]); // JR Z, pf_lookup_col
+        self.emit_relative("pf_lookup_col");
+        self.emit(&[0xD8]); // RET C (col2 < col1)
+        self.emit(&[0x11, 0x00, 0x01]); // LD DE, 0x0100
+        self.emit(&[0x3A]); // LD A, (RANGE_ROW2)
+        self.emit_word(layout.var(RANGE_ROW2));
+        self.emit(&[0xB9]); // CP C
+        self.emit(&[0x37]); // SCF
+        self.emit(&[0xC0]); // RET NZ (neither one column nor one row)
+        self.label("pf_lookup_col");
+        self.emit(&[0x3A]); // LD A, (RANGE_ROW2)
+        self.emit_word(layout.var(RANGE_ROW2));
+        self.emit(&[0xB9]); // CP C
+        self.emit(&[0xD8]); // RET C (row2 < row1)
+        self.emit(&[0x3E, 0xFF]); // LD A, 0xFF
+        self.emit(&[0x32]); // LD (FUNC_COUNT+1), A (no candidate)
+        self.emit_word(layout.var(FUNC_COUNT) + 1);
+        self.label("pf_lookup_loop");
+        self.push_de();
+        self.push_bc();
+        self.emit(&[0xCD]); // CALL load_cell_value
+        self.fixup("load_cell_value");
+        self.emit(&[0x38]); // JR C, pf_lookup_error
+        self.emit_relative("pf_lookup_error");
+        self.emit(&[0x3A]); // LD A, (TEMP1)
+        self.emit_word(layout.var(TEMP1));
+        self.emit(&[0x32]); // LD (SIGN_OP), A
+        self.emit_word(layout.var(SIGN_OP));
+        self.emit(&[0x3A]); // LD A, (FUNC_SIGN)
+        self.emit_word(layout.var(FUNC_SIGN));
+        self.emit(&[0x32]); // LD (SIGN_ACCUM), A
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x3E, 0x01]); // LD A, 1 (less)
+        self.emit(&[0xCD]); // CALL eval_cmp (NZ if x < entry)
+        self.fixup("eval_cmp");
+        self.pop_bc();
+        self.pop_de();
+        self.emit(&[0x20]); // JR NZ, pf_lookup_done
+        self.emit_relative("pf_lookup_done");
+        self.emit(&[0xED, 0x43]); // LD (FUNC_COUNT), BC
+        self.emit_word(layout.var(FUNC_COUNT));
+        self.emit(&[0x3A]); // LD A, (RANGE_COL2)
+        self.emit_word(layout.var(RANGE_COL2));
+        self.emit(&[0xB8]); // CP B
+        self.emit(&[0x20]); // JR NZ, pf_lookup_next
+        self.emit_relative("pf_lookup_next");
+        self.emit(&[0x3A]); // LD A, (RANGE_ROW2)
+        self.emit_word(layout.var(RANGE_ROW2));
+        self.emit(&[0xB9]); // CP C
+        self.emit(&[0x28]); // JR Z, pf_lookup_done
+        self.emit_relative("pf_lookup_done");
+        self.label("pf_lookup_next");
+        self.ld_a_b();
+        self.emit(&[0x82]); // ADD A, D
+        self.ld_b_a();
+        self.ld_a_c();
+        self.emit(&[0x83]); // ADD A, E
+        self.ld_c_a();
+        self.emit(&[0x18]); // JR pf_lookup_loop
+        self.emit_relative("pf_lookup_loop");
+        self.label("pf_lookup_done");
+        self.emit(&[0xED, 0x4B]); // LD BC, (FUNC_COUNT)
+        self.emit_word(layout.var(FUNC_COUNT));
+        self.ld_a_b();
+        self.inc_a();
+        self.emit(&[0x3E, ERR_NA]); // LD A, ERR_NA
+        self.emit(&[0xCA]); // JP Z, eval_fail (first entry already above x)
+        self.fixup("eval_fail");
+        // The result is one step across from the step direction, and
+        // #REF if that is off the edge of the grid
+        self.ld_a_b();
+        self.emit(&[0x83]); // ADD A, E
+        self.emit(&[0xFE, layout.cols()]); // CP cols
+        self.emit(&[0xD2]); // JP NC, eval_ref
+        self.fixup("eval_ref");
+        self.ld_b_a();
+        self.ld_a_c();
+        self.emit(&[0x82]); // ADD A, D
+        self.emit(&[0xFE, layout.rows()]); // CP rows
+        self.emit(&[0xD2]); // JP NC, eval_ref
+        self.fixup("eval_ref");
+        self.ld_c_a();
+        self.emit(&[0xC3]); // JP pf_table_cell
+        self.fixup("pf_table_cell");
+        self.label("pf_lookup_error");
+        self.pop_bc();
+        self.pop_de();
+        self.ret(); // carry still set
 
-        // MIN (2) or MAX (3): copy FUNC_BCD to BCD_TEMP1
-        self.label("pf_not_avg");
-        self.emit(&[0xFE, 0x02]); // CP 2
-        self.emit(&[0xCA]); // JP Z, pf_ret_bcd
-        self.fixup("pf_ret_bcd");
-        self.emit(&[0xFE, 0x03]); // CP 3
-        self.emit(&[0xCA]); // JP Z, pf_ret_bcd
-        self.fixup("pf_ret_bcd");
+        // bcd_round: add 1 to the last digit of BCD_TEMP1 if the digit
+        // dropped below it (in A) is 5 or more
+        self.label("bcd_round");
+        self.emit(&[0xFE, 5]); // CP 5
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x21]); // LD HL, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0xCD]); // CALL bcd_zero (HL = BCD_TEMP2+3)
+        self.fixup("bcd_zero");
+        self.emit(&[0x36, 0x01]); // LD (HL), 1
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0xC3]); // JP bcd_add
+        self.fixup("bcd_add");
 
-        // COUNT (4): convert count to BCD in BCD_TEMP1
-        self.emit(&[0x2A]); // LD HL, (FUNC_COUNT)
-        self.emit_word(FUNC_COUNT);
-        // Convert to BCD (same as above, but put in byte 2 for display as X.00)
-        self.emit(&[0x7D]); // LD A, L
-        self.emit(&[0x06, 0x00]); // LD B, 0 (tens)
-        self.label("pf_cnt_cvt");
-        self.emit(&[0xFE, 10]); // CP 10
-        self.emit(&[0xDA]); // JP C, pf_cnt_done
-        self.fixup("pf_cnt_done");
-        self.emit(&[0xD6, 10]); // SUB 10
-        self.inc_b();
-        self.emit(&[0xC3]); // JP pf_cnt_cvt
-        self.fixup("pf_cnt_cvt");
-        self.label("pf_cnt_done");
-        self.emit(&[0x4F]); // LD C, A (ones)
-        self.ld_a_b();
-        self.emit(&[0x07]); // RLCA ×4
-        self.emit(&[0x07]);
-        self.emit(&[0x07]);
-        self.emit(&[0x07]);
-        self.emit(&[0xB1]); // OR C
-        // A = BCD of count, store as count.00
-        self.push_af();
+        // bcd_shr: shift BCD_TEMP1 right one digit; A = the digit shifted out
+        self.label("bcd_shr");
         self.emit(&[0x21]); // LD HL, BCD_TEMP1
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0xCD]); // CALL bcd_zero
-        self.fixup("bcd_zero");
-        self.pop_af();
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1+2
-        self.emit_word(BCD_TEMP1 + 2);
-        self.emit(&[0x77]); // LD (HL), A
-        // COUNT is always positive
+        self.emit_word(layout.var(BCD_TEMP1));
         self.xor_a();
-        self.emit(&[0x32]); // LD (TEMP1), A
-        self.emit_word(TEMP1);
-        self.or_a_a();
+        for i in 0..4 {
+            if i > 0 {
+                self.inc_hl();
+            }
+            self.emit(&[0xED, 0x67]); // RRD
+        }
         self.ret();
 
-        // pf_ret_bcd: copy FUNC_BCD to BCD_TEMP1 for MIN/MAX result
-        self.label("pf_ret_bcd");
-        // bcd_copy copies from (DE) to (HL)
-        self.emit(&[0x21]); // LD HL, BCD_TEMP1 (dest)
-        self.emit_word(BCD_TEMP1);
-        self.emit(&[0x11]); // LD DE, FUNC_BCD (src)
-        self.emit_word(FUNC_BCD);
-        self.emit(&[0xCD]); // CALL bcd_copy
-        self.fixup("bcd_copy");
-        // Copy sign to TEMP1 for MIN/MAX result
-        self.emit(&[0x3A]); // LD A, (FUNC_SIGN)
-        self.emit_word(FUNC_SIGN);
-        self.emit(&[0x32]); // LD (TEMP1), A
-        self.emit_word(TEMP1);
-        self.or_a_a();
+        // bcd_shl: shift BCD_TEMP1 left one digit, a zero in at the bottom;
+        // bcd_shl_a shifts in the digit in A instead. Either returns the
+        // digit shifted out in A
+        self.label("bcd_shl");
+        self.xor_a();
+        self.label("bcd_shl_a");
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1+3
+        self.emit_word(layout.var(BCD_TEMP1) + 3);
+        for i in 0..4 {
+            if i > 0 {
+                self.emit(&[0x2B]); // DEC HL
+            }
+            self.emit(&[0xED, 0x6F]); // RLD
+        }
         self.ret();
 
         // 16-bit division (legacy, may be unused): HL / DE -> HL (quotient)
@@ -3819,27 +6191,893 @@ impl SpreadsheetCodeGen {
         self.ret();
     }
 
-    /// I/O routines (MC6850 ACIA style - ports 0x80/0x81)
+    /// Financial functions @PV, @PMT, @FV and @NPV
+    ///
+    /// The 6.2 fixed-point values are too coarse for compound interest, so
+    /// these work in 12-digit decimal floats whose products and quotients
+    /// are formed exactly in 24-digit buffers before being truncated back.
+    /// Powers of v = 1/(1+r) are built by squaring, so even 9999 periods
+    /// take a few dozen multiplications.
+    fn emit_finance(&mut self) {
+        let layout = self.layout;
+        let fin = layout.line_buf();
+
+        // @PMT(x,rate,n) and @PV(x,rate,n): A = 0 for PV, 1 for PMT, 2 for FV
+        self.label("pf_pmt");
+        self.emit(&[0x3E, 1]); // LD A, 1
+        self.emit(&[0x18]); // JR pf_fin
+        self.emit_relative("pf_fin");
+        self.label("pf_pv");
+        self.xor_a();
+        self.emit(&[0x18]); // JR pf_fin
+        self.emit_relative("pf_fin");
+
+        self.label("pf_fin_error");
+        self.pop_bc(); // function type
+        self.emit(&[0x37]); // SCF
+        self.ret();
+
+        // @FV(x,rate,n)
+        self.label("pf_fv");
+        self.emit(&[0x3E, 2]); // LD A, 2
+        // x is a payment (a principal for @PMT), rate in percent per
+        // period and n a whole number of periods from 1 to 9999
+        self.label("pf_fin");
+        self.push_af();
+        self.emit(&[0xCD]); // CALL pf_two_args
+        self.fixup("pf_two_args");
+        self.emit(&[0x38]); // JR C, pf_fin_error
+        self.emit_relative("pf_fin_error");
+        self.emit(&[0x3A]); // LD A, (SIGN_OP)
+        self.emit_word(layout.var(SIGN_OP));
+        self.emit(&[0x87]); // ADD A, A (carry = negative rate)
+        self.emit(&[0x38]); // JR C, pf_fin_error
+        self.emit_relative("pf_fin_error");
+        self.emit(&[0xCD]); // CALL eval_push (rate, with the sign of x)
+        self.fixup("eval_push");
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0xCD]); // CALL bcd_copy
+        self.fixup("bcd_copy");
+        self.emit(&[0xCD]); // CALL eval_push (x)
+        self.fixup("eval_push");
+        self.emit(&[0xCD]); // CALL pf_next_arg (n)
+        self.fixup("pf_next_arg");
+        self.emit(&[0x38]); // JR C, pf_fin_args
+        self.emit_relative("pf_fin_args");
+        self.emit(&[0xCD]); // CALL eval_close
+        self.fixup("eval_close");
+        self.emit(&[0x38]); // JR C, pf_fin_args
+        self.emit_relative("pf_fin_args");
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x87]); // ADD A, A (carry = negative n)
+        // Both pops keep the carry, so errors are checked after them
+        self.label("pf_fin_args");
+        self.emit(&[0xCD]); // CALL eval_pop (x to BCD_TEMP2)
+        self.fixup("eval_pop");
+        self.emit(&[0x21]); // LD HL, FIN_X
+        self.emit_word(fin + FIN_X);
+        self.emit(&[0x11]); // LD DE, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0xCD]); // CALL bcd_copy (flags kept)
+        self.fixup("bcd_copy");
+        self.emit(&[0xCD]); // CALL eval_pop (rate to BCD_TEMP2, sign of x)
+        self.fixup("eval_pop");
+        self.emit(&[0x38]); // JR C, pf_fin_error
+        self.emit_relative("pf_fin_error");
+        // n = hundreds * 100 + units from its BCD, decimals ignored
+        self.emit(&[0x3A]); // LD A, (BCD_TEMP1)
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, pf_fin_error (n over 9999)
+        self.emit_relative("pf_fin_error");
+        self.emit(&[0x3A]); // LD A, (BCD_TEMP1+1)
+        self.emit_word(layout.var(BCD_TEMP1) + 1);
+        self.emit(&[0xCD]); // CALL bcd_to_bin
+        self.fixup("bcd_to_bin");
+        self.emit(&[0x6F]); // LD L, A
+        self.emit(&[0x26, 0x00]); // LD H, 0
+        self.emit_mul_hl(100);
+        self.push_hl();
+        self.emit(&[0x3A]); // LD A, (BCD_TEMP1+2)
+        self.emit_word(layout.var(BCD_TEMP1) + 2);
+        self.emit(&[0xCD]); // CALL bcd_to_bin
+        self.fixup("bcd_to_bin");
+        self.pop_hl();
+        self.ld_e_a();
+        self.emit(&[0x16, 0x00]); // LD D, 0
+        self.add_hl_de();
+        self.emit(&[0x7C]); // LD A, H
+        self.or_l();
+        self.emit(&[0x28]); // JR Z, pf_fin_error (no periods)
+        self.emit_relative("pf_fin_error");
+        self.emit(&[0x22]); // LD (FIN_N), HL
+        self.emit_word(fin + FIN_N);
+        // x to a float
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, FIN_X
+        self.emit_word(fin + FIN_X);
+        self.emit(&[0xCD]); // CALL bcd_copy
+        self.fixup("bcd_copy");
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x0E, 6]); // LD C, 6
+        self.emit(&[0xCD]); // CALL fin_from
+        self.fixup("fin_from");
+        self.emit(&[0x21]); // LD HL, FIN_X
+        self.emit_word(fin + FIN_X);
+        self.emit(&[0xCD]); // CALL fin_sta
+        self.fixup("fin_sta");
+        // v from the rate, then the annuity factor and v^n
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, BCD_TEMP2
+        self.emit_word(layout.var(BCD_TEMP2));
+        self.emit(&[0xCD]); // CALL bcd_copy
+        self.fixup("bcd_copy");
+        self.emit(&[0xCD]); // CALL fin_rate
+        self.fixup("fin_rate");
+        self.emit(&[0xCD]); // CALL fin_annuity
+        self.fixup("fin_annuity");
+        // PV = x * a_n, PMT = x / a_n, FV = x * a_n / v^n
+        self.emit(&[0x11]); // LD DE, FIN_X
+        self.emit_word(fin + FIN_X);
+        self.emit(&[0xCD]); // CALL fin_lda
+        self.fixup("fin_lda");
+        self.emit(&[0x11]); // LD DE, FIN_AN
+        self.emit_word(fin + FIN_AN);
+        self.pop_af();
+        self.dec_a();
+        self.emit(&[0x20]); // JR NZ, pf_fin_value
+        self.emit_relative("pf_fin_value");
+        self.emit(&[0xCD]); // CALL fin_div
+        self.fixup("fin_div");
+        self.emit(&[0xC3]); // JP fin_to
+        self.fixup("fin_to");
+        self.label("pf_fin_value");
+        self.push_af();
+        self.emit(&[0xCD]); // CALL fin_mul
+        self.fixup("fin_mul");
+        self.pop_af();
+        self.emit(&[0xFA]); // JP M, fin_to (PV)
+        self.fixup("fin_to");
+        self.emit(&[0x11]); // LD DE, FIN_VN
+        self.emit_word(fin + FIN_VN);
+        self.emit(&[0xCD]); // CALL fin_div
+        self.fixup("fin_div");
+        self.emit(&[0xC3]); // JP fin_to
+        self.fixup("fin_to");
+
+        // @NPV(rate,range): each cell discounted by one more period than
+        // the one before it, column by column
+        self.label("pf_npv");
+        self.emit(&[0xCD]); // CALL pf_open
+        self.fixup("pf_open");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL eval_compare
+        self.fixup("eval_compare");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x3A]); // LD A, (SIGN_ACCUM)
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x87]); // ADD A, A (carry = negative rate)
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x3E, b',']); // LD A, ','
+        self.emit(&[0xCD]); // CALL eval_expect (HL = after the ',')
+        self.fixup("eval_expect");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL pf_range
+        self.fixup("pf_range");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0x22]); // LD (TEMP2), HL
+        self.emit_word(layout.var(TEMP2));
+        self.emit(&[0xCD]); // CALL eval_close
+        self.fixup("eval_close");
+        self.emit(&[0xD8]); // RET C
+        self.emit(&[0xCD]); // CALL fin_rate
+        self.fixup("fin_rate");
+        // No periods yet: v^0 = 1, the sum starts at zero
+        self.emit(&[0x21]); // LD HL, FIN_VN
+        self.emit_word(fin + FIN_VN);
+        self.emit(&[0x11]); // LD DE, fin_one
+        self.fixup("fin_one");
+        self.emit(&[0xCD]); // CALL fin_copy
+        self.fixup("fin_copy");
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0xCD]); // CALL bcd_zero
+        self.fixup("bcd_zero");
+        self.ld_c_a(); // A = 0
+        self.emit(&[0xCD]); // CALL fin_from
+        self.fixup("fin_from");
+        self.emit(&[0x21]); // LD HL, FIN_AN
+        self.emit_word(fin + FIN_AN);
+        self.emit(&[0xCD]); // CALL fin_sta
+        self.fixup("fin_sta");
+        // B = col1, C = row1, which is kept for each new column
+        self.emit(&[0x2A]); // LD HL, (TEMP1)
+        self.emit_word(layout.var(TEMP1));
+        self.emit(&[0x45]); // LD B, L
+        self.emit(&[0x4C]); // LD C, H
+        self.ld_a_c();
+        self.emit(&[0x32]); // LD (FIN_N), A
+        self.emit_word(fin + FIN_N);
+        self.emit(&[0x3A]); // LD A, (RANGE_COL2)
+        self.emit_word(layout.var(RANGE_COL2));
+        self.emit(&[0xB8]); // CP B
+        self.emit(&[0xDA]); // JP C, pf_error (reversed range)
+        self.fixup("pf_error");
+        self.emit(&[0x3A]); // LD A, (RANGE_ROW2)
+        self.emit_word(layout.var(RANGE_ROW2));
+        self.emit(&[0xB9]); // CP C
+        self.emit(&[0xDA]); // JP C, pf_error
+        self.fixup("pf_error");
+        self.label("pf_npv_cell");
+        self.push_bc();
+        self.emit(&[0xCD]); // CALL load_cell_value
+        self.fixup("load_cell_value");
+        self.emit(&[0xDA]); // JP C, pf_fin_error (error cell)
+        self.fixup("pf_fin_error");
+        self.emit(&[0x3A]); // LD A, (TEMP1) (sign)
+        self.emit_word(layout.var(TEMP1));
+        self.emit(&[0x0E, 6]); // LD C, 6
+        self.emit(&[0xCD]); // CALL fin_from
+        self.fixup("fin_from");
+        self.emit(&[0xCD]); // CALL fin_accrue
+        self.fixup("fin_accrue");
+        self.pop_bc();
+        self.emit(&[0x3A]); // LD A, (RANGE_ROW2)
+        self.emit_word(layout.var(RANGE_ROW2));
+        self.emit(&[0xB9]); // CP C
+        self.emit(&[0x28]); // JR Z, pf_npv_col
+        self.emit_relative("pf_npv_col");
+        self.inc_c();
+        self.emit(&[0x18]); // JR pf_npv_cell
+        self.emit_relative("pf_npv_cell");
+        self.label("pf_npv_col");
+        self.emit(&[0x3A]); // LD A, (RANGE_COL2)
+        self.emit_word(layout.var(RANGE_COL2));
+        self.emit(&[0xB8]); // CP B
+        self.emit(&[0x28]); // JR Z, pf_npv_done
+        self.emit_relative("pf_npv_done");
+        self.inc_b();
+        self.emit(&[0x3A]); // LD A, (FIN_N)
+        self.emit_word(fin + FIN_N);
+        self.ld_c_a();
+        self.emit(&[0x18]); // JR pf_npv_cell
+        self.emit_relative("pf_npv_cell");
+        self.label("pf_npv_done");
+        self.emit(&[0x11]); // LD DE, FIN_AN
+        self.emit_word(fin + FIN_AN);
+        self.emit(&[0xCD]); // CALL fin_lda
+        self.fixup("fin_lda");
+        // Fall through into fin_to
+
+        // fin_to: FA to BCD_TEMP1 in 6.2, rounded half up, with the sign in
+        // SIGN_ACCUM and TEMP1; carry set (ERR_OVF) after a divide by zero
+        // or if it does not fit
+        self.label("fin_to");
+        self.emit(&[0x3A]); // LD A, (FIN_ERR)
+        self.emit_word(fin + FIN_ERR);
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, fin_to_overflow
+        self.emit_relative("fin_to_overflow");
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0x11]); // LD DE, FA mantissa
+        self.emit_word(fin + FIN_A + 2);
+        self.emit(&[0xCD]); // CALL fin_unpack
+        self.fixup("fin_unpack");
+        // Shift the point 6 - exponent digits left of the mantissa
+        self.emit(&[0x3A]); // LD A, (FA exponent)
+        self.emit_word(fin + FIN_A + 1);
+        self.ld_b_a();
+        self.emit(&[0x3E, 6]); // LD A, 6
+        self.emit(&[0x90]); // SUB B
+        self.emit(&[0xFA]); // JP M, fin_to_overflow (a million or more)
+        self.fixup("fin_to_overflow");
+        self.emit(&[0x28]); // JR Z, fin_to_round
+        self.emit_relative("fin_to_round");
+        self.ld_b_a();
+        self.label("fin_to_shift");
+        self.push_bc();
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0xCD]); // CALL fin_shr
+        self.fixup("fin_shr");
+        self.pop_bc();
+        self.emit(&[0x10]); // DJNZ fin_to_shift
+        self.emit_relative("fin_to_shift");
+        self.label("fin_to_round");
+        self.emit(&[0x21]); // LD HL, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0x11]); // LD DE, P+1
+        self.emit_word(fin + FIN_P + 1);
+        self.emit(&[0xCD]); // CALL bcd_copy (DE = P+5)
+        self.fixup("bcd_copy");
+        self.emit(&[0x1A]); // LD A, (DE)
+        self.emit(&[0x0F, 0x0F, 0x0F, 0x0F]); // RRCA x4
+        self.emit(&[0xE6, 0x0F]); // AND 0x0F (third decimal)
+        self.emit(&[0xFE, 5]); // CP 5
+        self.emit(&[0x38]); // JR C, fin_to_sign
+        self.emit_relative("fin_to_sign");
+        self.emit(&[0xCD]); // CALL bcd_round
+        self.fixup("bcd_round");
+        self.emit(&[0x38]); // JR C, fin_to_overflow (rounded up to a million)
+        self.emit_relative("fin_to_overflow");
+        self.label("fin_to_sign");
+        self.emit(&[0x3A]); // LD A, (FA sign)
+        self.emit_word(fin + FIN_A);
+        self.emit(&[0x32]); // LD (SIGN_ACCUM), A
+        self.emit_word(layout.var(SIGN_ACCUM));
+        self.emit(&[0x32]); // LD (TEMP1), A
+        self.emit_word(layout.var(TEMP1));
+        self.or_a_a(); // clear carry
+        self.ret();
+        self.label("fin_to_overflow");
+        self.emit(&[0xC3]); // JP eval_overflow
+        self.fixup("eval_overflow");
+
+        // fin_rate: v = 1/(1 + BCD_TEMP1/100) to FIN_V and FA, and the
+        // error flag cleared. A zero rate gives v = 1, so a_n = n and
+        // v^n = 1 with no special case
+        self.label("fin_rate");
+        self.xor_a();
+        self.emit(&[0x32]); // LD (FIN_ERR), A
+        self.emit_word(fin + FIN_ERR);
+        self.emit(&[0x0E, 4]); // LD C, 4 (percent)
+        self.emit(&[0xCD]); // CALL fin_from
+        self.fixup("fin_from");
+        self.emit(&[0x11]); // LD DE, fin_one
+        self.fixup("fin_one");
+        self.emit(&[0xCD]); // CALL fin_add
+        self.fixup("fin_add");
+        self.emit(&[0x21]); // LD HL, FIN_V
+        self.emit_word(fin + FIN_V);
+        self.emit(&[0xCD]); // CALL fin_sta
+        self.fixup("fin_sta");
+        self.emit(&[0x11]); // LD DE, fin_one
+        self.fixup("fin_one");
+        self.emit(&[0xCD]); // CALL fin_lda
+        self.fixup("fin_lda");
+        self.emit(&[0x11]); // LD DE, FIN_V
+        self.emit_word(fin + FIN_V);
+        self.emit(&[0xCD]); // CALL fin_div
+        self.fixup("fin_div");
+        self.emit(&[0x21]); // LD HL, FIN_V
+        self.emit_word(fin + FIN_V);
+        self.emit(&[0x18]); // JR fin_sta
+        self.emit_relative("fin_sta");
+
+        // fin_annuity: a_n = v + v^2 + ... + v^n to FIN_AN and v^n to FIN_VN
+        // for n = FIN_N, with FA = v. Works down the bits of n below the
+        // top one: a_2m = a_m * (1 + v^m), and one period more through
+        // fin_accrue as for @NPV
+        self.label("fin_annuity");
+        self.emit(&[0x21]); // LD HL, FIN_AN
+        self.emit_word(fin + FIN_AN);
+        self.emit(&[0xCD]); // CALL fin_sta
+        self.fixup("fin_sta");
+        self.emit(&[0x21]); // LD HL, FIN_VN
+        self.emit_word(fin + FIN_VN);
+        self.emit(&[0xCD]); // CALL fin_sta
+        self.fixup("fin_sta");
+        self.emit(&[0x2A]); // LD HL, (FIN_N)
+        self.emit_word(fin + FIN_N);
+        self.emit(&[0x06, 17]); // LD B, 17
+        self.label("fin_annuity_top");
+        self.emit(&[0x05]); // DEC B
+        self.add_hl_hl();
+        self.emit(&[0x30]); // JR NC, fin_annuity_top
+        self.emit_relative("fin_annuity_top");
+        self.label("fin_annuity_bit");
+        self.emit(&[0x05]); // DEC B
+        self.ret_z();
+        self.push_bc();
+        self.push_hl();
+        self.emit(&[0xCD]); // CALL fin_double
+        self.fixup("fin_double");
+        self.pop_hl();
+        self.add_hl_hl();
+        self.push_hl();
+        self.emit(&[0xDC]); // CALL C, fin_accrue_one
+        self.fixup("fin_accrue_one");
+        self.pop_hl();
+        self.pop_bc();
+        self.emit(&[0x18]); // JR fin_annuity_bit
+        self.emit_relative("fin_annuity_bit");
+
+        // fin_double: twice the periods, FIN_AN = FIN_AN * (1 + FIN_VN)
+        // and FIN_VN squared
+        self.label("fin_double");
+        self.emit(&[0x11]); // LD DE, FIN_VN
+        self.emit_word(fin + FIN_VN);
+        self.emit(&[0xCD]); // CALL fin_lda
+        self.fixup("fin_lda");
+        self.emit(&[0x11]); // LD DE, fin_one
+        self.fixup("fin_one");
+        self.emit(&[0xCD]); // CALL fin_add
+        self.fixup("fin_add");
+        self.emit(&[0x11]); // LD DE, FIN_AN
+        self.emit_word(fin + FIN_AN);
+        self.emit(&[0xCD]); // CALL fin_mul
+        self.fixup("fin_mul");
+        self.emit(&[0x21]); // LD HL, FIN_AN
+        self.emit_word(fin + FIN_AN);
+        self.emit(&[0xCD]); // CALL fin_sta
+        self.fixup("fin_sta");
+        self.emit(&[0x21]); // LD HL, FIN_VN
+        self.emit_word(fin + FIN_VN);
+        self.emit(&[0x18]); // JR fin_vn_mul
+        self.emit_relative("fin_vn_mul");
+
+        // fin_accrue: one period more, the compounding step of every
+        // financial function: FIN_AN += FA * v * FIN_VN, then FIN_VN =
+        // FIN_VN * v. fin_accrue_one accrues 1, a period of the annuity
+        self.label("fin_accrue_one");
+        self.emit(&[0x11]); // LD DE, fin_one
+        self.fixup("fin_one");
+        self.emit(&[0xCD]); // CALL fin_lda
+        self.fixup("fin_lda");
+        self.label("fin_accrue");
+        self.emit(&[0x11]); // LD DE, FIN_V
+        self.emit_word(fin + FIN_V);
+        self.emit(&[0xCD]); // CALL fin_mul
+        self.fixup("fin_mul");
+        self.emit(&[0x11]); // LD DE, FIN_VN
+        self.emit_word(fin + FIN_VN);
+        self.emit(&[0xCD]); // CALL fin_mul
+        self.fixup("fin_mul");
+        self.emit(&[0x11]); // LD DE, FIN_AN
+        self.emit_word(fin + FIN_AN);
+        self.emit(&[0xCD]); // CALL fin_add
+        self.fixup("fin_add");
+        self.emit(&[0x21]); // LD HL, FIN_AN
+        self.emit_word(fin + FIN_AN);
+        self.emit(&[0xCD]); // CALL fin_sta
+        self.fixup("fin_sta");
+        self.emit(&[0x21]); // LD HL, FIN_V
+        self.emit_word(fin + FIN_V);
+        // fin_vn_mul: FIN_VN = FIN_VN * float at HL
+        self.label("fin_vn_mul");
+        self.push_hl();
+        self.emit(&[0x11]); // LD DE, FIN_VN
+        self.emit_word(fin + FIN_VN);
+        self.emit(&[0xCD]); // CALL fin_lda
+        self.fixup("fin_lda");
+        self.pop_de();
+        self.emit(&[0xCD]); // CALL fin_mul
+        self.fixup("fin_mul");
+        self.emit(&[0x21]); // LD HL, FIN_VN
+        self.emit_word(fin + FIN_VN);
+        // Fall through into fin_sta
+
+        // fin_sta: copy FA to the float at HL
+        self.label("fin_sta");
+        self.emit(&[0x11]); // LD DE, FIN_A
+        self.emit_word(fin + FIN_A);
+        self.emit(&[0x18]); // JR fin_copy
+        self.emit_relative("fin_copy");
+
+        // fin_lda: copy the float at DE to FA
+        self.label("fin_lda");
+        self.emit(&[0x21]); // LD HL, FIN_A
+        self.emit_word(fin + FIN_A);
+        // fin_copy: copy the float at DE to HL (flags kept)
+        self.label("fin_copy");
+        self.emit(&[0x06, 8]); // LD B, 8
+        self.emit(&[0xC3]); // JP bcd_copy_n
+        self.fixup("bcd_copy_n");
+
+        // fin_from: FA = BCD_TEMP1 (8 digits) * 10^(C-8), sign A. C = 6 reads
+        // a 6.2 value, C = 4 a percentage
+        self.label("fin_from");
+        self.emit(&[0x32]); // LD (FA sign), A
+        self.emit_word(fin + FIN_A);
+        self.ld_a_c();
+        self.emit(&[0x32]); // LD (FA exponent), A
+        self.emit_word(fin + FIN_A + 1);
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0xCD]); // CALL fin_clear
+        self.fixup("fin_clear");
+        self.emit(&[0x21]); // LD HL, P+1
+        self.emit_word(fin + FIN_P + 1);
+        self.emit(&[0x11]); // LD DE, BCD_TEMP1
+        self.emit_word(layout.var(BCD_TEMP1));
+        self.emit(&[0xCD]); // CALL bcd_copy
+        self.fixup("bcd_copy");
+        // Fall through into fin_pack
+
+        // fin_pack: normalise P into the FA mantissa, truncating. P[0] is
+        // at most 9 here; a result with nothing in 24 digits or below
+        // 10^-60 is zero
+        self.label("fin_pack");
+        self.emit(&[0x3A]); // LD A, (FIN_P)
+        self.emit_word(fin + FIN_P);
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, fin_pack_norm
+        self.emit_relative("fin_pack_norm");
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0xCD]); // CALL fin_shr
+        self.fixup("fin_shr");
+        self.emit(&[0x21]); // LD HL, FA exponent
+        self.emit_word(fin + FIN_A + 1);
+        self.emit(&[0x34]); // INC (HL)
+        self.label("fin_pack_norm");
+        self.emit(&[0x1E, 25]); // LD E, 25 (bcd_shift_digits keeps E)
+        self.label("fin_pack_loop");
+        self.emit(&[0x3A]); // LD A, (P+1)
+        self.emit_word(fin + FIN_P + 1);
+        self.emit(&[0xE6, 0xF0]); // AND 0xF0
+        self.emit(&[0x20]); // JR NZ, fin_pack_range
+        self.emit_relative("fin_pack_range");
+        self.emit(&[0x1D]); // DEC E
+        self.emit(&[0x28]); // JR Z, fin_pack_zero
+        self.emit_relative("fin_pack_zero");
+        self.emit(&[0x21]); // LD HL, P+12
+        self.emit_word(fin + FIN_P + 12);
+        self.emit(&[0x06, 13]); // LD B, 13
+        self.emit(&[0xCD]); // CALL bcd_shift_digits (A = 0)
+        self.fixup("bcd_shift_digits");
+        self.emit(&[0x21]); // LD HL, FA exponent
+        self.emit_word(fin + FIN_A + 1);
+        self.emit(&[0x35]); // DEC (HL)
+        self.emit(&[0x18]); // JR fin_pack_loop
+        self.emit_relative("fin_pack_loop");
+        self.label("fin_pack_range");
+        self.emit(&[0x3A]); // LD A, (FA exponent)
+        self.emit_word(fin + FIN_A + 1);
+        self.emit(&[0xEE, 0x80]); // XOR 0x80 (signed to unsigned)
+        self.emit(&[0xFE, 0x80 - 60]); // CP -60 + 0x80
+        self.emit(&[0x30]); // JR NC, fin_pack_store
+        self.emit_relative("fin_pack_store");
+        self.label("fin_pack_zero");
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0xCD]); // CALL fin_clear (A = 0)
+        self.fixup("fin_clear");
+        self.emit(&[0x32]); // LD (FA sign), A
+        self.emit_word(fin + FIN_A);
+        self.emit(&[0x3E, FIN_ZERO_EXP]); // LD A, FIN_ZERO_EXP
+        self.emit(&[0x32]); // LD (FA exponent), A
+        self.emit_word(fin + FIN_A + 1);
+        self.label("fin_pack_store");
+        self.emit(&[0x21]); // LD HL, FA mantissa
+        self.emit_word(fin + FIN_A + 2);
+        self.emit(&[0x11]); // LD DE, P+1
+        self.emit_word(fin + FIN_P + 1);
+        self.emit(&[0x06, 6]); // LD B, 6
+        self.emit(&[0xC3]); // JP bcd_copy_n
+        self.fixup("bcd_copy_n");
+
+        // fin_mul: FA = FA * float at DE. The 24-digit product is exact:
+        // for each digit of the operand, lowest first, the multiplicand is
+        // added that many times and the sum shifted right
+        self.label("fin_mul");
+        self.emit(&[0xCD]); // CALL fin_ldb
+        self.fixup("fin_ldb");
+        self.emit(&[0x86]); // ADD A, (HL) (FA exponent + FB exponent)
+        self.emit(&[0x32]); // LD (FA exponent), A
+        self.emit_word(fin + FIN_A + 1);
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0xCD]); // CALL fin_clear
+        self.fixup("fin_clear");
+        self.emit(&[0x21]); // LD HL, FB mantissa+5
+        self.emit_word(fin + FIN_B + 7);
+        self.emit(&[0x0E, 6]); // LD C, 6
+        self.label("fin_mul_byte");
+        self.ld_a_hl_ind();
+        self.emit(&[0xCD]); // CALL fin_mul_digit (low digit)
+        self.fixup("fin_mul_digit");
+        self.ld_a_hl_ind();
+        self.emit(&[0x0F, 0x0F, 0x0F, 0x0F]); // RRCA x4
+        self.emit(&[0xCD]); // CALL fin_mul_digit (high digit)
+        self.fixup("fin_mul_digit");
+        self.emit(&[0x2B]); // DEC HL
+        self.dec_c();
+        self.emit(&[0x20]); // JR NZ, fin_mul_byte
+        self.emit_relative("fin_mul_byte");
+        self.emit(&[0x18]); // JR fin_pack
+        self.emit_relative("fin_pack");
+
+        // P += E times the digit in the low nibble of A, then P shifted
+        // right; keeps HL and C
+        self.label("fin_mul_digit");
+        self.push_hl();
+        self.push_bc();
+        self.emit(&[0xE6, 0x0F]); // AND 0x0F
+        self.ld_c_a();
+        self.inc_c();
+        self.label("fin_mul_add");
+        self.dec_c();
+        self.emit(&[0x28]); // JR Z, fin_mul_shift
+        self.emit_relative("fin_mul_shift");
+        self.emit(&[0x21]); // LD HL, P+12
+        self.emit_word(fin + FIN_P + 12);
+        self.emit(&[0x11]); // LD DE, E+12
+        self.emit_word(fin + FIN_E + 12);
+        self.emit(&[0x06, 13]); // LD B, 13
+        self.emit(&[0xCD]); // CALL bcd_add_n
+        self.fixup("bcd_add_n");
+        self.emit(&[0x18]); // JR fin_mul_add
+        self.emit_relative("fin_mul_add");
+        self.label("fin_mul_shift");
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0xCD]); // CALL fin_shr
+        self.fixup("fin_shr");
+        self.pop_bc();
+        self.pop_hl();
+        self.ret();
+
+        // fin_div: FA = FA / float at DE, 13 quotient digits by long
+        // division; a zero divisor sets FIN_ERR instead
+        self.label("fin_div");
+        self.emit(&[0xCD]); // CALL fin_ldb
+        self.fixup("fin_ldb");
+        self.emit(&[0x96]); // SUB (HL) (FA exponent - FB exponent)
+        self.emit(&[0x32]); // LD (FA exponent), A
+        self.emit_word(fin + FIN_A + 1);
+        // FB exponent byte cleared: FB+1..FB+7 is the 14-digit divisor D
+        self.emit(&[0x36, 0x00]); // LD (HL), 0
+        self.emit(&[0x3A]); // LD A, (FB mantissa)
+        self.emit_word(fin + FIN_B + 2);
+        self.or_a_a();
+        self.emit(&[0x20]); // JR NZ, fin_div_start
+        self.emit_relative("fin_div_start");
+        self.dec_a();
+        self.emit(&[0x32]); // LD (FIN_ERR), A
+        self.emit_word(fin + FIN_ERR);
+        self.ret();
+        self.label("fin_div_start");
+        // Remainder R = E[0..6], quotient digits into P[0..6]
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0xCD]); // CALL fin_clear
+        self.fixup("fin_clear");
+        self.emit(&[0x0E, 13]); // LD C, 13
+        self.label("fin_div_digit");
+        self.push_bc();
+        self.emit(&[0x21]); // LD HL, P+6
+        self.emit_word(fin + FIN_P + 6);
+        self.emit(&[0x06, 7]); // LD B, 7
+        self.xor_a();
+        self.emit(&[0xCD]); // CALL bcd_shift_digits
+        self.fixup("bcd_shift_digits");
+        self.label("fin_div_sub");
+        self.emit(&[0x21]); // LD HL, FB+1
+        self.emit_word(fin + FIN_B + 1);
+        self.emit(&[0x11]); // LD DE, FIN_E
+        self.emit_word(fin + FIN_E);
+        self.emit(&[0x06, 7]); // LD B, 7
+        self.emit(&[0xCD]); // CALL bcd_cmp_n (carry if R < D)
+        self.fixup("bcd_cmp_n");
+        self.emit(&[0x38]); // JR C, fin_div_next
+        self.emit_relative("fin_div_next");
+        self.emit(&[0x21]); // LD HL, E+6
+        self.emit_word(fin + FIN_E + 6);
+        self.emit(&[0x11]); // LD DE, FB+7
+        self.emit_word(fin + FIN_B + 7);
+        self.emit(&[0x06, 7]); // LD B, 7
+        self.emit(&[0xCD]); // CALL bcd_sub_n
+        self.fixup("bcd_sub_n");
+        self.emit(&[0x21]); // LD HL, P+6
+        self.emit_word(fin + FIN_P + 6);
+        self.emit(&[0x34]); // INC (HL)
+        self.emit(&[0x18]); // JR fin_div_sub
+        self.emit_relative("fin_div_sub");
+        self.label("fin_div_next");
+        self.emit(&[0x21]); // LD HL, E+6
+        self.emit_word(fin + FIN_E + 6);
+        self.emit(&[0x06, 7]); // LD B, 7
+        self.xor_a();
+        self.emit(&[0xCD]); // CALL bcd_shift_digits
+        self.fixup("bcd_shift_digits");
+        self.pop_bc();
+        self.dec_c();
+        self.emit(&[0x20]); // JR NZ, fin_div_digit
+        self.emit_relative("fin_div_digit");
+        self.emit(&[0xC3]); // JP fin_pack
+        self.fixup("fin_pack");
+
+        // fin_add: FA = FA + float at DE. The operand with the smaller
+        // exponent is shifted right to line up, then magnitudes are added
+        // or subtracted in 26 digits
+        self.label("fin_add");
+        self.emit(&[0x21]); // LD HL, FIN_B
+        self.emit_word(fin + FIN_B);
+        self.emit(&[0xCD]); // CALL fin_copy
+        self.fixup("fin_copy");
+        self.emit(&[0x3A]); // LD A, (FA exponent)
+        self.emit_word(fin + FIN_A + 1);
+        self.emit(&[0x21]); // LD HL, FB exponent
+        self.emit_word(fin + FIN_B + 1);
+        self.emit(&[0x96]); // SUB (HL)
+        self.emit(&[0xF2]); // JP P, fin_add_align
+        self.fixup("fin_add_align");
+        self.emit(&[0xED, 0x44]); // NEG
+        self.push_af();
+        self.emit(&[0x21]); // LD HL, FIN_A
+        self.emit_word(fin + FIN_A);
+        self.emit(&[0x11]); // LD DE, FIN_B
+        self.emit_word(fin + FIN_B);
+        self.emit(&[0x06, 8]); // LD B, 8
+        self.label("fin_add_swap");
+        self.emit(&[0x1A]); // LD A, (DE)
+        self.emit(&[0x4E]); // LD C, (HL)
+        self.ld_hl_ind_a();
+        self.ld_a_c();
+        self.emit(&[0x12]); // LD (DE), A
+        self.inc_hl();
+        self.inc_de();
+        self.emit(&[0x10]); // DJNZ fin_add_swap
+        self.emit_relative("fin_add_swap");
+        self.pop_af();
+        self.label("fin_add_align");
+        self.push_af();
+        self.emit(&[0x21]); // LD HL, FIN_P
+        self.emit_word(fin + FIN_P);
+        self.emit(&[0x11]); // LD DE, FA mantissa
+        self.emit_word(fin + FIN_A + 2);
+        self.emit(&[0xCD]); // CALL fin_unpack
+        self.fixup("fin_unpack");
+        self.emit(&[0x21]); // LD HL, FIN_E
+        self.emit_word(fin + FIN_E);
+        self.emit(&[0x11]); // LD DE, FB mantissa
+        self.emit_word(fin + FIN_B + 2);
+        self.emit(&[0xCD]); // CALL fin_unpack
+        self.fixup("fin_unpack");
+        self.pop_af();
+        self.or_a_a();
+        self.emit(&[0x28]); // JR Z, fin_add_digits
+        self.emit_relative("fin_add_digits");
+        self.ld_b_a();
+        self.label("fin_add_shift");
+        self.push_bc();
+        self.emit(&[0x21]); // LD HL, FIN_E
+        self.emit_word(fin + FIN_E);
+        self.emit(&[0xCD]); // CALL fin_shr
+        self.fixup("fin_shr");
+        self.pop_bc();
+        self.emit(&[0x10]); // DJNZ fin_add_shift
+        self.emit_relative("fin_add_shift");
+        self.label("fin_add_digits");
+        self.emit(&[0x3A]); // LD A, (FB sign)
+        self.emit_word(fin + FIN_B);
+        self.ld_c_a();
+        self.emit(&[0x3A]); // LD A, (FA sign)
+        self.emit_word(fin + FIN_A);
+        self.emit(&[0xB9]); // CP C
+        self.emit(&[0x21]); // LD HL, P+12
+        self.emit_word(fin + FIN_P + 12);
+        self.emit(&[0x11]); // LD DE, E+12
+        self.emit_word(fin + FIN_E + 12);
+        self.emit(&[0x06, 13]); // LD B, 13
+        self.emit(&[0x20]); // JR NZ, fin_add_sub
+        self.emit_relative("fin_add_sub");
+        self.emit(&[0xCD]); // CALL bcd_add_n
+        self.fixup("bcd_add_n");
+        self.emit(&[0xC3]); // JP fin_pack
+        self.fixup("fin_pack");
+        self.label("fin_add_sub");
+        self.emit(&[0xCD]); // CALL bcd_sub_n
+        self.fixup("bcd_sub_n");
+        self.emit(&[0xD2]); // JP NC, fin_pack
+        self.fixup("fin_pack");
+        // E was larger: flip the sign and take the ten's complement of P
+        self.emit(&[0x21]); // LD HL, FIN_A
+        self.emit_word(fin + FIN_A);
+        self.ld_a_hl_ind();
+        self.emit(&[0xEE, 0x80]); // XOR 0x80
+        self.ld_hl_ind_a();
+        self.emit(&[0x21]); // LD HL, P+12
+        self.emit_word(fin + FIN_P + 12);
+        self.emit(&[0x06, 13]); // LD B, 13
+        self.or_a_a(); // no borrow in
+        self.label("fin_add_neg");
+        self.emit(&[0x3E, 0x00]); // LD A, 0
+        self.emit(&[0x9E]); // SBC A, (HL)
+        self.emit(&[0x27]); // DAA
+        self.ld_hl_ind_a();
+        self.emit(&[0x2B]); // DEC HL
+        self.emit(&[0x10]); // DJNZ fin_add_neg
+        self.emit_relative("fin_add_neg");
+        self.emit(&[0xC3]); // JP fin_pack
+        self.fixup("fin_pack");
+
+        // fin_ldb: FB = float at DE, FA sign = FA sign XOR FB sign, and
+        // unpack the FA mantissa into E. Returns A = FA exponent, HL at
+        // the FB exponent
+        self.label("fin_ldb");
+        self.emit(&[0x21]); // LD HL, FIN_B
+        self.emit_word(fin + FIN_B);
+        self.emit(&[0xCD]); // CALL fin_copy
+        self.fixup("fin_copy");
+        self.emit(&[0x21]); // LD HL, FIN_E
+        self.emit_word(fin + FIN_E);
+        self.emit(&[0x11]); // LD DE, FA mantissa
+        self.emit_word(fin + FIN_A + 2);
+        self.emit(&[0xCD]); // CALL fin_unpack
+        self.fixup("fin_unpack");
+        self.emit(&[0x21]); // LD HL, FIN_B
+        self.emit_word(fin + FIN_B);
+        self.emit(&[0x3A]); // LD A, (FA sign)
+        self.emit_word(fin + FIN_A);
+        self.emit(&[0xAE]); // XOR (HL)
+        self.emit(&[0x32]); // LD (FA sign), A
+        self.emit_word(fin + FIN_A);
+        self.inc_hl();
+        self.emit(&[0x3A]); // LD A, (FA exponent)
+        self.emit_word(fin + FIN_A + 1);
+        self.ret();
+
+        // fin_unpack: 13-byte buffer at HL = 0, then the 6 mantissa bytes
+        // at DE into bytes 1-6
+        self.label("fin_unpack");
+        self.push_hl();
+        self.emit(&[0xCD]); // CALL fin_clear
+        self.fixup("fin_clear");
+        self.pop_hl();
+        self.inc_hl();
+        self.emit(&[0x06, 6]); // LD B, 6
+        self.emit(&[0xC3]); // JP bcd_copy_n
+        self.fixup("bcd_copy_n");
+
+        // fin_clear: zero 13 bytes at HL; returns A = 0, keeps DE
+        self.label("fin_clear");
+        self.emit(&[0x06, 13]); // LD B, 13
+        self.xor_a();
+        self.label("fin_clear_loop");
+        self.ld_hl_ind_a();
+        self.inc_hl();
+        self.emit(&[0x10]); // DJNZ fin_clear_loop
+        self.emit_relative("fin_clear_loop");
+        self.ret();
+
+        // fin_shr: shift the 13-byte buffer at HL right one digit
+        self.label("fin_shr");
+        self.emit(&[0x06, 13]); // LD B, 13
+        self.xor_a();
+        self.label("fin_shr_loop");
+        self.emit(&[0xED, 0x67]); // RRD
+        self.inc_hl();
+        self.emit(&[0x10]); // DJNZ fin_shr_loop
+        self.emit_relative("fin_shr_loop");
+        self.ret();
+
+        // 1.0 as a float
+        self.label("fin_one");
+        self.emit_data(&[0x00, 0x01, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    /// I/O routines (polled UART from the serial profile)
     fn emit_io(&mut self) {
+        let layout = self.layout;
+        let uart = self.uart.clone();
+
         // Get character from input
-        // MC6850: bit 0 of status = RX ready
         self.label("getchar");
-        self.emit(&[0xDB, 0x80]); // IN A, (0x80) - status
-        self.emit(&[0xE6, 0x01]); // AND 0x01 - RX ready bit
+        self.emit(&[0xDB, uart.status_port]); // IN A, (status)
+        self.emit(&[0xE6, uart.rx_ready]); // AND rx_ready
         self.emit(&[0x28, 0xFA]); // JR Z, getchar (-6)
-        self.emit(&[0xDB, 0x81]); // IN A, (0x81) - data
+        self.emit(&[0xDB, uart.data_port]); // IN A, (data)
         self.ret();
 
         // Put character to output
-        // MC6850: bit 1 of status = TX ready
         self.label("putchar");
         self.push_af(); // save char
         self.label("putchar_wait");
-        self.emit(&[0xDB, 0x80]); // IN A, (0x80) - status
-        self.emit(&[0xE6, 0x02]); // AND 0x02 - TX ready bit
+        self.emit(&[0xDB, uart.status_port]); // IN A, (status)
+        self.emit(&[0xE6, uart.tx_ready]); // AND tx_ready
         self.emit(&[0x28, 0xFA]); // JR Z, putchar_wait (-6)
         self.pop_af(); // restore char
-        self.emit(&[0xD3, 0x81]); // OUT (0x81), A - data
+        self.emit(&[0xD3, uart.data_port]); // OUT (data), A
         self.ret();
 
         // Print newline
@@ -3858,9 +7096,9 @@ impl SpreadsheetCodeGen {
         self.label("int_to_str");
         self.xor_a();
         self.emit(&[0x32]); // LD (TEMP1), A  ; offset = 0
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.emit(&[0x32]); // LD (TEMP1+1), A  ; digit count = 0
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
 
         // Check if negative
         self.emit(&[0x7C]); // LD A, H
@@ -3870,10 +7108,10 @@ impl SpreadsheetCodeGen {
         // Negative - store minus and negate
         self.emit(&[0x3E, b'-']); // LD A, '-'
         self.emit(&[0x32]); // LD (INPUT_BUF), A
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.emit(&[0x3E, 0x01]); // LD A, 1
         self.emit(&[0x32]); // LD (TEMP1), A  ; offset = 1
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         // Negate HL
         self.emit(&[0x7C]); // LD A, H
         self.cpl();
@@ -3906,10 +7144,10 @@ impl SpreadsheetCodeGen {
         self.push_af(); //save digit)
         // Increment digit count
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.inc_a();
         self.emit(&[0x32]); // LD (TEMP1+1), A
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         // HL = quotient, check if zero
         self.emit(&[0x60]); // LD H, B
         self.emit(&[0x69]); // LD L, C
@@ -3921,37 +7159,34 @@ impl SpreadsheetCodeGen {
         // Pop digits and store in INPUT_BUF
         // DE = INPUT_BUF + offset
         self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.ld_e_a();
         self.emit(&[0x16, 0x00]); // LD D, 0
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.add_hl_de(); //HL = output ptr)
         // B = digit count
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.ld_b_a();
         self.label("int_to_str_pop");
         self.pop_af();
         self.ld_hl_ind_a();
         self.inc_hl();
         self.emit(&[0x10]); // DJNZ int_to_str_pop
-        let offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        self.rom_mut()[offset] = (self.get_label("int_to_str_pop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
+        self.emit_relative("int_to_str_pop");
 
         // Set INPUT_LEN = offset + digit count
         self.emit(&[0x3A]); // LD A, (TEMP1)
-        self.emit_word(TEMP1);
+        self.emit_word(layout.var(TEMP1));
         self.ld_b_a();
         self.emit(&[0x3A]); // LD A, (TEMP1+1)
-        self.emit_word(TEMP1 + 1);
+        self.emit_word(layout.var(TEMP1) + 1);
         self.emit(&[0x80]); // ADD A, B
         self.emit(&[0x32]); // LD (INPUT_LEN), A
-        self.emit_word(INPUT_LEN);
+        self.emit_word(layout.var(INPUT_LEN));
         self.emit(&[0x32]); // LD (INPUT_POS), A
-        self.emit_word(INPUT_POS);
+        self.emit_word(layout.var(INPUT_POS));
         self.ret();
 
         // === VT220/ANSI Escape Sequence Routines ===
@@ -4323,10 +7558,7 @@ impl SpreadsheetCodeGen {
         self.emit(&[0xCD]); // CALL putchar
         self.fixup("putchar");
         self.emit(&[0x10]); // DJNZ print_cell_pad_loop
-        let offset = self.rom().len();
-        self.emit(&[0x00]); // placeholder
-        self.rom_mut()[offset] = (self.get_label("print_cell_pad_loop").unwrap_or(0)
-            .wrapping_sub(self.pos())) as u8;
+        self.emit_relative("print_cell_pad_loop");
 
         self.label("print_cell_no_pad");
         self.pop_hl(); //restore original value)
@@ -4347,7 +7579,7 @@ impl SpreadsheetCodeGen {
         // Negative - need to handle minus sign
         // Scan for leading zeros first
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.emit(&[0x06, 5]); // LD B, 5
         self.label("skip_zeros_neg");
         self.ld_a_hl_ind();
@@ -4393,7 +7625,7 @@ impl SpreadsheetCodeGen {
         self.label("print_bcd_cell");
         // Scan INPUT_BUF positions 0-4 for leading zeros
         self.emit(&[0x21]); // LD HL, INPUT_BUF
-        self.emit_word(INPUT_BUF);
+        self.emit_word(layout.input_buf());
         self.emit(&[0x06, 5]); // LD B, 5 (max zeros to skip in positions 0-4)
         self.label("skip_zeros_loop");
         self.ld_a_hl_ind();
@@ -4467,22 +7699,42 @@ impl SpreadsheetCodeGen {
         self.label("width_prompt");
         self.emit_string("Width (5-15): ");
 
+        self.label("auto_str");
+        self.emit_string("AUTO ");
+        self.label("manual_str");
+        self.emit_string("MAN ");
+        self.label("by_row_str");
+        self.emit_string("ROW ");
+        self.label("by_col_str");
+        self.emit_string("COL ");
+        self.label("iter_str");
+        self.emit_string("ITER ");
+        self.label("circ_str");
+        self.emit_string("CIRC ");
+        self.label("free_str");
+        self.emit_string("FREE ");
+        self.label("passes_prompt");
+        self.emit_string("Passes (1-99): ");
+        self.label("memory_full_msg");
+        self.emit_string("Memory full");
+
         self.label("quit_msg");
         self.emit_string("\r\nGoodbye!\r\n");
 
-        self.label("error_str");
-        self.emit_string(" #ERR ");
+        self.label("error_names");
+        self.emit_data(ERROR_NAMES.concat().as_bytes());
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::harness::{Harness, StopReason};
 
     #[test]
     fn test_generate() {
         let mut codegen = SpreadsheetCodeGen::new();
-        codegen.generate();
+        codegen.generate().unwrap();
         let rom = codegen.into_rom();
         assert!(rom.len() > 256);
         assert!(rom.len() < 8192);
@@ -4492,13 +7744,79 @@ mod tests {
 
     #[test]
     fn test_cell_address_calculation() {
-        // Cell (0,0) should be at CELL_DATA
-        // Cell (1,0) should be at CELL_DATA + 6
-        // Cell (0,1) should be at CELL_DATA + 96
-        // Formula: CELL_DATA + (row * 16 + col) * 6
-        let base = CELL_DATA;
-        assert_eq!(base + (0 * 16 + 0) * 6, 0x2000);
-        assert_eq!(base + (0 * 16 + 1) * 6, 0x2006);
-        assert_eq!(base + (1 * 16 + 0) * 6, 0x2060);
+        // get_cell_addr against the layout, for power-of-two and odd widths
+        for layout in [
+            Layout::default(),
+            Layout::new(0x2000, 0x2000, 10, 50).unwrap(),
+            Layout::new(0x8000, 0x8000, 26, 99).unwrap(),
+        ] {
+            let mut codegen = SpreadsheetCodeGen::new();
+            codegen.set_layout(layout);
+            let mut h = Harness::boot(codegen);
+            let get_cell_addr = h.symbol("get_cell_addr").unwrap();
+            for (col, row) in [(0, 0), (1, 0), (0, 1), (layout.cols() - 1, layout.rows() - 1)] {
+                h.cpu.b = col;
+                h.cpu.c = row;
+                assert_eq!(h.call(get_cell_addr, 10_000), StopReason::Returned);
+                let hl = u16::from_le_bytes([h.cpu.l, h.cpu.h]);
+                assert_eq!(hl, layout.cell_addr(col, row), "{} ({}, {})", layout, col, row);
+            }
+        }
+        assert_eq!(Layout::default().cell_addr(0, 1), 0x2060);
+    }
+
+    #[test]
+    fn test_symbol_map_and_listing() {
+        let mut codegen = SpreadsheetCodeGen::new();
+        codegen.generate().unwrap();
+        let symbols = codegen.symbols();
+        let eval = symbols.iter().find(|(n, _)| n == "eval_expr").unwrap().1;
+        assert_eq!(codegen.get_label("eval_expr"), Some(eval));
+        assert!(symbols.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert!(codegen.symbol_map().contains(&format!("{:04X}  eval_expr\n", eval)));
+
+        let listing = codegen.listing();
+        assert!(listing.starts_with("  0000  31 FF 3F      LD SP,$3FFF\n"));
+        assert!(listing.contains("\neval_expr:\n"));
+        assert!(listing.contains("DB \"kz80_calc v0.1\",$0D,$0A,$00"));
+        assert!(listing.contains("; print_string\n"));
+    }
+
+    #[test]
+    fn test_build_problems_reported() {
+        let mut codegen = SpreadsheetCodeGen::new();
+        codegen.label("start");
+        codegen.call("missing");
+        codegen.emit(&[0x18]); // JR forward
+        codegen.emit_relative("far");
+        codegen.emit(&[0x10]); // DJNZ nowhere
+        codegen.emit_relative("nowhere");
+        codegen.emit(&[0x00; 200]);
+        codegen.label("far");
+        codegen.emit(&[0x00; ROM_SIZE]);
+        let err = codegen.finish().unwrap_err();
+        assert_eq!(
+            err.problems,
+            vec![
+                BuildProblem::UnresolvedLabel { label: "missing".into(), at: 1 },
+                BuildProblem::BranchOutOfRange { label: "far".into(), at: 4, distance: 202 },
+                BuildProblem::UnresolvedBranch { label: "nowhere".into(), at: 6 },
+                BuildProblem::RomOverflow { size: ROM_SIZE + 207, limit: ROM_SIZE },
+            ]
+        );
+        assert!(err.to_string().contains("0001: undefined label 'missing'"));
+    }
+
+    #[test]
+    fn test_forward_relative_branch() {
+        let mut codegen = SpreadsheetCodeGen::new();
+        codegen.emit(&[0x18]); // JR skip
+        codegen.emit_relative("skip");
+        codegen.emit(&[0x00, 0x00]);
+        codegen.label("skip");
+        codegen.emit(&[0x10]); // DJNZ skip
+        codegen.emit_relative("skip");
+        codegen.finish().unwrap();
+        assert_eq!(codegen.rom(), &[0x18, 0x02, 0x00, 0x00, 0x10, 0xFE]);
     }
 }
@@ -0,0 +1,79 @@
+//! Minimal CSV reading and writing for worksheet templates and dumps
+
+/// Split CSV text into records of unquoted fields
+///
+/// Fields may be quoted with `"`, with `""` standing for a literal quote
+/// inside a quoted field. Records end at `\n` or `\r\n`.
+pub fn parse(text: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
+/// Format one record, quoting fields that contain `,`, `"` or line breaks
+pub fn format_record(fields: &[&str]) -> String {
+    let quoted: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            if f.contains([',', '"', '\r', '\n']) {
+                format!("\"{}\"", f.replace('"', "\"\""))
+            } else {
+                f.to_string()
+            }
+        })
+        .collect();
+    quoted.join(",") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let records = parse("a,\"b,c\",\"say \"\"hi\"\"\"\r\n\n,x");
+        assert_eq!(
+            records,
+            vec![vec!["a", "b,c", "say \"hi\""], vec![""], vec!["", "x"]]
+        );
+        assert_eq!(parse(""), Vec::<Vec<String>>::new());
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let fields = ["B3", "\"Total, net", "=A1"];
+        let line = format_record(&fields);
+        assert_eq!(line, "B3,\"\"\"Total, net\",=A1\n");
+        assert_eq!(parse(&line), vec![fields.to_vec()]);
+    }
+}
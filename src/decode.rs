@@ -0,0 +1,322 @@
+//! Decoder for RAM dumps of a running sheet
+//!
+//! Takes an image of the board's RAM (0x2000-0x3FFF unless the ROM was
+//! built for another [`Layout`]), walks the 6-byte cell records and follows
+//! formula and label pointers into the `SCRATCH` heap. Each non-empty cell
+//! becomes one CSV record with its type, the text as typed and the value
+//! the ROM last stored. Anything that does not look like something the ROM
+//! could have written is reported as a [`Warning`] and left out of the
+//! value column.
+
+use std::fmt;
+
+use crate::codegen::{
+    CELL_ERROR, CELL_FORMULA, CELL_LABEL, CELL_NUMBER, CELL_REPEAT, CELL_SIZE, FORMULA_PTR,
+    FORMULA_VALUE,
+};
+use crate::csv;
+use crate::layout::Layout;
+use crate::model::{Bcd, CellError, Value};
+use crate::template::cell_name;
+
+/// Something in the dump the ROM would not have produced
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Warning {
+    /// `FORMULA_PTR` is outside the heap; strings are bounded by the heap end
+    FormulaPtr(u16),
+    /// Type byte that is not one of the `CELL_*` values
+    CorruptType { cell: String, kind: u8 },
+    /// Formula or label pointer outside the used part of the heap
+    DanglingPointer { cell: String, ptr: u16 },
+    /// Heap string runs past the used heap
+    Unterminated { cell: String, ptr: u16 },
+    /// Sign byte other than 0x00/0x80 or digits that are not packed BCD
+    CorruptValue { cell: String, bytes: Vec<u8> },
+    /// Error cell whose code is not one of the `ERR_*` values
+    CorruptError { cell: String, code: u8 },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::FormulaPtr(ptr) => write!(f, "FORMULA_PTR {:04X} is outside the heap", ptr),
+            Warning::CorruptType { cell, kind } => {
+                write!(f, "{}: unknown cell type {:02X}", cell, kind)
+            }
+            Warning::DanglingPointer { cell, ptr } => {
+                write!(
+                    f,
+                    "{}: pointer {:04X} is outside the formula heap",
+                    cell, ptr
+                )
+            }
+            Warning::Unterminated { cell, ptr } => {
+                write!(f, "{}: string at {:04X} runs past the used heap", cell, ptr)
+            }
+            Warning::CorruptValue { cell, bytes } => {
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                write!(
+                    f,
+                    "{}: value bytes {} are not a BCD number",
+                    cell,
+                    hex.join(" ")
+                )
+            }
+            Warning::CorruptError { cell, code } => {
+                write!(f, "{}: unknown error code {:02X}", cell, code)
+            }
+        }
+    }
+}
+
+/// One non-empty cell of the dump
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedCell {
+    pub col: u8,
+    pub row: u8,
+    /// `number`, `formula`, `error`, `repeat` or `label`
+    pub kind: &'static str,
+    /// Formula or label text as typed (with its `=` or `"`), repeat character
+    pub text: String,
+    /// Number or cached formula value, the error name for error cells
+    pub value: String,
+}
+
+/// Cells and warnings found in a dump
+#[derive(Clone, Debug, Default)]
+pub struct Decoded {
+    pub cells: Vec<DecodedCell>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Decoded {
+    /// CSV with a `cell,type,text,value` header and one record per cell
+    pub fn to_csv(&self) -> String {
+        let mut out = csv::format_record(&["cell", "type", "text", "value"]);
+        for c in &self.cells {
+            out += &csv::format_record(&[&cell_name(c.col, c.row), c.kind, &c.text, &c.value]);
+        }
+        out
+    }
+}
+
+/// View of the dumped RAM by absolute address
+struct Ram<'a> {
+    bytes: &'a [u8],
+    layout: &'a Layout,
+    /// End of the used heap (`FORMULA_PTR`, or the heap end if that is corrupt)
+    heap_end: u16,
+}
+
+impl Ram<'_> {
+    fn at(&self, addr: u16) -> u8 {
+        self.bytes[(addr - self.layout.ram_base()) as usize]
+    }
+
+    fn slice(&self, addr: u16, len: usize) -> &[u8] {
+        let start = (addr - self.layout.ram_base()) as usize;
+        &self.bytes[start..start + len]
+    }
+
+    /// NUL-terminated heap string at `ptr` and the address after the NUL
+    fn string(&self, cell: &str, ptr: u16) -> Result<(String, u16), Warning> {
+        if !(self.layout.scratch()..self.heap_end).contains(&ptr) {
+            return Err(Warning::DanglingPointer {
+                cell: cell.to_string(),
+                ptr,
+            });
+        }
+        let end = (ptr..self.heap_end)
+            .find(|&a| self.at(a) == 0)
+            .ok_or(Warning::Unterminated {
+                cell: cell.to_string(),
+                ptr,
+            })?;
+        let text = String::from_utf8_lossy(self.slice(ptr, (end - ptr) as usize)).into_owned();
+        Ok((text, end + 1))
+    }
+
+    /// Sign byte plus 4 BCD bytes, formatted like the display
+    fn value(&self, cell: &str, bytes: &[u8]) -> Result<String, Warning> {
+        let corrupt = || Warning::CorruptValue {
+            cell: cell.to_string(),
+            bytes: bytes.to_vec(),
+        };
+        let bcd = Bcd([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        if (bytes[0] != 0x00 && bytes[0] != 0x80) || bcd.hundredths().is_none() {
+            return Err(corrupt());
+        }
+        Ok(Value::new(bytes[0], bcd).to_string())
+    }
+}
+
+/// Decode a dump of the board's RAM (`layout.ram_size()` bytes from its base)
+pub fn decode(dump: &[u8], layout: &Layout) -> Result<Decoded, String> {
+    let base = layout.ram_base();
+    if dump.len() != layout.ram_size() {
+        return Err(format!(
+            "dump is {} bytes, expected {} ({:04X}-{:04X})",
+            dump.len(),
+            layout.ram_size(),
+            base,
+            layout.stack_top()
+        ));
+    }
+    let mut decoded = Decoded::default();
+    let ptr_at = (layout.var(FORMULA_PTR) - base) as usize;
+    let formula_ptr = u16::from_le_bytes([dump[ptr_at], dump[ptr_at + 1]]);
+    let heap_end = if (layout.scratch()..=layout.heap_end()).contains(&formula_ptr) {
+        formula_ptr
+    } else {
+        decoded.warnings.push(Warning::FormulaPtr(formula_ptr));
+        layout.heap_end()
+    };
+    let ram = Ram {
+        bytes: dump,
+        layout,
+        heap_end,
+    };
+
+    let cols = layout.cols() as usize;
+    for index in 0..layout.cell_count() {
+        let (col, row) = ((index % cols) as u8, (index / cols) as u8);
+        let record = ram.slice(layout.cell_addr(col, row), CELL_SIZE as usize);
+        let name = cell_name(col, row);
+        let ptr = u16::from_le_bytes([record[2], record[3]]);
+        let (kind, text, value) = match record[0] {
+            0 => continue,
+            CELL_NUMBER => ("number", Ok(String::new()), ram.value(&name, &record[1..])),
+            // The value is stored before the text
+            CELL_FORMULA if ptr < layout.scratch() + FORMULA_VALUE => {
+                let warning = Warning::DanglingPointer {
+                    cell: name.clone(),
+                    ptr,
+                };
+                ("formula", Err(warning), Ok(String::new()))
+            }
+            CELL_FORMULA => match ram.string(&name, ptr) {
+                Ok((text, _)) => {
+                    let value = ram.value(&name, ram.slice(ptr - FORMULA_VALUE, 5));
+                    ("formula", Ok(text), value)
+                }
+                Err(w) => ("formula", Err(w), Ok(String::new())),
+            },
+            CELL_ERROR => {
+                // A failed formula keeps its text; a bad number has no pointer
+                let text = match ptr >> 8 {
+                    0 => Ok(String::new()),
+                    _ => ram.string(&name, ptr).map(|(text, _)| text),
+                };
+                let value = CellError::from_code(record[1])
+                    .map(|e| e.name().to_string())
+                    .ok_or(Warning::CorruptError {
+                        cell: name.clone(),
+                        code: record[1],
+                    });
+                ("error", text, value)
+            }
+            CELL_REPEAT => (
+                "repeat",
+                Ok((record[2] as char).to_string()),
+                Ok(String::new()),
+            ),
+            CELL_LABEL => match ram.string(&name, ptr) {
+                Ok((text, _)) => ("label", Ok(text), Ok(String::new())),
+                Err(w) => ("label", Err(w), Ok(String::new())),
+            },
+            kind => {
+                decoded
+                    .warnings
+                    .push(Warning::CorruptType { cell: name, kind });
+                continue;
+            }
+        };
+        let mut keep = |r: Result<String, Warning>| {
+            r.unwrap_or_else(|w| {
+                decoded.warnings.push(w);
+                String::new()
+            })
+        };
+        let (text, value) = (keep(text), keep(value));
+        decoded.cells.push(DecodedCell {
+            col,
+            row,
+            kind,
+            text,
+            value,
+        });
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[test]
+    fn test_decode_rom_ram() {
+        let mut h = Harness::spreadsheet();
+        h.type_keys("12.5\rj-3\rj=A1*A2+1\rj\r\"Total, net\rl=zz\rj=1/0\rj-x\r");
+        let decoded = decode(h.ram(), h.layout()).unwrap();
+        assert_eq!(decoded.warnings, vec![]);
+        assert_eq!(
+            decoded.to_csv(),
+            "cell,type,text,value\n\
+             A1,number,,12.50\n\
+             A2,number,,-3.00\n\
+             A3,formula,=A1*A2+1,-36.50\n\
+             A4,label,\"\"\"Total, net\",\n\
+             B4,error,=zz,#REF\n\
+             B5,error,=1/0,#DIV/0\n\
+             B6,error,,#SYNTAX\n"
+        );
+    }
+
+    #[test]
+    fn test_decode_warnings() {
+        let mut h = Harness::spreadsheet();
+        h.type_keys("=1+1\rj\r\"x\r");
+        let layout = *h.layout();
+        let formula = layout.cell_addr(0, 0);
+        h.poke(formula + 3, 0x30); // formula pointer into cell data
+        h.poke(layout.cell_addr(0, 2), 9);
+        h.poke_bytes(
+            layout.cell_addr(1, 0),
+            &[CELL_NUMBER, 0x00, 0x00, 0x00, 0x1A, 0x00],
+        );
+        h.poke_bytes(layout.cell_addr(2, 0), &[CELL_ERROR, 0x09, 0, 0, 0, 0]);
+        let warnings = |h: &Harness| -> Vec<String> {
+            let decoded = decode(h.ram(), h.layout()).unwrap();
+            decoded.warnings.iter().map(|w| w.to_string()).collect()
+        };
+        assert_eq!(
+            warnings(&h),
+            [
+                "A1: pointer 3006 is outside the formula heap",
+                "B1: value bytes 00 00 00 1A 00 are not a BCD number",
+                "C1: unknown error code 09",
+                "A3: unknown cell type 09",
+            ]
+        );
+        assert_eq!(decode(h.ram(), &layout).unwrap().cells[3].text, "\"x");
+
+        // Cut the used heap inside the formula's text, before the label
+        h.poke(formula + 3, 0x3A);
+        let formula_ptr = layout.var(FORMULA_PTR);
+        h.poke_bytes(formula_ptr, &(layout.scratch() + 8).to_le_bytes());
+        assert_eq!(
+            warnings(&h),
+            [
+                "A1: string at 3A06 runs past the used heap",
+                "B1: value bytes 00 00 00 1A 00 are not a BCD number",
+                "C1: unknown error code 09",
+                "A2: pointer 3A17 is outside the formula heap",
+                "A3: unknown cell type 09",
+            ]
+        );
+        h.poke_bytes(formula_ptr, &0x1234u16.to_le_bytes());
+        assert_eq!(warnings(&h)[0], "FORMULA_PTR 1234 is outside the heap");
+        assert!(decode(&[0; 16], &layout).is_err());
+    }
+}
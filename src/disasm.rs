@@ -0,0 +1,334 @@
+//! Z80 disassembler for annotated ROM listings
+//!
+//! Decodes one instruction at a time using the same x/y/z/p/q opcode
+//! fields as the interpreter in [`crate::z80`]. Mnemonics use Zilog
+//! syntax with hex operands (`LD HL,$3DF8`, `JR NZ,$01A4`).
+
+/// A decoded instruction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    /// Length in bytes (1-4)
+    pub len: usize,
+    /// Mnemonic and operands
+    pub text: String,
+    /// Address operand that may name a label (jump/call target or 16-bit immediate)
+    pub target: Option<u16>,
+}
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const ACC: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+const IM: [&str; 8] = ["0", "0", "1", "2", "0", "0", "1", "2"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+/// Byte fetcher that tolerates running off the end of the image
+struct Reader<'a> {
+    mem: &'a [u8],
+    start: usize,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> u8 {
+        let b = self.mem.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        b
+    }
+
+    fn word(&mut self) -> u16 {
+        let lo = self.byte() as u16;
+        lo | (self.byte() as u16) << 8
+    }
+
+    /// Address of the next byte, as seen by the CPU
+    fn addr(&self, base: u16) -> u16 {
+        base.wrapping_add((self.pos - self.start) as u16)
+    }
+}
+
+/// Disassemble the instruction at `mem[offset]`, which lives at CPU address `addr`
+pub fn disassemble(mem: &[u8], offset: usize, addr: u16) -> Instruction {
+    let mut rd = Reader {
+        mem,
+        start: offset,
+        pos: offset,
+    };
+    let mut target = None;
+    let op = rd.byte();
+    let text = match op {
+        0xCB => decode_cb(&mut rd, None),
+        0xED => decode_ed(&mut rd, &mut target),
+        0xDD | 0xFD => {
+            let name = if op == 0xDD { "IX" } else { "IY" };
+            let next = rd.mem.get(rd.pos).copied().unwrap_or(0);
+            if matches!(next, 0xDD | 0xED | 0xFD) {
+                // Prefix has no effect; show it on its own
+                "NOP*".to_string()
+            } else if next == 0xCB {
+                rd.byte();
+                let d = rd.byte() as i8;
+                decode_cb(&mut rd, Some((name, d)))
+            } else {
+                let op = rd.byte();
+                decode_main(&mut rd, op, addr, Some(name), &mut target)
+            }
+        }
+        _ => decode_main(&mut rd, op, addr, None, &mut target),
+    };
+    Instruction {
+        len: rd.pos - offset,
+        text,
+        target,
+    }
+}
+
+/// Format an index displacement as `(IX+$05)` / `(IY-$02)`
+fn indexed(name: &str, d: i8) -> String {
+    if d < 0 {
+        format!("({}-${:02X})", name, d.unsigned_abs())
+    } else {
+        format!("({}+${:02X})", name, d)
+    }
+}
+
+fn decode_main(
+    rd: &mut Reader,
+    op: u8,
+    addr: u16,
+    ix: Option<&str>,
+    target: &mut Option<u16>,
+) -> String {
+    let x = op >> 6;
+    let y = (op >> 3) & 7;
+    let z = op & 7;
+    let p = (y >> 1) as usize;
+    let q = y & 1;
+    let hl = ix.unwrap_or("HL");
+
+    // Register operand, reading the displacement for (IX+d) when needed.
+    // H and L become IXH/IXL only when no (IX+d) operand is involved.
+    let mut disp: Option<i8> = None;
+    let mut reg = |rd: &mut Reader, r: u8, mem_used: bool| -> String {
+        match (r, ix) {
+            (6, Some(name)) => {
+                let d = *disp.get_or_insert_with(|| rd.byte() as i8);
+                indexed(name, d)
+            }
+            (4, Some(name)) if !mem_used => format!("{}H", name),
+            (5, Some(name)) if !mem_used => format!("{}L", name),
+            _ => R[r as usize].to_string(),
+        }
+    };
+    let rp = |p: usize| if p == 2 { hl } else { RP[p] };
+    let rp2 = |p: usize| if p == 2 { hl } else { RP2[p] };
+
+    match x {
+        0 => match z {
+            0 => match y {
+                0 => "NOP".to_string(),
+                1 => "EX AF,AF'".to_string(),
+                2 => format!("DJNZ {}", relative(rd, addr, target)),
+                3 => format!("JR {}", relative(rd, addr, target)),
+                _ => format!("JR {},{}", CC[(y - 4) as usize], relative(rd, addr, target)),
+            },
+            1 => {
+                if q == 0 {
+                    let nn = rd.word();
+                    *target = Some(nn);
+                    format!("LD {},${:04X}", rp(p), nn)
+                } else {
+                    format!("ADD {},{}", hl, rp(p))
+                }
+            }
+            2 => {
+                let (a, b) = match y {
+                    0 => ("(BC)".to_string(), "A".to_string()),
+                    1 => ("A".to_string(), "(BC)".to_string()),
+                    2 => ("(DE)".to_string(), "A".to_string()),
+                    3 => ("A".to_string(), "(DE)".to_string()),
+                    _ => {
+                        let nn = rd.word();
+                        *target = Some(nn);
+                        let m = format!("(${:04X})", nn);
+                        match y {
+                            4 => (m, hl.to_string()),
+                            5 => (hl.to_string(), m),
+                            6 => (m, "A".to_string()),
+                            _ => ("A".to_string(), m),
+                        }
+                    }
+                };
+                format!("LD {},{}", a, b)
+            }
+            3 => format!("{} {}", if q == 0 { "INC" } else { "DEC" }, rp(p)),
+            4 => format!("INC {}", reg(rd, y, y == 6)),
+            5 => format!("DEC {}", reg(rd, y, y == 6)),
+            6 => {
+                let dst = reg(rd, y, y == 6);
+                format!("LD {},${:02X}", dst, rd.byte())
+            }
+            _ => ACC[y as usize].to_string(),
+        },
+        1 => {
+            if y == 6 && z == 6 {
+                "HALT".to_string()
+            } else {
+                let mem = y == 6 || z == 6;
+                let dst = reg(rd, y, mem);
+                let src = reg(rd, z, mem);
+                format!("LD {},{}", dst, src)
+            }
+        }
+        2 => format!("{}{}", ALU[y as usize], reg(rd, z, z == 6)),
+        _ => match z {
+            0 => format!("RET {}", CC[y as usize]),
+            1 => match (q, p) {
+                (0, _) => format!("POP {}", rp2(p)),
+                (_, 0) => "RET".to_string(),
+                (_, 1) => "EXX".to_string(),
+                (_, 2) => format!("JP ({})", hl),
+                _ => format!("LD SP,{}", hl),
+            },
+            2 => {
+                let nn = rd.word();
+                *target = Some(nn);
+                format!("JP {},${:04X}", CC[y as usize], nn)
+            }
+            3 => match y {
+                0 => {
+                    let nn = rd.word();
+                    *target = Some(nn);
+                    format!("JP ${:04X}", nn)
+                }
+                2 => format!("OUT (${:02X}),A", rd.byte()),
+                3 => format!("IN A,(${:02X})", rd.byte()),
+                4 => format!("EX (SP),{}", hl),
+                5 => "EX DE,HL".to_string(),
+                6 => "DI".to_string(),
+                _ => "EI".to_string(),
+            },
+            4 => {
+                let nn = rd.word();
+                *target = Some(nn);
+                format!("CALL {},${:04X}", CC[y as usize], nn)
+            }
+            5 => {
+                if q == 0 {
+                    format!("PUSH {}", rp2(p))
+                } else {
+                    // Only 0xCD reaches here; the other prefixes are handled by the caller
+                    let nn = rd.word();
+                    *target = Some(nn);
+                    format!("CALL ${:04X}", nn)
+                }
+            }
+            6 => format!("{}${:02X}", ALU[y as usize], rd.byte()),
+            _ => format!("RST ${:02X}", y * 8),
+        },
+    }
+}
+
+/// Target of a JR/DJNZ displacement
+fn relative(rd: &mut Reader, addr: u16, target: &mut Option<u16>) -> String {
+    let d = rd.byte() as i8;
+    let t = rd.addr(addr).wrapping_add(d as u16);
+    *target = Some(t);
+    format!("${:04X}", t)
+}
+
+/// CB-prefixed rotates, shifts and bit operations (optionally DDCB/FDCB)
+fn decode_cb(rd: &mut Reader, index: Option<(&str, i8)>) -> String {
+    let op = rd.byte();
+    let x = op >> 6;
+    let y = op >> 3 & 7;
+    let z = op & 7;
+    let operand = match index {
+        Some((name, d)) => indexed(name, d),
+        None => R[z as usize].to_string(),
+    };
+    match x {
+        0 => format!("{} {}", ROT[y as usize], operand),
+        1 => format!("BIT {},{}", y, operand),
+        2 => format!("RES {},{}", y, operand),
+        _ => format!("SET {},{}", y, operand),
+    }
+}
+
+/// ED-prefixed extended instructions
+fn decode_ed(rd: &mut Reader, target: &mut Option<u16>) -> String {
+    let op = rd.byte();
+    let x = op >> 6;
+    let y = op >> 3 & 7;
+    let z = op & 7;
+    let p = (y >> 1) as usize;
+    let q = y & 1;
+    match x {
+        1 => match z {
+            0 if y == 6 => "IN (C)".to_string(),
+            0 => format!("IN {},(C)", R[y as usize]),
+            1 if y == 6 => "OUT (C),0".to_string(),
+            1 => format!("OUT (C),{}", R[y as usize]),
+            2 => format!("{} HL,{}", if q == 0 { "SBC" } else { "ADC" }, RP[p]),
+            3 => {
+                let nn = rd.word();
+                *target = Some(nn);
+                if q == 0 {
+                    format!("LD (${:04X}),{}", nn, RP[p])
+                } else {
+                    format!("LD {},(${:04X})", RP[p], nn)
+                }
+            }
+            4 => "NEG".to_string(),
+            5 if y == 1 => "RETI".to_string(),
+            5 => "RETN".to_string(),
+            6 => format!("IM {}", IM[y as usize]),
+            _ => ["LD I,A", "LD R,A", "LD A,I", "LD A,R", "RRD", "RLD", "NOP*", "NOP*"][y as usize]
+                .to_string(),
+        },
+        2 if z <= 3 && y >= 4 => BLOCK[(y - 4) as usize][z as usize].to_string(),
+        _ => format!("DB $ED,${:02X}", op),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis(bytes: &[u8], addr: u16) -> (usize, String) {
+        let i = disassemble(bytes, 0, addr);
+        (i.len, i.text)
+    }
+
+    #[test]
+    fn test_main_opcodes() {
+        assert_eq!(dis(&[0x31, 0xFF, 0x3F], 0), (3, "LD SP,$3FFF".into()));
+        assert_eq!(dis(&[0xCD, 0x34, 0x12], 0), (3, "CALL $1234".into()));
+        assert_eq!(dis(&[0x20, 0xFE], 0x100), (2, "JR NZ,$0100".into()));
+        assert_eq!(dis(&[0x10, 0x02], 0x100), (2, "DJNZ $0104".into()));
+        assert_eq!(dis(&[0x7E], 0), (1, "LD A,(HL)".into()));
+        assert_eq!(dis(&[0xDB, 0x80], 0), (2, "IN A,($80)".into()));
+        assert_eq!(dis(&[0xFE, 0x0D], 0), (2, "CP $0D".into()));
+        assert_eq!(dis(&[0x22, 0xF8, 0x3D], 0), (3, "LD ($3DF8),HL".into()));
+    }
+
+    #[test]
+    fn test_prefixed_opcodes() {
+        assert_eq!(dis(&[0xCB, 0x3F], 0), (2, "SRL A".into()));
+        assert_eq!(dis(&[0xED, 0xB0], 0), (2, "LDIR".into()));
+        assert_eq!(dis(&[0xED, 0x52], 0), (2, "SBC HL,DE".into()));
+        assert_eq!(dis(&[0xDD, 0x7E, 0xFE], 0), (3, "LD A,(IX-$02)".into()));
+        assert_eq!(dis(&[0xFD, 0x36, 0x03, 0x41], 0), (4, "LD (IY+$03),$41".into()));
+        assert_eq!(dis(&[0xDD, 0xCB, 0x01, 0x46], 0), (4, "BIT 0,(IX+$01)".into()));
+        assert_eq!(dis(&[0xDD, 0x66, 0x00], 0), (3, "LD H,(IX+$00)".into()));
+        assert_eq!(dis(&[0xDD, 0x7C], 0), (2, "LD A,IXH".into()));
+    }
+}
@@ -0,0 +1,544 @@
+//! Headless execution harness for the generated ROM
+//!
+//! Loads a ROM image into an emulated RetroShield Z80 board (8KB ROM at
+//! 0x0000, RAM where a [`Layout`] puts it, 8KB at 0x2000 by default) with a
+//! UART stand-in (MC6850 ACIA, Z80 SIO channel A or 8251) wired as a
+//! [`UartProfile`] describes. Tests feed
+//! keystrokes into the receive queue, run the CPU until the ROM blocks in
+//! `getchar`, and then inspect the transmitted bytes or the RAM image
+//! (cell data, formula storage, state variables).
+//!
+//! The stand-ins model each chip's control registers far enough that the
+//! receiver and transmitter stay disabled until the ROM has initialised
+//! the chip: bytes sent before that are lost, as on real hardware.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::codegen::{self, CELL_SIZE, FORMULA_PTR};
+use crate::layout::Layout;
+use crate::model::Sheet;
+use crate::screen::Screen;
+use crate::uart::{UartKind, UartProfile};
+use crate::z80::{Bus, Cpu};
+use crate::SpreadsheetCodeGen;
+
+/// ROM size mapped at address 0
+pub const ROM_SIZE: usize = codegen::ROM_SIZE;
+
+/// Return address pushed by [`Harness::call`]; execution stops when PC reaches it
+const CALL_SENTINEL: u16 = 0xFFFF;
+
+/// Cycle budget used by [`Harness::run_until_idle`] (about 12 seconds at 4MHz)
+pub const DEFAULT_CYCLE_BUDGET: u64 = 50_000_000;
+
+/// Why [`Harness::run`] returned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The ROM is polling for input and the receive queue is empty
+    Idle,
+    /// The CPU executed HALT
+    Halted,
+    /// A subroutine started by [`Harness::call`] returned
+    Returned,
+    /// The cycle budget ran out
+    CycleLimit,
+}
+
+/// Control register state of the UART stand-in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Chip {
+    /// MC6850: counter divide bits 11 are a master reset; any other
+    /// control word after a reset starts the chip
+    Mc6850 { reset: bool },
+    /// SIO: WR0 selects the register the next control write goes to;
+    /// WR3 bit 0 enables the receiver, WR5 bit 3 the transmitter
+    Sio { pointer: u8, wr3: u8, wr5: u8 },
+    /// 8251: a mode word (plus sync characters in sync mode), then command
+    /// words until one has the internal reset bit
+    I8251 { state: Usart },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Usart {
+    Mode,
+    Sync(u8),
+    Command,
+}
+
+impl Chip {
+    fn new(kind: UartKind) -> Self {
+        match kind {
+            UartKind::Mc6850 => Chip::Mc6850 { reset: false },
+            UartKind::Sio => Chip::Sio {
+                pointer: 0,
+                wr3: 0,
+                wr5: 0,
+            },
+            UartKind::I8251 => Chip::I8251 { state: Usart::Mode },
+        }
+    }
+
+    /// Real status register bits: (receive ready, transmit ready)
+    fn status_bits(self) -> (u8, u8) {
+        match self {
+            Chip::Mc6850 { .. } => (0x01, 0x02),
+            Chip::Sio { .. } => (0x01, 0x04),
+            Chip::I8251 { .. } => (0x02, 0x01),
+        }
+    }
+}
+
+/// Memory and serial port of the emulated board
+pub struct Board {
+    memory: Vec<u8>,
+    layout: Layout,
+    uart: UartProfile,
+    chip: Chip,
+    rx_enabled: bool,
+    tx_enabled: bool,
+    rx: VecDeque<u8>,
+    tx: Vec<u8>,
+    /// Bytes written to the data port while the transmitter was disabled
+    pub lost_tx: usize,
+    /// Last status read found the receive queue empty
+    polled_empty: bool,
+    /// Two status reads in a row found nothing to receive
+    idle: bool,
+}
+
+impl Board {
+    fn new(rom: &[u8], uart: UartProfile, layout: Layout) -> Self {
+        assert!(
+            rom.len() <= ROM_SIZE,
+            "ROM image is {} bytes, board has {}",
+            rom.len(),
+            ROM_SIZE
+        );
+        let mut memory = vec![0u8; 0x10000];
+        memory[..rom.len()].copy_from_slice(rom);
+        Self {
+            memory,
+            layout,
+            chip: Chip::new(uart.kind),
+            uart,
+            rx_enabled: false,
+            tx_enabled: false,
+            rx: VecDeque::new(),
+            tx: Vec::new(),
+            lost_tx: 0,
+            polled_empty: false,
+            idle: false,
+        }
+    }
+
+    fn is_ram(&self, addr: u16) -> bool {
+        self.layout.in_ram(addr)
+    }
+
+    /// Receiver and transmitter are both enabled
+    pub fn uart_ready(&self) -> bool {
+        self.rx_enabled && self.tx_enabled
+    }
+
+    fn write_control(&mut self, value: u8) {
+        match &mut self.chip {
+            Chip::Mc6850 { reset } => {
+                if value & 0x03 == 0x03 {
+                    *reset = true;
+                    self.rx_enabled = false;
+                    self.tx_enabled = false;
+                } else if *reset {
+                    self.rx_enabled = true;
+                    self.tx_enabled = true;
+                }
+            }
+            Chip::Sio { pointer, wr3, wr5 } => {
+                if *pointer == 0 {
+                    if (value >> 3) & 0x07 == 3 {
+                        // Channel reset
+                        *wr3 = 0;
+                        *wr5 = 0;
+                    }
+                    *pointer = value & 0x07;
+                } else {
+                    match *pointer {
+                        3 => *wr3 = value,
+                        5 => *wr5 = value,
+                        _ => {}
+                    }
+                    *pointer = 0;
+                }
+                self.rx_enabled = *wr3 & 0x01 != 0;
+                self.tx_enabled = *wr5 & 0x08 != 0;
+            }
+            Chip::I8251 { state } => {
+                *state = match *state {
+                    // Baud factor 00 selects sync mode: one or two sync characters follow
+                    Usart::Mode if value & 0x03 == 0 => {
+                        Usart::Sync(if value & 0x80 != 0 { 1 } else { 2 })
+                    }
+                    Usart::Mode | Usart::Sync(1) => Usart::Command,
+                    Usart::Sync(n) => Usart::Sync(n - 1),
+                    Usart::Command if value & 0x40 != 0 => {
+                        self.rx_enabled = false;
+                        self.tx_enabled = false;
+                        Usart::Mode
+                    }
+                    Usart::Command => {
+                        self.rx_enabled = value & 0x04 != 0;
+                        self.tx_enabled = value & 0x01 != 0;
+                        Usart::Command
+                    }
+                };
+            }
+        }
+    }
+}
+
+impl Bus for Board {
+    fn read(&mut self, addr: u16) -> u8 {
+        if (addr as usize) < ROM_SIZE || self.is_ram(addr) {
+            self.memory[addr as usize]
+        } else {
+            0xFF
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if self.is_ram(addr) {
+            self.memory[addr as usize] = value;
+        }
+    }
+
+    fn port_in(&mut self, port: u16) -> u8 {
+        let port = port as u8;
+        if port == self.uart.status_port {
+            let (rx_bit, tx_bit) = self.chip.status_bits();
+            let mut status = 0;
+            if self.tx_enabled {
+                status |= tx_bit;
+            }
+            if self.rx.is_empty() || !self.rx_enabled {
+                if self.polled_empty {
+                    self.idle = true;
+                }
+                self.polled_empty = true;
+            } else {
+                status |= rx_bit;
+            }
+            status
+        } else if port == self.uart.data_port {
+            self.polled_empty = false;
+            self.rx.pop_front().unwrap_or(0)
+        } else {
+            0xFF
+        }
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) {
+        let port = port as u8;
+        if port == self.uart.data_port {
+            self.polled_empty = false;
+            if self.tx_enabled {
+                self.tx.push(value);
+            } else {
+                self.lost_tx += 1;
+            }
+        } else if port == self.uart.status_port {
+            self.write_control(value);
+        }
+    }
+}
+
+/// Emulated board running a ROM image
+pub struct Harness {
+    pub cpu: Cpu,
+    pub board: Board,
+    /// Label addresses, when the ROM was generated by [`Harness::spreadsheet`]
+    symbols: HashMap<String, u16>,
+}
+
+impl Harness {
+    /// Load a ROM image and reset the CPU, with the default MC6850 at 0x80
+    /// and 8KB of RAM at 0x2000
+    pub fn new(rom: &[u8]) -> Self {
+        Self::with_hardware(rom, UartProfile::default(), Layout::default())
+    }
+
+    /// Load a ROM image on a board with the UART wired as `uart` describes
+    /// and RAM where `layout` puts it
+    pub fn with_hardware(rom: &[u8], uart: UartProfile, layout: Layout) -> Self {
+        Self {
+            cpu: Cpu::new(),
+            board: Board::new(rom, uart, layout),
+            symbols: HashMap::new(),
+        }
+    }
+
+    /// Generate the spreadsheet ROM and run it up to the first key prompt
+    pub fn spreadsheet() -> Self {
+        Self::boot(SpreadsheetCodeGen::new())
+    }
+
+    /// Generate a ROM from a configured code generator and boot it
+    pub fn boot(mut codegen: SpreadsheetCodeGen) -> Self {
+        if let Err(e) = codegen.generate() {
+            panic!("ROM build failed: {}", e);
+        }
+        let symbols = codegen.symbols().into_iter().collect();
+        let (uart, layout) = (codegen.uart().clone(), *codegen.layout());
+        let mut harness = Self::with_hardware(&codegen.into_rom(), uart, layout);
+        harness.symbols = symbols;
+        let stop = harness.run_until_idle();
+        assert_eq!(stop, StopReason::Idle, "ROM did not reach the input loop");
+        harness
+    }
+
+    /// Queue bytes on the UART receive side
+    pub fn send(&mut self, input: &[u8]) {
+        self.board.rx.extend(input.iter().copied());
+    }
+
+    /// Queue keystrokes and run until the ROM waits for more input
+    pub fn type_keys(&mut self, keys: &str) -> StopReason {
+        self.send(keys.as_bytes());
+        self.run_until_idle()
+    }
+
+    /// Run with the default cycle budget until idle, halted or out of cycles
+    pub fn run_until_idle(&mut self) -> StopReason {
+        self.run(DEFAULT_CYCLE_BUDGET)
+    }
+
+    /// Run for at most `max_cycles` T-states
+    pub fn run(&mut self, max_cycles: u64) -> StopReason {
+        let limit = self.cpu.cycles + max_cycles;
+        self.board.idle = false;
+        self.board.polled_empty = false;
+        while self.cpu.cycles < limit {
+            if self.cpu.halted {
+                return StopReason::Halted;
+            }
+            if self.cpu.pc == CALL_SENTINEL {
+                return StopReason::Returned;
+            }
+            self.cpu.step(&mut self.board);
+            if self.board.idle {
+                return StopReason::Idle;
+            }
+        }
+        StopReason::CycleLimit
+    }
+
+    /// Address of a ROM label, e.g. a subroutine to [`call`](Self::call)
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).copied()
+    }
+
+    /// ROM labels by address, when the ROM was generated by
+    /// [`Harness::spreadsheet`]
+    pub fn symbols(&self) -> Vec<(&str, u16)> {
+        let mut symbols: Vec<_> = self.symbols.iter().map(|(n, &a)| (n.as_str(), a)).collect();
+        symbols.sort_by_key(|&(_, addr)| addr);
+        symbols
+    }
+
+    /// Call a subroutine at `addr` and run until it returns
+    pub fn call(&mut self, addr: u16, max_cycles: u64) -> StopReason {
+        self.cpu.push(&mut self.board, CALL_SENTINEL);
+        self.cpu.pc = addr;
+        self.run(max_cycles)
+    }
+
+    /// Like [`call`](Self::call), handing `trace` the address and T-states
+    /// of each instruction run; input polling does not stop it
+    pub fn call_traced(
+        &mut self,
+        addr: u16,
+        max_cycles: u64,
+        mut trace: impl FnMut(u16, u64),
+    ) -> StopReason {
+        self.cpu.push(&mut self.board, CALL_SENTINEL);
+        self.cpu.pc = addr;
+        let limit = self.cpu.cycles + max_cycles;
+        while self.cpu.cycles < limit {
+            if self.cpu.halted {
+                return StopReason::Halted;
+            }
+            if self.cpu.pc == CALL_SENTINEL {
+                return StopReason::Returned;
+            }
+            let (pc, start) = (self.cpu.pc, self.cpu.cycles);
+            self.cpu.step(&mut self.board);
+            trace(pc, self.cpu.cycles - start);
+        }
+        StopReason::CycleLimit
+    }
+
+    /// Put a worksheet's cells, heap and `FORMULA_PTR` into RAM as the
+    /// model holds them; unlike a baked-in sheet this takes no ROM space
+    pub fn load_sheet(&mut self, sheet: &Sheet) {
+        let layout = *sheet.layout();
+        for row in 0..layout.rows() {
+            for col in 0..layout.cols() {
+                self.poke_bytes(layout.cell_addr(col, row), &sheet.cell(col, row));
+            }
+        }
+        let used = (sheet.formula_ptr() - layout.scratch()) as usize;
+        self.poke_bytes(layout.scratch(), &sheet.heap()[..used]);
+        self.poke_bytes(layout.var(FORMULA_PTR), &sheet.formula_ptr().to_le_bytes());
+    }
+
+    /// Everything the ROM has transmitted so far
+    pub fn output(&self) -> &[u8] {
+        &self.board.tx
+    }
+
+    /// Take and clear the transmitted bytes
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.board.tx)
+    }
+
+    /// Replay everything transmitted so far into a fresh VT220 screen
+    pub fn screen(&self) -> Screen {
+        let mut screen = Screen::new();
+        screen.feed(self.output());
+        screen
+    }
+
+    /// The RAM image (0x2000-0x3FFF in the default layout)
+    pub fn ram(&self) -> &[u8] {
+        let base = self.board.layout.ram_base() as usize;
+        &self.board.memory[base..base + self.board.layout.ram_size()]
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.board.memory[addr as usize]
+    }
+
+    pub fn peek_word(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.peek(addr), self.peek(addr.wrapping_add(1))])
+    }
+
+    pub fn peek_bytes(&self, addr: u16, len: usize) -> &[u8] {
+        &self.board.memory[addr as usize..addr as usize + len]
+    }
+
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self.board.write(addr, value);
+    }
+
+    pub fn poke_bytes(&mut self, addr: u16, bytes: &[u8]) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.board.write(addr.wrapping_add(i as u16), b);
+        }
+    }
+
+    /// RAM layout of the board (and the ROM it runs)
+    pub fn layout(&self) -> &Layout {
+        &self.board.layout
+    }
+
+    /// Address of a cell in the cell data (0-based column and row)
+    pub fn cell_addr(&self, col: u8, row: u8) -> u16 {
+        self.board.layout.cell_addr(col, row)
+    }
+
+    /// Raw 6-byte cell record (type, sign, 4 bytes of BCD or pointer)
+    pub fn cell(&self, col: u8, row: u8) -> &[u8] {
+        self.peek_bytes(self.cell_addr(col, row), CELL_SIZE as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_reaches_input_loop() {
+        let h = Harness::spreadsheet();
+        let out = String::from_utf8_lossy(h.output());
+        assert!(out.starts_with("kz80_calc v0.1\r\n"));
+        assert!(out.contains("Arrows:move"));
+        assert!(h.ram()[..6144].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_number_entry_stores_bcd() {
+        let mut h = Harness::spreadsheet();
+        assert_eq!(h.type_keys("12.5\r"), StopReason::Idle);
+        assert_eq!(h.cell(0, 0), &[1, 0x00, 0x00, 0x00, 0x12, 0x50]);
+
+        // Move right and enter a negative number in B1
+        h.type_keys("l-3\r");
+        assert_eq!(h.cell(1, 0), &[1, 0x80, 0x00, 0x00, 0x03, 0x00]);
+    }
+
+    #[test]
+    fn test_formula_value_precedes_text() {
+        let mut h = Harness::spreadsheet();
+        h.type_keys("4\r");
+        h.type_keys("j=A1*3\r");
+        let cell = h.cell(0, 1).to_vec();
+        assert_eq!(cell[0], 2);
+        let ptr = u16::from_le_bytes([cell[2], cell[3]]);
+        assert_eq!(h.peek_bytes(ptr, 6), b"=A1*3\0");
+        // Sign byte, then 12.00 in packed BCD, then the tokens' offset
+        assert_eq!(h.peek_bytes(ptr - 6, 6), &[0x00, 0x00, 0x00, 0x12, 0x00, 6]);
+        // A1, '*', 3.00
+        assert_eq!(
+            h.peek_bytes(ptr + 6, 10),
+            &[3, 0, 0, b'*', 1, 0, 0, 3, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_uart_backends() {
+        for uart in [
+            UartProfile::mc6850(0x80),
+            UartProfile::sio(0x10),
+            UartProfile::i8251(0xE0),
+        ] {
+            let kind = uart.kind;
+            let mut codegen = SpreadsheetCodeGen::new();
+            codegen.set_uart(uart);
+            let mut h = Harness::boot(codegen);
+            assert!(h.board.uart_ready(), "{} not initialised", kind);
+            assert_eq!(h.board.lost_tx, 0, "{}", kind);
+            let out = String::from_utf8_lossy(h.output());
+            assert!(out.starts_with("kz80_calc v0.1\r\n"), "{}", kind);
+            assert_eq!(h.type_keys("12.5\r"), StopReason::Idle, "{}", kind);
+            assert_eq!(h.cell(0, 0), &[1, 0x00, 0x00, 0x00, 0x12, 0x50], "{}", kind);
+        }
+    }
+
+    #[test]
+    fn test_uart_needs_init() {
+        // Without the init writes the chip never starts: putchar waits forever
+        let mut uart = UartProfile::sio(0x80);
+        uart.init.clear();
+        let mut codegen = SpreadsheetCodeGen::new();
+        codegen.set_uart(uart);
+        let h = Harness::boot(codegen);
+        assert!(!h.board.uart_ready());
+        assert!(h.output().is_empty());
+    }
+
+    #[test]
+    fn test_uart_base_limit() {
+        // FE still leaves FF for the second port; FF would wrap to port 00
+        let uart = UartProfile::from_name("8251", Some(crate::uart::MAX_BASE)).unwrap();
+        assert_eq!((uart.data_port, uart.status_port), (0xFE, 0xFF));
+        for name in ["mc6850", "sio", "8251"] {
+            let base = Some(0xFF);
+            assert!(std::panic::catch_unwind(|| UartProfile::from_name(name, base)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_quit_halts() {
+        let mut h = Harness::spreadsheet();
+        assert_eq!(h.type_keys("q"), StopReason::Halted);
+        assert!(String::from_utf8_lossy(h.output()).ends_with("Goodbye!\r\n"));
+    }
+}
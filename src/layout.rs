@@ -0,0 +1,290 @@
+//! RAM layout and grid dimensions
+//!
+//! Every RAM address the ROM uses is derived from the RAM base, the RAM
+//! size and the grid size. From the bottom of RAM up:
+//!
+//! ```text
+//!   ram_base        Cell data (cols x rows x 6 bytes)
+//!   + cells         Input buffer and display line buffer (512 bytes)
+//!   + 512           Formula/label heap, up to the state block
+//!   top - 0x23F     State block: BCD work area and variables (64 bytes)
+//!   top - 0x1FF     Stack (512 bytes), growing down from the last RAM byte
+//! ```
+//!
+//! The default is the RetroShield's 8KB at 0x2000 with a 16x64 grid,
+//! which puts the cells at 0x2000, the heap at 0x3A00 and the state block
+//! at 0x3DC0.
+
+use std::fmt;
+
+use crate::codegen::{CELL_SIZE, ROM_SIZE};
+
+/// RetroShield RAM: 8KB right after the ROM
+pub const DEFAULT_RAM_BASE: u16 = 0x2000;
+pub const DEFAULT_RAM_SIZE: usize = 0x2000;
+pub const DEFAULT_COLS: u8 = 16;
+pub const DEFAULT_ROWS: u8 = 64;
+
+/// Columns are single letters A-Z
+pub const MAX_COLS: u8 = 26;
+/// `/G` reads at most two row digits
+pub const MAX_ROWS: u8 = 99;
+
+/// Input line plus display line buffer
+const BUFFER_SIZE: u16 = 0x200;
+/// BCD work area and state variables
+const STATE_SIZE: u16 = 0x40;
+const STACK_SIZE: u16 = 0x200;
+/// Smallest heap worth building a ROM for (a few dozen formulas)
+pub const MIN_HEAP: usize = 0x100;
+
+/// Why a RAM size and grid cannot be laid out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    /// RAM is empty or runs past 0xFFFF
+    RamRange { base: u16, size: usize },
+    /// RAM starts inside the 8KB ROM
+    RamOverlapsRom { base: u16 },
+    /// Column count outside 1-26
+    Columns(u8),
+    /// Row count outside 1-99
+    Rows(u8),
+    /// Cells, buffers, a minimal heap, state and stack need more RAM
+    TooSmall { needed: usize, size: usize },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayoutError::RamRange { base, size } => write!(
+                f,
+                "{} bytes of RAM at {:04X} do not fit the address space",
+                size, base
+            ),
+            LayoutError::RamOverlapsRom { base } => write!(
+                f,
+                "RAM at {:04X} overlaps the ROM (0000-{:04X})",
+                base,
+                ROM_SIZE - 1
+            ),
+            LayoutError::Columns(cols) => {
+                write!(f, "{} columns, must be 1-{}", cols, MAX_COLS)
+            }
+            LayoutError::Rows(rows) => write!(f, "{} rows, must be 1-{}", rows, MAX_ROWS),
+            LayoutError::TooSmall { needed, size } => write!(
+                f,
+                "grid and work areas need {} bytes of RAM, only {} available",
+                needed, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// Where everything lives in RAM, and how big the grid is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    ram_base: u16,
+    ram_size: usize,
+    cols: u8,
+    rows: u8,
+    input_buf: u16,
+    scratch: u16,
+    state: u16,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_RAM_BASE,
+            DEFAULT_RAM_SIZE,
+            DEFAULT_COLS,
+            DEFAULT_ROWS,
+        )
+        .expect("default layout is valid")
+    }
+}
+
+impl Layout {
+    /// Lay out `cols` x `rows` cells in `ram_size` bytes of RAM at `ram_base`
+    pub fn new(ram_base: u16, ram_size: usize, cols: u8, rows: u8) -> Result<Self, LayoutError> {
+        if ram_size == 0 || ram_base as usize + ram_size > 0x10000 {
+            return Err(LayoutError::RamRange {
+                base: ram_base,
+                size: ram_size,
+            });
+        }
+        if (ram_base as usize) < ROM_SIZE {
+            return Err(LayoutError::RamOverlapsRom { base: ram_base });
+        }
+        if !(1..=MAX_COLS).contains(&cols) {
+            return Err(LayoutError::Columns(cols));
+        }
+        if !(1..=MAX_ROWS).contains(&rows) {
+            return Err(LayoutError::Rows(rows));
+        }
+        let cells = cols as usize * rows as usize * CELL_SIZE as usize;
+        let fixed = (BUFFER_SIZE + STATE_SIZE + STACK_SIZE) as usize;
+        let needed = cells + fixed + MIN_HEAP;
+        if needed > ram_size {
+            return Err(LayoutError::TooSmall {
+                needed,
+                size: ram_size,
+            });
+        }
+        let input_buf = ram_base + cells as u16;
+        let top = ram_base as usize + ram_size;
+        Ok(Self {
+            ram_base,
+            ram_size,
+            cols,
+            rows,
+            input_buf,
+            scratch: input_buf + BUFFER_SIZE,
+            state: (top - (STACK_SIZE + STATE_SIZE) as usize) as u16,
+        })
+    }
+
+    /// First RAM address
+    pub fn ram_base(&self) -> u16 {
+        self.ram_base
+    }
+
+    /// RAM size in bytes
+    pub fn ram_size(&self) -> usize {
+        self.ram_size
+    }
+
+    /// `addr` is in RAM
+    pub fn in_ram(&self, addr: u16) -> bool {
+        addr >= self.ram_base && ((addr - self.ram_base) as usize) < self.ram_size
+    }
+
+    /// Columns in the grid (A and up)
+    pub fn cols(&self) -> u8 {
+        self.cols
+    }
+
+    /// Rows in the grid (1 and up)
+    pub fn rows(&self) -> u8 {
+        self.rows
+    }
+
+    /// Total cells in the grid
+    pub fn cell_count(&self) -> usize {
+        self.cols as usize * self.rows as usize
+    }
+
+    /// Start of the cell records, row by row
+    pub fn cell_data(&self) -> u16 {
+        self.ram_base
+    }
+
+    /// Address of the 6-byte record of a cell (0-based column and row)
+    pub fn cell_addr(&self, col: u8, row: u8) -> u16 {
+        self.cell_data() + (row as u16 * self.cols as u16 + col as u16) * CELL_SIZE as u16
+    }
+
+    /// Line editor buffer
+    pub fn input_buf(&self) -> u16 {
+        self.input_buf
+    }
+
+    /// Display line buffer, the upper half of the buffer block; the
+    /// financial functions use it as work space
+    pub fn line_buf(&self) -> u16 {
+        self.input_buf + BUFFER_SIZE / 2
+    }
+
+    /// Start of the formula/label heap
+    pub fn scratch(&self) -> u16 {
+        self.scratch
+    }
+
+    /// End of the heap (exclusive): the state block starts here
+    pub fn heap_end(&self) -> u16 {
+        self.state
+    }
+
+    /// Address of a state variable, from its offset in the state block
+    pub fn var(&self, offset: u16) -> u16 {
+        debug_assert!(offset < STATE_SIZE);
+        self.state + offset
+    }
+
+    /// Initial stack pointer: the last RAM byte
+    pub fn stack_top(&self) -> u16 {
+        (self.ram_base as usize + self.ram_size - 1) as u16
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} grid, RAM {:04X}-{:04X}, heap {:04X}-{:04X}",
+            self.cols,
+            self.rows,
+            self.ram_base,
+            self.stack_top(),
+            self.scratch,
+            self.state - 1
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_layout() {
+        let layout = Layout::default();
+        assert_eq!(layout.cell_data(), 0x2000);
+        assert_eq!(layout.input_buf(), 0x3800);
+        assert_eq!(layout.scratch(), 0x3A00);
+        assert_eq!(layout.heap_end(), 0x3DC0);
+        assert_eq!(layout.stack_top(), 0x3FFF);
+        assert_eq!(layout.cell_addr(15, 63), 0x37FA);
+        assert_eq!(
+            layout.to_string(),
+            "16x64 grid, RAM 2000-3FFF, heap 3A00-3DBF"
+        );
+    }
+
+    #[test]
+    fn test_layout_errors() {
+        assert_eq!(
+            Layout::new(0x8000, 0x8001, 16, 64),
+            Err(LayoutError::RamRange {
+                base: 0x8000,
+                size: 0x8001
+            })
+        );
+        assert_eq!(
+            Layout::new(0x1000, 0x8000, 16, 64),
+            Err(LayoutError::RamOverlapsRom { base: 0x1000 })
+        );
+        assert_eq!(
+            Layout::new(0x2000, 0x2000, 27, 64),
+            Err(LayoutError::Columns(27))
+        );
+        assert_eq!(
+            Layout::new(0x2000, 0x2000, 16, 0),
+            Err(LayoutError::Rows(0))
+        );
+        // 26x99 cells alone are 15444 bytes
+        assert_eq!(
+            Layout::new(0x2000, 0x2000, 26, 99),
+            Err(LayoutError::TooSmall {
+                needed: 15444 + 0x440 + MIN_HEAP,
+                size: 0x2000
+            })
+        );
+        let big = Layout::new(0x8000, 0x8000, 26, 99).unwrap();
+        assert_eq!(big.scratch(), 0x8000 + 15444 + 0x200);
+        assert_eq!(big.heap_end(), 0xFDC0);
+        assert_eq!(big.stack_top(), 0xFFFF);
+    }
+}
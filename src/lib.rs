@@ -5,6 +5,20 @@
 //!
 //! Built on the retroshield-z80 framework.
 
+pub mod bench;
 pub mod codegen;
+pub mod csv;
+pub mod decode;
+pub mod disasm;
+pub mod harness;
+pub mod layout;
+pub mod model;
+pub mod screen;
+pub mod template;
+pub mod uart;
+pub mod z80;
 
 pub use codegen::SpreadsheetCodeGen;
+
+#[cfg(test)]
+mod bcd_fuzz;
@@ -1,32 +1,257 @@
 //! kz80_calc - VisiCalc-style spreadsheet for Z80
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::process;
 
-use kz80_calc::SpreadsheetCodeGen;
+use kz80_calc::layout::{self, Layout};
+use kz80_calc::uart::{self, UartProfile};
+use kz80_calc::{bench, decode, template, SpreadsheetCodeGen};
 
 fn print_help() {
     eprintln!("kz80_calc - VisiCalc-style spreadsheet for Z80");
     eprintln!();
     eprintln!("Usage: kz80_calc [options]");
+    eprintln!("       kz80_calc decode <dump.bin> [-o <file>] [layout options]");
+    eprintln!("       kz80_calc bench <sheet.csv> [layout options]");
     eprintln!();
     eprintln!("Options:");
     eprintln!("  -o <file>     Output binary file (default: calc.bin)");
+    eprintln!("  -m <file>     Write symbol map (label addresses)");
+    eprintln!("  -l <file>     Write annotated disassembly listing");
+    eprintln!("  --sheet <csv> Load this worksheet at power-on");
+    eprintln!("  --uart <type> Serial chip: mc6850 (default), sio or 8251");
+    eprintln!("  --uart-base <port>");
+    eprintln!("                UART base port in hex, 00-FE (default: 80)");
     eprintln!("  -h, --help    Show this help");
     eprintln!();
+    eprintln!("Layout options:");
+    eprintln!("  --ram-base <addr>  RAM start address in hex (default: 2000)");
+    eprintln!("  --ram-size <size>  RAM size in hex bytes or KB, e.g. 32K (default: 8K)");
+    eprintln!("  --grid <cols>x<rows>");
+    eprintln!("                     Grid size, up to 26x99 (default: 16x64)");
+    eprintln!();
     eprintln!("Examples:");
     eprintln!("  kz80_calc                    Generate calc.bin");
     eprintln!("  kz80_calc -o spreadsheet.bin Generate spreadsheet.bin");
+    eprintln!("  kz80_calc -m calc.map -l calc.lst");
+    eprintln!("                               Also write symbol map and listing");
+    eprintln!("  kz80_calc --sheet budget.csv Start with budget.csv loaded");
+    eprintln!("  kz80_calc --uart sio --uart-base 10");
+    eprintln!("                               Z80 SIO channel A at ports 10/11");
+    eprintln!("  kz80_calc --ram-base 8000 --ram-size 32K --grid 26x99");
+    eprintln!("                               Full A-Z grid for 32KB RAM at 8000");
+    eprintln!("  kz80_calc decode ram.bin     Print the cells of a 2000-3FFF RAM dump as CSV");
+    eprintln!("  kz80_calc bench budget.csv   Time a recalculation of budget.csv, by routine");
+}
+
+/// Value of a hex option, or exit with an error
+fn parse_hex(option: &str, text: &str) -> u32 {
+    u32::from_str_radix(text.trim_start_matches("0x"), 16).unwrap_or_else(|_| {
+        eprintln!("Error: {}: {} is not a hex number", option, text);
+        process::exit(1);
+    })
+}
+
+/// `--ram-base`, `--ram-size` and `--grid`, shared by building and decoding
+struct LayoutOptions {
+    ram_base: u32,
+    ram_size: u32,
+    cols: u32,
+    rows: u32,
+}
+
+impl LayoutOptions {
+    fn new() -> Self {
+        Self {
+            ram_base: layout::DEFAULT_RAM_BASE as u32,
+            ram_size: layout::DEFAULT_RAM_SIZE as u32,
+            cols: layout::DEFAULT_COLS as u32,
+            rows: layout::DEFAULT_ROWS as u32,
+        }
+    }
+
+    /// Take `args[i]` and its value if it is a layout option
+    fn parse(&mut self, args: &[String], i: usize) -> bool {
+        let option = args[i].as_str();
+        if !matches!(option, "--ram-base" | "--ram-size" | "--grid") {
+            return false;
+        }
+        let Some(value) = args.get(i + 1) else {
+            eprintln!("Error: {} requires an argument", option);
+            process::exit(1);
+        };
+        match option {
+            "--ram-base" => self.ram_base = parse_hex(option, value),
+            "--ram-size" => {
+                self.ram_size = match value.strip_suffix(['K', 'k']) {
+                    Some(kb) => kb.parse::<u32>().map_or(0, |kb| kb * 1024),
+                    None => parse_hex(option, value),
+                }
+            }
+            _ => {
+                let size = value
+                    .split_once(['x', 'X'])
+                    .and_then(|(c, r)| Some((c.parse::<u32>().ok()?, r.parse::<u32>().ok()?)));
+                let Some((cols, rows)) = size else {
+                    eprintln!("Error: --grid: {} is not <cols>x<rows>", value);
+                    process::exit(1);
+                };
+                (self.cols, self.rows) = (cols, rows);
+            }
+        }
+        true
+    }
+
+    /// The validated layout, or exit with the reason it does not fit
+    fn layout(&self) -> Layout {
+        let fits = |v: u32, max: u32| v.min(max + 1);
+        Layout::new(
+            fits(self.ram_base, 0xFFFF) as u16,
+            self.ram_size as usize,
+            fits(self.cols, 0xFF) as u8,
+            fits(self.rows, 0xFF) as u8,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        })
+    }
+}
+
+fn write_text(path: &str, text: &str) {
+    let mut file = File::create(path).expect("Failed to create output file");
+    file.write_all(text.as_bytes()).expect("Failed to write output file");
+}
+
+/// `decode <dump.bin> [-o <file>]`: RAM dump to CSV, warnings on stderr
+fn run_decode(args: &[String]) {
+    let mut input: Option<&String> = None;
+    let mut output: Option<&String> = None;
+    let mut layout = LayoutOptions::new();
+    let mut i = 0;
+    while i < args.len() {
+        if layout.parse(args, i) {
+            i += 2;
+            continue;
+        }
+        match args[i].as_str() {
+            "-o" => {
+                if i + 1 >= args.len() {
+                    eprintln!("Error: -o requires an argument");
+                    process::exit(1);
+                }
+                output = Some(&args[i + 1]);
+                i += 2;
+            }
+            arg if input.is_none() && !arg.starts_with('-') => {
+                input = Some(&args[i]);
+                i += 1;
+            }
+            arg => {
+                eprintln!("Unknown option: {}", arg);
+                print_help();
+                process::exit(1);
+            }
+        }
+    }
+    let Some(input) = input else {
+        eprintln!("Error: decode requires a dump file");
+        process::exit(1);
+    };
+
+    let dump = fs::read(input).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", input, e);
+        process::exit(1);
+    });
+    let decoded = decode::decode(&dump, &layout.layout()).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", input, e);
+        process::exit(1);
+    });
+    for warning in &decoded.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    match output {
+        Some(path) => {
+            write_text(path, &decoded.to_csv());
+            eprintln!("Wrote {} cells: {}", decoded.cells.len(), path);
+        }
+        None => print!("{}", decoded.to_csv()),
+    }
+}
+
+/// `bench <sheet.csv>`: time a recalculation of the sheet on the emulator
+fn run_bench(args: &[String]) {
+    let mut input: Option<&String> = None;
+    let mut layout = LayoutOptions::new();
+    let mut i = 0;
+    while i < args.len() {
+        if layout.parse(args, i) {
+            i += 2;
+            continue;
+        }
+        match args[i].as_str() {
+            arg if input.is_none() && !arg.starts_with('-') => {
+                input = Some(&args[i]);
+                i += 1;
+            }
+            arg => {
+                eprintln!("Unknown option: {}", arg);
+                print_help();
+                process::exit(1);
+            }
+        }
+    }
+    let Some(input) = input else {
+        eprintln!("Error: bench requires a CSV sheet");
+        process::exit(1);
+    };
+
+    let text = fs::read_to_string(input).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", input, e);
+        process::exit(1);
+    });
+    let sheet = template::load(&text, layout.layout()).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", input, e);
+        process::exit(1);
+    });
+    match bench::time_recalc(&sheet) {
+        Ok(timing) => print!("{}", timing),
+        Err(stop) => {
+            eprintln!("Error: {}: recalculation did not return ({:?})", input, stop);
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("decode") => {
+            run_decode(&args[2..]);
+            return;
+        }
+        Some("bench") => {
+            run_bench(&args[2..]);
+            return;
+        }
+        _ => {}
+    }
     let mut output_file = "calc.bin".to_string();
+    let mut map_file: Option<String> = None;
+    let mut listing_file: Option<String> = None;
+    let mut sheet_file: Option<String> = None;
+    let mut uart_name = "mc6850".to_string();
+    let mut uart_base: Option<u8> = None;
+    let mut layout = LayoutOptions::new();
 
     let mut i = 1;
     while i < args.len() {
+        if layout.parse(&args, i) {
+            i += 2;
+            continue;
+        }
         match args[i].as_str() {
             "-h" | "--help" => {
                 print_help();
@@ -40,6 +265,55 @@ fn main() {
                 output_file = args[i + 1].clone();
                 i += 2;
             }
+            "-m" | "-l" => {
+                if i + 1 >= args.len() {
+                    eprintln!("Error: {} requires an argument", args[i]);
+                    process::exit(1);
+                }
+                if args[i] == "-m" {
+                    map_file = Some(args[i + 1].clone());
+                } else {
+                    listing_file = Some(args[i + 1].clone());
+                }
+                i += 2;
+            }
+            "--sheet" => {
+                if i + 1 >= args.len() {
+                    eprintln!("Error: --sheet requires an argument");
+                    process::exit(1);
+                }
+                sheet_file = Some(args[i + 1].clone());
+                i += 2;
+            }
+            "--uart" => {
+                if i + 1 >= args.len() {
+                    eprintln!("Error: --uart requires an argument");
+                    process::exit(1);
+                }
+                uart_name = args[i + 1].clone();
+                i += 2;
+            }
+            "--uart-base" => {
+                if i + 1 >= args.len() {
+                    eprintln!("Error: --uart-base requires an argument");
+                    process::exit(1);
+                }
+                match u8::try_from(parse_hex("--uart-base", &args[i + 1])) {
+                    Ok(port) if port <= uart::MAX_BASE => uart_base = Some(port),
+                    Ok(_) => {
+                        eprintln!(
+                            "Error: --uart-base: {} leaves no port above it for the UART",
+                            args[i + 1]
+                        );
+                        process::exit(1);
+                    }
+                    Err(_) => {
+                        eprintln!("Error: --uart-base: {} is not a port", args[i + 1]);
+                        process::exit(1);
+                    }
+                }
+                i += 2;
+            }
             arg => {
                 eprintln!("Unknown option: {}", arg);
                 print_help();
@@ -49,8 +323,50 @@ fn main() {
     }
 
     // Generate the spreadsheet ROM
+    let layout = layout.layout();
     let mut codegen = SpreadsheetCodeGen::new();
-    codegen.generate();
+    codegen.set_layout(layout);
+    match UartProfile::from_name(&uart_name, uart_base) {
+        Some(uart) => codegen.set_uart(uart),
+        None => {
+            eprintln!(
+                "Error: unknown UART {} (use mc6850, sio or 8251)",
+                uart_name
+            );
+            process::exit(1);
+        }
+    }
+    if let Some(path) = &sheet_file {
+        let text = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Error: {}: {}", path, e);
+            process::exit(1);
+        });
+        match template::load(&text, layout) {
+            Ok(sheet) => codegen.set_sheet(sheet),
+            Err(e) => {
+                eprintln!("Error: {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(e) = codegen.generate() {
+        eprintln!("Error: ROM build failed:");
+        for problem in &e.problems {
+            eprintln!("  {}", problem);
+        }
+        process::exit(1);
+    }
+
+    if let Some(path) = &map_file {
+        write_text(path, &codegen.symbol_map());
+        eprintln!("Wrote symbol map: {}", path);
+    }
+    if let Some(path) = &listing_file {
+        write_text(path, &codegen.listing());
+        eprintln!("Wrote listing: {}", path);
+    }
+
+    let uart = codegen.uart().clone();
     let rom = codegen.into_rom();
 
     // Write output file
@@ -59,4 +375,9 @@ fn main() {
 
     eprintln!("Generated spreadsheet binary: {}", output_file);
     eprintln!("  {} bytes", rom.len());
+    eprintln!(
+        "  {} UART, status {:02X}, data {:02X}",
+        uart.kind, uart.status_port, uart.data_port
+    );
+    eprintln!("  {}", layout);
 }
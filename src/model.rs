@@ -0,0 +1,2980 @@
+//! Host-side reference model of the spreadsheet engine
+//!
+//! Mirrors what the generated ROM does, quirks included, so templates can
+//! be checked without hardware and differential tests have an oracle:
+//!
+//! - cells are the same raw 6-byte records as `CELL_DATA`; an error cell
+//!   holds its [`CellError`] code and its formula pointer (0 if it has no
+//!   formula) and keeps whatever value bytes the cell held before
+//! - labels and formulas are appended to a heap that mirrors `SCRATCH`;
+//!   a formula is kept with the tokens [`lex`] compiles from it, which the
+//!   ROM evaluates and which fail wherever the text does, so the model
+//!   evaluates the text
+//! - the grid size and heap bounds come from the sheet's [`Layout`]
+//! - numbers are 8-digit packed BCD in 6.2 fixed point; products and
+//!   quotients are truncated, and a number or result that needs more than
+//!   8 digits is an overflow error
+//! - `*` and `/` bind tighter than `+` and `-`, operators of equal
+//!   precedence group left to right, and parentheses nest up to
+//!   `MAX_NESTING` deep
+//! - `@SUM/@AVG/@MIN/@MAX/@COUNT` take ranges, cells and literal numbers
+//!   and walk ranges column by column
+//! - comparisons and `@IF/@AND/@OR/@NOT` give 1.00 or 0.00, and arguments
+//!   that cannot change the result are skipped without being checked
+//! - `@LOOKUP` stops at the first entry above the value, so its table must
+//!   be in ascending order; `@CHOOSE` evaluates only the chosen argument
+//! - `@INT` truncates toward zero, `@ROUND` rounds halves away from zero
+//!   and `@MOD` takes the sign of its first argument
+//! - `@NPV/@PMT/@PV/@FV` work in 12-digit decimal floats with exact
+//!   24-digit products, truncate every step and round the result half up
+//! - a formula that reads an error cell fails with that cell's error;
+//!   `@ISERROR` traps any failure of its argument
+//! - recalculation evaluates each formula after the cells it reads; a
+//!   reference cycle fails with `#CIRC` at the formula reached by following
+//!   the cells the first stuck formula waits for
+//! - changes are recalculated at once unless the mode is manual, and the
+//!   sweeps go row by row or column by column
+//! - with iteration a cycle keeps its values instead, and further passes
+//!   evaluate every formula in order until a pass changes nothing or the
+//!   pass limit is reached
+//!
+//! Inputs whose ROM behaviour depends on memory outside the cell grid and
+//! formula heap (reversed ranges, a lookup result past the last cell) or
+//! on non-BCD bytes are reported as [`Unmodeled`].
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::codegen::{
+    CELL_ERROR, CELL_FORMULA, CELL_LABEL, CELL_NUMBER, CELL_REPEAT, ERROR_NAMES, ERR_CIRC,
+    ERR_DIV0, ERR_ERROR, ERR_NA, ERR_OVF, ERR_PENDING, ERR_REF, ERR_SYNTAX, FORMULA_VALUE,
+    FUNC_NAMES, MAX_NESTING, REF_DOLLAR, REF_ROW_DOLLAR, TOK_CMP, TOK_DOT, TOK_END, TOK_FUNC,
+    TOK_NUM, TOK_OVF, TOK_REF,
+};
+use crate::layout::Layout;
+
+/// Longest line the input editor accepts
+pub const MAX_INPUT: usize = 40;
+
+const MODULUS: u32 = 100_000_000;
+
+/// Behaviour the model does not reproduce
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unmodeled {
+    /// A reference resolved outside the grid (linear cell index)
+    OutOfGrid(usize),
+    /// Arithmetic on bytes that are not valid packed BCD
+    InvalidBcd,
+}
+
+impl fmt::Display for Unmodeled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unmodeled::OutOfGrid(index) => write!(f, "cell index {} is outside the grid", index),
+            Unmodeled::InvalidBcd => write!(f, "arithmetic on non-BCD bytes"),
+        }
+    }
+}
+
+/// Error value of a cell, kept as its code in byte 1 of a `CELL_ERROR` cell
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CellError {
+    /// `@ERROR`
+    Error = ERR_ERROR,
+    /// Division by zero, also in `@MOD`
+    Div0 = ERR_DIV0,
+    /// Reference outside the grid, position outside a list or range
+    Ref = ERR_REF,
+    /// Malformed formula or bad argument
+    Syntax = ERR_SYNTAX,
+    /// Number or result too big for 8 digits, or a financial result of a
+    /// million or more
+    Overflow = ERR_OVF,
+    /// Circular reference
+    Circular = ERR_CIRC,
+    /// `@NA`, or no `@LOOKUP` entry at or below the value
+    NotAvailable = ERR_NA,
+}
+
+impl CellError {
+    const ALL: [CellError; 7] = [
+        CellError::Error,
+        CellError::Div0,
+        CellError::Ref,
+        CellError::Syntax,
+        CellError::Overflow,
+        CellError::Circular,
+        CellError::NotAvailable,
+    ];
+
+    /// Error with this code, if it is one
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| *e as u8 == code)
+    }
+
+    /// Name the ROM prints in the cell, like `#DIV/0`
+    pub fn name(self) -> &'static str {
+        ERROR_NAMES[self as usize - ERR_ERROR as usize].trim_start()
+    }
+}
+
+impl fmt::Display for CellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// 8-digit packed BCD magnitude, big-endian, two implied decimal places
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bcd(pub [u8; 4]);
+
+impl Bcd {
+    pub const ZERO: Bcd = Bcd([0; 4]);
+    pub const MAX: Bcd = Bcd([0x99; 4]);
+
+    /// Pack a value given in hundredths (wraps modulo 10^8)
+    pub fn from_hundredths(value: u32) -> Self {
+        let mut v = value % MODULUS;
+        let mut bytes = [0u8; 4];
+        for byte in bytes.iter_mut().rev() {
+            *byte = (((v / 10 % 10) << 4) | (v % 10)) as u8;
+            v /= 100;
+        }
+        Bcd(bytes)
+    }
+
+    /// Value in hundredths, or `None` if any nibble is above 9
+    pub fn hundredths(self) -> Option<u32> {
+        let mut v = 0;
+        for b in self.0 {
+            let (hi, lo) = (b >> 4, b & 0x0F);
+            if hi > 9 || lo > 9 {
+                return None;
+            }
+            v = v * 100 + (hi * 10 + lo) as u32;
+        }
+        Some(v)
+    }
+
+    fn value(self) -> Result<u32, Unmodeled> {
+        self.hundredths().ok_or(Unmodeled::InvalidBcd)
+    }
+
+    /// Nibble-weighted value; equal to `hundredths` for valid BCD
+    fn weight(self) -> u32 {
+        self.0
+            .iter()
+            .fold(0, |v, &b| v * 100 + (b >> 4) as u32 * 10 + (b & 0x0F) as u32)
+    }
+}
+
+impl fmt::Display for Bcd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.hundredths() {
+            Some(v) => write!(f, "{}.{:02}", v / 100, v % 100),
+            None => write!(f, "?{:02X}{:02X}{:02X}{:02X}", self.0[0], self.0[1], self.0[2], self.0[3]),
+        }
+    }
+}
+
+/// Signed value as the ROM keeps it: raw sign byte (0x00/0x80) plus magnitude
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Value {
+    pub sign: u8,
+    pub bcd: Bcd,
+}
+
+impl Value {
+    pub fn new(sign: u8, bcd: Bcd) -> Self {
+        Self { sign, bcd }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.sign & 0x80 != 0
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_negative() {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", self.bcd)
+    }
+}
+
+/// `bcd_add`: sum, or `None` if it carries out of the top digit
+pub fn bcd_add(a: Bcd, b: Bcd) -> Option<Bcd> {
+    let sum = a.weight() + b.weight();
+    (sum < MODULUS).then(|| Bcd::from_hundredths(sum))
+}
+
+/// `bcd_sub`: difference modulo 10^8 (ten's complement on borrow)
+pub fn bcd_sub(a: Bcd, b: Bcd) -> Bcd {
+    Bcd::from_hundredths(a.weight() + MODULUS - b.weight() % MODULUS)
+}
+
+/// `bcd_cmp`: byte-wise magnitude comparison
+pub fn bcd_cmp(a: Bcd, b: Bcd) -> Ordering {
+    a.0.cmp(&b.0)
+}
+
+/// `bcd_mul`: `multiplicand * multiplier / 100`, truncated; `None` if it
+/// needs more than 8 digits
+pub fn bcd_mul(multiplicand: Bcd, multiplier: Bcd) -> Option<Bcd> {
+    let product = multiplicand.weight() as u64 * multiplier.weight() as u64 / 100;
+    (product < MODULUS as u64).then(|| Bcd::from_hundredths(product as u32))
+}
+
+/// `bcd_div`: `dividend * 100 / divisor`, truncated
+pub fn bcd_div(dividend: Bcd, divisor: Bcd) -> Result<Bcd, CellError> {
+    if divisor == Bcd::ZERO {
+        return Err(CellError::Div0);
+    }
+    let quotient = dividend.weight() as u64 * 100 / divisor.weight() as u64;
+    if quotient >= MODULUS as u64 {
+        return Err(CellError::Overflow);
+    }
+    Ok(Bcd::from_hundredths(quotient as u32))
+}
+
+/// `bcd_div_noscale`: `dividend / divisor` without the ×100 (used by @AVG)
+pub fn bcd_div_noscale(dividend: Bcd, divisor: Bcd) -> Option<Bcd> {
+    if divisor == Bcd::ZERO {
+        return None;
+    }
+    Some(Bcd::from_hundredths(dividend.weight() / divisor.weight()))
+}
+
+/// `signed_add` / `eval_add`: add two sign-magnitude values
+///
+/// Equal magnitudes with opposite signs give zero carrying the
+/// accumulator's sign; `eval_expr` clears it, but @SUM/@AVG do not.
+/// `None` if the magnitude overflows.
+pub fn signed_add(acc: Value, op: Value) -> Result<Option<Value>, Unmodeled> {
+    acc.bcd.value()?;
+    op.bcd.value()?;
+    if acc.sign == op.sign {
+        return Ok(bcd_add(op.bcd, acc.bcd).map(|bcd| Value::new(acc.sign, bcd)));
+    }
+    if bcd_cmp(acc.bcd, op.bcd) == Ordering::Less {
+        Ok(Some(Value::new(op.sign, bcd_sub(op.bcd, acc.bcd))))
+    } else {
+        Ok(Some(Value::new(acc.sign, bcd_sub(acc.bcd, op.bcd))))
+    }
+}
+
+/// `ascii_to_bcd`: parse digits into 6.2 fixed point
+///
+/// Skips one leading `-` (the sign itself is handled by the caller), stops
+/// at the first character that is not a digit or `.`, ignores digits after
+/// the second fractional one. `None` if the value needs more than 8 digits.
+pub fn ascii_to_bcd(text: &[u8]) -> Option<Bcd> {
+    let mut i = usize::from(text.first() == Some(&b'-'));
+    let mut value: u32 = 0;
+    let mut decimal = false;
+    let mut frac = 0;
+    while let Some(&c) = text.get(i) {
+        if c == b'.' {
+            decimal = true;
+        } else if c.is_ascii_digit() && frac < 2 {
+            value = value * 10 + (c - b'0') as u32;
+            if value >= MODULUS {
+                return None;
+            }
+            if decimal {
+                frac += 1;
+            }
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    let scale = if decimal && frac == 1 { 10 } else if decimal && frac == 2 { 1 } else { 100 };
+    let value = value as u64 * scale;
+    (value < MODULUS as u64).then(|| Bcd::from_hundredths(value as u32))
+}
+
+/// `parse_number`: optional `-`, then a digit is required, possibly
+/// after the decimal point
+pub fn parse_number(text: &[u8]) -> Result<Value, CellError> {
+    let (sign, digits) = match text.first() {
+        Some(b'-') => (0x80, &text[1..]),
+        _ => (0x00, text),
+    };
+    let first = if digits.first() == Some(&b'.') { digits.get(1) } else { digits.first() };
+    if !first.is_some_and(u8::is_ascii_digit) {
+        return Err(CellError::Syntax);
+    }
+    let bcd = ascii_to_bcd(digits).ok_or(CellError::Overflow)?;
+    Ok(Value::new(sign, bcd))
+}
+
+/// `lex`: compile formula text (after the `=`) into the tokens the ROM
+/// evaluates
+///
+/// References carry their column and 0-based row, numbers their BCD and
+/// @function names their index in `FUNC_NAMES`; any other character is a
+/// token by itself.
+pub fn lex(text: &[u8]) -> Vec<u8> {
+    let letter = |c: Option<&u8>| c.is_some_and(|c| (c & 0xDF).wrapping_sub(b'A') < 26);
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while let Some(&c) = text.get(i) {
+        let rest = &text[i..];
+        match c {
+            b'@' => {
+                let name = FUNC_NAMES.iter().position(|name| {
+                    rest.len() > name.len() && rest[1..=name.len()].eq_ignore_ascii_case(name.as_bytes())
+                });
+                match name {
+                    Some(index) => {
+                        tokens.push(TOK_FUNC + index as u8);
+                        i += 1 + FUNC_NAMES[index].len();
+                    }
+                    None => {
+                        tokens.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            b'<' | b'>' | b'=' => {
+                let (mask, len) = match (c, rest.get(1)) {
+                    (b'<', Some(b'>')) => (5, 2),
+                    (b'<', Some(b'=')) => (3, 2),
+                    (b'<', _) => (1, 1),
+                    (b'>', Some(b'=')) => (6, 2),
+                    (b'>', _) => (4, 1),
+                    _ => (2, 1),
+                };
+                tokens.push(TOK_CMP | mask);
+                i += len;
+            }
+            b'.' | b'0'..=b'9' => {
+                match ascii_to_bcd(rest) {
+                    Some(bcd) => {
+                        tokens.push(if c == b'.' { TOK_DOT } else { TOK_NUM });
+                        tokens.extend_from_slice(&bcd.0);
+                    }
+                    None => tokens.push(TOK_OVF),
+                }
+                i += rest.iter().take_while(|c| **c == b'.' || c.is_ascii_digit()).count();
+            }
+            _ if letter(Some(&c)) || (c == b'$' && letter(rest.get(1))) => {
+                let mut col = 0;
+                if c == b'$' {
+                    col = REF_DOLLAR;
+                    i += 1;
+                }
+                col |= (text[i] & 0xDF) - b'A';
+                i += 1;
+                if text.get(i) == Some(&b'$') {
+                    col |= REF_ROW_DOLLAR;
+                    i += 1;
+                }
+                let mut row: u8 = 0;
+                while let Some(d) = text.get(i).filter(|d| d.is_ascii_digit()) {
+                    row = row.wrapping_mul(10).wrapping_add(d - b'0');
+                    i += 1;
+                }
+                tokens.extend_from_slice(&[TOK_REF, col, row.wrapping_sub(1)]);
+            }
+            _ => {
+                tokens.push(c);
+                i += 1;
+            }
+        }
+    }
+    tokens.push(TOK_END);
+    tokens
+}
+
+/// Length of the tokens at the start of `tokens`, their end included
+fn tokens_len(tokens: impl IntoIterator<Item = u8>) -> usize {
+    let mut tokens = tokens.into_iter();
+    let mut len = 0;
+    while let Some(token) = tokens.next() {
+        len += 1;
+        match token {
+            TOK_END => break,
+            TOK_NUM | TOK_DOT => {
+                len += 4;
+                tokens.nth(3);
+            }
+            TOK_REF => {
+                len += 2;
+                tokens.nth(1);
+            }
+            _ => {}
+        }
+    }
+    len
+}
+
+/// Aggregate selected by the first letters after `@`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Func {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+/// The cell grid and formula heap as the ROM lays them out in RAM
+#[derive(Clone, Debug)]
+pub struct Sheet {
+    layout: Layout,
+    cells: Vec<[u8; 6]>,
+    heap: Vec<u8>,
+    formula_ptr: u16,
+    /// First cell the last recalculation found on a cycle (`RECALC_CIRC`)
+    circ: Option<usize>,
+    /// Recalculate only on `!` (`RECALC_MANUAL`)
+    manual: bool,
+    /// Recalculate column by column (`RECALC_BY_COL`)
+    by_columns: bool,
+    /// Passes a recalculation may run (`RECALC_LIMIT`)
+    limit: u8,
+    /// Run every pass even once nothing changes (`RECALC_FULL`)
+    all_passes: bool,
+    /// Passes the last recalculation ran (`RECALC_PASSES`)
+    passes: u8,
+}
+
+impl Default for Sheet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sheet {
+    /// Empty sheet in the default layout, as left by the ROM's startup code
+    pub fn new() -> Self {
+        Self::with_layout(Layout::default())
+    }
+
+    /// Empty sheet for a ROM built with `layout`
+    pub fn with_layout(layout: Layout) -> Self {
+        Self {
+            layout,
+            cells: vec![[0; 6]; layout.cell_count()],
+            heap: vec![0; (layout.heap_end() - layout.scratch()) as usize],
+            formula_ptr: layout.scratch(),
+            circ: None,
+            manual: false,
+            by_columns: false,
+            limit: 1,
+            all_passes: false,
+            passes: 0,
+        }
+    }
+
+    /// RAM layout and grid size the sheet mirrors
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn index(&self, col: u8, row: u8) -> usize {
+        let (cols, rows) = (self.layout.cols(), self.layout.rows());
+        assert!(col < cols && row < rows, "cell ({}, {}) outside grid", col, row);
+        row as usize * cols as usize + col as usize
+    }
+
+    /// Cell the last recalculation marked `#CIRC` first, as the status
+    /// line shows it (0-based column and row)
+    pub fn circular(&self) -> Option<(u8, u8)> {
+        let cols = self.layout.cols() as usize;
+        self.circ.map(|index| ((index % cols) as u8, (index / cols) as u8))
+    }
+
+    /// Raw 6-byte record of a cell (0-based column and row)
+    pub fn cell(&self, col: u8, row: u8) -> [u8; 6] {
+        self.cells[self.index(col, row)]
+    }
+
+    /// Heap contents from the layout's `scratch` address
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// Next free heap address (`FORMULA_PTR`)
+    pub fn formula_ptr(&self) -> u16 {
+        self.formula_ptr
+    }
+
+    /// `heap_free`: heap bytes left, as the status line shows them
+    pub fn free(&self) -> u16 {
+        self.layout.heap_end() - self.formula_ptr
+    }
+
+    fn heap_byte(&self, addr: u16) -> u8 {
+        addr.checked_sub(self.layout.scratch())
+            .and_then(|i| self.heap.get(i as usize))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// NUL-terminated heap string at `addr` and the address after the NUL
+    fn heap_str(&self, addr: u16) -> (Vec<u8>, u16) {
+        let mut text = Vec::new();
+        let mut a = addr;
+        while a < self.layout.heap_end() && self.heap_byte(a) != 0 {
+            text.push(self.heap_byte(a));
+            a += 1;
+        }
+        (text, a + 1)
+    }
+
+    fn write_heap(&mut self, addr: u16, bytes: &[u8]) {
+        let start = (addr - self.layout.scratch()) as usize;
+        self.heap[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Text of a formula or label cell, or of an error cell's formula
+    pub fn text(&self, col: u8, row: u8) -> Option<String> {
+        let cell = self.cell(col, row);
+        let formula = cell[0] == CELL_ERROR && cell[3] != 0;
+        if cell[0] != CELL_FORMULA && cell[0] != CELL_LABEL && !formula {
+            return None;
+        }
+        let (text, _) = self.heap_str(u16::from_le_bytes([cell[2], cell[3]]));
+        Some(String::from_utf8_lossy(&text).into_owned())
+    }
+
+    /// Value of a number cell or the stored value of a formula cell
+    pub fn value(&self, col: u8, row: u8) -> Option<Value> {
+        let cell = self.cell(col, row);
+        match cell[0] {
+            CELL_NUMBER => Some(Self::cell_value(&cell)),
+            CELL_FORMULA => Some(self.formula_value(&cell)),
+            _ => None,
+        }
+    }
+
+    /// Error of an error cell
+    pub fn error(&self, col: u8, row: u8) -> Option<CellError> {
+        let cell = self.cell(col, row);
+        (cell[0] == CELL_ERROR).then(|| Self::cell_error(&cell))
+    }
+
+    fn cell_error(cell: &[u8; 6]) -> CellError {
+        CellError::from_code(cell[1]).expect("error cells hold a valid code")
+    }
+
+    fn cell_value(cell: &[u8; 6]) -> Value {
+        Value::new(cell[1], Bcd([cell[2], cell[3], cell[4], cell[5]]))
+    }
+
+    fn formula_value(&self, cell: &[u8; 6]) -> Value {
+        let value_addr = u16::from_le_bytes([cell[2], cell[3]]) - FORMULA_VALUE;
+        let b = |i: u16| self.heap_byte(value_addr + i);
+        Value::new(b(0), Bcd([b(1), b(2), b(3), b(4)]))
+    }
+
+    /// `parse_and_store`: confirm `input` as typed into a cell
+    ///
+    /// Only printable characters are kept and the line is cut at 40, as
+    /// the input editor does. `=` starts a formula, `"` a label, anything
+    /// else must be a number or the cell becomes a `#SYNTAX` error.
+    ///
+    /// `false` if a formula or label does not fit the heap even after
+    /// compacting it ("Memory full"); the cell is left as it was.
+    pub fn enter(&mut self, col: u8, row: u8, input: &str) -> Result<bool, Unmodeled> {
+        let text: Vec<u8> = input
+            .bytes()
+            .filter(|b| (0x20..0x7F).contains(b))
+            .take(MAX_INPUT)
+            .collect();
+        let index = self.index(col, row);
+        match text.first() {
+            None => Ok(true),
+            Some(b'=') => self.enter_formula(index, &text),
+            Some(b'"') => {
+                let Some(ptr) = self.alloc(text.len() + 1) else {
+                    return Ok(false);
+                };
+                self.write_heap(ptr, &text);
+                self.write_heap(ptr + text.len() as u16, &[0]);
+                self.formula_ptr = ptr + text.len() as u16 + 1;
+                self.set_pointer_cell(index, CELL_LABEL, ptr);
+                Ok(true)
+            }
+            Some(_) => {
+                match parse_number(&text) {
+                    Ok(v) => {
+                        let [b0, b1, b2, b3] = v.bcd.0;
+                        self.cells[index] = [CELL_NUMBER, v.sign, b0, b1, b2, b3];
+                    }
+                    Err(err) => self.store_error(index, err),
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    /// `store_error`: an error without a formula
+    fn store_error(&mut self, index: usize, err: CellError) {
+        self.cells[index][..4].copy_from_slice(&[CELL_ERROR, err as u8, 0, 0]);
+    }
+
+    fn enter_formula(&mut self, index: usize, text: &[u8]) -> Result<bool, Unmodeled> {
+        if text.len() < 2 {
+            self.store_error(index, CellError::Syntax);
+            return Ok(true);
+        }
+        // Sign and 4 BCD bytes, the tokens' offset from the text, the
+        // text, NUL and tokens. A formula that fails is kept with a zero
+        // value so recalculation can retry it
+        let tokens = lex(&text[1..]);
+        let Some(block) = self.alloc(text.len() + 7 + tokens.len()) else {
+            return Ok(false);
+        };
+        let ptr = block + FORMULA_VALUE;
+        self.write_heap(ptr - 1, &[text.len() as u8 + 1]);
+        self.write_heap(ptr, text);
+        self.write_heap(ptr + text.len() as u16, &[0]);
+        self.write_heap(ptr + text.len() as u16 + 1, &tokens);
+        self.formula_ptr = ptr + text.len() as u16 + 1 + tokens.len() as u16;
+        let result = self.eval(&text[1..])?;
+        let v = result.unwrap_or_default();
+        self.write_heap(block, &[v.sign]);
+        self.write_heap(block + 1, &v.bcd.0);
+        self.set_pointer_cell(index, CELL_FORMULA, ptr);
+        self.formula_status(index, result);
+        Ok(true)
+    }
+
+    /// `formula_status`: type and error code of a formula cell
+    fn formula_status(&mut self, index: usize, result: Result<Value, CellError>) {
+        let cell = &mut self.cells[index];
+        (cell[0], cell[1]) = match result {
+            Ok(_) => (CELL_FORMULA, 0),
+            Err(e) => (CELL_ERROR, e as u8),
+        };
+    }
+
+    /// `heap_alloc`: `FORMULA_PTR` if `len` bytes fit, compacting the
+    /// heap first when they do not
+    fn alloc(&mut self, len: usize) -> Option<u16> {
+        if len > self.free() as usize {
+            self.collect();
+        }
+        (len <= self.free() as usize).then_some(self.formula_ptr)
+    }
+
+    /// `heap_collect`: slide the blocks some cell still points at down
+    /// over the dead ones, moving every pointer to them along
+    fn collect(&mut self) {
+        let mut src = self.layout.scratch();
+        let mut dst = src;
+        while src < self.formula_ptr {
+            // A label is its text; a formula's block starts with its value
+            let offset = if self.heap_byte(src) == b'"' { 0 } else { FORMULA_VALUE };
+            let (_, mut next) = self.heap_str(src + offset);
+            if offset != 0 {
+                next += tokens_len((next..self.formula_ptr).map(|a| self.heap_byte(a))) as u16;
+            }
+            let mut live = false;
+            for cell in &mut self.cells {
+                let pointer = matches!(cell[0], CELL_FORMULA | CELL_ERROR | CELL_LABEL);
+                if pointer && u16::from_le_bytes([cell[2], cell[3]]) == src + offset {
+                    [cell[2], cell[3]] = (dst + offset).to_le_bytes();
+                    live = true;
+                }
+            }
+            if live {
+                let from = (src - self.layout.scratch()) as usize;
+                let len = (next - src) as usize;
+                let to = (dst - self.layout.scratch()) as usize;
+                self.heap.copy_within(from..from + len, to);
+                dst += len as u16;
+            }
+            src = next;
+        }
+        self.formula_ptr = dst;
+    }
+
+    fn set_pointer_cell(&mut self, index: usize, kind: u8, ptr: u16) {
+        let [lo, hi] = ptr.to_le_bytes();
+        let cell = &mut self.cells[index];
+        cell[..4].copy_from_slice(&[kind, 0, lo, hi]);
+    }
+
+    /// `/C`: mark a cell empty (only the type byte changes)
+    pub fn clear(&mut self, col: u8, row: u8) {
+        let index = self.index(col, row);
+        self.cells[index][0] = 0;
+    }
+
+    /// `/-`: fill a cell with a repeating character
+    pub fn repeat(&mut self, col: u8, row: u8, ch: u8) {
+        let index = self.index(col, row);
+        let cell = &mut self.cells[index];
+        cell[0] = CELL_REPEAT;
+        cell[2] = ch;
+    }
+
+    /// `/GRM` or `/GRA`: recalculate only on `!`, or after every change
+    pub fn set_manual(&mut self, manual: bool) {
+        self.manual = manual;
+    }
+
+    /// `/GOC` or `/GOR`: recalculate column by column, or row by row
+    pub fn set_by_columns(&mut self, by_columns: bool) {
+        self.by_columns = by_columns;
+    }
+
+    /// `/GIN`: let a recalculation run up to `limit` passes (1-99); above
+    /// 1, reference cycles are iterated rather than failing
+    pub fn set_passes(&mut self, limit: u8) {
+        assert!((1..100).contains(&limit), "{} passes", limit);
+        self.limit = limit;
+    }
+
+    /// `/GIF` or `/GIC`: run every pass, or stop once a pass changes
+    /// nothing
+    pub fn set_all_passes(&mut self, all_passes: bool) {
+        self.all_passes = all_passes;
+    }
+
+    /// Passes the last recalculation ran
+    pub fn passes(&self) -> u8 {
+        self.passes
+    }
+
+    /// `recalculate`: what the ROM does after a change, [`Sheet::recalc`]
+    /// unless the mode is manual
+    pub fn recalculate(&mut self) -> Result<(), Unmodeled> {
+        if self.manual {
+            return Ok(());
+        }
+        self.recalc()
+    }
+
+    /// `recalc_all`: re-evaluate every formula after the cells it reads
+    ///
+    /// Every formula is marked pending, then sweeps in row or column order
+    /// evaluate the pending ones until a sweep resolves none; a formula
+    /// that reads a pending cell has that cell evaluated first, depth
+    /// first, and stays pending if it is on or waits for a reference
+    /// cycle. When a sweep is stuck on a
+    /// reference cycle, following the pending cell each formula waits for,
+    /// from the first pending formula, for as many steps as there are
+    /// pending formulas ends on the cycle; that formula fails with
+    /// [`CellError::Circular`] and the sweeps go on.
+    /// Error cells with a formula are retried; formulas that fail keep
+    /// their previous value and take the new error.
+    ///
+    /// With a pass limit above 1 the formula on the cycle keeps its value
+    /// instead, and each further pass evaluates every formula once in the
+    /// same order, reading the values as they are, until a pass changes no
+    /// value or error (the first pass counts as changing if it found a
+    /// cycle) or the limit is reached.
+    pub fn recalc(&mut self) -> Result<(), Unmodeled> {
+        let (cols, rows) = (self.layout.cols() as usize, self.layout.rows() as usize);
+        let by_columns = self.by_columns;
+        let formulas: Vec<usize> = (0..self.layout.cell_count())
+            .map(|i| if by_columns { i % rows * cols + i / rows } else { i })
+            .filter(|&index| {
+                let cell = self.cells[index];
+                cell[0] == CELL_FORMULA || (cell[0] == CELL_ERROR && cell[3] != 0)
+            })
+            .collect();
+        for &index in &formulas {
+            let cell = &mut self.cells[index];
+            cell[..2].copy_from_slice(&[CELL_ERROR, ERR_PENDING]);
+            cell[4..].fill(0);
+        }
+        self.circ = None;
+        self.passes = 0;
+        loop {
+            let mut moved = false;
+            let mut first = None;
+            let mut left = 0;
+            for &index in &formulas {
+                if self.cells[index][..2] != [CELL_ERROR, ERR_PENDING] {
+                    continue;
+                }
+                match self.eval_formula(index)? {
+                    Ok(result) => {
+                        self.store_result(index, result);
+                    }
+                    Err(wait) => {
+                        if !self.chase(index, wait)? {
+                            first.get_or_insert(index);
+                            left += 1;
+                            continue;
+                        }
+                    }
+                }
+                moved = true;
+            }
+            if !moved {
+                let Some(mut index) = first else {
+                    break;
+                };
+                for _ in 0..left {
+                    index = match self.eval_formula(index)? {
+                        Err(wait) => wait,
+                        Ok(_) => unreachable!("a stuck sweep resolves nothing"),
+                    };
+                }
+                self.circ.get_or_insert(index);
+                let result = if self.limit > 1 { Ok(Value::default()) } else { Err(CellError::Circular) };
+                self.formula_status(index, result);
+            }
+        }
+        let mut changed = self.circ.is_some();
+        loop {
+            self.passes += 1;
+            if self.passes == self.limit || !(self.all_passes || changed) {
+                return Ok(());
+            }
+            changed = false;
+            for &index in &formulas {
+                let result = self.eval_formula(index)?.expect("nothing is pending after the first pass");
+                changed |= self.store_result(index, result);
+            }
+        }
+    }
+
+    /// `recalc_chase`: evaluate the pending cell `index` waits for, and
+    /// whatever that waits for in turn, before `index` itself; true if
+    /// `index` resolved, false if the chain reached a cell already on it
+    /// (a cycle), which leaves them all pending
+    ///
+    /// The ROM links the chain through bytes 4-5 of its cells and clears
+    /// them as it goes, so they end up 0 either way.
+    fn chase(&mut self, index: usize, mut wait: usize) -> Result<bool, Unmodeled> {
+        let mut chain = vec![index];
+        loop {
+            if chain.contains(&wait) {
+                return Ok(false);
+            }
+            chain.push(wait);
+            while let Some(&top) = chain.last() {
+                match self.eval_formula(top)? {
+                    Ok(result) => {
+                        self.store_result(top, result);
+                        chain.pop();
+                    }
+                    Err(next) => {
+                        wait = next;
+                        break;
+                    }
+                }
+            }
+            if chain.is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Store a recalculated formula's value and status; true if either
+    /// changed
+    fn store_result(&mut self, index: usize, result: Result<Value, CellError>) -> bool {
+        let mut changed = false;
+        if let Ok(v) = result {
+            let value_addr = self.pointer(index) - FORMULA_VALUE;
+            let bytes = [v.sign, v.bcd.0[0], v.bcd.0[1], v.bcd.0[2], v.bcd.0[3]];
+            changed = (0..5).any(|i| self.heap_byte(value_addr + i) != bytes[i as usize]);
+            self.write_heap(value_addr, &bytes);
+        }
+        let code = result.err().map_or(0, |e| e as u8);
+        changed |= self.cells[index][1] != code;
+        self.formula_status(index, result);
+        changed
+    }
+
+    fn pointer(&self, index: usize) -> u16 {
+        let cell = self.cells[index];
+        u16::from_le_bytes([cell[2], cell[3]])
+    }
+
+    /// `recalc_eval`: evaluate the formula of a cell during recalculation
+    fn eval_formula(&self, index: usize) -> Result<Result<Result<Value, CellError>, usize>, Unmodeled> {
+        let (text, _) = self.heap_str(self.pointer(index));
+        self.eval_pending(text.get(1..).unwrap_or(&[]))
+    }
+
+    /// `eval_expr`: evaluate an expression (the text after `=`)
+    ///
+    /// Returns `Ok(Err(error))` where the ROM signals an error, with the
+    /// code it stores in the cell.
+    pub fn eval(&self, expr: &[u8]) -> Result<Result<Value, CellError>, Unmodeled> {
+        Ok(self.eval_pending(expr)?.expect("no cell is pending outside recalc"))
+    }
+
+    /// `eval`, or `Err` with the index of the cell it waits for if it read
+    /// a cell still pending recalculation
+    fn eval_pending(&self, expr: &[u8]) -> Result<Result<Result<Value, CellError>, usize>, Unmodeled> {
+        let mut eval = Eval {
+            sheet: self,
+            expr,
+            pos: 0,
+            depth: 0,
+            err: CellError::Syntax,
+            pending: false,
+            wait: 0,
+        };
+        let value = eval.expr()?;
+        Ok(if eval.pending { Err(eval.wait) } else { Ok(value.ok_or(eval.err)) })
+    }
+}
+
+/// Cursor over an expression, reading NUL past the end like the ROM does
+struct Eval<'a> {
+    sheet: &'a Sheet,
+    expr: &'a [u8],
+    pos: usize,
+    /// Open parentheses (`EVAL_DEPTH`)
+    depth: u8,
+    /// Error of a failed evaluation (`EVAL_ERR`)
+    err: CellError,
+    /// Failed on a cell still pending recalculation (`ERR_PENDING`)
+    pending: bool,
+    /// Last error cell read (`RECALC_WAIT`)
+    wait: usize,
+}
+
+impl Eval<'_> {
+    fn at(&self, i: usize) -> u8 {
+        self.expr.get(i).copied().unwrap_or(0)
+    }
+
+    /// `eval_fail`: fail with `err` rather than a syntax error
+    fn fail<T>(&mut self, err: CellError) -> Result<Option<T>, Unmodeled> {
+        self.err = err;
+        Ok(None)
+    }
+
+    /// `cell_fail`: fail with the error of the error cell at `index`
+    fn cell_fail<T>(&mut self, index: usize, cell: &[u8; 6]) -> Result<Option<T>, Unmodeled> {
+        self.wait = index;
+        if cell[1] == ERR_PENDING {
+            self.pending = true;
+            return Ok(None);
+        }
+        self.fail(Sheet::cell_error(cell))
+    }
+
+    /// A whole expression: anything left after the last term is an error
+    fn expr(&mut self) -> Result<Option<Value>, Unmodeled> {
+        let value = self.compare()?;
+        Ok(value.filter(|_| self.at(self.pos) == 0))
+    }
+
+    /// `eval_compare`: sums joined by `< > = <= >= <>`, each giving 1 or 0
+    fn compare(&mut self) -> Result<Option<Value>, Unmodeled> {
+        let Some(mut acc) = self.sum()? else {
+            return Ok(None);
+        };
+        loop {
+            let (less, equal, greater) = (LESS, 2, GREATER);
+            let (mask, len) = match (self.at(self.pos), self.at(self.pos + 1)) {
+                (b'<', b'=') => (less | equal, 2),
+                (b'<', b'>') => (less | greater, 2),
+                (b'<', _) => (less, 1),
+                (b'>', b'=') => (greater | equal, 2),
+                (b'>', _) => (greater, 1),
+                (b'=', _) => (equal, 1),
+                _ => return Ok(Some(acc)),
+            };
+            self.pos += len;
+            let Some(rhs) = self.sum()? else {
+                return Ok(None);
+            };
+            acc = boolean(mask & order(acc, rhs) != 0);
+        }
+    }
+
+    /// `eval_sum`: terms joined by `+` and `-`
+    fn sum(&mut self) -> Result<Option<Value>, Unmodeled> {
+        let Some(mut acc) = self.term()? else {
+            return Ok(None);
+        };
+        loop {
+            let op = self.at(self.pos);
+            if op != b'+' && op != b'-' {
+                return Ok(Some(acc));
+            }
+            self.pos += 1;
+            let Some(mut operand) = self.term()? else {
+                return Ok(None);
+            };
+            if op == b'-' {
+                operand.sign ^= 0x80;
+            }
+            let Some(sum) = signed_add(acc, operand)? else {
+                return self.fail(CellError::Overflow);
+            };
+            acc = normalise(sum);
+        }
+    }
+
+    /// `eval_term`: factors joined by `*` and `/`
+    fn term(&mut self) -> Result<Option<Value>, Unmodeled> {
+        let Some(mut acc) = self.factor()? else {
+            return Ok(None);
+        };
+        loop {
+            let op = self.at(self.pos);
+            if op != b'*' && op != b'/' {
+                return Ok(Some(acc));
+            }
+            self.pos += 1;
+            let Some(operand) = self.factor()? else {
+                return Ok(None);
+            };
+            acc.bcd.value()?;
+            operand.bcd.value()?;
+            let result = if op == b'*' {
+                bcd_mul(operand.bcd, acc.bcd).ok_or(CellError::Overflow)
+            } else {
+                bcd_div(acc.bcd, operand.bcd)
+            };
+            let bcd = match result {
+                Ok(bcd) => bcd,
+                Err(err) => return self.fail(err),
+            };
+            acc = normalise(Value::new(acc.sign ^ operand.sign, bcd));
+        }
+    }
+
+    /// `eval_factor`: unary `+` and `-` signs, then a primary
+    fn factor(&mut self) -> Result<Option<Value>, Unmodeled> {
+        let mut sign = 0;
+        loop {
+            match self.at(self.pos) {
+                b'+' => {}
+                b'-' => sign ^= 0x80,
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        Ok(self.primary()?.map(|mut v| {
+            v.sign ^= sign;
+            normalise(v)
+        }))
+    }
+
+    /// `eval_primary`: a parenthesised expression or an operand
+    fn primary(&mut self) -> Result<Option<Value>, Unmodeled> {
+        if self.at(self.pos) != b'(' {
+            return Ok(self.operand()?.map(normalise));
+        }
+        if !self.open(self.pos) {
+            return Ok(None);
+        }
+        let value = self.compare()?;
+        Ok(value.filter(|_| self.close()))
+    }
+
+    /// 8-bit row number as the ROM accumulates it, converted to 0-based
+    fn row(&mut self, mut p: usize) -> (u8, usize) {
+        let mut row: u8 = 0;
+        while self.at(p).is_ascii_digit() {
+            row = row.wrapping_mul(10).wrapping_add(self.at(p) - b'0');
+            p += 1;
+        }
+        (row.wrapping_sub(1), p)
+    }
+
+    /// Linear index of a cell by column and (possibly wrapped) row
+    fn cell_index(&self, col: u8, row: u8) -> usize {
+        row as usize * self.sheet.layout.cols() as usize + col as usize
+    }
+
+    /// Raw record of a cell by column and (possibly wrapped) row
+    fn cell(&self, col: u8, row: u8) -> Result<[u8; 6], Unmodeled> {
+        let index = self.cell_index(col, row);
+        self.sheet.cells.get(index).copied().ok_or(Unmodeled::OutOfGrid(index))
+    }
+
+    /// `load_cell_value`: empty cells are zero, error cells fail with
+    /// their error, anything else is read as a number (labels included, as
+    /// the ROM does)
+    fn cell_value(&mut self, col: u8, row: u8) -> Result<Option<Value>, Unmodeled> {
+        let cell = self.cell(col, row)?;
+        Ok(Some(match cell[0] {
+            0 => Value::default(),
+            CELL_ERROR => return self.cell_fail(self.cell_index(col, row), &cell),
+            CELL_FORMULA => self.sheet.formula_value(&cell),
+            _ => Sheet::cell_value(&cell),
+        }))
+    }
+
+    /// `pf_range`: `X1:Y2` at `p` -> (col1, row1, col2, row2) and the end;
+    /// a lone `X1` is `X1:X1`. Corners outside the grid are `#REF`
+    fn range(&mut self, mut p: usize) -> Option<([u8; 4], usize)> {
+        let col1 = self.range_col(p)?;
+        let (row1, q) = self.range_row(p + 1)?;
+        p = q;
+        if self.at(p) != b':' {
+            return Some(([col1, row1, col1, row1], p));
+        }
+        p += 1;
+        let col2 = self.range_col(p)?;
+        let (row2, end) = self.range_row(p + 1)?;
+        Some(([col1, row1, col2, row2], end))
+    }
+
+    /// `pf_range_col`: a column letter in the grid
+    fn range_col(&mut self, p: usize) -> Option<u8> {
+        let col = (self.at(p) & 0xDF).wrapping_sub(b'A');
+        if col >= 26 {
+            return None;
+        }
+        if col >= self.sheet.layout.cols() {
+            self.err = CellError::Ref;
+            return None;
+        }
+        Some(col)
+    }
+
+    /// A row in the grid, and the end of its digits
+    fn range_row(&mut self, p: usize) -> Option<(u8, usize)> {
+        let (row, end) = self.row(p);
+        if row >= self.sheet.layout.rows() {
+            self.err = CellError::Ref;
+            return None;
+        }
+        Some((row, end))
+    }
+
+    /// `parse_operand`: cell reference, number or @function
+    fn operand(&mut self) -> Result<Option<Value>, Unmodeled> {
+        let mut p = self.pos;
+        if self.at(p) == b'@' {
+            return self.function();
+        }
+        if self.at(p) == b'$' {
+            p += 1;
+        }
+        let c = self.at(p).to_ascii_uppercase();
+        if c.is_ascii_uppercase() {
+            if c - b'A' >= self.sheet.layout.cols() {
+                return self.fail(CellError::Ref);
+            }
+            p += 1;
+            if self.at(p) == b'$' {
+                p += 1;
+            }
+            let (row, end) = self.row(p);
+            self.pos = end;
+            if row >= self.sheet.layout.rows() {
+                return self.fail(CellError::Ref);
+            }
+            return self.cell_value(c - b'A', row);
+        }
+
+        // Number: parsed from the original position, so a stray `$` is not
+        // skipped; always positive since `factor` has taken any signs.
+        // Anything else, a missing operand included, is a syntax error
+        let mut p = self.pos;
+        if self.at(p) != b'.' && !self.at(p).is_ascii_digit() {
+            return Ok(None);
+        }
+        let Some(bcd) = ascii_to_bcd(self.expr.get(p..).unwrap_or(&[])) else {
+            return self.fail(CellError::Overflow);
+        };
+        while self.at(p) == b'.' || self.at(p).is_ascii_digit() {
+            p += 1;
+        }
+        self.pos = p;
+        Ok(Some(Value::new(0, bcd)))
+    }
+
+    /// `pf_open`: `(` at `p` opens a nesting level
+    fn open(&mut self, p: usize) -> bool {
+        if self.at(p) != b'(' || self.depth >= MAX_NESTING {
+            return false;
+        }
+        self.depth += 1;
+        self.pos = p + 1;
+        true
+    }
+
+    /// `eval_expect`: take `c` if it is next
+    fn take(&mut self, c: u8) -> bool {
+        let found = self.at(self.pos) == c;
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// `eval_close`: take the `)` that closes a nesting level
+    fn close(&mut self) -> bool {
+        let found = self.take(b')');
+        if found {
+            self.depth -= 1;
+        }
+        found
+    }
+
+    /// `eval_skip_arg`: move to the next `,` or `)` outside parentheses
+    fn skip_arg(&mut self) {
+        let mut open = 0;
+        loop {
+            match self.at(self.pos) {
+                0 => return,
+                b'(' => open += 1,
+                b')' | b',' if open == 0 => return,
+                b')' => open -= 1,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// `@IF(cond,a,b)`: only the selected branch is evaluated
+    fn if_func(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let Some(cond) = self.compare()? else {
+            return Ok(None);
+        };
+        if !self.take(b',') {
+            return Ok(None);
+        }
+        let value = if cond.bcd != Bcd::ZERO {
+            let value = self.compare()?;
+            if !self.take(b',') {
+                return Ok(None);
+            }
+            self.skip_arg();
+            value
+        } else {
+            self.skip_arg();
+            if !self.take(b',') {
+                return Ok(None);
+            }
+            self.compare()?
+        };
+        Ok(value.filter(|_| self.close()))
+    }
+
+    /// `@NOT(x)`
+    fn not_func(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let Some(value) = self.compare()? else {
+            return Ok(None);
+        };
+        Ok(self.close().then(|| boolean(value.bcd == Bcd::ZERO)))
+    }
+
+    /// `@AND(...)` (`stop` false) and `@OR(...)` (`stop` true): the first
+    /// argument whose truth equals `stop` decides, the rest are skipped
+    fn logic(&mut self, stop: bool, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        loop {
+            let Some(value) = self.compare()? else {
+                return Ok(None);
+            };
+            if (value.bcd != Bcd::ZERO) == stop {
+                while self.take(b',') {
+                    self.skip_arg();
+                }
+                return Ok(self.close().then(|| boolean(stop)));
+            }
+            if !self.take(b',') {
+                return Ok(self.close().then(|| boolean(!stop)));
+            }
+        }
+    }
+
+    /// `@ABS(x)` and `@INT(x)`: the argument, closed
+    fn one_arg(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let value = self.compare()?;
+        Ok(value.filter(|_| self.close()))
+    }
+
+    /// `pf_two_args`: `(x,y` for `@MOD` and `@ROUND`, left open
+    fn two_args(&mut self, p: usize) -> Result<Option<(Value, Value)>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let Some(x) = self.compare()? else {
+            return Ok(None);
+        };
+        if !self.take(b',') {
+            return Ok(None);
+        }
+        Ok(self.compare()?.map(|y| (x, y)))
+    }
+
+    /// `@MOD(x,y)`: remainder of the magnitudes, with the sign of `x`
+    fn mod_func(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        let Some((x, y)) = self.two_args(p)? else {
+            return Ok(None);
+        };
+        let (x_h, y_h) = (x.bcd.value()?, y.bcd.value()?);
+        if y_h == 0 {
+            return self.fail(CellError::Div0);
+        }
+        if !self.close() {
+            return Ok(None);
+        }
+        Ok(Some(Value::new(x.sign, Bcd::from_hundredths(x_h % y_h))))
+    }
+
+    /// `@ROUND(x,n)`: `n` from 2 down to -5 decimals, halves away from
+    /// zero; the decimals of `n` are ignored and rounding up past the top
+    /// digit is an overflow
+    fn round_func(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        let Some((x, n)) = self.two_args(p)? else {
+            return Ok(None);
+        };
+        let units = n.bcd.value()? / 100;
+        let drop = match (n.is_negative(), units) {
+            (false, 0..=2) => 2 - units,
+            (true, 0..=5) => 2 + units,
+            _ => return Ok(None),
+        };
+        let x_h = x.bcd.value()?;
+        let rounded = if drop == 0 {
+            x_h
+        } else {
+            let scale = 10u32.pow(drop);
+            let up = (x_h / (scale / 10) % 10 >= 5) as u32;
+            (x_h / scale + up) * scale
+        };
+        if rounded >= MODULUS {
+            return self.fail(CellError::Overflow);
+        }
+        Ok(self.close().then(|| Value::new(x.sign, Bcd::from_hundredths(rounded))))
+    }
+
+    /// `@CHOOSE(n,a,b,...)`: only the n-th choice is evaluated
+    fn choose(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let Some(n) = self.compare()? else {
+            return Ok(None);
+        };
+        let Some(n) = position(n) else {
+            return self.fail(CellError::Ref);
+        };
+        for i in 1..=n {
+            if !self.take(b',') {
+                return self.fail(CellError::Ref);
+            }
+            if i < n {
+                self.skip_arg();
+            }
+        }
+        let Some(value) = self.compare()? else {
+            return Ok(None);
+        };
+        while self.take(b',') {
+            self.skip_arg();
+        }
+        Ok(self.close().then_some(value))
+    }
+
+    /// `pf_position_arg`: `,` and a 1-based position
+    fn position_arg(&mut self) -> Result<Option<u8>, Unmodeled> {
+        if !self.take(b',') {
+            return Ok(None);
+        }
+        let Some(n) = self.compare()? else {
+            return Ok(None);
+        };
+        match position(n) {
+            Some(n) => Ok(Some(n)),
+            None => self.fail(CellError::Ref),
+        }
+    }
+
+    /// `@INDEX(range,row,col)`: the cell at that position in the range
+    fn index(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let Some(([col1, row1, col2, row2], end)) = self.range(self.pos) else {
+            return Ok(None);
+        };
+        self.pos = end;
+        let Some(row) = self.position_arg()? else {
+            return Ok(None);
+        };
+        let Some(col) = self.position_arg()? else {
+            return Ok(None);
+        };
+        let col = col1.wrapping_add(col - 1);
+        let row = row1.wrapping_add(row - 1);
+        if col2 < col || row2 < row {
+            return self.fail(CellError::Ref);
+        }
+        let Some(value) = self.cell_value(col, row)? else {
+            return Ok(None);
+        };
+        Ok(self.close().then_some(value))
+    }
+
+    /// `@LOOKUP(x,range)`: down a column (or along a row) while entries
+    /// are <= x, then the cell beside (or below) the last of them, `#REF`
+    /// if that is outside the grid
+    fn lookup(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let Some(x) = self.compare()? else {
+            return Ok(None);
+        };
+        if !self.take(b',') {
+            return Ok(None);
+        }
+        let Some(([col1, row1, col2, row2], end)) = self.range(self.pos) else {
+            return Ok(None);
+        };
+        self.pos = end;
+        let (dcol, drow) = match col2.cmp(&col1) {
+            Ordering::Equal if row1 <= row2 => (0, 1),
+            Ordering::Greater if row1 == row2 => (1, 0),
+            _ => return Ok(None),
+        };
+        let (mut col, mut row) = (col1, row1);
+        let mut found = None;
+        loop {
+            let Some(entry) = self.cell_value(col, row)? else {
+                return Ok(None);
+            };
+            if order(x, entry) == LESS {
+                break;
+            }
+            found = Some((col, row));
+            if (col, row) == (col2, row2) {
+                break;
+            }
+            (col, row) = (col + dcol, row + drow);
+        }
+        let Some((col, row)) = found else {
+            return self.fail(CellError::NotAvailable);
+        };
+        let (col, row) = (col + drow, row + dcol);
+        if col >= self.sheet.layout.cols() || row >= self.sheet.layout.rows() {
+            return self.fail(CellError::Ref);
+        }
+        let Some(value) = self.cell_value(col, row)? else {
+            return Ok(None);
+        };
+        Ok(self.close().then_some(value))
+    }
+
+    /// `@PV(x,rate,n)`, `@PMT` and `@FV` (`kind` 0, 1, 2): rate in percent
+    /// per period, n whole periods from 1 to 9999 (decimals ignored)
+    fn finance(&mut self, kind: u8, p: usize) -> Result<Option<Value>, Unmodeled> {
+        let Some((x, rate)) = self.two_args(p)? else {
+            return Ok(None);
+        };
+        if rate.is_negative() || !self.take(b',') {
+            return Ok(None);
+        }
+        let Some(n) = self.compare()? else {
+            return Ok(None);
+        };
+        if !self.close() || n.is_negative() {
+            return Ok(None);
+        }
+        // `bcd_to_bin` on the hundreds and the units
+        let [high, hundreds, units, _] = n.bcd.0;
+        let bin = |b: u8| (b >> 4) as u16 * 10 + (b & 0x0F) as u16;
+        let n = bin(hundreds) * 100 + bin(units);
+        if high != 0 || n == 0 {
+            return Ok(None);
+        }
+        let x = fin_from(x.sign, 6, x.bcd.value()?);
+        let v = fin_rate(rate.bcd.value()?);
+        let (a, vn) = fin_annuity(v, n);
+        let result = match kind {
+            0 => Some(fin_mul(x, a)),
+            1 => fin_div(x, a),
+            _ => fin_div(fin_mul(x, a), vn),
+        };
+        match result.and_then(fin_to) {
+            Some(value) => Ok(Some(value)),
+            None => self.fail(CellError::Overflow),
+        }
+    }
+
+    /// `@NPV(rate,range)`: the cells discounted by 1, 2, ... periods,
+    /// column by column
+    fn npv(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let Some(rate) = self.compare()? else {
+            return Ok(None);
+        };
+        if rate.is_negative() || !self.take(b',') {
+            return Ok(None);
+        }
+        let Some(([col1, row1, col2, row2], end)) = self.range(self.pos) else {
+            return Ok(None);
+        };
+        self.pos = end;
+        if !self.close() {
+            return Ok(None);
+        }
+        let v = fin_rate(rate.bcd.value()?);
+        if col2 < col1 || row2 < row1 {
+            return Ok(None);
+        }
+        let (mut sum, mut discount) = (FIN_ZERO, FIN_ONE);
+        for col in col1..=col2 {
+            for row in row1..=row2 {
+                let Some(cell) = self.cell_value(col, row)? else {
+                    return Ok(None);
+                };
+                let x = fin_from(cell.sign, 6, cell.bcd.value()?);
+                (sum, discount) = fin_accrue(x, v, sum, discount);
+            }
+        }
+        match fin_to(sum) {
+            Some(value) => Ok(Some(value)),
+            None => self.fail(CellError::Overflow),
+        }
+    }
+
+    /// `@ISERROR(x)`: 1 if x fails, else 0; a failed x is skipped over.
+    /// Reading a cell still pending recalculation is not trapped
+    fn iserror(&mut self, p: usize) -> Result<Option<Value>, Unmodeled> {
+        if !self.open(p) {
+            return Ok(None);
+        }
+        let (start, depth) = (self.pos, self.depth);
+        let failed = self.compare()?.is_none();
+        if self.pending {
+            return Ok(None);
+        }
+        if failed {
+            self.depth = depth;
+            self.err = CellError::Syntax;
+            self.pos = start;
+            self.skip_arg();
+        }
+        Ok(self.close().then(|| boolean(failed)))
+    }
+
+    /// `parse_func`: `@NAME(A1:B2,C3,5)`, a logic, numeric, table, financial
+    /// or error function
+    fn function(&mut self) -> Result<Option<Value>, Unmodeled> {
+        let mut p = self.pos + 1;
+        let upper = |s: &Self, i: usize| s.at(i) & 0xDF;
+        let expect = |s: &Self, p: &mut usize, word: &[u8]| {
+            word.iter().all(|&w| {
+                *p += 1;
+                upper(s, *p) == w
+            })
+        };
+        let func = match upper(self, p) {
+            b'S' if expect(self, &mut p, b"UM") => Func::Sum,
+            b'A' if upper(self, p + 1) == b'N' => {
+                p += 1;
+                if !expect(self, &mut p, b"D") {
+                    return Ok(None);
+                }
+                return self.logic(false, p + 1);
+            }
+            b'A' if upper(self, p + 1) == b'B' => {
+                p += 1;
+                if !expect(self, &mut p, b"S") {
+                    return Ok(None);
+                }
+                return Ok(self.one_arg(p + 1)?.map(|v| Value::new(0, v.bcd)));
+            }
+            b'A' if expect(self, &mut p, b"VG") => Func::Avg,
+            b'M' => {
+                p += 1;
+                match upper(self, p) {
+                    b'I' if expect(self, &mut p, b"N") => Func::Min,
+                    b'A' if expect(self, &mut p, b"X") => Func::Max,
+                    b'O' if expect(self, &mut p, b"D") => return self.mod_func(p + 1),
+                    _ => return Ok(None),
+                }
+            }
+            b'C' if upper(self, p + 1) == b'H' => {
+                p += 1;
+                if !expect(self, &mut p, b"OOSE") {
+                    return Ok(None);
+                }
+                return self.choose(p + 1);
+            }
+            b'C' if expect(self, &mut p, b"OUNT") => Func::Count,
+            b'I' if upper(self, p + 1) == b'N' => {
+                p += 2;
+                if upper(self, p) == b'D' {
+                    if !expect(self, &mut p, b"EX") {
+                        return Ok(None);
+                    }
+                    return self.index(p + 1);
+                }
+                if upper(self, p) != b'T' {
+                    return Ok(None);
+                }
+                // Truncated toward zero; a -0 left over is normalised by `primary`
+                let value = self.one_arg(p + 1)?;
+                return Ok(value.map(|v| {
+                    let [a, b, c, _] = v.bcd.0;
+                    Value::new(v.sign, Bcd([a, b, c, 0]))
+                }));
+            }
+            b'I' if upper(self, p + 1) == b'S' => {
+                p += 1;
+                if !expect(self, &mut p, b"ERROR") {
+                    return Ok(None);
+                }
+                return self.iserror(p + 1);
+            }
+            b'I' if expect(self, &mut p, b"F") => return self.if_func(p + 1),
+            b'L' if expect(self, &mut p, b"OOKUP") => return self.lookup(p + 1),
+            b'N' if upper(self, p + 1) == b'P' => {
+                p += 1;
+                if !expect(self, &mut p, b"V") {
+                    return Ok(None);
+                }
+                return self.npv(p + 1);
+            }
+            b'N' if upper(self, p + 1) == b'A' => return self.fail(CellError::NotAvailable),
+            b'N' if expect(self, &mut p, b"OT") => return self.not_func(p + 1),
+            b'O' if expect(self, &mut p, b"R") => return self.logic(true, p + 1),
+            b'R' if expect(self, &mut p, b"OUND") => return self.round_func(p + 1),
+            b'P' if upper(self, p + 1) == b'V' => return self.finance(0, p + 2),
+            b'P' if expect(self, &mut p, b"MT") => return self.finance(1, p + 1),
+            b'F' if expect(self, &mut p, b"V") => return self.finance(2, p + 1),
+            b'E' if expect(self, &mut p, b"RROR") => return self.fail(CellError::Error),
+            _ => return Ok(None),
+        };
+        p += 1;
+        if self.at(p) != b'(' {
+            return Ok(None);
+        }
+
+        let mut acc = Value::default();
+        let mut count: u16 = 0;
+        // False once the running total overflows
+        let mut add = |v: Value| -> Result<bool, Unmodeled> {
+            count = count.wrapping_add(1);
+            match func {
+                Func::Sum | Func::Avg => match signed_add(acc, v)? {
+                    Some(sum) => acc = sum,
+                    None => return Ok(false),
+                },
+                Func::Min | Func::Max if count == 1 => acc = v,
+                Func::Min if order(acc, v) == GREATER => acc = v,
+                Func::Max if order(acc, v) == LESS => acc = v,
+                _ => {}
+            }
+            Ok(true)
+        };
+        // Ranges and single cells count only number and formula cells;
+        // literal numbers always count
+        loop {
+            p += 1;
+            if self.at(p) & 0xDF >= b'A' {
+                let Some(([col1, row1, col2, row2], q)) = self.range(p) else {
+                    return Ok(None);
+                };
+                p = q;
+                // Both loops test at the bottom, so every column and row runs at least once
+                let mut col = col1;
+                loop {
+                    let mut row = row1;
+                    loop {
+                        let cell = self.cell(col, row)?;
+                        let value = match cell[0] {
+                            CELL_NUMBER => Some(Sheet::cell_value(&cell)),
+                            CELL_ERROR => return self.cell_fail(self.cell_index(col, row), &cell),
+                            CELL_FORMULA => Some(self.sheet.formula_value(&cell)),
+                            _ => None,
+                        };
+                        if let Some(v) = value {
+                            if !add(v)? {
+                                return self.fail(CellError::Overflow);
+                            }
+                        }
+                        row = row.wrapping_add(1);
+                        if row2 < row {
+                            break;
+                        }
+                    }
+                    col = col.wrapping_add(1);
+                    if col2 < col {
+                        break;
+                    }
+                }
+            } else {
+                let digits = usize::from(self.at(p) == b'-');
+                let v = match parse_number(self.expr.get(p..).unwrap_or(&[])) {
+                    Ok(v) => v,
+                    Err(CellError::Overflow) => return self.fail(CellError::Overflow),
+                    Err(_) => return Ok(None),
+                };
+                if !add(v)? {
+                    return self.fail(CellError::Overflow);
+                }
+                p += digits;
+                while self.at(p) == b'.' || self.at(p).is_ascii_digit() {
+                    p += 1;
+                }
+            }
+            match self.at(p) {
+                b',' => {}
+                b')' => break,
+                _ => return Ok(None),
+            }
+        }
+        self.pos = p + 1;
+
+        Ok(Some(match func {
+            Func::Sum | Func::Min | Func::Max => acc,
+            Func::Avg if count == 0 => Value::default(),
+            Func::Avg => {
+                let divisor = Bcd([0, 0, 0, count_bcd(count)]);
+                divisor.value()?;
+                // A count byte of zero (256 cells) leaves the sum in place
+                Value::new(acc.sign, bcd_div_noscale(acc.bcd, divisor).unwrap_or(acc.bcd))
+            }
+            Func::Count => Value::new(0, Bcd([0, 0, count_bcd(count), 0])),
+        }))
+    }
+}
+
+const LESS: u8 = 1;
+const GREATER: u8 = 4;
+
+/// `eval_cmp`: 1 if `a < b`, 2 if equal, 4 if greater. Opposite signs
+/// make the negative side less whatever the magnitudes; equal signs
+/// compare magnitudes, reversed if negative
+fn order(a: Value, b: Value) -> u8 {
+    let (less, equal, greater) = (LESS, 2, GREATER);
+    if a.sign != b.sign {
+        return if a.sign != 0 { less } else { greater };
+    }
+    match (bcd_cmp(a.bcd, b.bcd), a.sign != 0) {
+        (Ordering::Equal, _) => equal,
+        (Ordering::Less, false) | (Ordering::Greater, true) => less,
+        _ => greater,
+    }
+}
+
+/// `pf_position`: a 1-based position from the units of a non-negative
+/// value below 100 (decimals ignored)
+fn position(value: Value) -> Option<u8> {
+    let [high, low, units, _] = value.bcd.0;
+    let n = (units >> 4) * 10 + (units & 0x0F);
+    (value.sign == 0 && high == 0 && low == 0 && n != 0).then_some(n)
+}
+
+/// `eval_bool`: 1.00 for true, 0.00 for false
+fn boolean(truth: bool) -> Value {
+    Value::new(0, Bcd::from_hundredths(if truth { 100 } else { 0 }))
+}
+
+/// `eval_norm`: a zero result is never negative
+fn normalise(mut value: Value) -> Value {
+    if value.bcd == Bcd::ZERO {
+        value.sign = 0;
+    }
+    value
+}
+
+/// The ROM's conversion of a cell count to one BCD byte
+///
+/// Only the low byte of the count is used, and tens above 9 spill into
+/// the nibble rotation rather than saturating.
+fn count_bcd(count: u16) -> u8 {
+    let low = count as u8;
+    (low / 10).rotate_left(4) | (low % 10)
+}
+
+/// `fin_*`: a 12-digit decimal float, `0.mant * 10^exp` with the first
+/// mantissa digit non-zero (zero has exponent `FIN_ZERO_EXP`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Float {
+    sign: u8,
+    exp: i8,
+    mant: u64,
+}
+
+const FIN_ZERO: Float = Float { sign: 0, exp: -64, mant: 0 };
+const FIN_ONE: Float = Float { sign: 0, exp: 1, mant: 100_000_000_000 };
+
+fn pow10(n: u32) -> u128 {
+    10u128.pow(n)
+}
+
+/// `fin_shr` `digits` times on a 26-digit buffer
+fn shift_right(n: u128, digits: u32) -> u128 {
+    10u128.checked_pow(digits).map_or(0, |d| n / d)
+}
+
+/// `fin_pack`: the 26-digit buffer `n` (24 decimals) normalised and
+/// truncated to 12 digits
+fn fin_pack(sign: u8, mut exp: i8, mut n: u128) -> Float {
+    if n >= pow10(24) {
+        n /= 10;
+        exp = exp.wrapping_add(1);
+    }
+    let mut tries = 25;
+    while n < pow10(23) {
+        tries -= 1;
+        if tries == 0 {
+            return FIN_ZERO;
+        }
+        n *= 10;
+        exp = exp.wrapping_sub(1);
+    }
+    if exp < -60 {
+        return FIN_ZERO;
+    }
+    Float { sign, exp, mant: (n / pow10(12)) as u64 }
+}
+
+/// `fin_from`: an 8-digit BCD magnitude times 10^(exp-8)
+fn fin_from(sign: u8, exp: i8, digits: u32) -> Float {
+    fin_pack(sign, exp, digits as u128 * pow10(16))
+}
+
+/// `fin_add`: the smaller exponent's mantissa is shifted right (digits
+/// beyond the 24th are lost), then magnitudes add or subtract
+fn fin_add(a: Float, b: Float) -> Float {
+    let (mut a, mut b) = (a, b);
+    let mut shift = a.exp.wrapping_sub(b.exp);
+    if shift < 0 {
+        (a, b) = (b, a);
+        shift = shift.wrapping_neg();
+    }
+    let p = a.mant as u128 * pow10(12);
+    let e = shift_right(b.mant as u128 * pow10(12), shift as u8 as u32);
+    if a.sign == b.sign {
+        fin_pack(a.sign, a.exp, p + e)
+    } else if p >= e {
+        fin_pack(a.sign, a.exp, p - e)
+    } else {
+        fin_pack(a.sign ^ 0x80, a.exp, e - p)
+    }
+}
+
+/// `fin_mul`: exact 24-digit product, then truncated
+fn fin_mul(a: Float, b: Float) -> Float {
+    fin_pack(a.sign ^ b.sign, a.exp.wrapping_add(b.exp), a.mant as u128 * b.mant as u128)
+}
+
+/// `fin_div`: 13 quotient digits; `None` for a zero divisor
+fn fin_div(a: Float, b: Float) -> Option<Float> {
+    if b.mant < 10_000_000_000 {
+        return None;
+    }
+    let q = a.mant as u128 * pow10(12) / b.mant as u128;
+    Some(fin_pack(a.sign ^ b.sign, a.exp.wrapping_sub(b.exp), q * pow10(12)))
+}
+
+/// `fin_to`: back to 6.2, rounding half up; `None` from a million up
+fn fin_to(f: Float) -> Option<Value> {
+    let shift = 6i8.wrapping_sub(f.exp);
+    if shift < 0 {
+        return None;
+    }
+    let n = shift_right(f.mant as u128 * pow10(12), shift as u32);
+    let mut hundredths = (n / pow10(16)) as u32;
+    if n / pow10(15) % 10 >= 5 {
+        hundredths += 1;
+        if hundredths == MODULUS {
+            return None;
+        }
+    }
+    Some(Value::new(f.sign, Bcd::from_hundredths(hundredths)))
+}
+
+/// `fin_rate`: v = 1/(1 + rate/100) for a rate in percent; exactly 1 at
+/// a zero rate, where the annuity factor is n and v^n is 1
+fn fin_rate(rate: u32) -> Float {
+    let one_plus_r = fin_add(fin_from(0, 4, rate), FIN_ONE);
+    fin_div(FIN_ONE, one_plus_r).expect("1 + rate is at least 1")
+}
+
+/// `fin_annuity`: (v + v^2 + ... + v^n, v^n), over the bits of `n`
+fn fin_annuity(v: Float, n: u16) -> (Float, Float) {
+    let (mut a, mut vn) = (v, v);
+    for bit in (0..15 - n.leading_zeros()).rev() {
+        a = fin_mul(fin_add(vn, FIN_ONE), a);
+        vn = fin_mul(vn, vn);
+        if n & (1 << bit) != 0 {
+            (a, vn) = fin_accrue(FIN_ONE, v, a, vn);
+        }
+    }
+    (a, vn)
+}
+
+/// `fin_accrue`: one period more, `x` discounted by it added to `sum`,
+/// and the discount `vn` times v
+fn fin_accrue(x: Float, v: Float, sum: Float, vn: Float) -> (Float, Float) {
+    (fin_add(fin_mul(fin_mul(x, v), vn), sum), fin_mul(vn, v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::FORMULA_PTR;
+    use crate::harness::{Harness, StopReason};
+
+    fn bcd(v: u32) -> Bcd {
+        Bcd::from_hundredths(v)
+    }
+
+    #[test]
+    fn test_ascii_to_bcd() {
+        assert_eq!(ascii_to_bcd(b"123.45"), Some(Bcd([0x00, 0x01, 0x23, 0x45])));
+        assert_eq!(ascii_to_bcd(b"123"), Some(bcd(12300)));
+        assert_eq!(ascii_to_bcd(b"0.5"), Some(bcd(50)));
+        assert_eq!(ascii_to_bcd(b"1.239"), Some(bcd(123)));
+        assert_eq!(ascii_to_bcd(b"12abc"), Some(bcd(1200)));
+        // Six integer digits fit; a seventh overflows once scaled, leading
+        // zeros do not count
+        assert_eq!(ascii_to_bcd(b"999999.99"), Some(Bcd::MAX));
+        assert_eq!(ascii_to_bcd(b"0000999999"), Some(bcd(99_999_900)));
+        assert_eq!(ascii_to_bcd(b"1000000"), None);
+        assert_eq!(ascii_to_bcd(b"1000000.0"), None);
+        assert_eq!(ascii_to_bcd(b"123456789"), None);
+        assert_eq!(parse_number(b"-3"), Ok(Value::new(0x80, bcd(300))));
+        assert_eq!(parse_number(b".5"), Ok(Value::new(0x00, bcd(50))));
+        assert_eq!(parse_number(b"-.25"), Ok(Value::new(0x80, bcd(25))));
+        assert_eq!(parse_number(b"-."), Err(CellError::Syntax));
+        assert_eq!(parse_number(b"-1234567"), Err(CellError::Overflow));
+    }
+
+    #[test]
+    fn test_bcd_arithmetic() {
+        assert_eq!(bcd_add(bcd(99_999_998), bcd(1)), Some(Bcd::MAX));
+        assert_eq!(bcd_add(bcd(99_999_999), bcd(2)), None);
+        assert_eq!(bcd_sub(bcd(100), bcd(250)), bcd(99_999_850));
+        assert_eq!(bcd_mul(bcd(250), bcd(400)), Some(bcd(1000)));
+        assert_eq!(bcd_mul(bcd(123), bcd(1)), Some(bcd(1)));
+        assert_eq!(bcd_div(bcd(1000), bcd(300)), Ok(bcd(333)));
+        assert_eq!(bcd_div(bcd(1000), Bcd::ZERO), Err(CellError::Div0));
+        // Full-width operands: 123456.78 / 1.00 and 1000.00 * 100.00
+        assert_eq!(bcd_div(bcd(12_345_678), bcd(100)), Ok(bcd(12_345_678)));
+        assert_eq!(bcd_mul(bcd(100_000), bcd(10_000)), Some(bcd(10_000_000)));
+        // 10000.00 * 100.00 and 100000.00 / 0.10 need seven integer digits
+        assert_eq!(bcd_mul(bcd(1_000_000), bcd(10_000)), None);
+        assert_eq!(bcd_div(bcd(10_000_000), bcd(10)), Err(CellError::Overflow));
+    }
+
+    #[test]
+    fn test_eval_precedence() {
+        let mut sheet = Sheet::new();
+        sheet.enter(0, 0, "4").unwrap();
+        sheet.enter(0, 1, "-1.5").unwrap();
+        let eval = |e: &str| sheet.eval(e.as_bytes()).unwrap().ok().map(|v| v.to_string());
+        assert_eq!(eval("2+3*4").as_deref(), Some("14.00"));
+        assert_eq!(eval("(2+3)*4").as_deref(), Some("20.00"));
+        assert_eq!(eval("10-4-3").as_deref(), Some("3.00"));
+        assert_eq!(eval("8/4/2").as_deref(), Some("1.00"));
+        assert_eq!(eval("A1*a2").as_deref(), Some("-6.00"));
+        assert_eq!(eval("A2+1.5").as_deref(), Some("0.00"));
+        assert_eq!(eval("1-A1*(A2+(2-1))").as_deref(), Some("3.00"));
+        assert_eq!(eval("-A1").as_deref(), Some("-4.00"));
+        assert_eq!(eval("A1*-2").as_deref(), Some("-8.00"));
+        assert_eq!(eval("-(A1+A2)").as_deref(), Some("-2.50"));
+        assert_eq!(eval("2--A2").as_deref(), Some("0.50"));
+        assert_eq!(eval("+-+-3").as_deref(), Some("3.00"));
+        assert_eq!(eval("-0").as_deref(), Some("0.00"));
+        assert_eq!(eval("7/0"), None);
+        assert_eq!(eval("1+7/0*2"), None);
+        // Unbalanced parentheses and stray operators are errors
+        assert_eq!(eval("(1+2"), None);
+        assert_eq!(eval("1+2)"), None);
+        assert_eq!(eval("1%2"), None);
+        let deep = |n: usize| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(eval(&deep(MAX_NESTING as usize)).as_deref(), Some("1.00"));
+        assert_eq!(eval(&deep(MAX_NESTING as usize + 1)), None);
+        assert_eq!(sheet.eval(b"A0"), Ok(Err(CellError::Ref)));
+    }
+
+    #[test]
+    fn test_logic() {
+        let mut sheet = Sheet::new();
+        sheet.enter(0, 0, "-2").unwrap();
+        sheet.enter(0, 1, "-10").unwrap();
+        let eval = |e: &str| sheet.eval(e.as_bytes()).unwrap().ok().map(|v| v.to_string());
+        for (expr, want) in [
+            ("A1<A2", "0.00"),
+            ("A2<A1", "1.00"),
+            ("A1<=-2", "1.00"),
+            ("A1>=-1.99", "0.00"),
+            ("A1<>A2", "1.00"),
+            ("A1=-2", "1.00"),
+            ("-0=0", "1.00"),
+            ("1+1=2", "1.00"),
+            ("1<2<3", "1.00"),
+            ("3>2>1", "0.00"),
+            ("@IF(A1<0,-A1,A1)*3", "6.00"),
+            ("@if(0,1/0,7)", "7.00"),
+            ("@IF(1,@IF(0,1,2),(3))", "2.00"),
+            ("@AND(1,2,-3)", "1.00"),
+            ("@AND(1,0,1/0)", "0.00"),
+            ("@OR(0,0)", "0.00"),
+            ("@OR(0,A1,@SUM(A1:A2))", "1.00"),
+            ("@NOT(A1+2)", "1.00"),
+            ("@NOT(@NOT(5))", "1.00"),
+        ] {
+            assert_eq!(eval(expr).as_deref(), Some(want), "{}", expr);
+        }
+        for expr in ["@IF(1,2)", "@AND(1,1/0)", "@OR(1", "@NOT 1", "@IFF(1,2,3)"] {
+            assert_eq!(eval(expr), None, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_numeric_functions() {
+        let mut sheet = Sheet::new();
+        sheet.enter(0, 0, "-7.25").unwrap();
+        let eval = |e: &str| sheet.eval(e.as_bytes()).unwrap().ok().map(|v| v.to_string());
+        for (expr, want) in [
+            ("@ABS(A1)", "7.25"),
+            ("@abs(-0)", "0.00"),
+            ("@INT(A1)", "-7.00"),
+            ("@INT(-0.99)", "0.00"),
+            ("@ROUND(A1,1)", "-7.30"),
+            ("@ROUND(A1,0)", "-7.00"),
+            ("@ROUND(1.5,0)", "2.00"),
+            ("@ROUND(1234.56,-2)", "1200.00"),
+            ("@ROUND(1250,-2)", "1300.00"),
+            ("@ROUND(A1,2.9)", "-7.25"),
+            ("@ROUND(456789,-5)", "500000.00"),
+            ("@MOD(A1,2)", "-1.25"),
+            ("@MOD(7,-2)", "1.00"),
+            ("@MOD(10,2.5)", "0.00"),
+            ("@MOD(0.07,0.05)", "0.02"),
+        ] {
+            assert_eq!(eval(expr).as_deref(), Some(want), "{}", expr);
+        }
+        for expr in [
+            "@MOD(1,0)",
+            "@ROUND(1,3)",
+            "@ROUND(1,-6)",
+            "@ROUND(1)",
+            "@ABS(1,2)",
+            "@ROUND(999999.99,1)",
+        ] {
+            assert_eq!(eval(expr), None, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_table_functions() {
+        let mut sheet = Sheet::new();
+        let table = [("0", "10"), ("100", "20"), ("500", "-30")];
+        for (row, (limit, rate)) in table.iter().enumerate() {
+            sheet.enter(0, row as u8, limit).unwrap();
+            sheet.enter(1, row as u8, rate).unwrap();
+        }
+        sheet.enter(2, 0, "7").unwrap();
+        let eval = |e: &str| sheet.eval(e.as_bytes()).unwrap().ok().map(|v| v.to_string());
+        for (expr, want) in [
+            ("@LOOKUP(0,A1:A3)", "10.00"),
+            ("@LOOKUP(99.99,A1:A3)", "10.00"),
+            ("@LOOKUP(100,A1:A3)", "20.00"),
+            ("@lookup(1000,A1:A3)", "-30.00"),
+            ("@LOOKUP(8,A1:C1)", "100.00"),
+            ("@LOOKUP(2*A3,A1:A2)*2", "40.00"),
+            ("@CHOOSE(2,1/0,B3,1/0)", "-30.00"),
+            ("@CHOOSE(3.7,1,2,3)", "3.00"),
+            ("@CHOOSE(1,@CHOOSE(2,5,6),7)", "6.00"),
+            ("@INDEX(A1:C3,2,1)", "100.00"),
+            ("@INDEX(A1:C3,1,3)", "7.00"),
+            ("@INDEX(A1:C3,@CHOOSE(2,1,3),2)+1", "-29.00"),
+        ] {
+            assert_eq!(eval(expr).as_deref(), Some(want), "{}", expr);
+        }
+        for expr in [
+            "@LOOKUP(-1,A1:A3)",
+            "@LOOKUP(1,A1:B2)",
+            "@LOOKUP(1,A3:A1)",
+            "@CHOOSE(0,1)",
+            "@CHOOSE(-1,1)",
+            "@CHOOSE(3,1,2)",
+            "@INDEX(A1:C3,4,1)",
+            "@INDEX(A1:C3,1,0)",
+            "@INDEX(A1:C3,1)",
+        ] {
+            assert_eq!(eval(expr), None, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_financial_functions() {
+        let mut sheet = Sheet::new();
+        for (row, v) in ["-1000", "300", "400", "500"].iter().enumerate() {
+            sheet.enter(0, row as u8, v).unwrap();
+        }
+        let eval = |e: &str| sheet.eval(e.as_bytes()).unwrap().ok().map(|v| v.to_string());
+        // Against the closed forms in f64, to the displayed cent
+        let annuity = |rate: f64, n: i32| (1.0 - (1.0 + rate).powi(-n)) / rate;
+        let cents = |v: f64| format!("{:.2}", v);
+        for (x, rate, n) in [
+            (200000.0, 0.5, 360),
+            (1000.0, 1.0, 12),
+            (1234.56, 0.07, 480),
+            (50.0, 0.25, 9999),
+            (9.99, 37.5, 30),
+        ] {
+            let r = rate / 100.0;
+            let a = annuity(r, n);
+            let fv = x * a * (1.0 + r).powi(n);
+            for (name, want) in [("PV", x * a), ("PMT", x / a), ("FV", fv)] {
+                if want < 999999.99 {
+                    let expr = format!("@{}({},{},{})", name, x, rate, n);
+                    assert_eq!(eval(&expr), Some(cents(want)), "{}", expr);
+                }
+            }
+        }
+        for (expr, want) in [
+            ("@PMT(200000,0.5,360)", "1199.10"),
+            ("@FV(500,0.5,120)", "81939.67"),
+            ("@pv(-100,10,1)", "-90.91"),
+            ("@PMT(1000,100,1.9)", "2000.00"),
+            ("@NPV(10,A1:A4)", "-19.12"),
+            ("@NPV(10,A2:A4)+A1", "-21.04"),
+            ("@NPV(5,A2:A2)", "285.71"),
+            ("@NPV(0.01,A1:A1)", "-999.90"),
+            // A zero rate: x/n, x*n and the plain sum
+            ("@PMT(1000,0,8)", "125.00"),
+            ("@PV(100,0,12)", "1200.00"),
+            ("@FV(-500,0,120)", "-60000.00"),
+            ("@NPV(0,A1:A4)", "200.00"),
+        ] {
+            assert_eq!(eval(expr).as_deref(), Some(want), "{}", expr);
+        }
+        for expr in [
+            "@PV(100,-1,12)",
+            "@PV(100,1,0)",
+            "@PV(100,1,-5)",
+            "@PV(100,1,10000)",
+            "@PV(100,1)",
+            "@FV(999999,1,12)",
+            "@FV(1,100,9999)",
+            "@NPV(-1,A1:A4)",
+            "@NPV(10,A4:A1)",
+            "@NPV(10)",
+        ] {
+            assert_eq!(eval(expr), None, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut sheet = Sheet::new();
+        for (row, v) in ["10", "-4", "7.5"].iter().enumerate() {
+            sheet.enter(1, row as u8, v).unwrap();
+        }
+        sheet.enter(1, 3, "\"total").unwrap();
+        let eval = |e: &str| sheet.eval(e.as_bytes()).unwrap().unwrap().to_string();
+        assert_eq!(eval("@SUM(B1:B4)"), "13.50");
+        assert_eq!(eval("@avg(B1:B4)"), "4.50");
+        assert_eq!(eval("@MIN(B1:B4)"), "-4.00");
+        assert_eq!(eval("@MAX(B1:B4)"), "10.00");
+        assert_eq!(eval("@COUNT(A1:B4)"), "3.00");
+        // Nothing to compare: zero, as for @SUM
+        assert_eq!(eval("@MIN(A1:A2)"), "0.00");
+        assert_eq!(eval("@SUM(B1:B3)*2"), "27.00");
+        // Argument lists: cells and literals count, empty cells and labels do not
+        assert_eq!(eval("@SUM(B1:B2,B3,-0.5)"), "13.00");
+        assert_eq!(eval("@COUNT(A1,B1,B4,2,B2:B3)"), "4.00");
+        assert_eq!(eval("@AVG(B1,B3,5)"), "7.50");
+        assert_eq!(eval("@MIN(B1,-12,B3)"), "-12.00");
+        assert_eq!(eval("@MAX(B2,-12,3)"), "3.00");
+        assert_eq!(eval("@MAX(5,-100)"), "5.00");
+        assert_eq!(eval("@MIN(5,-100)"), "-100.00");
+        assert_eq!(eval("@MAX(-1,-2)"), "-1.00");
+        assert_eq!(eval("@MIN(-1,-2)"), "-2.00");
+        assert_eq!(sheet.eval(b"@SUM(B1,)").unwrap(), Err(CellError::Syntax));
+        assert_eq!(sheet.eval(b"@SUM(B1;B2)").unwrap(), Err(CellError::Syntax));
+        assert_eq!(sheet.eval(b"@COUNT(-B1)").unwrap(), Err(CellError::Syntax));
+    }
+
+    #[test]
+    fn test_matches_rom() {
+        // Labels are typed after Enter opens the editor on the empty cell
+        let keys = "12.5\rj-3\rj=A1*A2+1\rj\r\"note\rl=@SUM(A1:A3)/4\rj=zz\rkk=@AVG(A1:A3)\r";
+        let mut h = Harness::spreadsheet();
+        h.type_keys(keys);
+
+        let mut sheet = Sheet::new();
+        sheet.enter(0, 0, "12.5").unwrap();
+        sheet.enter(0, 1, "-3").unwrap();
+        sheet.enter(0, 2, "=A1*A2+1").unwrap();
+        sheet.enter(0, 3, "\"note").unwrap();
+        sheet.enter(1, 3, "=@SUM(A1:A3)/4").unwrap();
+        sheet.enter(1, 4, "=zz").unwrap();
+        sheet.enter(1, 2, "=@AVG(A1:A3)").unwrap();
+
+        for row in 0..5 {
+            for col in 0..2 {
+                assert_eq!(h.cell(col, row), &sheet.cell(col, row), "cell {} {}", col, row);
+            }
+        }
+        let heap = sheet.layout().scratch();
+        let used = (sheet.formula_ptr() - heap) as usize;
+        assert_eq!(h.peek_bytes(heap, used), &sheet.heap()[..used]);
+        assert_eq!(sheet.value(0, 2).unwrap().to_string(), "-36.50");
+    }
+
+    /// Type `columns` top to bottom from column A into both the ROM and
+    /// the model (empty entries are skipped), check that cells and heap
+    /// agree, return the model
+    fn sheet_match_rom(columns: &[&[String]]) -> Sheet {
+        enter_match_rom(columns).1
+    }
+
+    /// Type `columns` into the ROM and the model, check they store the
+    /// same cells and heap; the ROM's cursor ends on row 1 just right of
+    /// the last column
+    fn enter_match_rom(columns: &[&[String]]) -> (Harness, Sheet) {
+        let mut h = Harness::spreadsheet();
+        let mut sheet = Sheet::new();
+        for (col, entries) in columns.iter().enumerate() {
+            for (row, entry) in entries.iter().enumerate() {
+                if !entry.is_empty() {
+                    h.type_keys(&format!("{}\r", entry));
+                    sheet.enter(col as u8, row as u8, entry).unwrap();
+                    sheet.recalculate().unwrap();
+                }
+                h.type_keys("j");
+            }
+            h.type_keys(&format!("l{}", "k".repeat(entries.len())));
+        }
+
+        for (col, entries) in columns.iter().enumerate() {
+            for (row, entry) in entries.iter().enumerate() {
+                let (col, row) = (col as u8, row as u8);
+                assert_eq!(h.cell(col, row), &sheet.cell(col, row), "{}", entry);
+            }
+        }
+        let heap = sheet.layout().scratch();
+        let used = (sheet.formula_ptr() - heap) as usize;
+        assert_eq!(h.peek_bytes(heap, used), &sheet.heap()[..used]);
+        (h, sheet)
+    }
+
+    /// A1=3, A2=-8 and `formulas` down column B
+    fn formulas_match_rom(formulas: &[String]) -> Sheet {
+        sheet_match_rom(&[&["3".to_string(), "-8".to_string()], formulas])
+    }
+
+    #[test]
+    fn test_expressions_match_rom() {
+        let deep = |n: usize| format!("={}2{}*3", "(".repeat(n), ")".repeat(n));
+        let formulas = [
+            "=A1+A2*2".to_string(),
+            "=(A1+A2)*2".to_string(),
+            "=A1-A2/4-1".to_string(),
+            "=100/(A1-A1)".to_string(),
+            "=((A1+1)*(A2-1))/2".to_string(),
+            "=@SUM(A1:A2)+A2*@MAX(A1:A2)".to_string(),
+            "=(1+2".to_string(),
+            "=1+2)".to_string(),
+            deep(MAX_NESTING as usize),
+            deep(MAX_NESTING as usize + 1),
+            "=-A1".to_string(),
+            "=A2*-2".to_string(),
+            "=-(A1+A2)".to_string(),
+            "=-@SUM(A1:A2)".to_string(),
+            "=A2<A1".to_string(),
+            "=(A1>=3)+(A2<>-8)*10".to_string(),
+            "=@IF(A1>A2,A1,A2)".to_string(),
+            "=@IF(A1=0,1/A1,-A1)".to_string(),
+            "=@AND(A1,A2<0,@NOT(A1=A2))".to_string(),
+            "=@OR(A1<0,A2>0)".to_string(),
+            "=@AND(A2>0,1/0)".to_string(),
+            "=1+".to_string(),
+            "=1*".to_string(),
+            "=+".to_string(),
+            "=(A1-)*2".to_string(),
+            "=.5+A1".to_string(),
+        ];
+        // Half of them at a time fill the default heap
+        let sheets = [formulas_match_rom(&formulas[..13]), formulas_match_rom(&formulas[13..])];
+        let value = |row: usize| sheets[row / 13].value(1, (row % 13) as u8).map(|v| v.to_string());
+        let error = |row: usize| sheets[row / 13].error(1, (row % 13) as u8);
+        assert_eq!(value(0).as_deref(), Some("-13.00"));
+        assert_eq!(value(1).as_deref(), Some("-10.00"));
+        assert_eq!(value(2).as_deref(), Some("4.00"));
+        assert_eq!(error(3), Some(CellError::Div0));
+        assert_eq!(value(4).as_deref(), Some("-18.00"));
+        assert_eq!(value(5).as_deref(), Some("-29.00"));
+        assert_eq!(value(8).as_deref(), Some("6.00"));
+        assert_eq!(error(9), Some(CellError::Syntax));
+        assert_eq!(value(10).as_deref(), Some("-3.00"));
+        assert_eq!(value(11).as_deref(), Some("16.00"));
+        assert_eq!(value(12).as_deref(), Some("5.00"));
+        assert_eq!(value(13).as_deref(), Some("5.00"));
+        assert_eq!(value(14).as_deref(), Some("1.00"));
+        assert_eq!(value(15).as_deref(), Some("1.00"));
+        assert_eq!(value(16).as_deref(), Some("3.00"));
+        assert_eq!(value(17).as_deref(), Some("-3.00"));
+        assert_eq!(value(18).as_deref(), Some("1.00"));
+        assert_eq!(value(19).as_deref(), Some("0.00"));
+        assert_eq!(value(20).as_deref(), Some("0.00"));
+        // A missing operand is not read as zero
+        for (row, formula) in formulas.iter().enumerate().take(25).skip(21) {
+            assert_eq!(error(row), Some(CellError::Syntax), "{}", formula);
+        }
+        assert_eq!(value(25).as_deref(), Some("3.50"));
+    }
+
+    #[test]
+    fn test_unary_matches_rom() {
+        let formulas = ["=-A1", "=+3", "=-(A1+2)", "=2*-3", "=--1"].map(String::from);
+        let sheet = formulas_match_rom(&formulas);
+        let values: Vec<_> = (0..5).map(|row| sheet.value(1, row).map(|v| v.to_string())).collect();
+        assert_eq!(values, ["-3.00", "3.00", "-5.00", "-6.00", "1.00"].map(|v| Some(v.to_string())));
+    }
+
+    #[test]
+    fn test_lex() {
+        assert_eq!(
+            lex(b"$B$3<>.5"),
+            [TOK_REF, 0xC1, 2, TOK_CMP | 5, TOK_DOT, 0, 0, 0, 0x50, TOK_END]
+        );
+        // Rows wrap as the text is read; names are matched ignoring case
+        assert_eq!(lex(b"A0+a257"), [TOK_REF, 0, 0xFF, b'+', TOK_REF, 0, 0, TOK_END]);
+        assert_eq!(lex(b"@sum("), [TOK_FUNC, b'(', TOK_END]);
+        assert_eq!(lex(b"@X"), [b'@', TOK_REF, 23, 0xFF, TOK_END]);
+        assert_eq!(lex(b"123456789>=1")[..2], [TOK_OVF, TOK_CMP | 6]);
+    }
+
+    #[test]
+    fn test_lex_matches_rom() {
+        // The heaps hold the tokens, so comparing them compares the lexers
+        let formulas = [
+            "=$A$1+a$2*$a1",
+            "=@sum(A1:A2)+@Avg(A1:A2)",
+            "=@FOO(1)",
+            "=Z1+A0",
+            "=A256+1",
+            "=123456789",
+            "=1.2.3<=.25",
+        ]
+        .map(String::from);
+        let sheet = formulas_match_rom(&formulas);
+        assert_eq!(sheet.value(1, 0).unwrap().to_string(), "-21.00");
+        assert_eq!(sheet.value(1, 1).unwrap().to_string(), "-7.50");
+        for (row, err) in [(2, CellError::Syntax), (3, CellError::Ref), (5, CellError::Overflow)] {
+            assert_eq!(sheet.error(1, row), Some(err), "{}", formulas[row as usize]);
+        }
+    }
+
+    #[test]
+    fn test_numeric_functions_match_rom() {
+        let formulas = [
+            "=@ABS(A2)",
+            "=@INT(-A2/3)",
+            "=@INT(-0.5)",
+            "=@ROUND(A2/3,1)",
+            "=@ROUND(-A2/3,0)",
+            "=@ROUND(A1*1234.5,-3)",
+            "=@ROUND(A1,3)",
+            "=@MOD(A2,A1)",
+            "=@MOD(A1,0)",
+            "=@MOD(12.34,-A1)",
+            "=@ROUND(@MOD(A1*10,A2),-1)",
+        ]
+        .map(String::from);
+        let sheet = formulas_match_rom(&formulas);
+        let value = |row| sheet.value(1, row).map(|v| v.to_string());
+        assert_eq!(value(0).as_deref(), Some("8.00"));
+        assert_eq!(value(1).as_deref(), Some("2.00"));
+        assert_eq!(value(2).as_deref(), Some("0.00"));
+        assert_eq!(value(3).as_deref(), Some("-2.70"));
+        assert_eq!(value(4).as_deref(), Some("3.00"));
+        assert_eq!(value(5).as_deref(), Some("4000.00"));
+        assert_eq!(sheet.error(1, 6), Some(CellError::Syntax));
+        assert_eq!(value(7).as_deref(), Some("-2.00"));
+        assert_eq!(sheet.error(1, 8), Some(CellError::Div0));
+        assert_eq!(value(9).as_deref(), Some("0.34"));
+        assert_eq!(value(10).as_deref(), Some("10.00"));
+    }
+
+    #[test]
+    fn test_financial_functions_match_rom() {
+        let column = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let flows = column(&["-1000", "300", "400", "", "500.5"]);
+        let formulas = column(&[
+            "=@PMT(200000,0.5,360)",
+            "=@PV(-100,10,1)",
+            "=@FV(500,0.5,120)",
+            "=@PV(50,0.25,9999)",
+            "=@PMT(A2,7.5,A3/100)",
+            "=@FV(-A5,1.25,@PMT(A3,5,2))",
+            "=@NPV(10,A1:A5)",
+            "=@NPV(0.01,A1:B2)",
+            "=@PV(100,0,12)",
+            "=@FV(1,100,9999)",
+            "=@FV(999999,1,12)",
+            "=@NPV(10,A5:A1)",
+            "=@PMT(1000,0,3)",
+            "=@FV(-A5,0,2)",
+            "=@NPV(0,A1:A5)",
+        ]);
+        let sheet = sheet_match_rom(&[&flows, &formulas]);
+        let value = |row| sheet.value(1, row).map(|v| v.to_string());
+        assert_eq!(value(0).as_deref(), Some("1199.10"));
+        assert_eq!(value(1).as_deref(), Some("-90.91"));
+        assert_eq!(value(2).as_deref(), Some("81939.67"));
+        assert_eq!(value(3).as_deref(), Some("20000.00"));
+        assert_eq!(value(4).as_deref(), Some("89.57"));
+        assert_eq!(value(6).as_deref(), Some("-49.86"));
+        assert_eq!(value(8).as_deref(), Some("1200.00"));
+        assert_eq!(value(12).as_deref(), Some("333.33"));
+        assert_eq!(value(13).as_deref(), Some("-1001.00"));
+        assert_eq!(value(14).as_deref(), Some("200.50"));
+        let error = |row| sheet.error(1, row);
+        assert_eq!(error(9), Some(CellError::Overflow));
+        assert_eq!(error(10), Some(CellError::Overflow));
+        assert_eq!(error(11), Some(CellError::Syntax));
+    }
+
+    #[test]
+    fn test_table_functions_match_rom() {
+        let column = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let limits = column(&["0", "1000", "2500", "", "1"]);
+        let rates = column(&["0.1", "0.15", "0.2", "", "2"]);
+        let formulas = column(&[
+            "=@LOOKUP(1200,A1:A3)",
+            "=@LOOKUP(A3,A1:A3)*100",
+            "=@LOOKUP(-A5,A1:A3)",
+            "=@LOOKUP(0.12,A1:C1)",
+            "=@LOOKUP(A2,A1:B3)",
+            "=@CHOOSE(A5+1,1/0,B2,1/0)",
+            "=@CHOOSE(4,1,2,3)",
+            "=@INDEX(A1:B3,3,2)",
+            "=@INDEX(A1:B3,A5,B5)*2",
+            "=@INDEX(A1:B3,2,3)",
+        ]);
+        let sheet = sheet_match_rom(&[&limits, &rates, &formulas]);
+        let value = |row| sheet.value(2, row).map(|v| v.to_string());
+        assert_eq!(value(0).as_deref(), Some("0.15"));
+        assert_eq!(value(1).as_deref(), Some("20.00"));
+        let error = |row| sheet.error(2, row);
+        assert_eq!(error(2), Some(CellError::NotAvailable));
+        assert_eq!(value(3).as_deref(), Some("0.15"));
+        assert_eq!(error(4), Some(CellError::Syntax));
+        assert_eq!(value(5).as_deref(), Some("0.15"));
+        assert_eq!(error(6), Some(CellError::Ref));
+        assert_eq!(value(7).as_deref(), Some("0.20"));
+        assert_eq!(value(8).as_deref(), Some("0.20"));
+        assert_eq!(error(9), Some(CellError::Ref));
+    }
+
+    #[test]
+    fn test_lookup_off_grid_matches_rom() {
+        // Tables on the last column and row have no result cells in the grid
+        let mut sheet = Sheet::new();
+        for (i, v) in ["1", "2", "3"].iter().enumerate() {
+            sheet.enter(15, i as u8, v).unwrap();
+            sheet.enter(i as u8, 63, v).unwrap();
+        }
+        sheet.enter(0, 3, "777").unwrap();
+        sheet.enter(0, 0, "=@LOOKUP(5,P1:P3)").unwrap();
+        sheet.enter(0, 1, "=@LOOKUP(5,A64:C64)").unwrap();
+        sheet.enter(0, 2, "=@LOOKUP(2,P2:P3)").unwrap();
+        sheet.recalculate().unwrap();
+        assert_eq!(sheet.error(0, 0), Some(CellError::Ref));
+        assert_eq!(sheet.error(0, 1), Some(CellError::Ref));
+        assert_eq!(sheet.error(0, 2), Some(CellError::Ref));
+
+        let mut h = Harness::spreadsheet();
+        h.load_sheet(&sheet);
+        let recalc = h.symbol("recalc_all").unwrap();
+        assert_eq!(h.call(recalc, 10_000_000), StopReason::Returned);
+        for row in 0..3 {
+            assert_eq!(h.cell(0, row), &sheet.cell(0, row));
+        }
+    }
+
+    #[test]
+    fn test_aggregate_lists_match_rom() {
+        let column = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let values = column(&["10", "-4", "7.5", "", "", "=A1*2"]);
+        let formulas = column(&[
+            "=@SUM(A1:A2,A3,A6)",
+            "=@SUM(A1,A1,-2.25,100)",
+            "=@AVG(A1:A6,0)",
+            "=@MIN(A1,A3,2.5)",
+            "=@MAX(A2,A3,-30)",
+            "=@COUNT(A1:A3,A4,A5,1,A6)",
+            "=@SUM(A1,)",
+            "=@MAX(A1 A2)",
+            "=@NPV(10,A1)",
+            "=@MAX(-1,-2)",
+            "=@MIN(A1:A2)",
+            "=@MIN(A4:A5)",
+        ]);
+        let sheet = sheet_match_rom(&[&values, &formulas]);
+        let value = |row| sheet.value(1, row).map(|v| v.to_string());
+        assert_eq!(value(0).as_deref(), Some("33.50"));
+        assert_eq!(value(1).as_deref(), Some("117.75"));
+        assert_eq!(value(2).as_deref(), Some("6.70"));
+        assert_eq!(value(3).as_deref(), Some("2.50"));
+        assert_eq!(value(4).as_deref(), Some("7.50"));
+        assert_eq!(value(5).as_deref(), Some("5.00"));
+        assert_eq!(sheet.error(1, 6), Some(CellError::Syntax));
+        assert_eq!(sheet.error(1, 7), Some(CellError::Syntax));
+        assert_eq!(value(8).as_deref(), Some("9.09"));
+        assert_eq!(value(9).as_deref(), Some("-1.00"));
+        assert_eq!(value(10).as_deref(), Some("-4.00"));
+        assert_eq!(value(11).as_deref(), Some("0.00"));
+    }
+
+    #[test]
+    fn test_errors() {
+        for (i, name) in ERROR_NAMES.iter().enumerate() {
+            let e = CellError::from_code(i as u8 + ERR_ERROR).unwrap();
+            assert_eq!(name.len(), 7);
+            assert_eq!(e.to_string(), name.trim_start());
+        }
+        assert_eq!(CellError::from_code(0), None);
+        assert_eq!(CellError::from_code(ERR_NA + 1), None);
+
+        let mut sheet = Sheet::new();
+        sheet.enter(0, 0, "=1/0").unwrap();
+        sheet.enter(0, 1, "=@NA").unwrap();
+        sheet.enter(0, 2, "-x").unwrap();
+        assert_eq!(sheet.error(0, 0), Some(CellError::Div0));
+        assert_eq!(sheet.text(0, 0).unwrap(), "=1/0");
+        assert_eq!(sheet.error(0, 2), Some(CellError::Syntax));
+        assert_eq!(sheet.text(0, 2), None);
+        let eval = |e: &str| sheet.eval(e.as_bytes()).unwrap().map(|v| v.to_string());
+        for (expr, want) in [
+            ("A1+1", Err(CellError::Div0)),
+            ("2*(A3)", Err(CellError::Syntax)),
+            ("@SUM(A1:A2)", Err(CellError::Div0)),
+            ("@COUNT(A2,A1)", Err(CellError::NotAvailable)),
+            ("@ERROR+1", Err(CellError::Error)),
+            ("Q1", Err(CellError::Ref)),
+            ("A65", Err(CellError::Ref)),
+            ("@SUM(A1:Q2)", Err(CellError::Ref)),
+            ("@SUM(A1:[2)", Err(CellError::Syntax)),
+            ("@MOD(5,0)", Err(CellError::Div0)),
+            ("@CHOOSE(0,1)", Err(CellError::Ref)),
+            ("@ISERROR(A1)", Ok("1.00".to_string())),
+            ("@ISERROR(@SUM(A3,B1))+@ISERROR(2)", Ok("1.00".to_string())),
+            ("@ISERROR(@FOO(1,2))*5", Ok("5.00".to_string())),
+            ("@IF(@ISERROR(A2),0,A2)", Ok("0.00".to_string())),
+            ("@ISERROR(1,2)", Err(CellError::Syntax)),
+            ("@AND(1/0)", Err(CellError::Div0)),
+        ] {
+            assert_eq!(eval(expr), want, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_errors_match_rom() {
+        let column = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let values = column(&["=10/0", "=@NA", "-x", "5", "=@ERROR"]);
+        let formulas = column(&[
+            "=A1*2",
+            "=@SUM(A4,A2)",
+            "=-A3",
+            "=@ISERROR(A1)+@ISERROR(A4)",
+            "=@ISERROR(@MOD(1,A1:A2))",
+            "=@IF(@ISERROR(A5),-1,A5)",
+            "=Z1",
+            "=A99",
+            "=@INDEX(A1:A5,6,1)",
+            "=@SUM(A4:A6,@NA)",
+        ]);
+        let sheet = sheet_match_rom(&[&values, &formulas]);
+        let value = |row| sheet.value(1, row).map(|v| v.to_string());
+        let error = |row| sheet.error(1, row);
+        assert_eq!(error(0), Some(CellError::Div0));
+        assert_eq!(error(1), Some(CellError::NotAvailable));
+        assert_eq!(error(2), Some(CellError::Syntax));
+        assert_eq!(value(3).as_deref(), Some("1.00"));
+        assert_eq!(value(4).as_deref(), Some("1.00"));
+        assert_eq!(value(5).as_deref(), Some("-1.00"));
+        assert_eq!(error(6), Some(CellError::Ref));
+        assert_eq!(error(7), Some(CellError::Ref));
+        assert_eq!(error(8), Some(CellError::Ref));
+        assert_eq!(error(9), Some(CellError::Error));
+    }
+
+    #[test]
+    fn test_numbers_match_rom() {
+        let column = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let sheet = sheet_match_rom(&[&column(&[".5", "-.25", "5.", ".", "-.x", "-"])]);
+        assert_eq!(sheet.value(0, 0).unwrap().to_string(), "0.50");
+        assert_eq!(sheet.value(0, 1).unwrap().to_string(), "-0.25");
+        assert_eq!(sheet.value(0, 2).unwrap().to_string(), "5.00");
+        for row in 3..6 {
+            assert_eq!(sheet.error(0, row), Some(CellError::Syntax));
+        }
+    }
+
+    #[test]
+    fn test_error_recalc_matches_rom() {
+        // A formula that failed is retried on recalculation and recovers
+        let mut h = Harness::spreadsheet();
+        let mut sheet = Sheet::new();
+        h.type_keys("=1/B1\rl4\r!");
+        sheet.enter(0, 0, "=1/B1").unwrap();
+        assert_eq!(sheet.error(0, 0), Some(CellError::Div0));
+        sheet.enter(1, 0, "4").unwrap();
+        sheet.recalc().unwrap();
+        assert_eq!(sheet.value(0, 0).unwrap().to_string(), "0.25");
+        // and fails again, keeping its last value
+        h.type_keys("=1+2)\r!");
+        sheet.enter(1, 0, "=1+2)").unwrap();
+        sheet.recalc().unwrap();
+        assert_eq!(sheet.error(0, 0), Some(CellError::Syntax));
+        assert_eq!(sheet.formula_value(&sheet.cell(0, 0)).to_string(), "0.25");
+        for col in 0..2 {
+            assert_eq!(h.cell(col, 0), &sheet.cell(col, 0));
+        }
+        let used = (sheet.formula_ptr() - sheet.layout().scratch()) as usize;
+        assert_eq!(h.peek_bytes(sheet.layout().scratch(), used), &sheet.heap()[..used]);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut sheet = Sheet::new();
+        sheet.enter(0, 0, "999999").unwrap();
+        sheet.enter(0, 1, "-1000000").unwrap();
+        assert_eq!(sheet.error(0, 1), Some(CellError::Overflow));
+        let eval = |e: &str| sheet.eval(e.as_bytes()).unwrap().map(|v| v.to_string());
+        for (expr, want) in [
+            ("A1+0.99", Ok("999999.99".to_string())),
+            ("A1+1", Err(CellError::Overflow)),
+            ("-A1-1", Err(CellError::Overflow)),
+            ("A1*10", Err(CellError::Overflow)),
+            ("A1/0.5", Err(CellError::Overflow)),
+            ("A1+1-1", Err(CellError::Overflow)),
+            ("1234567-1", Err(CellError::Overflow)),
+            ("@SUM(A1,A1,-999999)", Err(CellError::Overflow)),
+            ("@SUM(A1,-999999,A1)", Ok("999999.00".to_string())),
+            ("@COUNT(A1,A1,A1)", Ok("3.00".to_string())),
+            ("@MAX(A1,9999999)", Err(CellError::Overflow)),
+            ("@ROUND(999999.5,0)", Err(CellError::Overflow)),
+            ("@ROUND(999999.49,0)", Ok("999999.00".to_string())),
+            ("@ISERROR(A1*A1)", Ok("1.00".to_string())),
+        ] {
+            assert_eq!(eval(expr), want, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_overflow_matches_rom() {
+        let column = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let values = column(&["999999", "1234567", "-99999999", "999999.99", "1000000.0"]);
+        let formulas = column(&[
+            "=A1*10",
+            "=A1+A4",
+            "=A1-A4",
+            "=-A1-A4",
+            "=A4/0.5",
+            "=@SUM(A1,A4)",
+            "=@AVG(A1:A1,A4)",
+            "=@COUNT(A1,A4,A1)",
+            "=@SUM(99999999)",
+            "=@ROUND(A4,0)",
+            "=1234567+1",
+            "=@ISERROR(A1*A1)",
+        ]);
+        let sheet = sheet_match_rom(&[&values, &formulas]);
+        for row in [1, 2, 4] {
+            assert_eq!(sheet.error(0, row), Some(CellError::Overflow));
+        }
+        for row in [0, 1, 3, 4, 5, 6, 8, 9, 10] {
+            assert_eq!(sheet.error(1, row), Some(CellError::Overflow), "{}", formulas[row as usize]);
+        }
+        assert_eq!(sheet.value(1, 2).unwrap().to_string(), "-0.99");
+        assert_eq!(sheet.value(1, 7).unwrap().to_string(), "3.00");
+        assert_eq!(sheet.value(1, 11).unwrap().to_string(), "1.00");
+    }
+
+    #[test]
+    fn test_recalc_order_matches_rom() {
+        // Formulas reading cells below or to the right of them, through a
+        // range and through @ISERROR, plus a two-cell cycle C1/C2
+        let column = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let (mut h, mut sheet) = enter_match_rom(&[
+            &column(&["=A2+1", "=E1*2", "=@ISERROR(A1)"]),
+            &column(&["=@SUM(A1:A3)"]),
+            &column(&["=C2+1", "=C1+1"]),
+            &column(&["=C2*10"]),
+        ]);
+        h.type_keys("7\r!");
+        sheet.enter(4, 0, "7").unwrap();
+        sheet.recalc().unwrap();
+        let value = |col, row| sheet.value(col, row).map(|v| v.to_string());
+        for (col, row, want) in [
+            (0, 1, "14.00"),
+            (0, 0, "15.00"),
+            (0, 2, "0.00"),
+            (1, 0, "29.00"),
+        ] {
+            assert_eq!(value(col, row).as_deref(), Some(want), "{} {}", col, row);
+        }
+        // The walk from C1 ends on C2 and the others read its error
+        for (col, row) in [(2, 0), (2, 1), (3, 0)] {
+            assert_eq!(sheet.error(col, row), Some(CellError::Circular), "{} {}", col, row);
+        }
+        assert_eq!(sheet.circular(), Some((2, 1)));
+        let flags = format!("AUTO ROW FREE {} CIRC C2", sheet.free());
+        assert!(h.screen().status().ends_with(&flags));
+        for row in 0..3 {
+            for col in 0..5 {
+                assert_eq!(h.cell(col, row), &sheet.cell(col, row), "{} {}", col, row);
+            }
+        }
+        let used = (sheet.formula_ptr() - sheet.layout().scratch()) as usize;
+        assert_eq!(h.peek_bytes(sheet.layout().scratch(), used), &sheet.heap()[..used]);
+    }
+
+    #[test]
+    fn test_recalc_chain_matches_rom() {
+        // Column A reads upward, one link per row; column B's chain runs
+        // into the B3/B4 cycle, which leaves it all pending for the walk
+        let mut sheet = Sheet::new();
+        for row in 0..20 {
+            sheet.enter(0, row, &format!("=A{}+1", row + 2)).unwrap();
+        }
+        sheet.enter(0, 20, "5").unwrap();
+        for (row, formula) in ["=B2+1", "=B3+1", "=B4", "=B3*2"].iter().enumerate() {
+            sheet.enter(1, row as u8, formula).unwrap();
+        }
+        sheet.recalc().unwrap();
+        assert_eq!(sheet.value(0, 0).map(|v| v.to_string()).as_deref(), Some("25.00"));
+        assert_eq!(sheet.circular(), Some((1, 2)));
+        for row in 0..4 {
+            assert_eq!(sheet.error(1, row), Some(CellError::Circular), "B{}", row + 1);
+        }
+
+        let mut h = Harness::spreadsheet();
+        h.load_sheet(&sheet);
+        let recalc = h.symbol("recalc_all").unwrap();
+        assert_eq!(h.call(recalc, 10_000_000), StopReason::Returned);
+        for row in 0..21 {
+            for col in 0..2 {
+                assert_eq!(h.cell(col, row), &sheet.cell(col, row), "{} {}", col, row);
+            }
+        }
+        let used = (sheet.formula_ptr() - sheet.layout().scratch()) as usize;
+        assert_eq!(h.peek_bytes(sheet.layout().scratch(), used), &sheet.heap()[..used]);
+    }
+
+    #[test]
+    fn test_circular_matches_rom() {
+        // A1 reads the B2/B3 cycle and comes first in memory order, but the
+        // cell flagged is on the cycle
+        let column = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let (mut h, mut sheet) = enter_match_rom(&[
+            &column(&["=B2"]),
+            &column(&["", "=B3*2", "=B2+A1"]),
+        ]);
+        assert_eq!(sheet.circular(), Some((1, 1)));
+        for (col, row) in [(0, 0), (1, 1), (1, 2)] {
+            assert_eq!(sheet.error(col, row), Some(CellError::Circular), "{} {}", col, row);
+            assert_eq!(h.cell(col, row), &sheet.cell(col, row), "{} {}", col, row);
+        }
+        let flags = format!("AUTO ROW FREE {} CIRC B2", sheet.free());
+        assert_eq!(h.screen().status(), format!("C1:{}{}", " ".repeat(42), flags));
+
+        // Breaking the cycle clears the flag
+        h.type_keys("hjj5\r");
+        sheet.enter(1, 2, "5").unwrap();
+        sheet.recalculate().unwrap();
+        assert_eq!(sheet.circular(), None);
+        assert_eq!(sheet.value(0, 0).map(|v| v.to_string()).as_deref(), Some("10.00"));
+        assert_eq!(h.cell(0, 0), &sheet.cell(0, 0));
+        let flags = format!("AUTO ROW FREE {}", sheet.free());
+        assert_eq!(h.screen().status(), format!("B3: 5.00{}{}", " ".repeat(37), flags));
+    }
+
+    #[test]
+    fn test_recalc_modes_match_rom() {
+        let mut h = Harness::spreadsheet();
+        let mut sheet = Sheet::new();
+        let same = |h: &Harness, sheet: &Sheet| {
+            for (col, row) in [(0, 0), (1, 0), (2, 0), (0, 1)] {
+                assert_eq!(h.cell(col, row), &sheet.cell(col, row), "{} {}", col, row);
+            }
+        };
+        let value = |sheet: &Sheet, col, row| sheet.value(col, row).map(|v| v.to_string());
+
+        // Manual: A1 keeps the value it had when entered until '!'
+        h.type_keys("/GRM=B1*2\rl5\r");
+        sheet.set_manual(true);
+        sheet.enter(0, 0, "=B1*2").unwrap();
+        sheet.recalculate().unwrap();
+        sheet.enter(1, 0, "5").unwrap();
+        sheet.recalculate().unwrap();
+        same(&h, &sheet);
+        assert_eq!(value(&sheet, 0, 0).as_deref(), Some("0.00"));
+        assert!(h.screen().status().ends_with(&format!("MAN ROW FREE {}", sheet.free())));
+
+        // Switching back to automatic catches up at once
+        h.type_keys("/gra");
+        sheet.set_manual(false);
+        sheet.recalculate().unwrap();
+        same(&h, &sheet);
+        assert_eq!(value(&sheet, 0, 0).as_deref(), Some("10.00"));
+
+        // The order decides where a cycle is first seen: A2 in column
+        // order, C1 in row order
+        h.type_keys("/GOChj=C1\rkll=A2\r");
+        sheet.set_by_columns(true);
+        sheet.recalculate().unwrap();
+        sheet.enter(0, 1, "=C1").unwrap();
+        sheet.recalculate().unwrap();
+        sheet.enter(2, 0, "=A2").unwrap();
+        sheet.recalculate().unwrap();
+        same(&h, &sheet);
+        assert_eq!(sheet.circular(), Some((0, 1)));
+        let flags = format!("AUTO COL FREE {} CIRC A2", sheet.free());
+        assert!(h.screen().status().ends_with(&flags));
+        h.type_keys("/GOR");
+        sheet.set_by_columns(false);
+        sheet.recalculate().unwrap();
+        same(&h, &sheet);
+        assert_eq!(sheet.circular(), Some((2, 0)));
+        let flags = format!("AUTO ROW FREE {} CIRC C1", sheet.free());
+        assert!(h.screen().status().ends_with(&flags));
+
+        // A digit after the letter is still a goto, even to column O
+        h.type_keys("/GO5\r");
+        assert!(h.screen().status().starts_with("O5:"));
+    }
+
+    #[test]
+    fn test_iteration_matches_rom() {
+        // Interest on the average of the opening and closing balances
+        let mut h = Harness::spreadsheet();
+        let mut sheet = Sheet::new();
+        h.type_keys("/GIN20\r1000\rl=A1+C1\rl=(A1+B1)/2*0.1\r");
+        sheet.set_passes(20);
+        for (col, entry) in ["1000", "=A1+C1", "=(A1+B1)/2*0.1"].iter().enumerate() {
+            sheet.enter(col as u8, 0, entry).unwrap();
+            sheet.recalculate().unwrap();
+        }
+        let same = |h: &Harness, sheet: &Sheet| {
+            for col in 0..3 {
+                assert_eq!(h.cell(col, 0), &sheet.cell(col, 0), "{}", col);
+            }
+            let used = (sheet.formula_ptr() - sheet.layout().scratch()) as usize;
+            assert_eq!(h.peek_bytes(sheet.layout().scratch(), used), &sheet.heap()[..used]);
+        };
+        same(&h, &sheet);
+        let value = |sheet: &Sheet, col| sheet.value(col, 0).map(|v| v.to_string());
+        assert_eq!(value(&sheet, 2).as_deref(), Some("105.26"));
+        assert_eq!(value(&sheet, 1).as_deref(), Some("1105.26"));
+        assert_eq!(sheet.passes(), 6);
+        let flags = format!("AUTO ROW FREE {} ITER 6 CIRC B1", sheet.free());
+        assert!(h.screen().status().ends_with(&flags));
+
+        // Every pass, even once the values have settled
+        h.type_keys("/GIF");
+        sheet.set_all_passes(true);
+        sheet.recalculate().unwrap();
+        same(&h, &sheet);
+        assert_eq!(sheet.passes(), 20);
+        assert_eq!(value(&sheet, 2).as_deref(), Some("105.26"));
+        let flags = format!("AUTO ROW FREE {} ITER 20 CIRC B1", sheet.free());
+        assert!(h.screen().status().ends_with(&flags));
+
+        // One pass: the cycle fails again
+        h.type_keys("/GIN1\r");
+        sheet.set_passes(1);
+        sheet.recalculate().unwrap();
+        same(&h, &sheet);
+        assert_eq!(sheet.error(1, 0), Some(CellError::Circular));
+        assert_eq!(sheet.error(2, 0), Some(CellError::Circular));
+        let flags = format!("AUTO ROW FREE {} CIRC B1", sheet.free());
+        assert!(h.screen().status().ends_with(&flags));
+    }
+
+    #[test]
+    fn test_heap_collect_matches_rom() {
+        // "first" lies below B1's formula, so compacting slides the formula
+        // down, and D1 with it: /R left D1 pointing at the same text
+        let mut h = Harness::spreadsheet();
+        let mut sheet = Sheet::new();
+        h.type_keys("\r\"first\rl=C1*2\r/RD1\rh5\rhh");
+        for (col, entry) in ["\"first", "=C1*2", "5"].iter().enumerate() {
+            sheet.enter(col as u8, 0, entry).unwrap();
+            sheet.recalculate().unwrap();
+        }
+        let shared = h.cell(3, 0)[2..4].to_vec();
+        assert_eq!(shared, sheet.cell(1, 0)[2..4]);
+
+        // Each edit of A1 leaves its old formula behind
+        let mut collected = false;
+        for i in 0..30 {
+            let formula = format!("=C1+{}{}", i, "+0".repeat(16));
+            h.type_keys(&format!("{}\r", formula));
+            let before = sheet.formula_ptr();
+            assert!(sheet.enter(0, 0, &formula).unwrap());
+            sheet.recalculate().unwrap();
+            collected |= sheet.formula_ptr() < before;
+            for col in 0..3 {
+                assert_eq!(h.cell(col, 0), &sheet.cell(col, 0), "{} {}", i, col);
+            }
+            assert_eq!(h.peek_word(sheet.layout().var(FORMULA_PTR)), sheet.formula_ptr());
+            let used = (sheet.formula_ptr() - sheet.layout().scratch()) as usize;
+            assert_eq!(h.peek_bytes(sheet.layout().scratch(), used), &sheet.heap()[..used]);
+        }
+        assert!(collected);
+        assert_eq!(h.cell(3, 0)[2..4], sheet.cell(1, 0)[2..4]);
+        assert_ne!(h.cell(3, 0)[2..4], shared[..]);
+        assert_eq!(sheet.text(1, 0).as_deref(), Some("=C1*2"));
+        assert_eq!(h.screen().cell_text('D', 1).unwrap().trim(), "10.00");
+        assert_eq!(h.screen().cell_text('A', 1).unwrap().trim(), "34.00");
+        let flags = format!("AUTO ROW FREE {}", sheet.free());
+        assert!(h.screen().status().ends_with(&flags));
+    }
+
+    #[test]
+    fn test_memory_full_matches_rom() {
+        // 23 labels of 41 bytes leave 17 of the 960
+        let mut h = Harness::spreadsheet();
+        let mut sheet = Sheet::new();
+        for row in 0..23 {
+            let label = format!("\"{:039}", row);
+            h.type_keys(&format!("\r{}\rj", label));
+            assert!(sheet.enter(0, row, &label).unwrap());
+        }
+        assert_eq!(sheet.free(), 17);
+
+        // Nothing to reclaim: the cell stays empty until a key is pressed
+        let label = format!("\"{}", "x".repeat(39));
+        h.type_keys(&format!("\r{}\r", label));
+        assert_eq!(h.screen().input(), "Memory full");
+        assert!(!sheet.enter(0, 23, &label).unwrap());
+        assert_eq!(sheet.cell(0, 23)[0], 0);
+        h.type_keys(" ");
+        assert_eq!(h.cell(0, 23), &sheet.cell(0, 23));
+        assert!(h.screen().status().ends_with("AUTO ROW FREE 17"));
+
+        // A shorter entry still fits
+        h.type_keys("=2\r");
+        assert!(sheet.enter(0, 23, "=2").unwrap());
+        assert_eq!(h.cell(0, 23), &sheet.cell(0, 23));
+        assert_eq!(sheet.free(), 2);
+        assert!(h.screen().status().ends_with("AUTO ROW FREE 2"));
+    }
+}
@@ -0,0 +1,207 @@
+//! VT220 screen model for asserting on rendered output
+//!
+//! Interprets the subset of VT220/ANSI escape sequences the ROM emits
+//! (cursor positioning, home, clear screen, clear to end of line,
+//! cursor show/hide) into an 80x24 character grid. Rows and columns in
+//! the public API are 1-based, matching the `*_ROW` constants used by
+//! the code generator.
+
+use crate::codegen::{DATA_ROW, HEADER_ROW, INPUT_ROW, STATUS_ROW, VISIBLE_ROWS};
+
+pub const SCREEN_COLS: usize = 80;
+pub const SCREEN_ROWS: usize = 24;
+
+/// Width of a cell's value field on screen (between the cursor markers)
+const VALUE_WIDTH: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// 80x24 terminal grid fed with raw serial output
+#[derive(Clone, Debug)]
+pub struct Screen {
+    grid: Vec<[u8; SCREEN_COLS]>,
+    row: usize,
+    col: usize,
+    cursor_visible: bool,
+    state: ParseState,
+    params: Vec<u8>,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Self {
+            grid: vec![[b' '; SCREEN_COLS]; SCREEN_ROWS],
+            row: 0,
+            col: 0,
+            cursor_visible: true,
+            state: ParseState::Ground,
+            params: Vec::new(),
+        }
+    }
+
+    /// Interpret a chunk of terminal output
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.feed_byte(b);
+        }
+    }
+
+    fn feed_byte(&mut self, b: u8) {
+        match self.state {
+            ParseState::Ground => match b {
+                0x1B => self.state = ParseState::Escape,
+                b'\r' => self.col = 0,
+                b'\n' => self.row = (self.row + 1).min(SCREEN_ROWS - 1),
+                0x08 => self.col = self.col.saturating_sub(1),
+                0x20..=0x7E => {
+                    if self.col < SCREEN_COLS {
+                        self.grid[self.row][self.col] = b;
+                    }
+                    self.col = (self.col + 1).min(SCREEN_COLS);
+                }
+                _ => {}
+            },
+            ParseState::Escape => {
+                if b == b'[' {
+                    self.params.clear();
+                    self.state = ParseState::Csi;
+                } else {
+                    self.state = ParseState::Ground;
+                }
+            }
+            ParseState::Csi => {
+                if (0x40..=0x7E).contains(&b) {
+                    self.state = ParseState::Ground;
+                    self.csi(b);
+                } else {
+                    self.params.push(b);
+                }
+            }
+        }
+    }
+
+    /// Numeric parameters of the current CSI sequence (missing = 0)
+    fn numbers(&self) -> Vec<usize> {
+        let text = String::from_utf8_lossy(&self.params);
+        text.trim_start_matches('?')
+            .split(';')
+            .map(|s| s.parse().unwrap_or(0))
+            .collect()
+    }
+
+    fn csi(&mut self, cmd: u8) {
+        let private = self.params.first() == Some(&b'?');
+        let n = self.numbers();
+        match cmd {
+            b'H' | b'f' => {
+                let row = n.first().copied().unwrap_or(1).max(1);
+                let col = n.get(1).copied().unwrap_or(1).max(1);
+                self.row = (row - 1).min(SCREEN_ROWS - 1);
+                self.col = (col - 1).min(SCREEN_COLS - 1);
+            }
+            b'J' if n[0] == 2 => {
+                for line in &mut self.grid {
+                    *line = [b' '; SCREEN_COLS];
+                }
+            }
+            b'K' if self.col < SCREEN_COLS => self.grid[self.row][self.col..].fill(b' '),
+            b'h' | b'l' if private && n[0] == 25 => self.cursor_visible = cmd == b'h',
+            _ => {}
+        }
+    }
+
+    /// Text of a screen row (1-based), trailing spaces removed
+    pub fn row_text(&self, row: u8) -> String {
+        let line = &self.grid[row as usize - 1];
+        String::from_utf8_lossy(line).trim_end().to_string()
+    }
+
+    /// The whole screen, one line per row, trailing spaces removed
+    pub fn text(&self) -> String {
+        (1..=SCREEN_ROWS as u8)
+            .map(|r| self.row_text(r))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Cursor position as 1-based (row, col)
+    pub fn cursor(&self) -> (u8, u8) {
+        (self.row as u8 + 1, self.col as u8 + 1)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    /// Displayed value field of a cell, e.g. `cell_text('B', 3)`
+    ///
+    /// Locates the column by its letter in the header row and the sheet
+    /// row by its number in the left margin, so it works for any scroll
+    /// position. Returns `None` if the cell is not on screen.
+    pub fn cell_text(&self, col: char, row: u8) -> Option<String> {
+        let header = &self.grid[HEADER_ROW as usize - 1];
+        let letter = col.to_ascii_uppercase() as u8;
+        // Column letters sit directly above the first character of the value field
+        let x = (5..SCREEN_COLS).find(|&x| {
+            header[x] == letter && header[x - 1] == b' ' && header.get(x + 1).map_or(true, |&c| c == b' ')
+        })?;
+        let label = row.to_string();
+        let y = (0..VISIBLE_ROWS as usize)
+            .map(|i| DATA_ROW as usize - 1 + i)
+            .find(|&y| String::from_utf8_lossy(&self.grid[y][..4]).trim() == label)?;
+        let end = (x + VALUE_WIDTH).min(SCREEN_COLS);
+        Some(String::from_utf8_lossy(&self.grid[y][x..end]).to_string())
+    }
+
+    /// Status line text (current cell and its contents)
+    pub fn status(&self) -> String {
+        self.row_text(STATUS_ROW)
+    }
+
+    /// Input line text (prompts, the line being edited, messages)
+    pub fn input(&self) -> String {
+        self.row_text(INPUT_ROW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[test]
+    fn test_escape_sequences() {
+        let mut s = Screen::new();
+        s.feed(b"hello\x1b[2J\x1b[H\x1b[3;5Hab\x1b[?25l");
+        assert_eq!(s.row_text(1), "");
+        assert_eq!(s.row_text(3), "    ab");
+        assert_eq!(s.cursor(), (3, 7));
+        assert!(!s.cursor_visible());
+        s.feed(b"\x1b[3;6H\x1b[K\x1b[?25h");
+        assert_eq!(s.row_text(3), "    a");
+        assert!(s.cursor_visible());
+    }
+
+    #[test]
+    fn test_rendered_sheet() {
+        let mut h = Harness::spreadsheet();
+        h.type_keys("ljj12.5\r");
+        let screen = h.screen();
+        assert_eq!(screen.cell_text('B', 3).as_deref(), Some("  12.50"));
+        assert_eq!(screen.cell_text('A', 1).as_deref(), Some("       "));
+        assert_eq!(screen.status(), format!("B3: 12.50{}AUTO ROW FREE 960", " ".repeat(36)));
+        assert!(screen.row_text(4).starts_with("     A        B"));
+        assert_eq!(screen.cell_text('Z', 3), None);
+    }
+}
@@ -0,0 +1,297 @@
+//! Worksheet templates loaded from CSV
+//!
+//! Each CSV record is a sheet row and each field a column, rows 1-64 and
+//! columns A-P in the default [`Layout`].
+//! A field is entered exactly as if typed into the cell:
+//!
+//! - empty fields leave the cell empty
+//! - `=...` is a formula
+//! - `-123.45` style numbers (at most 6 integer and 2 fractional digits)
+//!   are numbers
+//! - anything else is a label (a leading `"` is optional)
+//!
+//! The entries are run through the reference model, so cell records, heap
+//! layout and cached formula values are byte-for-byte what the ROM would
+//! hold after typing the sheet in row by row and pressing `!`.
+
+use std::fmt;
+
+use crate::csv;
+use crate::layout::Layout;
+use crate::model::{Sheet, Unmodeled, MAX_INPUT};
+
+/// Why a CSV file cannot be turned into a worksheet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    /// A non-empty field below the last row (1-based CSV line of the record)
+    TooManyRows { row: usize, rows: u8 },
+    /// A non-empty field right of the last column
+    TooManyColumns { row: usize, count: usize, cols: u8 },
+    /// Entry longer than the input editor accepts
+    TooLong { cell: String, len: usize },
+    /// Number with more than 6 integer or 2 fractional digits
+    NumberRange { cell: String, text: String },
+    /// The heap has no room left for the entry
+    MemoryFull { cell: String },
+    /// The model cannot reproduce what the ROM would do with the entry
+    Unmodeled { cell: String, reason: Unmodeled },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::TooManyRows { row, rows } => {
+                write!(f, "row {}: the sheet has only {} rows", row, rows)
+            }
+            TemplateError::TooManyColumns { row, count, cols } => write!(
+                f,
+                "row {}: {} columns, the sheet has only {}",
+                row, count, cols
+            ),
+            TemplateError::TooLong { cell, len } => write!(
+                f,
+                "{}: {} characters, the editor accepts {}",
+                cell, len, MAX_INPUT
+            ),
+            TemplateError::NumberRange { cell, text } => {
+                write!(f, "{}: {} does not fit 6.2 digits", cell, text)
+            }
+            TemplateError::MemoryFull { cell } => write!(f, "{}: memory full", cell),
+            TemplateError::Unmodeled { cell, reason } => write!(f, "{}: {}", cell, reason),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Spreadsheet name of a cell, e.g. `B3` for column 1, row 2
+pub fn cell_name(col: u8, row: u8) -> String {
+    format!("{}{}", (b'A' + col) as char, row as u32 + 1)
+}
+
+/// `-?digits(.digits)?` or `-?.digits`, the number syntax the editor
+/// accepts
+fn number_parts(text: &str) -> Option<(&str, &str)> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    let all_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    let some_digit = !int.is_empty() || !frac.is_empty();
+    (some_digit && all_digits(int) && all_digits(frac)).then_some((int, frac))
+}
+
+/// Build a worksheet for a ROM with `layout` from CSV text
+pub fn load(text: &str, layout: Layout) -> Result<Sheet, TemplateError> {
+    let mut sheet = Sheet::with_layout(layout);
+    for (r, record) in csv::parse(text).iter().enumerate() {
+        let used = record
+            .iter()
+            .rposition(|f| !f.trim().is_empty())
+            .map_or(0, |i| i + 1);
+        if used == 0 {
+            continue;
+        }
+        if r >= layout.rows() as usize {
+            return Err(TemplateError::TooManyRows {
+                row: r + 1,
+                rows: layout.rows(),
+            });
+        }
+        if used > layout.cols() as usize {
+            return Err(TemplateError::TooManyColumns {
+                row: r + 1,
+                count: used,
+                cols: layout.cols(),
+            });
+        }
+        for (c, field) in record[..used].iter().enumerate() {
+            let (col, row) = (c as u8, r as u8);
+            let cell = cell_name(col, row);
+            let field = field.trim();
+            let input = match field.as_bytes().first() {
+                None => continue,
+                Some(b'=') | Some(b'"') => field.to_string(),
+                Some(_) => match number_parts(field) {
+                    Some((int, frac)) if int.len() > 6 || frac.len() > 2 => {
+                        return Err(TemplateError::NumberRange {
+                            cell,
+                            text: field.to_string(),
+                        });
+                    }
+                    Some(_) => field.to_string(),
+                    None => format!("\"{}", field),
+                },
+            };
+            if input.len() > MAX_INPUT {
+                return Err(TemplateError::TooLong {
+                    cell,
+                    len: input.len(),
+                });
+            }
+            let stored = sheet.enter(col, row, &input).map_err(|reason| {
+                let cell = cell.clone();
+                TemplateError::Unmodeled { cell, reason }
+            })?;
+            if !stored {
+                return Err(TemplateError::MemoryFull { cell });
+            }
+        }
+    }
+    sheet.recalc().map_err(|reason| TemplateError::Unmodeled {
+        cell: "recalc".to_string(),
+        reason,
+    })?;
+    Ok(sheet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::{BuildProblem, CELL_FORMULA, CELL_LABEL, CELL_NUMBER, FORMULA_PTR};
+    use crate::harness::Harness;
+    use crate::model::CellError;
+    use crate::SpreadsheetCodeGen;
+
+    const BUDGET: &str = "Item,Cost\r\nRent,1200\nFood,\"350.5\"\n\"Total, net\",=@SUM(B2:B3)\n";
+
+    #[test]
+    fn test_load() {
+        let sheet = load(BUDGET, Layout::default()).unwrap();
+        assert_eq!(sheet.cell(0, 0)[0], CELL_LABEL);
+        assert_eq!(sheet.text(0, 0).unwrap(), "\"Item");
+        assert_eq!(sheet.text(0, 3).unwrap(), "\"Total, net");
+        assert_eq!(sheet.cell(1, 2)[0], CELL_NUMBER);
+        assert_eq!(sheet.cell(1, 3)[0], CELL_FORMULA);
+        assert_eq!(sheet.value(1, 3).unwrap().to_string(), "1550.50");
+        // Numbers may leave out the integer part, but need a digit
+        let sheet = load(".5,-.5,.,-\n", Layout::default()).unwrap();
+        assert_eq!(sheet.value(0, 0).unwrap().to_string(), "0.50");
+        assert_eq!(sheet.value(1, 0).unwrap().to_string(), "-0.50");
+        assert_eq!(sheet.text(2, 0).unwrap(), "\".");
+        assert_eq!(sheet.text(3, 0).unwrap(), "\"-");
+
+        let err = |csv: &str| load(csv, Layout::default()).unwrap_err();
+        assert_eq!(
+            err("1234567\n"),
+            TemplateError::NumberRange {
+                cell: "A1".to_string(),
+                text: "1234567".to_string()
+            }
+        );
+        let wide = format!("{}x\n", ",".repeat(16));
+        assert_eq!(
+            err(&wide),
+            TemplateError::TooManyColumns {
+                row: 1,
+                count: 17,
+                cols: 16
+            }
+        );
+        assert_eq!(
+            err(&format!("{}1,,\n", "\n".repeat(64))),
+            TemplateError::TooManyRows { row: 65, rows: 64 }
+        );
+        assert_eq!(
+            err(&"=1+".repeat(14)),
+            TemplateError::TooLong {
+                cell: "A1".to_string(),
+                len: 42
+            }
+        );
+        // 23 labels of 41 heap bytes fit in 960
+        let labels = format!("{}\n", "x".repeat(39)).repeat(24);
+        assert_eq!(
+            err(&labels),
+            TemplateError::MemoryFull {
+                cell: "A24".to_string()
+            }
+        );
+        // A table on the last row has nothing below it
+        let sheet = load("\"=@LOOKUP(1,A64:B64)\"\n", Layout::default()).unwrap();
+        assert_eq!(sheet.error(0, 0), Some(CellError::Ref));
+    }
+
+    #[test]
+    fn test_template_in_rom() {
+        // A1 refers forward to A2, so it is only right after the startup recalc
+        let csv = "=A2*2,Label\n5,=A1+B3\n,.5,-.5\n";
+        for layout in [
+            Layout::default(),
+            Layout::new(0x8000, 0x8000, 20, 30).unwrap(),
+        ] {
+            let sheet = load(csv, layout).unwrap();
+            assert_eq!(sheet.value(0, 0).unwrap().to_string(), "10.00");
+
+            let mut codegen = SpreadsheetCodeGen::new();
+            codegen.set_layout(layout);
+            codegen.set_sheet(sheet.clone());
+            let h = Harness::boot(codegen);
+            for row in 0..3 {
+                for col in 0..3 {
+                    assert_eq!(
+                        h.cell(col, row),
+                        &sheet.cell(col, row),
+                        "{} {}",
+                        layout,
+                        cell_name(col, row)
+                    );
+                }
+            }
+            let heap = layout.scratch();
+            let used = (sheet.formula_ptr() - heap) as usize;
+            assert_eq!(h.peek_word(layout.var(FORMULA_PTR)), sheet.formula_ptr());
+            assert_eq!(h.peek_bytes(heap, used), &sheet.heap()[..used]);
+            let screen = h.screen();
+            assert_eq!(screen.cell_text('A', 1).unwrap().trim(), "10.00");
+            assert_eq!(screen.cell_text('B', 1).unwrap().trim(), "Label");
+        }
+    }
+
+    #[test]
+    fn test_budget_fits_rom() {
+        let csv = "Item,Monthly,Yearly\n\
+                   Rent,1200,=B2*12\n\
+                   Food,350.50,=B3*12\n\
+                   Power,85.25,=B4*12\n\
+                   Travel,-40,=B5*12\n\
+                   Total,=@SUM(B2:B5),=@SUM(C2:C5)\n";
+        let layout = Layout::default();
+        let sheet = load(csv, layout).unwrap();
+        assert_eq!(sheet.value(2, 5).unwrap().to_string(), "19149.00");
+
+        let mut codegen = SpreadsheetCodeGen::new();
+        codegen.set_sheet(sheet.clone());
+        let h = Harness::boot(codegen);
+        for row in 0..6 {
+            for col in 0..3 {
+                assert_eq!(
+                    h.cell(col, row),
+                    &sheet.cell(col, row),
+                    "{}",
+                    cell_name(col, row)
+                );
+            }
+        }
+        let used = (sheet.formula_ptr() - layout.scratch()) as usize;
+        assert_eq!(h.peek_word(layout.var(FORMULA_PTR)), sheet.formula_ptr());
+        assert_eq!(h.peek_bytes(layout.scratch(), used), &sheet.heap()[..used]);
+    }
+
+    #[test]
+    fn test_template_layout_mismatch() {
+        let layout = Layout::new(0x2000, 0x2000, 8, 20).unwrap();
+        let mut codegen = SpreadsheetCodeGen::new();
+        codegen.set_sheet(load("1\n", layout).unwrap());
+        let err = codegen.generate().unwrap_err();
+        assert_eq!(
+            err.problems,
+            [BuildProblem::SheetLayout {
+                sheet: layout,
+                rom: Layout::default()
+            }]
+        );
+        assert_eq!(
+            load("1,2,3,4,5,6,7,8,9\n", layout).unwrap_err().to_string(),
+            "row 1: 9 columns, the sheet has only 8"
+        );
+    }
+}
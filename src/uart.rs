@@ -0,0 +1,142 @@
+//! Serial hardware profiles for the generated ROM
+//!
+//! A profile says which UART the board has, where it is wired in I/O
+//! space, which status bits mean "byte received" and "ready to send", and
+//! which control writes bring the chip up at power-on. `getchar`/`putchar`
+//! and the startup code are generated from it.
+
+use std::fmt;
+
+/// Supported UART chips
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UartKind {
+    /// Motorola MC6850 ACIA: control/status at base, data at base+1
+    Mc6850,
+    /// Zilog Z80 SIO/2, channel A: control at base, data at base+1
+    Sio,
+    /// Intel 8251 USART: data at base, control/status at base+1
+    I8251,
+}
+
+impl UartKind {
+    /// Name as accepted by `--uart`
+    pub fn name(self) -> &'static str {
+        match self {
+            UartKind::Mc6850 => "mc6850",
+            UartKind::Sio => "sio",
+            UartKind::I8251 => "8251",
+        }
+    }
+}
+
+impl fmt::Display for UartKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// UART type, wiring and initialisation used by the generated I/O code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UartProfile {
+    pub kind: UartKind,
+    pub status_port: u8,
+    pub data_port: u8,
+    /// Status bit(s) set when a received byte is waiting
+    pub rx_ready: u8,
+    /// Status bit(s) set when the transmitter can take a byte
+    pub tx_ready: u8,
+    /// `OUT (port), value` writes issued at startup, in order
+    pub init: Vec<(u8, u8)>,
+}
+
+/// Default base port for every UART type (the RetroShield ACIA address)
+pub const DEFAULT_BASE: u8 = 0x80;
+
+/// Highest base port; every chip also uses the port above its base
+pub const MAX_BASE: u8 = 0xFE;
+
+/// The port above `base`
+fn next_port(base: u8) -> u8 {
+    assert!(
+        base <= MAX_BASE,
+        "UART base {:02X} has no port above it",
+        base
+    );
+    base + 1
+}
+
+impl Default for UartProfile {
+    fn default() -> Self {
+        Self::mc6850(DEFAULT_BASE)
+    }
+}
+
+impl UartProfile {
+    /// MC6850: master reset, then ÷16 clock, 8N1, RTS low, no interrupts
+    pub fn mc6850(base: u8) -> Self {
+        Self {
+            kind: UartKind::Mc6850,
+            status_port: base,
+            data_port: next_port(base),
+            rx_ready: 0x01, // RDRF
+            tx_ready: 0x02, // TDRE
+            init: vec![(base, 0x03), (base, 0x15)],
+        }
+    }
+
+    /// Z80 SIO channel A: reset, x16 clock 8N1, receiver and transmitter on
+    pub fn sio(base: u8) -> Self {
+        let ctrl = base;
+        Self {
+            kind: UartKind::Sio,
+            status_port: ctrl,
+            data_port: next_port(base),
+            rx_ready: 0x01, // RR0: Rx character available
+            tx_ready: 0x04, // RR0: Tx buffer empty
+            init: vec![
+                (ctrl, 0x18), // WR0: channel reset
+                (ctrl, 0x04), // select WR4
+                (ctrl, 0x44), // x16 clock, 1 stop bit, no parity
+                (ctrl, 0x03), // select WR3
+                (ctrl, 0xC1), // Rx 8 bits, Rx enable
+                (ctrl, 0x05), // select WR5
+                (ctrl, 0xEA), // DTR, Tx 8 bits, Tx enable, RTS
+            ],
+        }
+    }
+
+    /// 8251: worst-case reset sequence, async x16 8N1, Rx/Tx enabled
+    pub fn i8251(base: u8) -> Self {
+        let ctrl = next_port(base);
+        Self {
+            kind: UartKind::I8251,
+            status_port: ctrl,
+            data_port: base,
+            rx_ready: 0x02, // RxRDY
+            tx_ready: 0x01, // TxRDY
+            init: vec![
+                // Three zeros leave the chip expecting a command whatever
+                // state it powered up in; 0x40 is then an internal reset
+                (ctrl, 0x00),
+                (ctrl, 0x00),
+                (ctrl, 0x00),
+                (ctrl, 0x40),
+                (ctrl, 0x4E), // mode: async x16, 8 bits, no parity, 1 stop
+                (ctrl, 0x37), // command: RTS, error reset, RxE, DTR, TxEN
+            ],
+        }
+    }
+
+    /// Profile for a `--uart` name, at `base` or the default base port
+    ///
+    /// Panics if `base` is above [`MAX_BASE`]
+    pub fn from_name(name: &str, base: Option<u8>) -> Option<Self> {
+        let base = base.unwrap_or(DEFAULT_BASE);
+        match name.to_ascii_lowercase().as_str() {
+            "mc6850" | "6850" | "acia" => Some(Self::mc6850(base)),
+            "sio" | "z80sio" | "sio2" => Some(Self::sio(base)),
+            "8251" | "i8251" => Some(Self::i8251(base)),
+            _ => None,
+        }
+    }
+}
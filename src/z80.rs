@@ -0,0 +1,1079 @@
+//! Minimal Z80 CPU interpreter for host-side testing
+//!
+//! Executes the documented Z80 instruction set (plus the undocumented
+//! IXH/IXL/IYH/IYL forms) against a [`Bus`] supplied by the caller.
+//! Timing is tracked in T-states so emulated runs can be used for
+//! benchmarks as well as functional tests.
+
+/// Flag bits in F
+pub const FLAG_C: u8 = 0x01;
+pub const FLAG_N: u8 = 0x02;
+pub const FLAG_PV: u8 = 0x04;
+pub const FLAG_X: u8 = 0x08;
+pub const FLAG_H: u8 = 0x10;
+pub const FLAG_Y: u8 = 0x20;
+pub const FLAG_Z: u8 = 0x40;
+pub const FLAG_S: u8 = 0x80;
+
+/// Memory and I/O as seen by the CPU
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+    fn port_in(&mut self, port: u16) -> u8;
+    fn port_out(&mut self, port: u16, value: u8);
+}
+
+/// Which register pair an instruction's "HL" slot refers to
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Index {
+    Hl,
+    Ix,
+    Iy,
+}
+
+/// Z80 register file and execution state
+#[derive(Clone, Debug, Default)]
+pub struct Cpu {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub af_alt: u16,
+    pub bc_alt: u16,
+    pub de_alt: u16,
+    pub hl_alt: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub i: u8,
+    pub r: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub im: u8,
+    pub halted: bool,
+    /// Total T-states executed since reset
+    pub cycles: u64,
+}
+
+fn parity(v: u8) -> bool {
+    v.count_ones() % 2 == 0
+}
+
+/// S, Z, X, Y and P/V (parity) flags for a logical result
+fn szp(v: u8) -> u8 {
+    let mut f = v & (FLAG_S | FLAG_X | FLAG_Y);
+    if v == 0 {
+        f |= FLAG_Z;
+    }
+    if parity(v) {
+        f |= FLAG_PV;
+    }
+    f
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+        Self {
+            sp: 0xFFFF,
+            a: 0xFF,
+            f: 0xFF,
+            ..Default::default()
+        }
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b, self.c])
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d, self.e])
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h, self.l])
+    }
+
+    pub fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f])
+    }
+
+    pub fn set_bc(&mut self, v: u16) {
+        [self.b, self.c] = v.to_be_bytes();
+    }
+
+    pub fn set_de(&mut self, v: u16) {
+        [self.d, self.e] = v.to_be_bytes();
+    }
+
+    pub fn set_hl(&mut self, v: u16) {
+        [self.h, self.l] = v.to_be_bytes();
+    }
+
+    pub fn set_af(&mut self, v: u16) {
+        [self.a, self.f] = v.to_be_bytes();
+    }
+
+    pub fn flag(&self, mask: u8) -> bool {
+        self.f & mask != 0
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let v = bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        v
+    }
+
+    fn fetch_word<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let lo = self.fetch(bus);
+        let hi = self.fetch(bus);
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn read_word<B: Bus>(bus: &mut B, addr: u16) -> u16 {
+        let lo = bus.read(addr);
+        let hi = bus.read(addr.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn write_word<B: Bus>(bus: &mut B, addr: u16, v: u16) {
+        let [lo, hi] = v.to_le_bytes();
+        bus.write(addr, lo);
+        bus.write(addr.wrapping_add(1), hi);
+    }
+
+    pub fn push<B: Bus>(&mut self, bus: &mut B, v: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        Self::write_word(bus, self.sp, v);
+    }
+
+    pub fn pop<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let v = Self::read_word(bus, self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        v
+    }
+
+    fn inc_r(&mut self) {
+        self.r = (self.r & 0x80) | (self.r.wrapping_add(1) & 0x7F);
+    }
+
+    fn idx(&self, ix: Index) -> u16 {
+        match ix {
+            Index::Hl => self.hl(),
+            Index::Ix => self.ix,
+            Index::Iy => self.iy,
+        }
+    }
+
+    fn set_idx(&mut self, ix: Index, v: u16) {
+        match ix {
+            Index::Hl => self.set_hl(v),
+            Index::Ix => self.ix = v,
+            Index::Iy => self.iy = v,
+        }
+    }
+
+    /// Read 8-bit register by table index (6 = (HL) is handled by the caller)
+    fn reg(&self, r: u8, ix: Index) -> u8 {
+        match r {
+            0 => self.b,
+            1 => self.c,
+            2 => self.d,
+            3 => self.e,
+            4 => (self.idx(ix) >> 8) as u8,
+            5 => self.idx(ix) as u8,
+            7 => self.a,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_reg(&mut self, r: u8, ix: Index, v: u8) {
+        match r {
+            0 => self.b = v,
+            1 => self.c = v,
+            2 => self.d = v,
+            3 => self.e = v,
+            4 => {
+                let w = self.idx(ix);
+                self.set_idx(ix, (w & 0x00FF) | ((v as u16) << 8));
+            }
+            5 => {
+                let w = self.idx(ix);
+                self.set_idx(ix, (w & 0xFF00) | v as u16);
+            }
+            7 => self.a = v,
+            _ => unreachable!(),
+        }
+    }
+
+    fn rp(&self, p: u8, ix: Index) -> u16 {
+        match p {
+            0 => self.bc(),
+            1 => self.de(),
+            2 => self.idx(ix),
+            _ => self.sp,
+        }
+    }
+
+    fn set_rp(&mut self, p: u8, ix: Index, v: u16) {
+        match p {
+            0 => self.set_bc(v),
+            1 => self.set_de(v),
+            2 => self.set_idx(ix, v),
+            _ => self.sp = v,
+        }
+    }
+
+    fn rp2(&self, p: u8, ix: Index) -> u16 {
+        match p {
+            3 => self.af(),
+            _ => self.rp(p, ix),
+        }
+    }
+
+    fn set_rp2(&mut self, p: u8, ix: Index, v: u16) {
+        match p {
+            3 => self.set_af(v),
+            _ => self.set_rp(p, ix, v),
+        }
+    }
+
+    fn cond(&self, y: u8) -> bool {
+        match y {
+            0 => !self.flag(FLAG_Z),
+            1 => self.flag(FLAG_Z),
+            2 => !self.flag(FLAG_C),
+            3 => self.flag(FLAG_C),
+            4 => !self.flag(FLAG_PV),
+            5 => self.flag(FLAG_PV),
+            6 => !self.flag(FLAG_S),
+            _ => self.flag(FLAG_S),
+        }
+    }
+
+    // ---- ALU ----
+
+    fn add8(&mut self, v: u8, carry: bool) {
+        let a = self.a;
+        let c = carry as u16;
+        let r = a as u16 + v as u16 + c;
+        let res = r as u8;
+        let mut f = res & (FLAG_S | FLAG_X | FLAG_Y);
+        if res == 0 {
+            f |= FLAG_Z;
+        }
+        if (a & 0x0F) as u16 + (v & 0x0F) as u16 + c > 0x0F {
+            f |= FLAG_H;
+        }
+        if (a ^ res) & (v ^ res) & 0x80 != 0 {
+            f |= FLAG_PV;
+        }
+        if r > 0xFF {
+            f |= FLAG_C;
+        }
+        self.a = res;
+        self.f = f;
+    }
+
+    fn sub_flags(&self, v: u8, carry: bool) -> (u8, u8) {
+        let a = self.a;
+        let c = carry as i16;
+        let r = a as i16 - v as i16 - c;
+        let res = r as u8;
+        let mut f = FLAG_N | (res & FLAG_S);
+        if res == 0 {
+            f |= FLAG_Z;
+        }
+        if ((a & 0x0F) as i16) - ((v & 0x0F) as i16) - c < 0 {
+            f |= FLAG_H;
+        }
+        if (a ^ v) & (a ^ res) & 0x80 != 0 {
+            f |= FLAG_PV;
+        }
+        if r < 0 {
+            f |= FLAG_C;
+        }
+        (res, f)
+    }
+
+    fn alu(&mut self, op: u8, v: u8) {
+        match op {
+            0 => self.add8(v, false),
+            1 => {
+                let c = self.flag(FLAG_C);
+                self.add8(v, c)
+            }
+            2 => {
+                let (r, f) = self.sub_flags(v, false);
+                self.a = r;
+                self.f = f | (r & (FLAG_X | FLAG_Y));
+            }
+            3 => {
+                let c = self.flag(FLAG_C);
+                let (r, f) = self.sub_flags(v, c);
+                self.a = r;
+                self.f = f | (r & (FLAG_X | FLAG_Y));
+            }
+            4 => {
+                self.a &= v;
+                self.f = szp(self.a) | FLAG_H;
+            }
+            5 => {
+                self.a ^= v;
+                self.f = szp(self.a);
+            }
+            6 => {
+                self.a |= v;
+                self.f = szp(self.a);
+            }
+            _ => {
+                let (_, f) = self.sub_flags(v, false);
+                self.f = f | (v & (FLAG_X | FLAG_Y));
+            }
+        }
+    }
+
+    fn inc8(&mut self, v: u8) -> u8 {
+        let r = v.wrapping_add(1);
+        let mut f = (self.f & FLAG_C) | (r & (FLAG_S | FLAG_X | FLAG_Y));
+        if r == 0 {
+            f |= FLAG_Z;
+        }
+        if v & 0x0F == 0x0F {
+            f |= FLAG_H;
+        }
+        if v == 0x7F {
+            f |= FLAG_PV;
+        }
+        self.f = f;
+        r
+    }
+
+    fn dec8(&mut self, v: u8) -> u8 {
+        let r = v.wrapping_sub(1);
+        let mut f = (self.f & FLAG_C) | FLAG_N | (r & (FLAG_S | FLAG_X | FLAG_Y));
+        if r == 0 {
+            f |= FLAG_Z;
+        }
+        if v & 0x0F == 0 {
+            f |= FLAG_H;
+        }
+        if v == 0x80 {
+            f |= FLAG_PV;
+        }
+        self.f = f;
+        r
+    }
+
+    fn daa(&mut self) {
+        let a = self.a;
+        let n = self.flag(FLAG_N);
+        let mut diff = 0u8;
+        let mut carry = self.flag(FLAG_C);
+        if carry || a > 0x99 {
+            diff |= 0x60;
+            carry = true;
+        }
+        if self.flag(FLAG_H) || (a & 0x0F) > 9 {
+            diff |= 0x06;
+        }
+        let r = if n { a.wrapping_sub(diff) } else { a.wrapping_add(diff) };
+        let half = if n {
+            self.flag(FLAG_H) && (a & 0x0F) < 6
+        } else {
+            (a & 0x0F) > 9
+        };
+        let mut f = szp(r) | (self.f & FLAG_N);
+        if half {
+            f |= FLAG_H;
+        }
+        if carry {
+            f |= FLAG_C;
+        }
+        self.a = r;
+        self.f = f;
+    }
+
+    fn add16(&mut self, a: u16, b: u16) -> u16 {
+        let r = a as u32 + b as u32;
+        let res = r as u16;
+        let mut f = self.f & (FLAG_S | FLAG_Z | FLAG_PV);
+        f |= ((res >> 8) as u8) & (FLAG_X | FLAG_Y);
+        if (a & 0x0FFF) + (b & 0x0FFF) > 0x0FFF {
+            f |= FLAG_H;
+        }
+        if r > 0xFFFF {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        res
+    }
+
+    fn adc16(&mut self, a: u16, b: u16) -> u16 {
+        let c = (self.f & FLAG_C) as u32;
+        let r = a as u32 + b as u32 + c;
+        let res = r as u16;
+        let mut f = ((res >> 8) as u8) & (FLAG_S | FLAG_X | FLAG_Y);
+        if res == 0 {
+            f |= FLAG_Z;
+        }
+        if (a & 0x0FFF) as u32 + (b & 0x0FFF) as u32 + c > 0x0FFF {
+            f |= FLAG_H;
+        }
+        if (a ^ res) & (b ^ res) & 0x8000 != 0 {
+            f |= FLAG_PV;
+        }
+        if r > 0xFFFF {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        res
+    }
+
+    fn sbc16(&mut self, a: u16, b: u16) -> u16 {
+        let c = (self.f & FLAG_C) as i32;
+        let r = a as i32 - b as i32 - c;
+        let res = r as u16;
+        let mut f = FLAG_N | (((res >> 8) as u8) & (FLAG_S | FLAG_X | FLAG_Y));
+        if res == 0 {
+            f |= FLAG_Z;
+        }
+        if ((a & 0x0FFF) as i32) - ((b & 0x0FFF) as i32) - c < 0 {
+            f |= FLAG_H;
+        }
+        if (a ^ b) & (a ^ res) & 0x8000 != 0 {
+            f |= FLAG_PV;
+        }
+        if r < 0 {
+            f |= FLAG_C;
+        }
+        self.f = f;
+        res
+    }
+
+    /// CB-prefix rotate/shift group
+    fn rot(&mut self, op: u8, v: u8) -> u8 {
+        let c = self.flag(FLAG_C) as u8;
+        let (r, carry) = match op {
+            0 => (v.rotate_left(1), v & 0x80 != 0),
+            1 => (v.rotate_right(1), v & 0x01 != 0),
+            2 => ((v << 1) | c, v & 0x80 != 0),
+            3 => ((v >> 1) | (c << 7), v & 0x01 != 0),
+            4 => (v << 1, v & 0x80 != 0),
+            5 => ((v >> 1) | (v & 0x80), v & 0x01 != 0),
+            6 => ((v << 1) | 1, v & 0x80 != 0),
+            _ => (v >> 1, v & 0x01 != 0),
+        };
+        self.f = szp(r) | if carry { FLAG_C } else { 0 };
+        r
+    }
+
+    fn bit(&mut self, n: u8, v: u8) {
+        let set = v & (1 << n);
+        let mut f = (self.f & FLAG_C) | FLAG_H | (v & (FLAG_X | FLAG_Y));
+        if set == 0 {
+            f |= FLAG_Z | FLAG_PV;
+        }
+        if n == 7 && set != 0 {
+            f |= FLAG_S;
+        }
+        self.f = f;
+    }
+
+    /// Accumulator rotates (RLCA/RRCA/RLA/RRA) only touch H, N and C
+    fn rot_a(&mut self, op: u8) {
+        let keep = self.f & (FLAG_S | FLAG_Z | FLAG_PV);
+        let r = self.rot(op, self.a);
+        self.a = r;
+        self.f = keep | (self.f & FLAG_C) | (r & (FLAG_X | FLAG_Y));
+    }
+
+    /// Resolve the (HL)/(IX+d)/(IY+d) operand address, fetching d if needed
+    fn mem_operand<B: Bus>(&mut self, bus: &mut B, ix: Index) -> u16 {
+        match ix {
+            Index::Hl => self.hl(),
+            _ => {
+                let d = self.fetch(bus) as i8;
+                self.idx(ix).wrapping_add(d as i16 as u16)
+            }
+        }
+    }
+
+    /// Execute one instruction, returning the T-states it took
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.halted {
+            self.cycles += 4;
+            return 4;
+        }
+        let t = self.exec(bus, Index::Hl, 0);
+        self.cycles += t as u64;
+        t
+    }
+
+    fn exec<B: Bus>(&mut self, bus: &mut B, ix: Index, extra: u32) -> u32 {
+        self.inc_r();
+        let op = self.fetch(bus);
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+        let t: u32 = match x {
+            0 => match z {
+                0 => match y {
+                    0 => 4,
+                    1 => {
+                        let af = self.af();
+                        self.set_af(self.af_alt);
+                        self.af_alt = af;
+                        4
+                    }
+                    2 => {
+                        let d = self.fetch(bus) as i8;
+                        self.b = self.b.wrapping_sub(1);
+                        if self.b != 0 {
+                            self.pc = self.pc.wrapping_add(d as i16 as u16);
+                            13
+                        } else {
+                            8
+                        }
+                    }
+                    3 => {
+                        let d = self.fetch(bus) as i8;
+                        self.pc = self.pc.wrapping_add(d as i16 as u16);
+                        12
+                    }
+                    _ => {
+                        let d = self.fetch(bus) as i8;
+                        if self.cond(y - 4) {
+                            self.pc = self.pc.wrapping_add(d as i16 as u16);
+                            12
+                        } else {
+                            7
+                        }
+                    }
+                },
+                1 => {
+                    if q == 0 {
+                        let v = self.fetch_word(bus);
+                        self.set_rp(p, ix, v);
+                        10
+                    } else {
+                        let a = self.idx(ix);
+                        let b = self.rp(p, ix);
+                        let r = self.add16(a, b);
+                        self.set_idx(ix, r);
+                        11
+                    }
+                }
+                2 => match (q, p) {
+                    (0, 0) => {
+                        bus.write(self.bc(), self.a);
+                        7
+                    }
+                    (0, 1) => {
+                        bus.write(self.de(), self.a);
+                        7
+                    }
+                    (0, 2) => {
+                        let addr = self.fetch_word(bus);
+                        Self::write_word(bus, addr, self.idx(ix));
+                        16
+                    }
+                    (0, _) => {
+                        let addr = self.fetch_word(bus);
+                        bus.write(addr, self.a);
+                        13
+                    }
+                    (_, 0) => {
+                        self.a = bus.read(self.bc());
+                        7
+                    }
+                    (_, 1) => {
+                        self.a = bus.read(self.de());
+                        7
+                    }
+                    (_, 2) => {
+                        let addr = self.fetch_word(bus);
+                        let v = Self::read_word(bus, addr);
+                        self.set_idx(ix, v);
+                        16
+                    }
+                    (_, _) => {
+                        let addr = self.fetch_word(bus);
+                        self.a = bus.read(addr);
+                        13
+                    }
+                },
+                3 => {
+                    let v = self.rp(p, ix);
+                    let v = if q == 0 { v.wrapping_add(1) } else { v.wrapping_sub(1) };
+                    self.set_rp(p, ix, v);
+                    6
+                }
+                4 | 5 => {
+                    if y == 6 {
+                        let addr = self.mem_operand(bus, ix);
+                        let v = bus.read(addr);
+                        let r = if z == 4 { self.inc8(v) } else { self.dec8(v) };
+                        bus.write(addr, r);
+                        if ix == Index::Hl {
+                            11
+                        } else {
+                            19
+                        }
+                    } else {
+                        let v = self.reg(y, ix);
+                        let r = if z == 4 { self.inc8(v) } else { self.dec8(v) };
+                        self.set_reg(y, ix, r);
+                        4
+                    }
+                }
+                6 => {
+                    if y == 6 {
+                        let addr = self.mem_operand(bus, ix);
+                        let v = self.fetch(bus);
+                        bus.write(addr, v);
+                        if ix == Index::Hl {
+                            10
+                        } else {
+                            15
+                        }
+                    } else {
+                        let v = self.fetch(bus);
+                        self.set_reg(y, ix, v);
+                        7
+                    }
+                }
+                _ => {
+                    match y {
+                        0..=3 => self.rot_a(y),
+                        4 => self.daa(),
+                        5 => {
+                            self.a = !self.a;
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV | FLAG_C))
+                                | FLAG_H
+                                | FLAG_N
+                                | (self.a & (FLAG_X | FLAG_Y));
+                        }
+                        6 => {
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV))
+                                | FLAG_C
+                                | (self.a & (FLAG_X | FLAG_Y));
+                        }
+                        _ => {
+                            let c = self.flag(FLAG_C);
+                            self.f = (self.f & (FLAG_S | FLAG_Z | FLAG_PV))
+                                | (self.a & (FLAG_X | FLAG_Y))
+                                | if c { FLAG_H } else { FLAG_C };
+                        }
+                    }
+                    4
+                }
+            },
+            1 => {
+                if y == 6 && z == 6 {
+                    self.halted = true;
+                    4
+                } else if y == 6 {
+                    let addr = self.mem_operand(bus, ix);
+                    let v = self.reg(z, Index::Hl);
+                    bus.write(addr, v);
+                    if ix == Index::Hl {
+                        7
+                    } else {
+                        15
+                    }
+                } else if z == 6 {
+                    let addr = self.mem_operand(bus, ix);
+                    let v = bus.read(addr);
+                    self.set_reg(y, Index::Hl, v);
+                    if ix == Index::Hl {
+                        7
+                    } else {
+                        15
+                    }
+                } else {
+                    let v = self.reg(z, ix);
+                    self.set_reg(y, ix, v);
+                    4
+                }
+            }
+            2 => {
+                if z == 6 {
+                    let addr = self.mem_operand(bus, ix);
+                    let v = bus.read(addr);
+                    self.alu(y, v);
+                    if ix == Index::Hl {
+                        7
+                    } else {
+                        15
+                    }
+                } else {
+                    let v = self.reg(z, ix);
+                    self.alu(y, v);
+                    4
+                }
+            }
+            _ => match z {
+                0 => {
+                    if self.cond(y) {
+                        self.pc = self.pop(bus);
+                        11
+                    } else {
+                        5
+                    }
+                }
+                1 => {
+                    if q == 0 {
+                        let v = self.pop(bus);
+                        self.set_rp2(p, ix, v);
+                        10
+                    } else {
+                        match p {
+                            0 => {
+                                self.pc = self.pop(bus);
+                                10
+                            }
+                            1 => {
+                                let (bc, de, hl) = (self.bc(), self.de(), self.hl());
+                                self.set_bc(self.bc_alt);
+                                self.set_de(self.de_alt);
+                                self.set_hl(self.hl_alt);
+                                self.bc_alt = bc;
+                                self.de_alt = de;
+                                self.hl_alt = hl;
+                                4
+                            }
+                            2 => {
+                                self.pc = self.idx(ix);
+                                4
+                            }
+                            _ => {
+                                self.sp = self.idx(ix);
+                                6
+                            }
+                        }
+                    }
+                }
+                2 => {
+                    let addr = self.fetch_word(bus);
+                    if self.cond(y) {
+                        self.pc = addr;
+                    }
+                    10
+                }
+                3 => match y {
+                    0 => {
+                        self.pc = self.fetch_word(bus);
+                        10
+                    }
+                    1 => return self.exec_cb(bus, ix) + extra,
+                    2 => {
+                        let n = self.fetch(bus);
+                        bus.port_out(u16::from_be_bytes([self.a, n]), self.a);
+                        11
+                    }
+                    3 => {
+                        let n = self.fetch(bus);
+                        self.a = bus.port_in(u16::from_be_bytes([self.a, n]));
+                        11
+                    }
+                    4 => {
+                        let v = Self::read_word(bus, self.sp);
+                        Self::write_word(bus, self.sp, self.idx(ix));
+                        self.set_idx(ix, v);
+                        19
+                    }
+                    5 => {
+                        let de = self.de();
+                        self.set_de(self.hl());
+                        self.set_hl(de);
+                        4
+                    }
+                    6 => {
+                        self.iff1 = false;
+                        self.iff2 = false;
+                        4
+                    }
+                    _ => {
+                        self.iff1 = true;
+                        self.iff2 = true;
+                        4
+                    }
+                },
+                4 => {
+                    let addr = self.fetch_word(bus);
+                    if self.cond(y) {
+                        let pc = self.pc;
+                        self.push(bus, pc);
+                        self.pc = addr;
+                        17
+                    } else {
+                        10
+                    }
+                }
+                5 => {
+                    if q == 0 {
+                        let v = self.rp2(p, ix);
+                        self.push(bus, v);
+                        11
+                    } else {
+                        match p {
+                            0 => {
+                                let addr = self.fetch_word(bus);
+                                let pc = self.pc;
+                                self.push(bus, pc);
+                                self.pc = addr;
+                                17
+                            }
+                            1 => return self.exec(bus, Index::Ix, extra + 4),
+                            2 => return self.exec_ed(bus) + extra,
+                            _ => return self.exec(bus, Index::Iy, extra + 4),
+                        }
+                    }
+                }
+                6 => {
+                    let v = self.fetch(bus);
+                    self.alu(y, v);
+                    7
+                }
+                _ => {
+                    let pc = self.pc;
+                    self.push(bus, pc);
+                    self.pc = (y as u16) * 8;
+                    11
+                }
+            },
+        };
+        t + extra
+    }
+
+    fn exec_cb<B: Bus>(&mut self, bus: &mut B, ix: Index) -> u32 {
+        // DDCB/FDCB put the displacement before the opcode
+        let addr = if ix == Index::Hl {
+            self.inc_r();
+            None
+        } else {
+            Some(self.mem_operand(bus, ix))
+        };
+        let op = self.fetch(bus);
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let (v, mem) = match addr {
+            Some(a) => (bus.read(a), Some(a)),
+            None if z == 6 => (bus.read(self.hl()), Some(self.hl())),
+            None => (self.reg(z, Index::Hl), None),
+        };
+        let result = match x {
+            0 => Some(self.rot(y, v)),
+            1 => {
+                self.bit(y, v);
+                None
+            }
+            2 => Some(v & !(1 << y)),
+            _ => Some(v | (1 << y)),
+        };
+        if let Some(r) = result {
+            match mem {
+                Some(a) => {
+                    bus.write(a, r);
+                    if ix != Index::Hl && z != 6 {
+                        self.set_reg(z, Index::Hl, r);
+                    }
+                }
+                None => self.set_reg(z, Index::Hl, r),
+            }
+        }
+        match (ix, mem, x) {
+            (Index::Hl, None, _) => 8,
+            (Index::Hl, Some(_), 1) => 12,
+            (Index::Hl, Some(_), _) => 15,
+            (_, _, 1) => 20,
+            _ => 23,
+        }
+    }
+
+    fn exec_ed<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        self.inc_r();
+        let op = self.fetch(bus);
+        let x = op >> 6;
+        let y = (op >> 3) & 7;
+        let z = op & 7;
+        let p = y >> 1;
+        let q = y & 1;
+        match x {
+            1 => match z {
+                0 => {
+                    let v = bus.port_in(self.bc());
+                    self.f = (self.f & FLAG_C) | szp(v);
+                    if y != 6 {
+                        self.set_reg(y, Index::Hl, v);
+                    }
+                    12
+                }
+                1 => {
+                    let v = if y == 6 { 0 } else { self.reg(y, Index::Hl) };
+                    bus.port_out(self.bc(), v);
+                    12
+                }
+                2 => {
+                    let hl = self.hl();
+                    let v = self.rp(p, Index::Hl);
+                    let r = if q == 0 { self.sbc16(hl, v) } else { self.adc16(hl, v) };
+                    self.set_hl(r);
+                    15
+                }
+                3 => {
+                    let addr = self.fetch_word(bus);
+                    if q == 0 {
+                        let v = self.rp(p, Index::Hl);
+                        Self::write_word(bus, addr, v);
+                    } else {
+                        let v = Self::read_word(bus, addr);
+                        self.set_rp(p, Index::Hl, v);
+                    }
+                    20
+                }
+                4 => {
+                    let a = self.a;
+                    self.a = 0;
+                    self.alu(2, a);
+                    8
+                }
+                5 => {
+                    self.pc = self.pop(bus);
+                    self.iff1 = self.iff2;
+                    14
+                }
+                6 => {
+                    self.im = match y & 3 {
+                        2 => 1,
+                        3 => 2,
+                        _ => 0,
+                    };
+                    8
+                }
+                _ => match y {
+                    0 => {
+                        self.i = self.a;
+                        9
+                    }
+                    1 => {
+                        self.r = self.a;
+                        9
+                    }
+                    2 | 3 => {
+                        self.a = if y == 2 { self.i } else { self.r };
+                        let mut f = (self.f & FLAG_C) | (self.a & (FLAG_S | FLAG_X | FLAG_Y));
+                        if self.a == 0 {
+                            f |= FLAG_Z;
+                        }
+                        if self.iff2 {
+                            f |= FLAG_PV;
+                        }
+                        self.f = f;
+                        9
+                    }
+                    4 | 5 => {
+                        let hl = self.hl();
+                        let m = bus.read(hl);
+                        let (nm, na) = if y == 4 {
+                            // RRD
+                            ((self.a << 4) | (m >> 4), (self.a & 0xF0) | (m & 0x0F))
+                        } else {
+                            // RLD
+                            ((m << 4) | (self.a & 0x0F), (self.a & 0xF0) | (m >> 4))
+                        };
+                        bus.write(hl, nm);
+                        self.a = na;
+                        self.f = (self.f & FLAG_C) | szp(na);
+                        18
+                    }
+                    _ => 8,
+                },
+            },
+            2 if z <= 3 && y >= 4 => self.block_op(bus, y, z),
+            _ => 8,
+        }
+    }
+
+    fn block_op<B: Bus>(&mut self, bus: &mut B, y: u8, z: u8) -> u32 {
+        let dec = y & 1 == 1;
+        let repeat = y >= 6;
+        let step = |v: u16| if dec { v.wrapping_sub(1) } else { v.wrapping_add(1) };
+        match z {
+            0 => {
+                // LDI/LDD/LDIR/LDDR
+                let v = bus.read(self.hl());
+                bus.write(self.de(), v);
+                self.set_hl(step(self.hl()));
+                self.set_de(step(self.de()));
+                self.set_bc(self.bc().wrapping_sub(1));
+                let mut f = self.f & (FLAG_S | FLAG_Z | FLAG_C);
+                if self.bc() != 0 {
+                    f |= FLAG_PV;
+                }
+                self.f = f;
+                if repeat && self.bc() != 0 {
+                    self.pc = self.pc.wrapping_sub(2);
+                    21
+                } else {
+                    16
+                }
+            }
+            1 => {
+                // CPI/CPD/CPIR/CPDR
+                let v = bus.read(self.hl());
+                let c = self.f & FLAG_C;
+                let (r, f) = self.sub_flags(v, false);
+                self.set_hl(step(self.hl()));
+                self.set_bc(self.bc().wrapping_sub(1));
+                let mut f = (f & (FLAG_S | FLAG_Z | FLAG_H)) | FLAG_N | c;
+                if self.bc() != 0 {
+                    f |= FLAG_PV;
+                }
+                self.f = f;
+                if repeat && self.bc() != 0 && r != 0 {
+                    self.pc = self.pc.wrapping_sub(2);
+                    21
+                } else {
+                    16
+                }
+            }
+            2 => {
+                // INI/IND/INIR/INDR
+                let v = bus.port_in(self.bc());
+                bus.write(self.hl(), v);
+                self.set_hl(step(self.hl()));
+                self.b = self.b.wrapping_sub(1);
+                self.f = (self.f & FLAG_C) | FLAG_N | if self.b == 0 { FLAG_Z } else { 0 };
+                if repeat && self.b != 0 {
+                    self.pc = self.pc.wrapping_sub(2);
+                    21
+                } else {
+                    16
+                }
+            }
+            _ => {
+                // OUTI/OUTD/OTIR/OTDR
+                let v = bus.read(self.hl());
+                self.b = self.b.wrapping_sub(1);
+                bus.port_out(self.bc(), v);
+                self.set_hl(step(self.hl()));
+                self.f = (self.f & FLAG_C) | FLAG_N | if self.b == 0 { FLAG_Z } else { 0 };
+                if repeat && self.b != 0 {
+                    self.pc = self.pc.wrapping_sub(2);
+                    21
+                } else {
+                    16
+                }
+            }
+        }
+    }
+}